@@ -0,0 +1,14 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=proto/roinstxs.proto");
+    #[cfg(feature = "grpc")]
+    {
+        // no protoc needs to be installed to build the gRPC API
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/roinstxs.proto"], &["proto"])
+            .expect("proto/roinstxs.proto compiles");
+    }
+}
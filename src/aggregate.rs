@@ -0,0 +1,156 @@
+//! Rolling aggregates emitted at a fixed interval while the stream server runs.
+//!
+//! Each interval produces one JSON line with the ingest rate per transaction
+//! type, the money moved (sum of absolute changes to account totals) and the
+//! number of disputes opened. Unlike snapshots it never touches the account
+//! book, so it stays cheap however large the book grows.
+
+use crate::amount::Amount;
+use crate::engine::TxType;
+use crate::events::Event;
+use crate::loadgen::parse_duration;
+use crate::metrics::{Metrics, MetricsSnapshot};
+use anyhow::{Context, Result};
+use clap::Args;
+use serde_json::{json, Value};
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct AggregateArgs {
+    /// Emit rolling aggregates (rates by type, money moved, new disputes) this often, e.g. 10s
+    #[arg(long, global = true, value_parser = parse_duration)]
+    aggregate_every: Option<Duration>,
+    /// Append aggregates to this file as NDJSON, `-` for stdout
+    #[arg(long, global = true, value_name = "PATH", default_value = "-", requires = "aggregate_every")]
+    aggregate_out: PathBuf,
+}
+
+/// What the engine did during the current interval.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Window {
+    money_moved: Amount,
+    new_disputes: u64,
+}
+
+pub(crate) struct Aggregator {
+    every: Duration,
+    out: PathBuf,
+    window: Arc<Mutex<Window>>,
+}
+
+impl Aggregator {
+    pub(crate) fn new(args: &AggregateArgs) -> Option<Self> {
+        Some(Self {
+            every: args.aggregate_every?,
+            out: args.aggregate_out.clone(),
+            window: Arc::default(),
+        })
+    }
+
+    pub(crate) fn observer(&self) -> impl FnMut(&Event) + Send + 'static {
+        let window = self.window.clone();
+        move |event: &Event| {
+            if let Event::BalanceChanged {
+                cause,
+                before,
+                after,
+                ..
+            } = event
+            {
+                let mut window = window.lock().unwrap();
+                window.money_moved += (after.total - before.total).abs();
+                if *cause == TxType::Dispute {
+                    window.new_disputes += 1;
+                }
+            }
+        }
+    }
+
+    pub(crate) async fn run(self, metrics: Arc<Metrics>) -> Result<()> {
+        let mut out: Box<dyn Write + Send> = if self.out.as_os_str() == "-" {
+            Box::new(LineWriter::new(std::io::stdout()))
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.out)
+                .with_context(|| format!("could not open {}", self.out.display()))?;
+            Box::new(LineWriter::new(file))
+        };
+
+        let mut ticker = tokio::time::interval(self.every);
+        ticker.tick().await;
+        let mut last = (Instant::now(), metrics.snapshot());
+        loop {
+            ticker.tick().await;
+            let now = (Instant::now(), metrics.snapshot());
+            let window = std::mem::take(&mut *self.window.lock().unwrap());
+            let line = aggregate(&last.1, &now.1, window, now.0 - last.0);
+            writeln!(out, "{line}")
+                .with_context(|| format!("could not write {}", self.out.display()))?;
+            last = now;
+        }
+    }
+}
+
+fn aggregate(prev: &MetricsSnapshot, now: &MetricsSnapshot, window: Window, elapsed: Duration) -> Value {
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+    let rate = |now: u64, prev: u64| now.saturating_sub(prev) as f64 / secs;
+    let tps: serde_json::Map<String, Value> = now
+        .processed
+        .iter()
+        .zip(&prev.processed)
+        .map(|((kind, n), (_, p))| (kind.as_str().to_owned(), json!(rate(*n, *p))))
+        .collect();
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    json!({
+        "at": at,
+        "interval_secs": secs,
+        "tps": tps,
+        "total_tps": rate(now.total_processed(), prev.total_processed()),
+        "rejected": now.rejected.saturating_sub(prev.rejected),
+        "money_moved": window.money_moved,
+        "new_disputes": window.new_disputes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Tx, TxEngine};
+
+    #[test]
+    fn test_interval_aggregate() {
+        let aggregator = Aggregator {
+            every: Duration::from_secs(1),
+            out: "-".into(),
+            window: Arc::default(),
+        };
+        let mut engine = TxEngine::new();
+        engine.subscribe(aggregator.observer());
+        let metrics = Metrics::default();
+
+        let prev = metrics.snapshot();
+        for line in ["deposit, 1, 1, 10.0", "withdrawal, 1, 2, 4.0", "dispute, 1, 1,"] {
+            let tx = Tx::from_str(line).unwrap();
+            metrics.record_processed(tx.tx_type());
+            engine.process_tx(tx).unwrap();
+        }
+        metrics.record_rejected("bad line".to_owned());
+
+        let window = *aggregator.window.lock().unwrap();
+        let line = aggregate(&prev, &metrics.snapshot(), window, Duration::from_secs(2));
+        assert_eq!(line["tps"]["deposit"], 0.5);
+        assert_eq!(line["total_tps"], 1.5);
+        assert_eq!(line["rejected"], 1);
+        assert_eq!(line["money_moved"], "14");
+        assert_eq!(line["new_disputes"], 1);
+    }
+}
@@ -0,0 +1,263 @@
+//! Fixed-point money amounts.
+//!
+//! Every amount the engine keeps is an [`Amount`]: a whole number of
+//! ten-thousandths in an `i64`, so balances stay exact however many records
+//! move them, where `f64` sums drift. Amounts are parsed from decimal text,
+//! which may not be more precise than four decimal places nor use exponents,
+//! and written back with trailing zeros trimmed, `10` and `2.5` as before. In
+//! JSON they are decimal text too, read back from text or whole numbers, so
+//! no amount passes through an `f64` on its way in or out. Balances move by
+//! checked sums, a record that would take one out of range being refused.
+//! Ratios, rates and the WebAssembly and script APIs work on `f64` and
+//! convert at their boundary.
+
+use anyhow::Result;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+/// Decimal places kept.
+pub(crate) const SCALE: u32 = 4;
+const UNIT: i64 = 10_i64.pow(SCALE);
+
+/// A money amount exact to four decimal places, parsed from and displayed as
+/// decimal text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const ZERO: Self = Self(0);
+
+    /// `units` whole currency units.
+    #[cfg(test)]
+    pub(crate) const fn from_units(units: i64) -> Self {
+        Self(units * UNIT)
+    }
+
+    /// The amount nearest to `value`, `None` for values that are not finite
+    /// or out of range.
+    pub fn from_f64(value: f64) -> Option<Self> {
+        let scaled = (value * UNIT as f64).round();
+        // i64::MAX as f64 rounds up, out of range itself
+        (scaled.is_finite() && scaled.abs() < i64::MAX as f64).then_some(Self(scaled as i64))
+    }
+
+    /// The `f64` nearest to the amount.
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / UNIT as f64
+    }
+
+    pub fn abs(self) -> Self {
+        Self(self.0.abs())
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// `self + other`, `None` when out of range.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    /// `self - other`, `None` when out of range.
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        anyhow::ensure!(
+            !(whole.is_empty() && fraction.is_empty())
+                && whole
+                    .bytes()
+                    .chain(fraction.bytes())
+                    .all(|b| b.is_ascii_digit()),
+            "invalid amount {s:?}"
+        );
+        let out_of_range = || anyhow::anyhow!("amount {s:?} is out of range");
+        let mut scaled: i64 = match whole {
+            "" => 0,
+            whole => whole.parse::<i64>().map_err(|_| out_of_range())?,
+        };
+        scaled = scaled.checked_mul(UNIT).ok_or_else(out_of_range)?;
+        let (kept, rest) = fraction.split_at(fraction.len().min(SCALE as usize));
+        anyhow::ensure!(
+            rest.bytes().all(|b| b == b'0'),
+            "amount {s:?} has more than {SCALE} decimal places"
+        );
+        let mut unit = UNIT;
+        for digit in kept.bytes().map(|b| i64::from(b - b'0')) {
+            unit /= 10;
+            scaled = scaled.checked_add(digit * unit).ok_or_else(out_of_range)?;
+        }
+        Ok(Self(if negative { -scaled } else { scaled }))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let unit = UNIT as u64;
+        let (whole, fraction) = (abs / unit, abs % unit);
+        // padded as a whole, so that `{:>16}` aligns amounts like numbers
+        if fraction == 0 {
+            return f.pad(&format!("{sign}{whole}"));
+        }
+        let fraction = format!("{fraction:0width$}", width = SCALE as usize);
+        f.pad(&format!("{sign}{whole}.{}", fraction.trim_end_matches('0')))
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// decimal text, or a whole number, never a float that may have rounded
+struct AmountVisitor;
+
+impl Visitor<'_> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a decimal amount")
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Amount, E> {
+        value
+            .checked_mul(UNIT)
+            .map(Amount)
+            .ok_or_else(|| E::custom(format!("amount {value} is out of range")))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Amount, E> {
+        let value = i64::try_from(value)
+            .map_err(|_| E::custom(format!("amount {value} is out of range")))?;
+        self.visit_i64(value)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Amount, E> {
+        value.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
+
+impl Add for Amount {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self(self.0 + other.0)
+    }
+}
+
+impl Sub for Amount {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self(self.0 - other.0)
+    }
+}
+
+impl Neg for Amount {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self(-self.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Self) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, other: Self) {
+        self.0 -= other.0;
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::ZERO, Add::add)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_display_and_exact_sums() {
+        let amount = |s: &str| s.parse::<Amount>().unwrap();
+        for (text, shown) in [
+            ("10", "10"),
+            ("10.0", "10"),
+            ("2.5", "2.5"),
+            ("+.25", "0.25"),
+            ("-3.1416", "-3.1416"),
+            ("1.23450", "1.2345"),
+            ("-0.0001", "-0.0001"),
+        ] {
+            assert_eq!(amount(text).to_string(), shown, "{text}");
+        }
+        assert_eq!(format!("{:>6}|", amount("2.5")), "   2.5|");
+        for bad in [
+            "",
+            "-",
+            ".",
+            "1e3",
+            "0.00015",
+            "NaN",
+            "inf",
+            "1.2.3",
+            "99999999999999999",
+            "922337203685477.9999",
+        ] {
+            assert!(bad.parse::<Amount>().is_err(), "{bad}");
+        }
+
+        // 0.1 ten thousand times, which drifts as f64
+        let sum: Amount = std::iter::repeat_n(amount("0.1"), 10_000).sum();
+        assert_eq!(sum, Amount::from_units(1000));
+        // JSON carries decimal text, which no f64 rounds on the way
+        for text in [
+            "1234.5678",
+            "99999999999.9999",
+            "123456789012345.6789",
+            "-900000000000000",
+        ] {
+            let json = serde_json::to_string(&amount(text)).unwrap();
+            assert_eq!(json, format!("\"{text}\""));
+            assert_eq!(serde_json::from_str::<Amount>(&json).unwrap(), amount(text));
+        }
+        assert_eq!(
+            serde_json::from_str::<Amount>("12").unwrap(),
+            Amount::from_units(12)
+        );
+        assert!(serde_json::from_str::<Amount>("\"ten\"").is_err());
+        assert!(serde_json::from_str::<Amount>("2.5").is_err());
+        assert_eq!(Amount(i64::MAX).checked_add(amount("0.0001")), None);
+        assert_eq!(Amount(i64::MIN).checked_sub(amount("0.0001")), None);
+    }
+}
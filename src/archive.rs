@@ -0,0 +1,253 @@
+//! Archival of idle accounts, bounding the engine's memory in server mode.
+//!
+//! With `--archive-after` an account that saw no record for that many records
+//! (e.g. `100000`) or that long (e.g. `7d`) and holds no funds is moved,
+//! together with its transactions, out of the engine into an archive backend.
+//! The next record for the client restores it first, so archiving never
+//! changes a balance; it only makes an idle client's next record slower.
+//! Archived accounts are missing from summaries, snapshots and the HTTP API
+//! until restored.
+//!
+//! The file backend appends one JSON line per archived account to
+//! `--archive-path` and keeps only an offset per client in memory; restored
+//! lines are blanked, so erased clients leave nothing behind. The file is
+//! truncated on startup, since it is only meaningful next to the engine that
+//! wrote it.
+
+use crate::engine::{Account, Tx};
+use crate::loadgen::parse_duration;
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// How long an account must be idle before it is archived.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Inactivity {
+    /// Records processed by the engine since the client's last one.
+    Records(u64),
+    /// Engine clock time since the client's last record.
+    Time(Duration),
+}
+
+fn parse_inactivity(s: &str) -> Result<Inactivity> {
+    match s.parse() {
+        Ok(records) => Ok(Inactivity::Records(records)),
+        Err(_) => parse_duration(s).map(Inactivity::Time),
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct ArchiveArgs {
+    /// Archive accounts idle for this many records, or this long (e.g. 7d), holding no funds
+    #[arg(
+        long,
+        global = true,
+        value_name = "RECORDS|DURATION",
+        value_parser = parse_inactivity,
+        requires = "archive_path"
+    )]
+    archive_after: Option<Inactivity>,
+    /// File archived accounts are written to
+    #[arg(long, global = true, value_name = "PATH", requires = "archive_after")]
+    archive_path: Option<PathBuf>,
+}
+
+impl ArchiveArgs {
+    pub(crate) fn archive(&self) -> Result<Option<Archive>> {
+        let (Some(after), Some(path)) = (self.archive_after, &self.archive_path) else {
+            return Ok(None);
+        };
+        let backend = FileArchive::create(path.clone())?;
+        Ok(Some(Archive::new(after, Box::new(backend))))
+    }
+}
+
+/// An account moved out of the engine, with the transactions it owned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchivedAccount {
+    pub(crate) account: Account,
+    /// Transactions with the time they were recorded, when the engine tracks it.
+    pub(crate) txs: Vec<(Tx, Option<SystemTime>)>,
+}
+
+/// Where archived accounts are kept.
+pub(crate) trait ArchiveBackend: Send {
+    fn store(&mut self, archived: &ArchivedAccount) -> Result<()>;
+    /// Removes and returns the client's archived account, if any.
+    fn take(&mut self, client: u16) -> Result<Option<ArchivedAccount>>;
+}
+
+pub(crate) struct FileArchive {
+    path: PathBuf,
+    file: File,
+    // offset of the line holding each client's account
+    index: HashMap<u16, u64>,
+}
+
+impl FileArchive {
+    fn create(path: PathBuf) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        Ok(Self {
+            path,
+            file,
+            index: HashMap::new(),
+        })
+    }
+}
+
+impl ArchiveBackend for FileArchive {
+    fn store(&mut self, archived: &ArchivedAccount) -> Result<()> {
+        let mut line = serde_json::to_vec(archived)?;
+        line.push(b'\n');
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        self.file
+            .write_all(&line)
+            .with_context(|| format!("could not write {}", self.path.display()))?;
+        self.index.insert(archived.account.client, offset);
+        Ok(())
+    }
+
+    fn take(&mut self, client: u16) -> Result<Option<ArchivedAccount>> {
+        let Some(offset) = self.index.remove(&client) else {
+            return Ok(None);
+        };
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        BufReader::new(&self.file).read_line(&mut line)?;
+        let archived = serde_json::from_str(&line)
+            .with_context(|| format!("corrupted archive {}", self.path.display()))?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file
+            .write_all(&vec![b' '; line.trim_end().len()])
+            .with_context(|| format!("could not write {}", self.path.display()))?;
+        Ok(Some(archived))
+    }
+}
+
+/// The archival policy plus per-client activity it is judged on.
+pub(crate) struct Archive {
+    after: Inactivity,
+    backend: Box<dyn ArchiveBackend>,
+    // engine sequence number and clock time of each hot client's last record
+    last_active: HashMap<u16, (u64, SystemTime)>,
+}
+
+impl Archive {
+    pub(crate) fn new(after: Inactivity, backend: Box<dyn ArchiveBackend>) -> Self {
+        Self {
+            after,
+            backend,
+            last_active: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn touch(&mut self, client: u16, seq: u64, now: SystemTime) {
+        self.last_active.insert(client, (seq, now));
+    }
+
+    /// Clients idle for longer than the policy allows.
+    pub(crate) fn idle(&self, seq: u64, now: SystemTime) -> Vec<u16> {
+        self.last_active
+            .iter()
+            .filter(|(_, &(last_seq, last_at))| match self.after {
+                Inactivity::Records(n) => seq - last_seq > n,
+                Inactivity::Time(d) => now.duration_since(last_at).is_ok_and(|idle| idle > d),
+            })
+            .map(|(&client, _)| client)
+            .collect()
+    }
+
+    pub(crate) fn store(&mut self, archived: &ArchivedAccount) -> Result<()> {
+        self.backend.store(archived)?;
+        self.last_active.remove(&archived.account.client);
+        Ok(())
+    }
+
+    pub(crate) fn take(&mut self, client: u16) -> Result<Option<ArchivedAccount>> {
+        self.backend.take(client)
+    }
+
+    pub(crate) fn forget(&mut self, client: u16) {
+        self.last_active.remove(&client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::engine::TxEngine;
+
+    #[test]
+    fn test_file_archive_round_trip() {
+        let path = std::env::temp_dir().join(format!("roinstxs-archive-{}", std::process::id()));
+        let mut archive = FileArchive::create(path.clone()).unwrap();
+        for client in [1, 2] {
+            let archived = ArchivedAccount {
+                account: Account {
+                    client,
+                    available: Amount::from_units(5),
+                    total: Amount::from_units(5),
+                    ..Default::default()
+                },
+                txs: vec![(
+                    Tx::from_str(&format!("deposit, {client}, {client}, 5.0")).unwrap(),
+                    None,
+                )],
+            };
+            archive.store(&archived).unwrap();
+        }
+
+        let restored = archive.take(2).unwrap().unwrap();
+        assert_eq!(restored.account.client, 2);
+        assert_eq!(restored.txs[0].0.tx_id(), 2);
+        assert!(archive.take(2).unwrap().is_none());
+        assert_eq!(archive.take(1).unwrap().unwrap().account.total, Amount::from_units(5));
+        assert!(std::fs::read_to_string(&path).unwrap().trim().is_empty());
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(parse_inactivity("100").unwrap(), Inactivity::Records(100));
+        assert_eq!(
+            parse_inactivity("2h").unwrap(),
+            Inactivity::Time(Duration::from_secs(7200))
+        );
+    }
+
+    #[test]
+    fn test_engine_archives_idle_accounts_and_restores_them() {
+        let path = std::env::temp_dir().join(format!("roinstxs-archive-engine-{}", std::process::id()));
+        let backend = FileArchive::create(path.clone()).unwrap();
+        let mut engine = TxEngine::new();
+        engine.set_archive(Archive::new(Inactivity::Records(100), Box::new(backend)));
+
+        engine
+            .process_tx(Tx::from_str("deposit, 1, 1, 10.0").unwrap())
+            .unwrap();
+        for tx in 2..1100 {
+            engine
+                .process_tx(Tx::from_str(&format!("deposit, 2, {tx}, 1.0")).unwrap())
+                .unwrap();
+        }
+        assert!(engine.account(1).is_none());
+        assert_eq!(engine.retained().accounts, 1);
+
+        // the dispute needs tx 1, which comes back with the account
+        engine
+            .process_tx(Tx::from_str("dispute, 1, 1,").unwrap())
+            .unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available, account.held), (Amount::ZERO, Amount::from_units(10)));
+        std::fs::remove_file(path).unwrap();
+    }
+}
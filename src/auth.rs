@@ -0,0 +1,134 @@
+//! Shared-secret authentication of stream producers.
+//!
+//! With `--stream-token` every connection to a `tcp://`, `tcp+proxy://`,
+//! `tls://` or `unix://` listener must open with an `AUTH <token>` line, ahead
+//! of any directive. Connections opening with anything else, or not sending
+//! a line within `TIMEOUT`, are answered `ERR unauthenticated`, recorded as a
+//! rejection and closed. `http://` listeners take the token as an
+//! `Authorization: Bearer <token>` header and answer requests without it with
+//! 401. The token travels in the clear on `tcp://` listeners, so producers
+//! outside a trusted network should use `tls://` ones. `--stream-key`
+//! signatures are checked on the records of authenticated connections as
+//! before. The gRPC API checks the token on every call likewise, see `grpc`,
+//! and the `--http` API on the routes erasing, locking and unlocking
+//! accounts, see `http`. Replicas of `--replicate-listen` open with the same
+//! line, see `replication`. Raft members open their connections to one another
+//! with an `AUTH` line of their own `--raft-token`, see `raft`.
+
+use crate::metrics::Metrics;
+use clap::Args;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub(crate) const AUTH_COMMAND: &str = "AUTH ";
+pub(crate) const UNAUTHENTICATED: &str = "unauthenticated";
+// a producer's time to send the `AUTH` line
+const TIMEOUT: Duration = Duration::from_secs(10);
+// longest `AUTH` line read, so that a peer cannot make the server buffer
+// an endless one
+const MAX_LINE: u64 = 4096;
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct AuthArgs {
+    /// Shared secret stream producers must open connections with, as an
+    /// `AUTH <token>` line
+    #[arg(long, env = "ROINSTXS_STREAM_TOKEN", hide_env_values = true)]
+    stream_token: Option<String>,
+}
+
+impl AuthArgs {
+    pub(crate) fn token(&self) -> Option<Arc<str>> {
+        self.stream_token.as_deref().map(Arc::from)
+    }
+}
+
+/// Whether `sent` is `token`. Digests are compared, so that the time taken
+/// tells nothing about how much of the token a guess got right.
+pub(crate) fn is_token(sent: &str, token: &str) -> bool {
+    Sha256::digest(sent) == Sha256::digest(token)
+}
+
+/// Whether an `authorization` header `value` is `Bearer <token>`.
+pub(crate) fn is_bearer(value: Option<&str>, token: &str) -> bool {
+    value
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|sent| is_token(sent, token))
+}
+
+/// Reads the `AUTH` line a connection opens with, answering
+/// `ERR unauthenticated` on `replies` and recording a rejection in `metrics`
+/// unless it carries `token`.
+pub(crate) async fn handshake<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut R,
+    replies: &mut W,
+    token: &str,
+    metrics: &Metrics,
+) -> bool {
+    let authenticated = read_auth(reader, token).await;
+    if !authenticated {
+        metrics.record_rejected(format!("connection: {UNAUTHENTICATED}"));
+        let _ = replies
+            .write_all(format!("ERR {UNAUTHENTICATED}\n").as_bytes())
+            .await;
+    }
+    authenticated
+}
+
+/// Whether the first line `reader` sends within `TIMEOUT` is
+/// `AUTH <token>`.
+pub(crate) async fn read_auth<R: AsyncBufRead + Unpin>(reader: &mut R, token: &str) -> bool {
+    let mut line = String::new();
+    let read = tokio::time::timeout(TIMEOUT, reader.take(MAX_LINE).read_line(&mut line)).await;
+    matches!(read, Ok(Ok(_)))
+        && line
+            .trim_end()
+            .strip_prefix(AUTH_COMMAND)
+            .is_some_and(|sent| is_token(sent, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_stream;
+    use crate::drain::Draining;
+    use crate::TxEngine;
+    use tokio::sync::Mutex;
+
+    async fn send(input: &'static [u8]) -> (String, Arc<Mutex<TxEngine>>) {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let engine = Arc::new(Mutex::new(TxEngine::new()));
+        let serving = tokio::spawn(csv_stream::serve_connection(
+            server,
+            "producer".to_owned(),
+            Some(Arc::from("s3cret")),
+            engine.clone(),
+            Arc::new(Metrics::default()),
+            None,
+            Draining::never(),
+        ));
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        serving.await.unwrap();
+        (replies, engine)
+    }
+
+    #[tokio::test]
+    async fn test_connections_must_open_with_the_token() {
+        let (replies, engine) = send(b"AUTH s3cret\n#ack\ndeposit, 1, 1, 10.0\n").await;
+        assert_eq!(replies, "OK 1\n");
+        assert!(engine.lock().await.account(1).is_some());
+
+        for input in [
+            &b"AUTH guess\ndeposit, 1, 1, 10.0\n"[..],
+            b"deposit, 1, 1, 10.0\nAUTH s3cret\n",
+        ] {
+            let (replies, engine) = send(input).await;
+            assert_eq!(replies, "ERR unauthenticated\n");
+            assert!(engine.lock().await.account(1).is_none());
+        }
+    }
+}
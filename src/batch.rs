@@ -0,0 +1,300 @@
+//! Coalescing of stream records into batches applied under one engine lock.
+//!
+//! Records read from a connection are verified and parsed as they arrive but
+//! applied in batches of up to `--batch-size`, the engine being locked once
+//! per batch rather than once per record. A batch is applied once full, once
+//! its first record has waited `--batch-flush`, and before anything that must
+//! see it applied: a `QUERY`, the drain and the end of the connection. Acks
+//! of `#ack` connections go out in record order once their batch is applied.
+//! `--batch-size 1` applies every record as it arrives. `/api/metrics`
+//! reports how many batches were applied and their mean and largest size.
+//! A record carrying a `correlation_id` is answered once: the replies to the
+//! last `ANSWERED` such records are kept, and a record repeating the key,
+//! type, client and tx of one of them, as a producer resends after a broken
+//! connection, gets the same reply without being applied again.
+//! Under `--raft-id` a batch is proposed to the raft log instead, and applied
+//! once committed, see `raft`.
+
+use crate::engine::Tx;
+use crate::loadgen::parse_duration;
+use crate::metrics::Metrics;
+use crate::raft::{self, Proposer};
+use crate::TxEngine;
+use anyhow::{anyhow, Result};
+use clap::Args;
+use std::collections::{HashMap, VecDeque};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, Args)]
+pub(crate) struct BatchArgs {
+    /// Most stream records applied under one engine lock
+    #[arg(long, global = true, default_value_t = 64, value_parser = clap::value_parser!(u64).range(1..))]
+    batch_size: u64,
+    /// Longest a stream record waits for its batch to fill before it is applied
+    #[arg(long, global = true, value_parser = parse_duration, default_value = "1ms")]
+    batch_flush: Duration,
+}
+
+impl Default for BatchArgs {
+    fn default() -> Self {
+        Self {
+            batch_size: 64,
+            batch_flush: Duration::from_millis(1),
+        }
+    }
+}
+
+// only set once the command line is parsed
+static CONFIG: OnceLock<BatchArgs> = OnceLock::new();
+
+/// Keyed records whose replies are kept for producers resending them.
+const ANSWERED: usize = 100_000;
+
+static REPLIES: std::sync::Mutex<Option<Replies>> = std::sync::Mutex::new(None);
+
+// replies to the last `ANSWERED` keyed records, the tx id or why it was
+// rejected or refused
+#[derive(Default)]
+struct Replies {
+    replies: HashMap<String, Result<u32, String>>,
+    order: VecDeque<String>,
+}
+
+// what identifies a resent record: its idempotency key along with what it is
+fn idempotency_key(tx: &Tx) -> Option<String> {
+    let key = tx.meta()?.correlation_id.as_deref()?;
+    let (kind, client, tx_id) = (tx.type_name(), tx.client(), tx.tx_id());
+    Some(format!("{key} {kind} {client} {tx_id}"))
+}
+
+// the stored reply of every record answered before, by position
+fn replayed(keys: &[Option<String>]) -> Vec<Option<Result<u32>>> {
+    let replies = REPLIES.lock().unwrap_or_else(|e| e.into_inner());
+    keys.iter()
+        .map(|key| {
+            let reply = replies.as_ref()?.replies.get(key.as_ref()?)?;
+            Some(reply.clone().map_err(|reason| anyhow!(reason)))
+        })
+        .collect()
+}
+
+fn remember(keys: Vec<Option<String>>, applied: &[Result<u32>]) {
+    let mut replies = REPLIES.lock().unwrap_or_else(|e| e.into_inner());
+    let replies = replies.get_or_insert_with(Replies::default);
+    for (key, reply) in keys.into_iter().zip(applied) {
+        let Some(key) = key else { continue };
+        if replies.replies.contains_key(&key) {
+            continue;
+        }
+        if replies.order.len() == ANSWERED {
+            if let Some(oldest) = replies.order.pop_front() {
+                replies.replies.remove(&oldest);
+            }
+        }
+        let reply = reply.as_ref().copied().map_err(|err| format!("{err:#}"));
+        replies.replies.insert(key.clone(), reply);
+        replies.order.push_back(key);
+    }
+}
+
+pub(crate) fn configure(args: BatchArgs) {
+    CONFIG.get_or_init(|| args);
+}
+
+/// Records of one connection waiting to be applied, each parsed or the
+/// reason it was rejected.
+pub(crate) struct Batch {
+    records: Vec<Result<Tx>>,
+    deadline: Instant,
+    config: BatchArgs,
+}
+
+impl Batch {
+    pub(crate) fn new() -> Self {
+        let config = CONFIG.get().copied().unwrap_or_default();
+        Self {
+            records: Vec::with_capacity(config.batch_size as usize),
+            deadline: Instant::now(),
+            config,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// When the batch must be applied, if not full by then.
+    pub(crate) fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Adds a record, returning whether the batch is full.
+    pub(crate) fn push(&mut self, record: Result<Tx>) -> bool {
+        if self.records.is_empty() {
+            self.deadline = Instant::now() + self.config.batch_flush;
+        }
+        self.records.push(record);
+        self.records.len() as u64 >= self.config.batch_size
+    }
+
+    /// Applies the parsed records and empties the batch, returning for every
+    /// record in order its tx id or why it was rejected or refused.
+    pub(crate) async fn apply(
+        &mut self,
+        engine: &Mutex<TxEngine>,
+        metrics: &Metrics,
+    ) -> Vec<Result<u32>> {
+        let records = std::mem::take(&mut self.records);
+        let keys: Vec<_> = records
+            .iter()
+            .map(|r| r.as_ref().ok().and_then(idempotency_key))
+            .collect();
+        let replayed = replayed(&keys);
+        let fresh = records
+            .into_iter()
+            .zip(&replayed)
+            .filter(|(_, replay)| replay.is_none())
+            .map(|(record, _)| record)
+            .collect();
+        let mut applied = apply_records(fresh, engine, metrics).await.into_iter();
+        let answers: Vec<_> = replayed
+            .into_iter()
+            .map(|replay| replay.unwrap_or_else(|| applied.next().expect("one answer per record")))
+            .collect();
+        remember(keys, &answers);
+        answers
+    }
+}
+
+// `Batch::apply` of the records not answered before
+async fn apply_records(
+    records: Vec<Result<Tx>>,
+    engine: &Mutex<TxEngine>,
+    metrics: &Metrics,
+) -> Vec<Result<u32>> {
+    let parsed = records.iter().filter(|r| r.is_ok()).count();
+    if parsed == 0 {
+        return records
+            .into_iter()
+            .map(|r| r.map(|tx| tx.tx_id()))
+            .collect();
+    }
+    if let Some(raft) = raft::proposer() {
+        return replicate(raft, records, parsed, metrics).await;
+    }
+    let mut engine = engine.lock().await;
+    let started = std::time::Instant::now();
+    let applied = records
+        .into_iter()
+        .map(|record| {
+            let tx = record?;
+            let (kind, tx_id) = (tx.tx_type(), tx.tx_id());
+            let applying = std::time::Instant::now();
+            engine.process_tx(tx)?;
+            metrics.record_latency(applying.elapsed());
+            metrics.record_processed(kind);
+            Ok(tx_id)
+        })
+        .collect();
+    drop(engine);
+    metrics.record_batch(parsed, started.elapsed());
+    applied
+}
+
+// `Batch::apply` through the raft log
+async fn replicate(
+    raft: &Proposer,
+    records: Vec<Result<Tx>>,
+    parsed: usize,
+    metrics: &Metrics,
+) -> Vec<Result<u32>> {
+    let started = std::time::Instant::now();
+    let mut txs = Vec::with_capacity(parsed);
+    let records: Vec<Result<_>> = records
+        .into_iter()
+        .map(|record| {
+            let tx = record?;
+            let ids = (tx.tx_type(), tx.tx_id());
+            txs.push(tx);
+            Ok(ids)
+        })
+        .collect();
+    let mut committed = raft.records(txs).await.into_iter();
+    let elapsed = started.elapsed();
+    let applied = records
+        .into_iter()
+        .map(|record| {
+            let (kind, tx_id) = record?;
+            committed.next().expect("one answer per record")?;
+            metrics.record_latency(elapsed);
+            metrics.record_processed(kind);
+            Ok(tx_id)
+        })
+        .collect();
+    metrics.record_batch(parsed, elapsed);
+    applied
+}
+
+/// Applies one record on its own, through the raft log under `--raft-id`,
+/// returning its tx id once applied.
+pub(crate) async fn apply_one(tx: Tx, engine: &Mutex<TxEngine>, metrics: &Metrics) -> Result<u32> {
+    let (kind, tx_id) = (tx.tx_type(), tx.tx_id());
+    let applying = match raft::proposer() {
+        Some(raft) => {
+            let applying = std::time::Instant::now();
+            raft.record(tx).await?;
+            applying
+        }
+        None => {
+            let mut engine = engine.lock().await;
+            let applying = std::time::Instant::now();
+            engine.process_tx(tx)?;
+            applying
+        }
+    };
+    metrics.record_latency(applying.elapsed());
+    metrics.record_processed(kind);
+    Ok(tx_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+
+    #[tokio::test]
+    async fn test_batch_applies_in_order_under_one_lock() {
+        let engine = Mutex::new(TxEngine::new());
+        let metrics = Metrics::default();
+        let mut batch = Batch {
+            records: Vec::new(),
+            deadline: Instant::now(),
+            config: BatchArgs {
+                batch_size: 3,
+                batch_flush: Duration::from_secs(1),
+            },
+        };
+        assert!(!batch.push(Tx::from_str("deposit, 1, 1, 10.0").map_err(Into::into)));
+        assert!(batch.deadline() > Instant::now());
+        assert!(!batch.push(Tx::from_str("deposit, x, 2, 1.0").map_err(Into::into)));
+        assert!(batch.push(Tx::from_str("withdrawal, 1, 3, 4.0").map_err(Into::into)));
+
+        let applied = batch.apply(&engine, &metrics).await;
+        assert!(batch.is_empty());
+        assert_eq!(applied[0].as_ref().unwrap(), &1);
+        assert!(applied[1].is_err());
+        assert_eq!(applied[2].as_ref().unwrap(), &3);
+        assert_eq!(
+            engine.lock().await.account(1).unwrap().available,
+            Amount::from_units(6)
+        );
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            (snapshot.batches, snapshot.batched, snapshot.largest_batch),
+            (1, 2, 2)
+        );
+    }
+}
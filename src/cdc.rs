@@ -0,0 +1,301 @@
+//! Change data capture: one record per account mutation.
+//!
+//! Balance changes become `balance` records carrying the per-field deltas and
+//! the resulting balances, in the `currency` they moved once a record named
+//! one; freezes become `lock` records, closures `close`
+//! records and administrative holds `hold` records carrying their reason
+//! code. All name the transaction that caused them, so a downstream mirror
+//! can be kept exactly in sync by applying records in order. Erased clients get an `erase` tombstone
+//! telling the mirror to drop everything it holds about them. Records go out as NDJSON to a file or
+//! stdout, or to a Kafka topic keyed by client with the `kafka` feature.
+
+use crate::amount::Amount;
+use crate::engine::{LockInfo, TxType};
+use crate::events::{Balances, Event};
+use crate::log;
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum CdcRecord {
+    Balance {
+        client: u16,
+        tx: u32,
+        cause: TxType,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Box<str>>,
+        delta: Balances,
+        after: Balances,
+    },
+    Lock {
+        client: u16,
+        // records written before provenance was kept carry only `tx`
+        #[serde(flatten)]
+        lock: LockInfo,
+    },
+    Unlock {
+        client: u16,
+    },
+    Close {
+        client: u16,
+        tx: u32,
+    },
+    Hold {
+        client: u16,
+        tx: u32,
+        cause: TxType,
+        amount: Amount,
+        reason: Box<str>,
+    },
+    Erase {
+        client: u16,
+    },
+}
+
+impl CdcRecord {
+    pub(crate) fn from_event(event: &Event) -> Option<Self> {
+        match *event {
+            Event::BalanceChanged {
+                client,
+                tx,
+                cause,
+                ref currency,
+                before,
+                after,
+            } => Some(Self::Balance {
+                client,
+                tx,
+                cause,
+                currency: currency.clone(),
+                delta: Balances {
+                    available: after.available - before.available,
+                    held: after.held - before.held,
+                    total: after.total - before.total,
+                },
+                after,
+            }),
+            Event::AccountLocked { client, lock } => Some(Self::Lock { client, lock }),
+            Event::AccountUnlocked { client } => Some(Self::Unlock { client }),
+            Event::AccountClosed { client, tx } => Some(Self::Close { client, tx }),
+            Event::AdminHold {
+                client,
+                tx,
+                cause,
+                amount,
+                ref reason,
+            } => Some(Self::Hold {
+                client,
+                tx,
+                cause,
+                amount,
+                reason: reason.clone(),
+            }),
+            Event::ClientErased { client } => Some(Self::Erase { client }),
+            // already captured by the balance change they come with
+            Event::Chargeback { .. } | Event::MinimumBreached { .. } => None,
+        }
+    }
+
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    fn client(&self) -> u16 {
+        match self {
+            Self::Balance { client, .. }
+            | Self::Lock { client, .. }
+            | Self::Unlock { client }
+            | Self::Close { client, .. }
+            | Self::Hold { client, .. }
+            | Self::Erase { client } => *client,
+        }
+    }
+}
+
+/// Where CDC records are delivered.
+pub(crate) trait CdcSink: Send {
+    fn send(&mut self, record: &CdcRecord) -> Result<()>;
+}
+
+/// Writes one JSON object per line, flushing after each record.
+pub(crate) struct NdjsonSink<W: Write> {
+    writer: LineWriter<W>,
+}
+
+impl<W: Write> NdjsonSink<W> {
+    pub(crate) fn new(writer: W) -> Self {
+        Self {
+            writer: LineWriter::new(writer),
+        }
+    }
+}
+
+impl<W: Write + Send> CdcSink for NdjsonSink<W> {
+    fn send(&mut self, record: &CdcRecord) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use super::{CdcRecord, CdcSink};
+    use crate::log;
+    use anyhow::{Context, Result};
+    use rdkafka::producer::{BaseRecord, DefaultProducerContext, Producer, ThreadedProducer};
+    use rdkafka::ClientConfig;
+    use std::time::Duration;
+
+    /// Produces records keyed by client, so each client's changes stay ordered
+    /// within one partition. Delivery happens on librdkafka's own thread.
+    pub(crate) struct KafkaSink {
+        producer: ThreadedProducer<DefaultProducerContext>,
+        topic: String,
+    }
+
+    impl KafkaSink {
+        pub(crate) fn new(brokers: &str, topic: String) -> Result<Self> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .set("enable.idempotence", "true")
+                .create()
+                .with_context(|| format!("could not create kafka producer for {brokers}"))?;
+            Ok(Self { producer, topic })
+        }
+    }
+
+    impl CdcSink for KafkaSink {
+        fn send(&mut self, record: &CdcRecord) -> Result<()> {
+            let key = record.client().to_string();
+            let payload = serde_json::to_vec(record)?;
+            self.producer
+                .send(BaseRecord::to(&self.topic).key(&key).payload(&payload))
+                .map_err(|(err, _)| err)
+                .context("could not enqueue cdc record")
+        }
+    }
+
+    impl Drop for KafkaSink {
+        fn drop(&mut self) {
+            if let Err(err) = self.producer.flush(Duration::from_secs(5)) {
+                log::error!("cdc: could not flush kafka producer: {err}");
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct CdcArgs {
+    /// Append a change record per account mutation to this file as NDJSON, `-` for stdout
+    #[arg(long, global = true, value_name = "PATH")]
+    cdc: Option<PathBuf>,
+    /// Produce change records to Kafka at these brokers, e.g. localhost:9092
+    #[cfg(feature = "kafka")]
+    #[arg(long, global = true, value_name = "BROKERS")]
+    cdc_kafka: Option<String>,
+    /// Kafka topic for change records
+    #[cfg(feature = "kafka")]
+    #[arg(long, global = true, default_value = "roinstxs.cdc")]
+    cdc_topic: String,
+}
+
+impl CdcArgs {
+    fn sinks(&self) -> Result<Vec<Box<dyn CdcSink>>> {
+        let mut sinks: Vec<Box<dyn CdcSink>> = Vec::new();
+        match &self.cdc {
+            Some(path) if path.as_os_str() == "-" => {
+                sinks.push(Box::new(NdjsonSink::new(std::io::stdout())));
+            }
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("could not open {}", path.display()))?;
+                sinks.push(Box::new(NdjsonSink::new(file)));
+            }
+            None => {}
+        }
+        #[cfg(feature = "kafka")]
+        if let Some(brokers) = &self.cdc_kafka {
+            sinks.push(Box::new(kafka::KafkaSink::new(brokers, self.cdc_topic.clone())?));
+        }
+        Ok(sinks)
+    }
+}
+
+/// Returns the engine observer feeding every configured sink, or `None` when
+/// change capture is off. Sink errors are logged and do not stop processing.
+pub(crate) fn observer(args: &CdcArgs) -> Result<Option<impl FnMut(&Event) + Send + 'static>> {
+    let mut sinks = args.sinks()?;
+    if sinks.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(move |event: &Event| {
+        let Some(record) = CdcRecord::from_event(event) else {
+            return;
+        };
+        for sink in &mut sinks {
+            if let Err(err) = sink.send(&record) {
+                log::error!("cdc: {err:#}");
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TxEngine;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_records_per_mutation() {
+        let out = Shared::default();
+        let mut sink = NdjsonSink::new(out.clone());
+        let mut engine = TxEngine::new();
+        engine.subscribe(move |event: &Event| {
+            if let Some(record) = CdcRecord::from_event(event) {
+                sink.send(&record).unwrap();
+            }
+        });
+
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "dispute, 1, 1,",
+            "chargeback, 1, 1,",
+            "withdrawal, 1, 2, 5.0",
+        ] {
+            engine.apply_line(line);
+        }
+
+        let bytes = out.0.lock().unwrap().clone();
+        let records: Vec<serde_json::Value> = String::from_utf8(bytes)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let ops: Vec<&str> = records.iter().map(|r| r["op"].as_str().unwrap()).collect();
+        assert_eq!(ops, ["balance", "balance", "balance", "lock"]);
+        assert_eq!(records[1]["delta"]["available"], "-10");
+        assert_eq!(records[1]["delta"]["held"], "10");
+        assert_eq!(records[2]["after"]["total"], "0");
+        assert_eq!(records[2]["cause"], "chargeback");
+    }
+}
@@ -0,0 +1,143 @@
+//! Fault injection for exercising failure handling outside of production.
+//!
+//! Only compiled with the `chaos` feature. Faults are configured through the
+//! `ROINSTXS_CHAOS` environment variable, a comma separated list such as
+//! `write_fail=0.01,flush_delay_ms=50,drop=0.001,seed=42`:
+//!
+//! - `write_fail`: probability that a write to a wrapped sink or storage file fails
+//! - `flush_delay_ms`: upper bound of a random delay added to every flush
+//! - `drop`: probability that a connection is dropped after reading a line
+//! - `seed`: makes the fault sequence reproducible (defaults to the current time)
+
+use crate::log;
+use crate::rng::XorShift;
+use anyhow::{Context, Result};
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const ENV_VAR: &str = "ROINSTXS_CHAOS";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ChaosConfig {
+    write_fail: f64,
+    flush_delay_ms: u64,
+    drop: f64,
+    seed: Option<u64>,
+}
+
+impl ChaosConfig {
+    fn parse(spec: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .with_context(|| format!("expected key=value, got {pair}"))?;
+            let value = value.trim();
+            match key.trim() {
+                "write_fail" => config.write_fail = value.parse()?,
+                "flush_delay_ms" => config.flush_delay_ms = value.parse()?,
+                "drop" => config.drop = value.parse()?,
+                "seed" => config.seed = Some(value.parse()?),
+                other => anyhow::bail!("unknown chaos setting {other}"),
+            }
+        }
+        Ok(config)
+    }
+}
+
+struct Chaos {
+    config: ChaosConfig,
+    rng: Mutex<XorShift>,
+}
+
+fn chaos() -> &'static Chaos {
+    static CHAOS: OnceLock<Chaos> = OnceLock::new();
+    CHAOS.get_or_init(|| {
+        let config = match std::env::var(ENV_VAR) {
+            Ok(spec) => ChaosConfig::parse(&spec).unwrap_or_else(|err| {
+                log::warn!("ignoring invalid {ENV_VAR}: {err}");
+                ChaosConfig::default()
+            }),
+            Err(_) => ChaosConfig::default(),
+        };
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+        Chaos {
+            config,
+            rng: Mutex::new(XorShift::new(seed)),
+        }
+    })
+}
+
+fn roll(p: f64) -> bool {
+    chaos().rng.lock().unwrap().chance(p)
+}
+
+/// Whether the connection currently being read should be dropped.
+pub(crate) fn should_drop_connection() -> bool {
+    roll(chaos().config.drop)
+}
+
+fn flush_delay() -> Option<Duration> {
+    let max = chaos().config.flush_delay_ms;
+    if max == 0 {
+        return None;
+    }
+    let ms = chaos().rng.lock().unwrap().below(max + 1);
+    Some(Duration::from_millis(ms))
+}
+
+/// Wraps a sink or storage writer, failing writes and slowing flushes
+/// according to the configured fault rates.
+pub(crate) struct ChaosWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> ChaosWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self { inner }
+    }
+}
+
+impl<W: Write> Write for ChaosWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if roll(chaos().config.write_fail) {
+            return Err(io::Error::other("chaos: injected write failure"));
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(delay) = flush_delay() {
+            std::thread::sleep(delay);
+        }
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config =
+            ChaosConfig::parse("write_fail=0.5, flush_delay_ms=10,drop=0.01,seed=7").unwrap();
+        assert_eq!(
+            config,
+            ChaosConfig {
+                write_fail: 0.5,
+                flush_delay_ms: 10,
+                drop: 0.01,
+                seed: Some(7),
+            }
+        );
+        assert!(ChaosConfig::parse("explode=1").is_err());
+        assert!(ChaosConfig::parse("").unwrap() == ChaosConfig::default());
+    }
+}
@@ -0,0 +1,146 @@
+//! Parallel parsing of transaction files, a chunk of lines at a time.
+//!
+//! With `--parse-threads N` the lines of CSV and JSON lines files are read in
+//! chunks of `CHUNK` lines, and each chunk is parsed on a pool of N threads
+//! while the next ones are read. Parsed chunks are handed on in file order,
+//! so the engine applies the records exactly as it would have parsing them
+//! one by one, only without waiting for the parser. Up to `IN_FLIGHT` chunks
+//! are read ahead, bounding the memory held by lines not yet applied.
+//!
+//! Records are split at newlines, so a quoted CSV field cannot span lines.
+
+use crate::engine::Tx;
+use crate::input::Record;
+use anyhow::Result;
+use rayon::prelude::*;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+
+// lines parsed by one task
+const CHUNK: usize = 16 * 1024;
+// chunks read ahead of the one being applied
+const IN_FLIGHT: usize = 8;
+
+/// Starts the pool of `threads` threads parsing chunks.
+pub(crate) fn configure(threads: usize) -> Result<()> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("parse-{i}"))
+        .build_global()
+        .map_err(|err| anyhow::anyhow!("could not start the parser threads: {err}"))
+}
+
+type Parse = dyn Fn(&str) -> Result<Tx> + Send + Sync;
+
+/// Records of numbered `lines`, parsed by `parse` on the pool in chunks and
+/// yielded in order. A read error ends the records after those of the lines
+/// before it.
+pub(crate) struct Chunked<I> {
+    lines: I,
+    parse: Arc<Parse>,
+    in_flight: VecDeque<Receiver<Vec<Record>>>,
+    ready: std::vec::IntoIter<Record>,
+    // the read error, or none, once `lines` ended
+    end: Option<Option<io::Error>>,
+}
+
+impl<I: Iterator<Item = io::Result<(usize, String)>>> Chunked<I> {
+    pub(crate) fn new(
+        lines: I,
+        parse: impl Fn(&str) -> Result<Tx> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            lines,
+            parse: Arc::new(parse),
+            in_flight: VecDeque::new(),
+            ready: Vec::new().into_iter(),
+            end: None,
+        }
+    }
+
+    // reads the next chunk and has the pool parse it
+    fn read_chunk(&mut self) {
+        let mut chunk = Vec::with_capacity(CHUNK);
+        while chunk.len() < CHUNK {
+            match self.lines.next() {
+                Some(Ok(line)) => chunk.push(line),
+                Some(Err(err)) => {
+                    self.end = Some(Some(err));
+                    break;
+                }
+                None => {
+                    self.end = Some(None);
+                    break;
+                }
+            }
+        }
+        if chunk.is_empty() {
+            return;
+        }
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let parse = self.parse.clone();
+        rayon::spawn(move || {
+            let records = chunk
+                .into_par_iter()
+                .map(|(line_no, line)| Record::new(line_no, parse(&line), || line))
+                .collect();
+            let _ = sender.send(records);
+        });
+        self.in_flight.push_back(receiver);
+    }
+}
+
+impl<I: Iterator<Item = io::Result<(usize, String)>>> Iterator for Chunked<I> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.ready.next() {
+                return Some(Ok(record));
+            }
+            while self.end.is_none() && self.in_flight.len() < IN_FLIGHT {
+                self.read_chunk();
+            }
+            match self.in_flight.pop_front() {
+                Some(parsed) => {
+                    // parsing panicked, like it would have on this thread
+                    let records = parsed.recv().expect("a parser thread panicked");
+                    self.ready = records.into_iter();
+                }
+                None => return self.end.take().flatten().map(|err| Err(err.into())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_keep_file_order() {
+        let lines = (1..=3 * CHUNK + 5).map(|line_no| {
+            let line = match line_no % 10 {
+                0 => "deposit, 1, x, 1.0".to_owned(),
+                _ => format!("deposit, 1, {line_no}, 1.0"),
+            };
+            Ok((line_no, line))
+        });
+        let broken = std::iter::once(Err(io::Error::other("disk gone")));
+        let parse = |line: &str| Ok(line.parse::<Tx>()?);
+        let records: Vec<_> = Chunked::new(lines.chain(broken), parse).collect();
+
+        assert_eq!(records.len(), 3 * CHUNK + 6);
+        for (i, record) in records[..3 * CHUNK + 5].iter().enumerate() {
+            let record = record.as_ref().unwrap();
+            assert_eq!(record.line_no, i + 1);
+            match record.line_no % 10 {
+                0 => assert!(record.tx.is_err()),
+                _ => assert_eq!(record.tx.as_ref().unwrap().tx_id() as usize, i + 1),
+            }
+        }
+        assert!(records.last().unwrap().is_err());
+    }
+}
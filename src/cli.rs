@@ -0,0 +1,776 @@
+//! The `roinstxs` command line: processing files and serving streams with
+//! the options of every module.
+
+#[cfg(feature = "chaos")]
+use crate::chaos;
+use crate::engine::*;
+use crate::exit::{ErrorFormat, Failure};
+#[cfg(feature = "grpc")]
+use crate::grpc;
+#[cfg(unix)]
+use crate::handoff;
+#[cfg(feature = "kafka")]
+use crate::kafka_source;
+use crate::log;
+use crate::output::{Output, OutputFormat, Sink};
+#[cfg(feature = "scripting")]
+use crate::script;
+#[cfg(feature = "wasm")]
+use crate::wasm;
+use crate::{
+    aggregate, anonymize, archive, batch, cdc, client, crypt, daily, drain, exit, ha, http, input,
+    limits, listen, loadgen, manifest, merge, metrics, notify, order, partition, raft, recent,
+    reconcile, redact, rejects, repl, replay, replication, report, router, schedule, shard, signing,
+    snapshot, snapshot_diff, soak, statsd, store, template, tui, wal,
+};
+use anyhow::{Result, Context};
+use clap::{CommandFactory, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+/// Applies every transaction of `file_path` to `tx_engine`, returning how many
+/// unparsable lines were skipped under `lenient`.
+pub(crate) fn process_file(
+    tx_engine: &mut TxEngine,
+    file_path: &Path,
+    lenient: bool,
+) -> Result<usize> {
+    // records the engine refuses are logged and reported by it
+    for_each_tx(file_path, lenient, |tx| {
+        let _ = tx_engine.process_tx(tx);
+    })
+}
+
+/// Parses `file_path` in its input format (see `input`) and hands every
+/// transaction to `on_tx` in input order.
+pub(crate) fn for_each_tx(
+    file_path: &Path,
+    lenient: bool,
+    mut on_tx: impl FnMut(Tx),
+) -> Result<usize> {
+    let mut skipped = 0;
+
+    // line numbers double as the records' sequence numbers
+    for record in input::records(file_path)? {
+        let input::Record { line_no, tx, input } = record?;
+
+        let tx = match tx {
+            Ok(tx) => tx,
+            Err(err) if lenient => {
+                log::warn!("skipping line {}: {:#}", line_no, err);
+                rejects::unparsed(Some(line_no), input.as_deref().unwrap_or_default(), &err);
+                skipped += 1;
+                continue;
+            }
+            Err(err) => {
+                rejects::unparsed(Some(line_no), input.as_deref().unwrap_or_default(), &err);
+                return Err(err)
+                    .context(format!("line {}: could not convert str to Tx", line_no))
+                    .context(Failure::Parse);
+            }
+        };
+        on_tx(tx.with_seq(line_no as u64));
+    }
+    Ok(skipped)
+}
+
+/// How `reader_loop` applies the records of the file.
+enum Apply {
+    Engine,
+    /// Writing a summary per business day on the way.
+    Daily(daily::DailySummaries),
+    /// Across shards, merged into the engine once the file is read.
+    Sharded(shard::ShardedTxEngine),
+}
+
+fn reader_loop(
+    mut tx_engine: TxEngine,
+    file_path: &Path,
+    mut sink: Sink,
+    output: &Output,
+    lenient: bool,
+    manifest: Option<manifest::Manifest>,
+    apply: Apply,
+) -> Result<()> {
+    let skipped = match apply {
+        Apply::Engine => process_file(&mut tx_engine, file_path, lenient)?,
+        Apply::Daily(mut daily) => daily.process(&mut tx_engine, file_path, lenient, output)?,
+        Apply::Sharded(mut shards) => {
+            let skipped = for_each_tx(file_path, lenient, |tx| shards.process_tx(tx))?;
+            tx_engine = shards.finish(tx_engine)?;
+            skipped
+        }
+    };
+    let stdout = &mut sink;
+    #[cfg(feature = "chaos")]
+    let stdout = chaos::ChaosWriter::new(stdout);
+    let mut stdout = manifest::HashingWriter::new(stdout);
+    output.write(&tx_engine, &mut stdout)?;
+    if let Some(manifest) = manifest {
+        let counts = manifest::RunCounts {
+            records: tx_engine.seq(),
+            skipped,
+        };
+        manifest.write(file_path, counts, &stdout)?;
+    }
+    sink.commit()?;
+
+    if skipped > 0 {
+        return Err(anyhow::anyhow!("skipped {} unparsable lines", skipped))
+            .context(Failure::Partial);
+    }
+    Ok(())
+}
+
+
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Transactions file (CSV, JSON lines or Parquet, optionally gzipped), like `file <FILE>`;
+    /// starts the TCP server when omitted, like `serve`
+    file: Option<PathBuf>,
+
+    /// Format of transaction files and streamed lines, detected from magic bytes and extension,
+    /// or per line, by default
+    #[arg(long, value_enum, global = true, default_value_t)]
+    input_format: input::InputFormat,
+
+    /// Parse the lines of CSV and JSON lines files on this many threads, in chunks applied in
+    /// file order
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    parse_threads: Option<u16>,
+
+    /// Skip unparsable lines instead of aborting; exits with the partial-success code
+    #[arg(long, global = true)]
+    lenient: bool,
+
+    /// Summary format written to stdout
+    #[arg(long, global = true, value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// Write the summary to this file instead of stdout (`-`), replacing it only once complete;
+    /// in server mode the summary is written there when the server stops
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        conflicts_with = "partitions"
+    )]
+    output: Option<PathBuf>,
+
+    /// Row template of --format template, e.g. '{{client}}|{{total}}|{{locked}}'
+    #[arg(long, global = true, value_parser = template::Template::parse, required_if_eq("format", "template"))]
+    template: Option<template::Template>,
+
+    /// Write client ids in the summary and reports as salted hashes
+    #[arg(long, global = true, conflicts_with = "partitions")]
+    anonymize: bool,
+
+    /// Salt for --anonymize, keeping hashes stable across runs; random per run otherwise
+    #[arg(
+        long,
+        global = true,
+        env = "ROINSTXS_ANONYMIZE_SALT",
+        hide_env_values = true
+    )]
+    anonymize_salt: Option<String>,
+
+    /// Summarize each sub-account on its own row instead of rolling them up per client
+    #[arg(long, global = true, conflicts_with = "partitions")]
+    sub_accounts: bool,
+
+    /// Add the lock_tx, lock_rule and locked_at columns telling what locked each account
+    #[arg(long, global = true, conflicts_with_all = ["partitions", "sub_accounts"])]
+    lock_details: bool,
+
+    #[command(flatten)]
+    partition: partition::PartitionArgs,
+
+    #[command(flatten)]
+    report: report::ReportArgs,
+
+    #[command(flatten)]
+    manifest: manifest::ManifestArgs,
+
+    #[command(flatten)]
+    daily: daily::DailyArgs,
+
+    #[command(flatten)]
+    limits: limits::LimitArgs,
+
+    /// Hold records whose timestamp is in the future until the clock reaches it
+    #[arg(long, global = true)]
+    defer_future_dated: bool,
+
+    /// Ignore disputes of transactions older than this (e.g. 90d), by their timestamp or arrival
+    #[arg(long, global = true, value_name = "DURATION", value_parser = loadgen::parse_duration)]
+    dispute_window: Option<std::time::Duration>,
+
+    /// Drop stored transactions past --dispute-window unless disputed, bounding memory
+    #[arg(long, global = true, requires = "dispute_window")]
+    evict_expired: bool,
+
+    /// How disputes of withdrawals move funds
+    #[arg(long, global = true, value_enum, default_value_t)]
+    withdrawal_disputes: WithdrawalDisputes,
+
+    /// What happens to deposits and withdrawals of locked accounts
+    #[arg(long, global = true, value_enum, default_value_t)]
+    locked_policy: LockedPolicy,
+
+    /// Sort the summary by client and reject records that reach the engine out
+    /// of input order for their client, so parallel and single-threaded runs
+    /// print byte-identical summaries
+    #[arg(long, global = true)]
+    deterministic: bool,
+
+    /// Apply a file's records on this many threads, each owning the clients
+    /// `client % N` picks for it; transfers between shards are refused and a
+    /// tx id taken on two shards fails the run; not for stream servers, which
+    /// keep a single engine
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        conflicts_with = "daily_dir",
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    shards: Option<u16>,
+
+    /// Hash client ids and mask amounts in logs and recorded rejections
+    #[arg(long, global = true)]
+    redact: bool,
+
+    /// Disable colors in human output (also honours NO_COLOR)
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// Show a live dashboard while the TCP server runs
+    #[arg(long, global = true)]
+    tui: bool,
+
+    /// Shared HMAC-SHA256 key; stream records must then end with a signature column
+    #[arg(long, env = "ROINSTXS_STREAM_KEY", hide_env_values = true)]
+    stream_key: Option<String>,
+
+    /// Accept administrative `close`, `hold`, `release` and `unlock` records
+    /// from stream producers
+    #[arg(long, global = true)]
+    stream_admin_records: bool,
+
+    /// Also serve the HTTP API and web dashboard on this address, e.g. 127.0.0.1:8080
+    #[arg(long)]
+    http: Option<std::net::SocketAddr>,
+
+    #[command(flatten)]
+    listen: listen::ListenArgs,
+
+    #[command(flatten)]
+    drain: drain::DrainArgs,
+
+    #[command(flatten)]
+    batch: batch::BatchArgs,
+
+    #[command(flatten)]
+    notify: notify::NotifyArgs,
+
+    #[command(flatten)]
+    cdc: cdc::CdcArgs,
+
+    #[command(flatten)]
+    snapshots: snapshot::SnapshotArgs,
+
+    #[command(flatten)]
+    wal: wal::WalArgs,
+
+    #[command(flatten)]
+    store: store::StoreArgs,
+
+    #[command(flatten)]
+    encryption: crypt::KeyArgs,
+
+    #[command(flatten)]
+    aggregates: aggregate::AggregateArgs,
+
+    #[command(flatten)]
+    statsd: statsd::StatsdArgs,
+
+    #[command(flatten)]
+    recent: recent::RecentArgs,
+
+    #[command(flatten)]
+    rejects: rejects::RejectsArgs,
+
+    #[command(flatten)]
+    archive: archive::ArchiveArgs,
+
+    #[command(flatten)]
+    schedule: schedule::ScheduleArgs,
+
+    #[command(flatten)]
+    ha: ha::HaArgs,
+
+    #[command(flatten)]
+    replication: replication::ReplicationArgs,
+
+    #[command(flatten)]
+    raft: raft::RaftArgs,
+
+    #[cfg(unix)]
+    #[command(flatten)]
+    handoff: handoff::HandoffArgs,
+
+    #[cfg(feature = "kafka")]
+    #[command(flatten)]
+    kafka_source: kafka_source::KafkaSourceArgs,
+
+    #[cfg(feature = "grpc")]
+    #[command(flatten)]
+    grpc: grpc::GrpcArgs,
+
+    /// Handle a custom transaction type with a WebAssembly plugin, e.g. --plugin bonus=bonus.wasm
+    #[cfg(feature = "wasm")]
+    #[arg(long = "plugin", global = true, value_name = "TYPE=PATH", value_parser = wasm::parse_plugin_arg)]
+    plugins: Vec<(String, PathBuf)>,
+
+    /// Rhai script defining filter(tx, account) and/or after(tx, account) hooks
+    #[cfg(feature = "scripting")]
+    #[arg(long = "script", global = true, value_name = "PATH")]
+    scripts: Vec<PathBuf>,
+
+    /// How to print a fatal error on stderr
+    #[arg(long, value_enum, global = true, default_value_t)]
+    error_format: ErrorFormat,
+
+    /// Least severe lines logged on stderr: failures only, refused records and
+    /// connections too, or everything servers report
+    #[arg(long, value_enum, global = true, default_value_t)]
+    log_level: log::Level,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Process a transactions file and print the accounts summary; options may follow the file,
+    /// except --http, --listen, --stream-key, --stream-token and the encryption key options
+    File {
+        /// Transactions file (CSV, JSON lines or Parquet, optionally gzipped)
+        file: PathBuf,
+    },
+    /// Run the stream server until it is drained; options may follow the subcommand, except
+    /// --http, --listen, --stream-key, --stream-token and the encryption key options
+    Serve,
+    /// Stream synthetic transactions to a running server and report throughput
+    Loadgen(loadgen::LoadgenArgs),
+    /// Feed the engine synthetic load for a long period, asserting invariants and memory bounds
+    Soak(soak::SoakArgs),
+    /// Interactive shell for applying transactions and inspecting accounts
+    Repl,
+    /// Process transactions and diff the resulting balances against an expected balance file
+    Reconcile(reconcile::ReconcileArgs),
+    /// Print the plaintext of a file encrypted at rest, e.g. a snapshot
+    Decrypt(crypt::DecryptArgs),
+    /// Verify the summary is unchanged when clients' transactions are interleaved differently
+    CheckOrder(order::CheckOrderArgs),
+    /// Combine the disjoint summaries of several instances into one
+    Merge(merge::MergeArgs),
+    /// Report per-client balance, account and dispute-state differences between two snapshots
+    SnapshotDiff(snapshot_diff::SnapshotDiffArgs),
+    /// Rebuild the accounts from a --cdc change log, optionally checking them against a snapshot
+    Replay(replay::ReplayArgs),
+    /// Forward records to several stream servers by client id and merge their accounts
+    Route(router::RouteArgs),
+    /// Submit a transactions file to a running server, reporting rejected records
+    Submit(client::SubmitArgs),
+    /// Print accounts as a running server holds them now
+    Query(client::QueryArgs),
+    /// Print transactions as a running server holds them, with their dispute state and account
+    Transaction(http::TransactionArgs),
+    /// Print a shell completion script to stdout
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+}
+
+#[tokio::main]
+pub async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let error_format = cli.error_format;
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => exit::report(&err, error_format),
+    }
+}
+
+/// Builds an engine with every `--plugin` registered as a custom type handler
+/// and every `--script` installed as a hook, enforcing minimum balances and
+/// overdraft limits and publishing changes when `--cdc` is given.
+fn build_engine(cli: &Cli, shard: Option<usize>) -> Result<TxEngine> {
+    let mut engine = TxEngine::new();
+    engine.set_store(cli.store.store(shard)?);
+    engine.min_balance = cli.limits.min_balance()?;
+    if let Some(overdraft) = cli.limits.overdraft()? {
+        engine.set_overdraft(overdraft);
+    }
+    engine.defer_future_dated = cli.defer_future_dated;
+    engine.dispute_window = cli.dispute_window;
+    engine.evict_expired = cli.evict_expired;
+    engine.withdrawal_disputes = cli.withdrawal_disputes;
+    engine.locked_policy = cli.locked_policy;
+    #[cfg(feature = "wasm")]
+    for (type_name, path) in &cli.plugins {
+        engine.register_handler(type_name, wasm::WasmHandler::load(path)?);
+    }
+    #[cfg(feature = "scripting")]
+    for path in &cli.scripts {
+        engine.add_hook(Box::new(script::ScriptHook::load(path)?));
+    }
+    if let Some(observer) = cdc::observer(&cli.cdc)? {
+        engine.subscribe(observer);
+    }
+    Ok(engine)
+}
+
+/// How the summary is written, as `--format` and the options refining it ask.
+fn summary_output(cli: &Cli) -> Result<Output> {
+    let no_color = cli.no_color || !Sink::is_stdout(cli.output.as_deref());
+    let mut output = Output::new(cli.format, no_color);
+    output.partitioning = cli.partition.partitioning();
+    output.report = cli.report.report()?;
+    output.sorted = cli.deterministic;
+    output.sub_accounts = cli.sub_accounts;
+    output.template = cli.template.clone();
+    output.lock_details = cli.lock_details;
+    output.anonymizer = cli
+        .anonymize
+        .then(|| anonymize::Anonymizer::new(cli.anonymize_salt.as_deref()));
+    Ok(output)
+}
+
+async fn run(mut cli: Cli) -> Result<()> {
+    log::set_level(cli.log_level);
+    if cli.redact {
+        redact::enable();
+    }
+    input::set_format(cli.input_format);
+    if cli.stream_admin_records {
+        input::allow_admin_records();
+    }
+    if let Some(threads) = cli.parse_threads {
+        input::set_parse_threads(threads.into())?;
+    }
+    batch::configure(cli.batch);
+    cli.recent.install();
+    cli.rejects.install()?;
+    let engine = build_engine(&cli, None)?;
+    let manifest = cli.manifest.manifest(&format!("{cli:?}"));
+    match (cli.command.take(), cli.file.take()) {
+        (Some(Command::Loadgen(args)), _) => {
+            loadgen::run(args).await?;
+        }
+        (Some(Command::Soak(args)), _) => {
+            soak::run(args).await?;
+        }
+        (Some(Command::Reconcile(args)), _) => {
+            reconcile::run(args, engine)?;
+        }
+        (Some(Command::Decrypt(args)), _) => {
+            crypt::decrypt(args)?;
+        }
+        (Some(Command::CheckOrder(args)), _) => {
+            order::run(args)?;
+        }
+        (Some(Command::Merge(args)), _) => {
+            merge::run(args)?;
+        }
+        (Some(Command::SnapshotDiff(args)), _) => {
+            snapshot_diff::run(args)?;
+        }
+        (Some(Command::Replay(args)), _) => {
+            replay::run(args)?;
+        }
+        (Some(Command::Route(args)), _) => {
+            router::run(args).await?;
+        }
+        (Some(Command::Submit(args)), _) => {
+            client::submit(args).await?;
+        }
+        (Some(Command::Query(args)), _) => {
+            client::query(args).await?;
+        }
+        (Some(Command::Transaction(args)), _) => {
+            http::transaction(args).await?;
+        }
+        (Some(Command::Repl), _) => {
+            repl::run(cli.no_color)?;
+        }
+        (Some(Command::Completions { shell }), _) => {
+            let mut cmd = Cli::command();
+            let name = cmd.get_name().to_owned();
+            clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+        }
+        (Some(Command::File { .. } | Command::Serve), Some(file)) => {
+            anyhow::bail!("unexpected argument {} before the subcommand", file.display());
+        }
+        (Some(Command::File { file: file_path }), None) | (None, Some(file_path)) => {
+            let output = summary_output(&cli)?;
+            let mut engine = engine;
+            engine.deterministic = cli.deterministic;
+            if let Some(observer) = output.report.as_ref().and_then(|r| r.observer()) {
+                engine.subscribe(observer);
+            }
+            let apply = match (cli.daily.daily()?, cli.shards) {
+                (Some(daily), _) => Apply::Daily(daily),
+                (None, Some(shards)) => {
+                    let report = output.report.as_ref();
+                    let engines = (0..shards as usize)
+                        .map(|shard| {
+                            let mut engine = build_engine(&cli, Some(shard))?;
+                            engine.deterministic = cli.deterministic;
+                            if let Some(observer) = report.and_then(|r| r.observer()) {
+                                engine.subscribe(observer);
+                            }
+                            Ok(engine)
+                        })
+                        .collect::<Result<_>>()?;
+                    Apply::Sharded(shard::ShardedTxEngine::new(engines)?)
+                }
+                (None, None) => Apply::Engine,
+            };
+            let sink = Sink::open(cli.output.as_deref())?;
+            reader_loop(engine, &file_path, sink, &output, cli.lenient, manifest, apply)?;
+        }
+        (Some(Command::Serve), None) | (None, None) => {
+            anyhow::ensure!(
+                cli.shards.is_none(),
+                "--shards applies the records of a file, stream servers keep a single engine"
+            );
+            let mut engine = engine;
+            // written once the server stopped
+            let summary = match cli.output {
+                Some(_) => Some(summary_output(&cli)?),
+                None => None,
+            };
+            if let Some(observer) = summary.as_ref().and_then(|o| o.report.as_ref()?.observer()) {
+                engine.subscribe(observer);
+            }
+            let key = cli.encryption.key()?;
+            // the predecessor holds the leadership until it handed over
+            #[cfg(unix)]
+            let taken_over = handoff::take_over(&cli.handoff).await?;
+            let _leadership = cli.ha.lead().await?;
+            // as the previous leader left them, not as they were while standing by
+            cli.snapshots.restore(&mut engine, key.as_ref())?;
+            cli.wal.recover(&mut engine, key.as_ref())?;
+            let raft = cli.raft.open(&mut engine)?;
+            #[cfg(unix)]
+            if let Some(state) = taken_over {
+                engine.restore_state(state)?;
+            }
+            if let Some(observer) = notify::observer(&cli.notify) {
+                engine.subscribe(observer);
+            }
+            let snapshots = snapshot::Snapshots::new(&cli.snapshots, key.clone());
+            if let Some(observer) = snapshots.as_ref().map(|s| s.observer()) {
+                engine.subscribe(observer);
+            }
+            let aggregator = aggregate::Aggregator::new(&cli.aggregates);
+            if let Some(aggregator) = &aggregator {
+                engine.subscribe(aggregator.observer());
+            }
+            if let Some(archive) = cli.archive.archive()? {
+                engine.set_archive(archive);
+            }
+            let primary = replication::Primary::new(&cli.replication, cli.listen.token())?;
+            if let Some(primary) = &primary {
+                engine.subscribe(primary.observer());
+            }
+            #[cfg(feature = "grpc")]
+            let api = grpc::Api::new(&cli.grpc);
+            #[cfg(feature = "grpc")]
+            if let Some(api) = &api {
+                engine.subscribe(api.observer());
+            }
+            let exporter = statsd::Exporter::new(&cli.statsd)?;
+            let scheduler = cli.schedule.scheduler(engine.now())?;
+            let engine = Arc::new(tokio::sync::Mutex::new(engine));
+            let metrics = Arc::new(metrics::Metrics::default());
+            let drain = drain::Drain::new(&cli.drain);
+            #[cfg(unix)]
+            let handoff = handoff::Handoff::new(&cli.handoff);
+            let snapshotting = snapshots.is_some();
+
+            let http = async {
+                match cli.http {
+                    Some(addr) => {
+                        let token = cli.listen.token();
+                        http::serve(addr, engine.clone(), metrics.clone(), token).await
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            let dashboard = async {
+                match cli.tui {
+                    true => tui::run(engine.clone(), metrics.clone()).await,
+                    false => std::future::pending().await,
+                }
+            };
+            let snapshots = async {
+                match snapshots {
+                    Some(snapshots) => snapshots.run(engine.clone(), drain.draining()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let aggregates = async {
+                match aggregator {
+                    Some(aggregator) => aggregator.run(metrics.clone()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let statsd = async {
+                match exporter {
+                    Some(exporter) => exporter.run(engine.clone(), metrics.clone()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let schedule = async {
+                match scheduler {
+                    Some(scheduler) => scheduler.run(engine.clone(), metrics.clone()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let deferred = async {
+                match cli.defer_future_dated {
+                    true => schedule::release_pending(engine.clone()).await,
+                    false => std::future::pending().await,
+                }
+            };
+            let replication = async {
+                match primary {
+                    Some(primary) => primary.run(engine.clone(), metrics.clone()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let raft = async {
+                match raft {
+                    Some(raft) => raft.run(engine.clone()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let verifier = cli.stream_key.as_deref().map(|key| Arc::new(signing::Verifier::new(key)));
+            // a replica takes its accounts from the primary, not from producers
+            let ingest = async {
+                match cli.replication.replica_of() {
+                    Some(addr) => {
+                        replication::follow(addr, cli.listen.token(), engine.clone()).await
+                    }
+                    None => {
+                        let verifier = verifier.clone();
+                        let (engine, metrics) = (engine.clone(), metrics.clone());
+                        listen::run(&cli.listen, engine, metrics, verifier, drain.draining()).await
+                    }
+                }
+            };
+            let source = async {
+                #[cfg(feature = "kafka")]
+                if cli.kafka_source.enabled() {
+                    let (engine, metrics) = (engine.clone(), metrics.clone());
+                    return kafka_source::run(&cli.kafka_source, engine, metrics, verifier.clone()).await;
+                }
+                std::future::pending::<Result<()>>().await
+            };
+            let grpc = async {
+                #[cfg(feature = "grpc")]
+                if let Some(api) = api {
+                    let (engine, metrics) = (engine.clone(), metrics.clone());
+                    let token = cli.listen.token();
+                    return api.run(engine, metrics, verifier.is_some(), token).await;
+                }
+                std::future::pending::<Result<()>>().await
+            };
+            // a successor took the engine's state, leaving it empty
+            let handed_over = std::cell::Cell::new(false);
+            let drained = async {
+                #[cfg(unix)]
+                if let Some(successor) = drain.run(&metrics, handoff.successor()).await? {
+                    handed_over.set(true);
+                    return successor.hand_over(&engine).await;
+                }
+                #[cfg(not(unix))]
+                drain.run(&metrics, std::future::pending::<Result<()>>()).await?;
+                // the snapshots task ends the server once the final one is written
+                if snapshotting {
+                    std::future::pending::<()>().await;
+                }
+                anyhow::Ok(())
+            };
+            tokio::select! {
+                res = drained => res?,
+                res = ingest => res?,
+                res = source => res?,
+                res = http => res?,
+                res = grpc => res?,
+                res = dashboard => res?,
+                res = snapshots => res?,
+                res = aggregates => res?,
+                res = statsd => res?,
+                res = schedule => res?,
+                res = deferred => res?,
+                res = replication => res?,
+                res = raft => res?,
+            }
+            if handed_over.get() {
+                return Ok(());
+            }
+            let mut engine = engine.lock().await;
+            if let Some(output) = summary {
+                let mut sink = Sink::open(cli.output.as_deref())?;
+                output.write(&engine, &mut sink)?;
+                sink.commit()?;
+            }
+            if cli.snapshots.save(&engine, key.as_ref())? {
+                engine.truncate_wal()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_options_may_follow_the_subcommand() {
+        Cli::command().debug_assert();
+        let cli = Cli::parse_from(["roinstxs", "file", "x.csv", "--format", "json", "--lenient"]);
+        assert!(cli.format == OutputFormat::Json && cli.lenient);
+        let cli = Cli::parse_from(["roinstxs", "serve", "--log-level", "warn"]);
+        assert_eq!(cli.log_level, log::Level::Warn);
+    }
+
+    #[test]
+    fn test_non_positive_amounts_are_parse_errors() {
+        let path = std::env::temp_dir().join(format!("roinstxs-cli-{}.csv", std::process::id()));
+        let records = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            withdrawal,1,2,-1000\n\
+            deposit,2,3,0\n\
+            deposit,2,4,5\n";
+        std::fs::write(&path, records).unwrap();
+
+        let mut applied = Vec::new();
+        let skipped = for_each_tx(&path, true, |tx| applied.push(tx.tx_id())).unwrap();
+        assert_eq!((skipped, applied), (2, vec![1, 4]));
+
+        let err = for_each_tx(&path, false, |_| ()).unwrap_err();
+        assert!(format!("{err:#}").contains("line 3"), "{err:#}");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,457 @@
+//! Producer-side client for the stream server's line protocol.
+//!
+//! [`TxClient`] frames transactions as schema 2 records, signs them when
+//! given the stream key, and opens every connection with the `#ack`
+//! directive so each record is answered with `OK` or `ERR`, after an `AUTH`
+//! line when given the stream token. A broken
+//! connection is reopened and the records not yet answered are sent again,
+//! with backoff, up to `RETRIES` times. A draining server's `GOAWAY` makes it
+//! reconnect once the records in flight are answered.
+//!
+//! Every record carries an idempotency key in its `correlation_id` column,
+//! the transaction's own correlation id when it has one and a key unique to
+//! this client otherwise. A record sent again after a reconnect keeps its
+//! key, and the server answers a key it already answered with the same reply
+//! instead of applying the record twice, so a record applied before the
+//! connection broke is acked `OK` rather than refused as a duplicate.
+//!
+//! The `submit` and `query` subcommands are built on it.
+
+use crate::auth::{AUTH_COMMAND, UNAUTHENTICATED};
+use crate::csv_stream::{
+    ACK_DIRECTIVE, GOAWAY, QUERY_COMMAND, SUMMARY_COMMAND, SUMMARY_END, UNKNOWN_CLIENT,
+};
+use crate::engine::{Account, Tx, LOCK_COLUMNS};
+use crate::exit::Failure;
+use crate::log;
+use crate::reconcile::merge_summary_row;
+use crate::signing::Verifier;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+const RETRIES: u32 = 5;
+const BACKOFF: Duration = Duration::from_millis(200);
+
+/// The server's answer to one submitted record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ack {
+    /// The record reached the engine.
+    Accepted,
+    /// The record was rejected before reaching the engine, for this reason.
+    Rejected(String),
+}
+
+struct Connection {
+    replies: Lines<BufReader<OwnedReadHalf>>,
+    requests: BufWriter<OwnedWriteHalf>,
+    // the server is draining; reconnect once the answers are in
+    going_away: bool,
+}
+
+impl Connection {
+    async fn open(addr: SocketAddr, token: Option<&str>) -> Result<Self> {
+        let socket = TcpStream::connect(addr)
+            .await
+            .with_context(|| format!("could not connect to {addr}"))?;
+        let (reader, writer) = socket.into_split();
+        let mut connection = Self {
+            replies: BufReader::new(reader).lines(),
+            requests: BufWriter::new(writer),
+            going_away: false,
+        };
+        if let Some(token) = token {
+            let auth = format!("{AUTH_COMMAND}{token}\n");
+            connection.requests.write_all(auth.as_bytes()).await?;
+        }
+        connection
+            .requests
+            .write_all(format!("#schema=2\n{ACK_DIRECTIVE}\n").as_bytes())
+            .await?;
+        Ok(connection)
+    }
+
+    async fn reply(&mut self) -> Result<String> {
+        loop {
+            let reply = self
+                .replies
+                .next_line()
+                .await?
+                .context("server closed the connection")?;
+            if reply == format!("ERR {UNAUTHENTICATED}") {
+                anyhow::bail!("server refused the stream token");
+            }
+            if reply != GOAWAY {
+                return Ok(reply);
+            }
+            self.going_away = true;
+        }
+    }
+}
+
+/// Producer-side client of the stream server's line protocol, submitting
+/// [`Tx`] records with an idempotency key each and reading accounts back.
+/// Broken connections are reopened and unanswered records sent again.
+pub struct TxClient {
+    addr: SocketAddr,
+    connection: Option<Connection>,
+    token: Option<String>,
+    signer: Option<Verifier>,
+    // prefix and counter of generated idempotency keys
+    key_prefix: String,
+    next_key: u64,
+}
+
+impl TxClient {
+    /// Connects to the server at `addr`, opening connections with an `AUTH`
+    /// line carrying `token` when given.
+    pub async fn connect(addr: SocketAddr, token: Option<&str>) -> Result<Self> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        Ok(Self {
+            addr,
+            connection: Some(Connection::open(addr, token).await?),
+            token: token.map(str::to_owned),
+            signer: None,
+            key_prefix: format!("{:x}-{started:x}", std::process::id()),
+            next_key: 0,
+        })
+    }
+
+    /// Signs every record and query with the server's stream key.
+    pub fn with_key(mut self, key: &str) -> Self {
+        self.signer = Some(Verifier::new(key));
+        self
+    }
+
+    fn sign(&self, line: String) -> String {
+        match &self.signer {
+            Some(signer) => signer.sign(&line),
+            None => line,
+        }
+    }
+
+    // the schema 2 record of `tx`, carrying its idempotency key
+    fn frame(&mut self, tx: &Tx) -> String {
+        let meta = tx.meta().cloned().unwrap_or_default();
+        let key = match meta.correlation_id {
+            Some(id) => id.into_string(),
+            None => {
+                self.next_key += 1;
+                format!("{}-{}", self.key_prefix, self.next_key)
+            }
+        };
+        let column = |v: Option<&str>| v.unwrap_or_default().to_owned();
+        let line = [
+            tx.type_name().to_owned(),
+            tx.client().to_string(),
+            tx.tx_id().to_string(),
+            tx.amount().map(|a| a.to_string()).unwrap_or_default(),
+            meta.timestamp.map(|t| t.to_string()).unwrap_or_default(),
+            column(meta.currency.as_deref()),
+            key,
+            column(meta.reason.as_deref()),
+            column(meta.sub_account.as_deref()),
+        ]
+        .join(", ");
+        self.sign(line)
+    }
+
+    async fn connection(&mut self) -> Result<&mut Connection> {
+        match &mut self.connection {
+            Some(connection) => Ok(connection),
+            slot @ None => {
+                let connection = Connection::open(self.addr, self.token.as_deref()).await?;
+                Ok(slot.insert(connection))
+            }
+        }
+    }
+
+    // sends `lines` and collects their answers into `acks`, which keeps what
+    // was answered when the connection breaks
+    async fn exchange(&mut self, lines: &[String], acks: &mut Vec<Ack>) -> Result<()> {
+        let connection = self.connection().await?;
+        for line in lines {
+            connection.requests.write_all(line.as_bytes()).await?;
+            connection.requests.write_all(b"\n").await?;
+        }
+        connection.requests.flush().await?;
+        for _ in lines {
+            let reply = connection.reply().await?;
+            let ack = match reply.split_once(' ') {
+                Some(("OK", _)) => Ack::Accepted,
+                Some(("ERR", reason)) => Ack::Rejected(reason.to_owned()),
+                _ => anyhow::bail!("unexpected reply {reply:?}"),
+            };
+            acks.push(ack);
+        }
+        if connection.going_away {
+            self.connection = None;
+        }
+        Ok(())
+    }
+
+    /// Submits one transaction and waits for its answer.
+    pub async fn submit(&mut self, tx: &Tx) -> Result<Ack> {
+        let mut acks = self.submit_batch(std::slice::from_ref(tx)).await?;
+        Ok(acks.remove(0))
+    }
+
+    /// Submits `txs` in one write and returns their answers in order.
+    pub async fn submit_batch(&mut self, txs: &[Tx]) -> Result<Vec<Ack>> {
+        let lines: Vec<String> = txs.iter().map(|tx| self.frame(tx)).collect();
+        let mut acks = Vec::with_capacity(lines.len());
+        let mut attempt = 0;
+        while acks.len() < lines.len() {
+            let Err(err) = self.exchange(&lines[acks.len()..], &mut acks).await else {
+                continue;
+            };
+            self.connection = None;
+            attempt += 1;
+            if attempt > RETRIES {
+                return Err(err.context(format!("giving up after {RETRIES} reconnects")));
+            }
+            log::warn!("client: {err:#}, reconnecting");
+            tokio::time::sleep(BACKOFF * attempt).await;
+        }
+        Ok(acks)
+    }
+
+    /// The client's account as the engine holds it now, `None` when it has
+    /// none.
+    pub async fn query(&mut self, client: u16) -> Result<Option<Account>> {
+        let mut reply = self.command(format!("{QUERY_COMMAND}{client}")).await?;
+        match reply.strip_prefix("ERR ") {
+            Some(reason) if reason.starts_with(UNKNOWN_CLIENT) => return Ok(None),
+            Some(reason) => anyhow::bail!("query refused: {reason}"),
+            None => {}
+        }
+        let mut accounts = BTreeMap::new();
+        while reply != SUMMARY_END {
+            merge_summary_row(&mut accounts, &reply, with_currency(&reply))?;
+            reply = self.connection().await?.reply().await?;
+        }
+        Ok(accounts.remove(&client))
+    }
+
+    /// Every account as the engine holds it now, ordered by client.
+    pub async fn summary(&mut self) -> Result<Vec<Account>> {
+        let mut reply = self.command(SUMMARY_COMMAND.to_owned()).await?;
+        let mut accounts = BTreeMap::new();
+        while reply != SUMMARY_END {
+            if let Some(reason) = reply.strip_prefix("ERR ") {
+                anyhow::bail!("summary refused: {reason}");
+            }
+            merge_summary_row(&mut accounts, &reply, with_currency(&reply))?;
+            reply = self.connection().await?.reply().await?;
+        }
+        Ok(accounts.into_values().collect())
+    }
+
+    // sends `command`, returning the first line of its answer
+    async fn command(&mut self, command: String) -> Result<String> {
+        let line = self.sign(command);
+        let connection = self.connection().await?;
+        connection
+            .requests
+            .write_all(format!("{line}\n").as_bytes())
+            .await?;
+        connection.requests.flush().await?;
+        connection.reply().await
+    }
+}
+
+// whether a summary row of the server has a `currency` column, the rows of
+// accounts holding currencies having ten columns rather than nine
+fn with_currency(row: &str) -> bool {
+    row.split(',').count() == 10
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct SubmitArgs {
+    /// Transactions CSV to submit
+    file: PathBuf,
+    /// Stream server to submit to
+    #[arg(long, default_value = "127.0.0.1:6969")]
+    target: SocketAddr,
+    /// Records per write
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    batch: u64,
+    /// Key the server verifies records with
+    #[arg(long, env = "ROINSTXS_STREAM_KEY", hide_env_values = true)]
+    stream_key: Option<String>,
+    /// Token the server authenticates connections with
+    #[arg(long, env = "ROINSTXS_STREAM_TOKEN", hide_env_values = true)]
+    stream_token: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct QueryArgs {
+    /// Clients whose accounts to print, every account when none
+    clients: Vec<u16>,
+    /// Stream server to query
+    #[arg(long, default_value = "127.0.0.1:6969")]
+    target: SocketAddr,
+    /// Key the server verifies queries with
+    #[arg(long, env = "ROINSTXS_STREAM_KEY", hide_env_values = true)]
+    stream_key: Option<String>,
+    /// Token the server authenticates connections with
+    #[arg(long, env = "ROINSTXS_STREAM_TOKEN", hide_env_values = true)]
+    stream_token: Option<String>,
+}
+
+async fn client(target: SocketAddr, token: Option<&str>, key: Option<&str>) -> Result<TxClient> {
+    let client = TxClient::connect(target, token).await?;
+    Ok(match key {
+        Some(key) => client.with_key(key),
+        None => client,
+    })
+}
+
+/// Submits every record of the file, reporting rejected ones on stderr.
+pub(crate) async fn submit(args: SubmitArgs) -> Result<()> {
+    let mut txs = Vec::new();
+    let skipped = crate::cli::for_each_tx(&args.file, false, |tx| txs.push(tx))?;
+    debug_assert_eq!(skipped, 0);
+    let (token, key) = (args.stream_token.as_deref(), args.stream_key.as_deref());
+    let mut client = client(args.target, token, key).await?;
+    let mut rejected = 0;
+    for batch in txs.chunks(args.batch as usize) {
+        let acks = client.submit_batch(batch).await?;
+        for (tx, ack) in batch.iter().zip(acks) {
+            if let Ack::Rejected(reason) = ack {
+                eprintln!("tx {}: rejected: {reason}", tx.tx_id());
+                rejected += 1;
+            }
+        }
+    }
+    eprintln!("submitted {} records, {rejected} rejected", txs.len());
+    if rejected > 0 {
+        return Err(anyhow::anyhow!("{rejected} records rejected")).context(Failure::Partial);
+    }
+    Ok(())
+}
+
+/// Prints the summary rows of the clients' accounts, or of every account.
+pub(crate) async fn query(args: QueryArgs) -> Result<()> {
+    let (token, key) = (args.stream_token.as_deref(), args.stream_key.as_deref());
+    let mut client = client(args.target, token, key).await?;
+    let accounts = match args.clients.is_empty() {
+        true => client.summary().await?,
+        false => {
+            let mut accounts = Vec::new();
+            for id in args.clients {
+                match client.query(id).await? {
+                    Some(account) => accounts.push(account),
+                    None => eprintln!("client {id}: no account"),
+                }
+            }
+            accounts
+        }
+    };
+    let with_currency = accounts.iter().any(|a| !a.currencies.is_empty());
+    let header = Account::summary_header(true, with_currency);
+    println!("{header},{LOCK_COLUMNS}");
+    for account in accounts {
+        for row in account.summary_lines(true, with_currency) {
+            println!("{row},{}", account.lock_columns());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::csv_stream::serve_lines;
+    use crate::drain::Draining;
+    use crate::engine::TxEngine;
+    use crate::metrics::Metrics;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_submit_and_query() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let engine = Arc::new(Mutex::new(TxEngine::new()));
+        let server = engine.clone();
+        tokio::spawn(async move {
+            let verifier = Verifier::new("secret");
+            // the first connection is dropped unanswered, forcing a reconnect
+            drop(listener.accept().await.unwrap());
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let (reader, writer) = socket.into_split();
+                serve_lines(
+                    reader,
+                    writer,
+                    &server,
+                    &Metrics::default(),
+                    Some(&verifier),
+                    Draining::never(),
+                )
+                .await;
+            }
+        });
+
+        let client = TxClient::connect(addr, None).await.unwrap();
+        let mut client = client.with_key("secret");
+        let txs: Vec<Tx> = [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 5.0",
+            "withdrawal, 1, 3, 3.0",
+        ]
+        .into_iter()
+        .map(|l| Tx::from_str(l).unwrap())
+        .collect();
+        let acks = client.submit_batch(&txs).await.unwrap();
+        assert_eq!(acks, vec![Ack::Accepted; 3]);
+        let overdraft = Tx::from_str("withdrawal, 1, 4, 100.0").unwrap();
+        // answered with the reason the engine refused it for
+        assert_eq!(
+            client.submit(&overdraft).await.unwrap(),
+            Ack::Rejected("insufficient funds".into())
+        );
+
+        let account = client.query(1).await.unwrap().unwrap();
+        assert_eq!(account.available, Amount::from_units(12));
+        assert!(client.query(2).await.unwrap().is_none());
+        client
+            .submit(&Tx::from_str("deposit, 2, 5, 1.0").unwrap())
+            .await
+            .unwrap();
+        let mut accounts = client.summary().await.unwrap();
+        accounts.sort_by_key(Account::client);
+        let clients: Vec<u16> = accounts.iter().map(Account::client).collect();
+        assert_eq!(clients, [1, 2]);
+        assert_eq!(accounts[1].total, Amount::from_units(1));
+        assert_eq!(engine.lock().await.seq(), 5);
+
+        // an account holding currencies is answered a row per currency
+        for line in ["deposit, 3, 6, 2.0,, EUR", "deposit, 3, 7, 1.0,, USD"] {
+            let tx = crate::schema::Schema::V2.parse(line).unwrap();
+            assert_eq!(client.submit(&tx).await.unwrap(), Ack::Accepted);
+        }
+        let account = client.query(3).await.unwrap().unwrap();
+        // never added up across currencies
+        assert_eq!(account.total, Amount::ZERO);
+        let available = |currency| account.in_currency(Some(currency)).available;
+        assert_eq!(available("EUR"), Amount::from_units(2));
+        assert_eq!(available("USD"), Amount::from_units(1));
+        let accounts = client.summary().await.unwrap();
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(accounts[2].currencies, account.currencies);
+        assert!(client.query(1).await.unwrap().is_some());
+    }
+}
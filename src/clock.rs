@@ -0,0 +1,50 @@
+use std::time::SystemTime;
+
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+
+/// Source of wall-clock time for everything time-dependent in the engine.
+///
+/// Production code uses [`SystemClock`]; tests inject a [`MockClock`] so expiry
+/// windows and periodic behaviour can be driven step by step.
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub(crate) fn new(start: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(start),
+        }
+    }
+
+    pub(crate) fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
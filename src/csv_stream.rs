@@ -1,67 +1,514 @@
-use crate::{Tx, TxEngine};
-use anyhow::Result;
-use std::io::Write;
+use crate::auth;
+use crate::batch::{self, Batch};
+use crate::drain::Draining;
+use crate::engine::{Account, Tx};
+use crate::input;
+use crate::log;
+use crate::metrics::Metrics;
+use crate::net::{self, TcpArgs};
+use crate::recent;
+use crate::redact;
+use crate::rejects;
+use crate::schema::Schema;
+use crate::signing::Verifier;
+use crate::TxEngine;
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::net::TcpListener;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::sync::Mutex;
+use tokio_rustls::TlsAcceptor;
 
-const HOST: &str = "127.0.0.1:6969";
+pub(crate) const ACK_DIRECTIVE: &str = "#ack";
+pub(crate) const SUMMARY_DIRECTIVE: &str = "#summary";
+pub(crate) const QUERY_COMMAND: &str = "QUERY ";
+pub(crate) const SUMMARY_COMMAND: &str = "SUMMARY";
+/// Ends the rows answering a `SUMMARY` line.
+pub(crate) const SUMMARY_END: &str = "END";
+pub(crate) const UNKNOWN_CLIENT: &str = "unknown client";
+/// Sent to connected producers when the server starts draining.
+pub(crate) const GOAWAY: &str = "GOAWAY";
 
-struct TestWriter;
-impl Write for TestWriter {
-    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
-        todo!();
-    }
-    fn flush(&mut self) -> std::io::Result<()> {
-        todo!();
-    }
+/// What connections to a TCP listener open with before the line protocol.
+#[derive(Clone, Default)]
+pub(crate) struct Handshake {
+    /// A PROXY protocol header, whose producer address stands for the peer's
+    /// in logs and metrics.
+    pub(crate) proxy: bool,
+    /// A TLS handshake, see `tls`.
+    pub(crate) tls: Option<TlsAcceptor>,
+    /// An `AUTH` line carrying this token, see `auth`.
+    pub(crate) token: Option<Arc<str>>,
 }
 
-unsafe impl Send for TestWriter {}
-
-pub async fn handle_stream() -> Result<()> {
-    let tx_engine = Arc::new(Mutex::new(TxEngine::new()));
-    let listener = TcpListener::bind(HOST).await?;
+/// Serves the line protocol on `addr` until the server drains, with the
+/// socket options of `tcp`, every connection opening with `handshake`.
+pub async fn handle_stream(
+    addr: SocketAddr,
+    handshake: Handshake,
+    tcp: TcpArgs,
+    tx_engine: Arc<Mutex<TxEngine>>,
+    metrics: Arc<Metrics>,
+    verifier: Option<Arc<Verifier>>,
+    mut draining: Draining,
+) -> Result<()> {
+    #[cfg(unix)]
+    let listener = crate::handoff::tcp_listener(addr, || tcp.bind(addr))?;
+    #[cfg(not(unix))]
+    let listener = tcp.bind(addr)?;
 
     loop {
-        let (socket, _) = listener.accept().await?;
-        let tx_engine_clone = tx_engine.clone();
-
+        let (mut socket, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = draining.started() => return Ok(()),
+        };
+        let peer = net::peer_addr(peer);
+        if let Err(err) = tcp.tune(&socket) {
+            log::error!("could not set socket options for {peer}: {err}");
+        }
+        let (engine, metrics, verifier) = (tx_engine.clone(), metrics.clone(), verifier.clone());
+        let (draining, tls) = (draining.clone(), handshake.tls.clone());
+        let token = handshake.token.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_connection(socket, tx_engine_clone).await {
-                eprintln!("could not handle conn: {}", err);
+            let producer = match handshake.proxy {
+                true => net::read_proxy_header(&mut socket).await,
+                false => Ok(None),
+            };
+            let producer = match producer {
+                Ok(producer) => producer.unwrap_or(peer),
+                Err(err) => {
+                    log::warn!("rejecting conn from {peer}: {err:#}");
+                    return;
+                }
+            };
+            metrics.record_accepted(producer);
+            let peer = producer.to_string();
+            match tls {
+                Some(tls) => match tls.accept(socket).await {
+                    Ok(socket) => {
+                        serve_connection(socket, peer, token, engine, metrics, verifier, draining)
+                            .await
+                    }
+                    Err(err) => log::warn!("TLS handshake with {peer} failed: {err}"),
+                },
+                None => {
+                    serve_connection(socket, peer, token, engine, metrics, verifier, draining).await
+                }
             }
         });
     }
 }
 
-async fn handle_connection(
-    socket: tokio::net::TcpStream,
+/// `handle_stream` on a Unix domain socket at `path`, replacing a socket
+/// left there by an earlier run. Connections open with an `AUTH` line when
+/// `token` is given.
+#[cfg(unix)]
+pub(crate) async fn handle_unix_stream(
+    path: &Path,
+    token: Option<Arc<str>>,
+    tx_engine: Arc<Mutex<TxEngine>>,
+    metrics: Arc<Metrics>,
+    verifier: Option<Arc<Verifier>>,
+    mut draining: Draining,
+) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    let listener = crate::handoff::unix_listener(path, || {
+        if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path)
+                .with_context(|| format!("could not remove stale socket {}", path.display()))?;
+        }
+        tokio::net::UnixListener::bind(path)
+            .with_context(|| format!("could not bind {}", path.display()))
+    })?;
+
+    loop {
+        let (socket, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = draining.started() => return Ok(()),
+        };
+        let peer = path.display().to_string();
+        let (engine, metrics, verifier) = (tx_engine.clone(), metrics.clone(), verifier.clone());
+        let (token, draining) = (token.clone(), draining.clone());
+        tokio::spawn(serve_connection(socket, peer, token, engine, metrics, verifier, draining));
+    }
+}
+
+/// Serves the line protocol on one connection, which must open with an
+/// `AUTH` line carrying `token` when one is given.
+pub(crate) async fn serve_connection<S: AsyncRead + AsyncWrite>(
+    socket: S,
+    peer: String,
+    token: Option<Arc<str>>,
+    tx_engine: Arc<Mutex<TxEngine>>,
+    metrics: Arc<Metrics>,
+    verifier: Option<Arc<Verifier>>,
+    draining: Draining,
+) {
+    let (token, verifier) = (token.as_deref(), verifier.as_deref());
+    let handled = handle_connection(socket, token, tx_engine, &metrics, verifier, draining);
+    if let Err(err) = handled.await {
+        log::error!("could not handle conn from {peer}: {}", err);
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite>(
+    socket: S,
+    token: Option<&str>,
     engine: Arc<Mutex<TxEngine>>,
+    metrics: &Metrics,
+    verifier: Option<&Verifier>,
+    draining: Draining,
 ) -> Result<()> {
-    let reader = BufReader::new(socket);
+    let _active = metrics.connection();
+    let (reader, mut writer) = tokio::io::split(socket);
+    let mut reader = BufReader::new(reader);
+    if let Some(token) = token {
+        if !auth::handshake(&mut reader, &mut writer, token, metrics).await {
+            let _ = writer.shutdown().await;
+            anyhow::bail!(auth::UNAUTHENTICATED);
+        }
+    }
+    serve_lines(reader, &mut writer, &engine, metrics, verifier, draining).await;
+    // TLS peers expect a close_notify before the connection closes; a peer
+    // that is gone already needs none
+    let _ = writer.shutdown().await;
+    Ok(())
+}
+
+/// Reads transaction lines until the peer closes the stream, applying each one
+/// to the shared engine. Unparsable lines, and unsigned or badly signed ones
+/// when a `verifier` is given, are logged and skipped.
+pub(crate) async fn ingest_lines<R: AsyncRead + Unpin>(
+    reader: R,
+    engine: &Mutex<TxEngine>,
+    metrics: &Metrics,
+    verifier: Option<&Verifier>,
+) {
+    let draining = Draining::never();
+    serve_lines(reader, tokio::io::sink(), engine, metrics, verifier, draining).await;
+}
+
+/// `ingest_lines`, answering the peer on `replies`. Records, CSV lines or
+/// JSON objects (see `input::parse_streamed`), are applied in batches, see
+/// `batch`. `QUERY <client>` lines are answered with the
+/// client's summary row, `closed` and lock columns included, then an `END`
+/// line, or `ERR unknown client` when the engine has no account for it, and
+/// `SUMMARY` lines with such a row for every account, in no particular
+/// order, then an `END` line. An account holding currencies is a row per
+/// currency instead, with a `currency` column after the client. On
+/// connections opened with an `#ack` directive every record is answered
+/// with `OK <tx>` once the engine applied it, or `ERR <reason>` when it was
+/// rejected before or refused by the engine, a resent keyed record getting
+/// its first reply again, see `batch`. Connections opened with a
+/// `#summary` directive get the summary of every account once the peer
+/// closed its side of the stream. Once the server drains, the
+/// peer is sent `GOAWAY` and served until it closes the stream or the drain's
+/// grace period is over.
+pub(crate) async fn serve_lines<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: R,
+    mut replies: W,
+    engine: &Mutex<TxEngine>,
+    metrics: &Metrics,
+    verifier: Option<&Verifier>,
+    mut draining: Draining,
+) {
+    let reader = BufReader::new(reader);
     let mut lines = reader.lines();
+    let mut schema = Schema::default();
+    let mut ack = false;
+    let mut summary = false;
+    let mut opening = true;
+    let mut going_away = false;
+    let mut drained = draining.clone();
+    let mut batch = Batch::new();
 
-    while let Ok(Some(line)) = lines.next_line().await {
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line,
+            _ = tokio::time::sleep_until(batch.deadline()), if !batch.is_empty() => {
+                if apply_batch(&mut batch, &mut replies, ack, engine, metrics).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            _ = draining.started(), if !going_away => {
+                going_away = true;
+                if replies.write_all(format!("{GOAWAY}\n").as_bytes()).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            _ = drained.finished() => {
+                let _ = apply_batch(&mut batch, &mut replies, ack, engine, metrics).await;
+                return;
+            }
+        };
+        let Ok(Some(line)) = line else {
+            let applied = apply_batch(&mut batch, &mut replies, ack, engine, metrics).await;
+            if applied.is_ok() && summary {
+                // rendered under the lock, written once it is released so a
+                // slow peer does not stall the other connections
+                let mut rendered = Vec::new();
+                {
+                    let engine = engine.lock().await;
+                    let _ = engine.summarize_accounts_async(&mut rendered).await;
+                }
+                let _ = replies.write_all(&rendered).await;
+                let _ = replies.flush().await;
+            }
+            return;
+        };
         if line.is_empty() { continue; }
 
-        let tx = match Tx::from_str(&line) {
-            Ok(tx) => tx,
-            Err(err) => {
-                eprintln!("error processing trasnactions {}", err);
+        // a connection may open with `#schema=N`, `#ack` and `#summary` directives
+        if opening {
+            if line.trim() == ACK_DIRECTIVE {
+                ack = true;
                 continue;
             }
-        };
-        let mut engine = engine.lock().await;
-        engine.process_tx(tx);
+            if line.trim() == SUMMARY_DIRECTIVE {
+                summary = true;
+                continue;
+            }
+            match Schema::from_directive(&line) {
+                Some(Ok(directive)) => {
+                    schema = directive;
+                    continue;
+                }
+                Some(Err(err)) => {
+                    log::warn!("closing connection: {err}");
+                    metrics.record_rejected(format!("{}: {err:#}", redact::record(&line)));
+                    return;
+                }
+                None => opening = false,
+            }
+        }
+
+        if line.starts_with(QUERY_COMMAND) || is_summary(&line) {
+            // the answer reflects every record sent before the query
+            if apply_batch(&mut batch, &mut replies, ack, engine, metrics).await.is_err() {
+                return;
+            }
+            let reply = match verifier.map(|v| v.verify(&line)).transpose() {
+                Ok(command) => query(command.unwrap_or(&line), engine).await,
+                Err(err) => format!("ERR {err:#}"),
+            };
+            if replies.write_all(format!("{reply}\n").as_bytes()).await.is_err() {
+                return;
+            }
+            continue;
+        }
+
+        let full = batch.push(parse_record(&line, schema, metrics, verifier));
+        if full && apply_batch(&mut batch, &mut replies, ack, engine, metrics).await.is_err() {
+            return;
+        }
     }
+}
 
-    // NOTE: The destination for these summarized accounts is not specified.
-    //       Any entity that implements the `Write` trait is acceptable as a destination.
-    //       It could be a Kafka connector, a writer for SQL or NoSQL databases
-    let engine = engine.lock().await;
-    engine.summarize_accounts(TestWriter).unwrap();
+// applies the batch, acking its records on `#ack` connections
+async fn apply_batch<W: AsyncWrite + Unpin>(
+    batch: &mut Batch,
+    replies: &mut W,
+    ack: bool,
+    engine: &Mutex<TxEngine>,
+    metrics: &Metrics,
+) -> std::io::Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let applied = batch.apply(engine, metrics).await;
+    if ack {
+        let mut acks = String::new();
+        for ingested in &applied {
+            match ingested {
+                Ok(tx_id) => acks.push_str(&format!("OK {tx_id}\n")),
+                Err(err) => acks.push_str(&format!("ERR {err:#}\n")),
+            }
+        }
+        replies.write_all(acks.as_bytes()).await?;
+    }
 
+    #[cfg(feature = "chaos")]
+    if applied.iter().any(Result::is_ok) && crate::chaos::should_drop_connection() {
+        log::warn!("chaos: dropping connection");
+        return Err(std::io::ErrorKind::ConnectionAborted.into());
+    }
     Ok(())
 }
+
+// `SUMMARY`, or `SUMMARY,<signature>`; records of a custom type of that
+// name have more columns
+fn is_summary(line: &str) -> bool {
+    line.strip_prefix(SUMMARY_COMMAND)
+        .is_some_and(|rest| rest.is_empty() || (rest.starts_with(',') && !rest[1..].contains(',')))
+}
+
+// the reply to a verified `QUERY <client>` or `SUMMARY` command
+async fn query(command: &str, engine: &Mutex<TxEngine>) -> String {
+    // a row per currency of accounts holding currencies, each ended by `\n`
+    let rows = |account: &Account| {
+        let with_currency = !account.currencies.is_empty();
+        let mut rows = String::new();
+        for line in account.summary_lines(true, with_currency) {
+            rows.push_str(&format!("{line},{}\n", account.lock_columns()));
+        }
+        rows
+    };
+    let Some(client) = command.strip_prefix(QUERY_COMMAND) else {
+        let engine = engine.lock().await;
+        let mut reply = String::new();
+        for account in engine.accounts() {
+            reply.push_str(&rows(account));
+        }
+        reply.push_str(SUMMARY_END);
+        return reply;
+    };
+    let Ok(client) = client.trim().parse::<u16>() else {
+        return "ERR could not parse client to u16".to_owned();
+    };
+    match engine.lock().await.account(client) {
+        Some(account) => format!("{}{SUMMARY_END}", rows(account)),
+        None => format!("ERR {UNKNOWN_CLIENT} {client}"),
+    }
+}
+
+/// Verifies and parses one record. Rejections are logged and recorded in
+/// `metrics`.
+fn parse_record(
+    line: &str,
+    schema: Schema,
+    metrics: &Metrics,
+    verifier: Option<&Verifier>,
+) -> Result<Tx> {
+    let record = match verifier.map(|v| v.verify(line)).transpose() {
+        Ok(record) => record.unwrap_or(line),
+        Err(err) => {
+            log::warn!("rejecting record: {err}");
+            metrics.record_rejected(format!("{}: {err:#}", redact::record(line)));
+            rejects::unparsed(None, line, &err);
+            return Err(err);
+        }
+    };
+    match input::parse_streamed(record, schema) {
+        Ok(tx) => {
+            recent::received(tx.client(), record);
+            Ok(tx)
+        }
+        Err(err) => {
+            log::warn!("error processing transactions {}", err);
+            metrics.record_rejected(format!("{}: {err:#}", redact::record(line)));
+            rejects::unparsed(None, line, &err);
+            Err(err)
+        }
+    }
+}
+
+/// Verifies, parses and applies one record, returning its tx id once the
+/// engine applied it. Rejections are logged and recorded in `metrics`.
+#[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+pub(crate) async fn ingest_record(
+    line: &str,
+    schema: Schema,
+    engine: &Mutex<TxEngine>,
+    metrics: &Metrics,
+    verifier: Option<&Verifier>,
+) -> Result<u32> {
+    let tx = parse_record(line, schema, metrics, verifier)?;
+    batch::apply_one(tx, engine, metrics).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+
+    #[tokio::test]
+    async fn test_summary_after_peer_closes() {
+        let engine = Mutex::new(TxEngine::new());
+        let metrics = Metrics::default();
+        let reader = format!(
+            "{SUMMARY_DIRECTIVE}\ndeposit, 1, 1, 10.0\n{}\n",
+            r#"{"type":"deposit","client":2,"tx":2,"amount":2.5}"#
+        );
+        let mut replies = Vec::new();
+        let draining = Draining::never();
+        serve_lines(reader.as_bytes(), &mut replies, &engine, &metrics, None, draining).await;
+
+        let replies = String::from_utf8(replies).unwrap();
+        let mut lines: Vec<&str> = replies.lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            ["1,10,0,10,false", "2,2.5,0,2.5,false", "client,available,held,total,locked"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ack_answers_every_record() {
+        let engine = Mutex::new(TxEngine::new());
+        let metrics = Metrics::default();
+        let reader = format!(
+            "{ACK_DIRECTIVE}\ndeposit, 1, 1, 10.0\ndeposit, x, 2, 1.0\nunlock, 1, 3,\nclose, 1, 4,\n"
+        );
+        let mut replies = Vec::new();
+        let draining = Draining::never();
+        serve_lines(reader.as_bytes(), &mut replies, &engine, &metrics, None, draining).await;
+
+        let replies = String::from_utf8(replies).unwrap();
+        let lines: Vec<&str> = replies.lines().collect();
+        assert_eq!(lines[0], "OK 1");
+        assert!(lines[1].starts_with("ERR "), "{replies}");
+        // producers may not unlock accounts without --stream-admin-records
+        assert_eq!(lines[2], "ERR unlock records need --stream-admin-records");
+        assert_eq!(lines[3], "ERR close records need --stream-admin-records");
+        assert_eq!(lines.len(), 4);
+        assert_eq!(metrics.snapshot().rejected, 3);
+    }
+
+    // the replies of a connection sending `sent`
+    async fn served(sent: &str, engine: &Mutex<TxEngine>, metrics: &Metrics) -> String {
+        let mut replies = Vec::new();
+        let (sent, draining) = (sent.as_bytes(), Draining::never());
+        serve_lines(sent, &mut replies, engine, metrics, None, draining).await;
+        String::from_utf8(replies).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_query_replies_end_with_end() {
+        let engine = Mutex::new(TxEngine::new());
+        let metrics = Metrics::default();
+        let sent = "#schema=2\ndeposit, 1, 1, 10.0\ndeposit, 2, 2, 1.0,, EUR\n\
+                    deposit, 2, 3, 2.0,, USD\nQUERY 1\nQUERY 2\nQUERY 3\n";
+        let replies = served(sent, &engine, &metrics).await;
+        let lines: Vec<&str> = replies.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "1,10,0,10,false,false,,,",
+                "END",
+                "2,EUR,1,0,1,false,false,,,",
+                "2,USD,2,0,2,false,false,,,",
+                "END",
+                "ERR unknown client 3"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resent_records_get_the_stored_reply() {
+        let engine = Mutex::new(TxEngine::new());
+        let metrics = Metrics::default();
+        let sent = format!("#schema=2\n{ACK_DIRECTIVE}\ndeposit, 1, 7, 10.0, , , resend-7\n");
+        assert_eq!(served(&sent, &engine, &metrics).await, "OK 7\n");
+        assert_eq!(served(&sent, &engine, &metrics).await, "OK 7\n");
+        let total = engine.lock().await.account(1).unwrap().total();
+        assert_eq!(total, Amount::from_units(10));
+
+        // the same tx under another key is a new record, refused as a duplicate
+        let other = sent.replace("resend-7", "resend-8");
+        assert!(served(&other, &engine, &metrics).await.starts_with("ERR "));
+    }
+}
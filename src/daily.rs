@@ -0,0 +1,163 @@
+//! End-of-day summaries of a timestamped input, written in one pass.
+//!
+//! With `--daily-dir` every record's timestamp assigns it to a business day:
+//! day D ends at the `--cutoff` time on D in `--timezone`, so with a 17:00
+//! cutoff a record at 18:00 already counts towards the next day. When the
+//! first record of a later day arrives, the balances as of the end of the
+//! current day are written to `summary-D.csv` in the directory. Records
+//! without a timestamp, or dated before the current day, count towards the
+//! current day.
+
+use crate::engine::{Account, Tx, TxEngine};
+use crate::output::Output;
+use anyhow::{Context, Result};
+use clap::Args;
+use jiff::civil::{Date, Time};
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct DailyArgs {
+    /// Also write a summary per business day of the input's timestamps to this directory
+    #[arg(long, global = true, value_name = "DIR")]
+    daily_dir: Option<PathBuf>,
+    /// Local time at which a business day ends, e.g. 17:00
+    #[arg(long, global = true, default_value = "00:00", requires = "daily_dir")]
+    cutoff: Time,
+    /// IANA time zone of the cutoff, e.g. Europe/Istanbul
+    #[arg(long, global = true, default_value = "UTC", requires = "daily_dir")]
+    timezone: String,
+}
+
+impl DailyArgs {
+    pub(crate) fn daily(&self) -> Result<Option<DailySummaries>> {
+        let Some(dir) = &self.daily_dir else {
+            return Ok(None);
+        };
+        let tz = TimeZone::get(&self.timezone)
+            .with_context(|| format!("unknown time zone {}", self.timezone))?;
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("could not create {}", dir.display()))?;
+        Ok(Some(DailySummaries {
+            dir: dir.clone(),
+            cutoff: self.cutoff,
+            tz,
+            day: None,
+        }))
+    }
+}
+
+pub(crate) struct DailySummaries {
+    dir: PathBuf,
+    cutoff: Time,
+    tz: TimeZone,
+    // business day of the records applied so far
+    day: Option<Date>,
+}
+
+impl DailySummaries {
+    /// Applies every record of `file_path` to `engine`, writing a summary
+    /// each time a business day ends and one for the last day.
+    pub(crate) fn process(
+        &mut self,
+        engine: &mut TxEngine,
+        file_path: &Path,
+        lenient: bool,
+        output: &Output,
+    ) -> Result<usize> {
+        let mut failed = None;
+        let skipped = crate::cli::for_each_tx(file_path, lenient, |tx| {
+            if failed.is_some() {
+                return;
+            }
+            match self.roll_over(engine, &tx, output) {
+                Ok(()) => {
+                    let _ = engine.process_tx(tx);
+                }
+                Err(err) => failed = Some(err),
+            }
+        })?;
+        if let Some(err) = failed {
+            return Err(err);
+        }
+        if let Some(day) = self.day {
+            self.write_day(engine, day, output)?;
+        }
+        Ok(skipped)
+    }
+
+    fn business_day(&self, secs: u64) -> Result<Date> {
+        let at = Timestamp::from_second(secs.try_into()?)?;
+        let local = at.to_zoned(self.tz.clone()).datetime();
+        if self.cutoff != Time::midnight() && local.time() >= self.cutoff {
+            return Ok(local.date().tomorrow()?);
+        }
+        Ok(local.date())
+    }
+
+    // closes the current day when `tx` belongs to a later one
+    fn roll_over(&mut self, engine: &TxEngine, tx: &Tx, output: &Output) -> Result<()> {
+        let Some(secs) = tx.meta().and_then(|m| m.timestamp) else {
+            return Ok(());
+        };
+        let day = self.business_day(secs)?;
+        match self.day {
+            Some(current) if day > current => self.write_day(engine, current, output)?,
+            Some(_) => return Ok(()),
+            None => {}
+        }
+        self.day = Some(day);
+        Ok(())
+    }
+
+    fn write_day(&self, engine: &TxEngine, day: Date, output: &Output) -> Result<()> {
+        let path = self.dir.join(format!("summary-{day}.csv"));
+        let file =
+            File::create(&path).with_context(|| format!("could not create {}", path.display()))?;
+        let mut accounts: Vec<&Account> = engine.accounts().collect();
+        accounts.sort_unstable_by_key(|a| a.client);
+        output.write_accounts(&accounts, file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_per_business_day() {
+        let dir = std::env::temp_dir().join(format!("roinstxs-daily-{}", std::process::id()));
+        let input = dir.with_extension("csv");
+        // 2024-03-01 16:00 and 18:00, 2024-03-04 09:00 in Istanbul (UTC+3)
+        std::fs::write(
+            &input,
+            "#schema=2\ntype,client,tx,amount,timestamp\n\
+             deposit, 1, 1, 10.0, 1709298000\n\
+             deposit, 1, 2, 5.0, 1709305200\n\
+             deposit, 1, 3, 2.0,\n\
+             withdrawal, 1, 4, 1.0, 1709532000\n",
+        )
+        .unwrap();
+        let args = DailyArgs {
+            daily_dir: Some(dir.clone()),
+            cutoff: "17:00".parse().unwrap(),
+            timezone: "Europe/Istanbul".to_owned(),
+        };
+        let mut engine = TxEngine::new();
+        args.daily()
+            .unwrap()
+            .unwrap()
+            .process(&mut engine, &input, false, &Output::default())
+            .unwrap();
+
+        let day = |d: &str| std::fs::read_to_string(dir.join(format!("summary-{d}.csv"))).unwrap();
+        assert!(day("2024-03-01").ends_with("\n1,10,0,10,false\n"));
+        assert!(day("2024-03-02").ends_with("\n1,17,0,17,false\n"));
+        assert!(day("2024-03-04").ends_with("\n1,16,0,16,false\n"));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_file(input).unwrap();
+    }
+}
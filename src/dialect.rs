@@ -0,0 +1,116 @@
+//! Column delimiters of CSV transaction files.
+//!
+//! Files from some partners separate their columns with `;` or tabs rather
+//! than `,`, the `;` ones often writing amounts with a decimal comma. Before
+//! the records of a file are read, its header and first records are sniffed
+//! for the delimiter splitting most of them into at least three columns, `,`
+//! winning ties. Records are then split on that delimiter alone, and amounts
+//! of files not delimited by `,` may use a decimal comma, with `.` grouping
+//! thousands. A record using another delimiter than its file's is rejected
+//! like any unparsable record, in comma-delimited files any `;` or tab.
+//!
+//! Schema 1 files with the standard header are read by the typed reader of
+//! `input` instead, which keeps reading the legacy `hold, 1, 7, 2.5; reason`
+//! form of administrative records.
+
+use crate::engine::Tx;
+use crate::schema::Schema;
+use anyhow::Result;
+
+/// Lines, the header included, looked at to pick the delimiter of a file.
+pub(crate) const SNIFF_LINES: usize = 32;
+const DELIMITERS: [char; 3] = [',', ';', '\t'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Dialect {
+    delimiter: char,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self { delimiter: ',' }
+    }
+}
+
+fn columns(line: &str, delimiter: char) -> usize {
+    line.matches(delimiter).count() + 1
+}
+
+impl Dialect {
+    /// The dialect of a file starting with `lines`.
+    pub(crate) fn sniff<'a>(lines: impl IntoIterator<Item = &'a str>) -> Self {
+        let lines: Vec<&str> = lines.into_iter().collect();
+        let delimiter = DELIMITERS
+            .into_iter()
+            // the last of equal maximums is kept, so `,` wins ties
+            .rev()
+            .max_by_key(|d| lines.iter().filter(|l| columns(l, *d) >= 3).count())
+            .expect("there are delimiters");
+        Self { delimiter }
+    }
+
+    pub(crate) fn parse(self, schema: Schema, line: &str) -> Result<Tx> {
+        let other = match self.delimiter {
+            // any other delimiter in a comma-delimited record is a mix
+            ',' => line.chars().find(|c| *c != ',' && DELIMITERS.contains(c)),
+            // commas being decimal ones, only records that split better on
+            // another delimiter are
+            _ if columns(line, self.delimiter) < 3 => {
+                DELIMITERS.into_iter().find(|d| columns(line, *d) >= 3)
+            }
+            _ => None,
+        };
+        if let Some(other) = other {
+            anyhow::bail!(
+                "record delimited by {other:?} in a file delimited by {:?}",
+                self.delimiter
+            );
+        }
+        let amount;
+        let mut fields: Vec<&str> = line.split(self.delimiter).map(str::trim).collect();
+        // a decimal comma, `.` being the thousands separator if any
+        if let Some(field) = fields.get_mut(3).filter(|f| f.contains(',')) {
+            amount = field.replace('.', "").replace(',', ".");
+            *field = &amount;
+        }
+        schema.parse_fields(&fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+
+    #[test]
+    fn test_sniff_and_decimal_comma() {
+        let semicolon =
+            Dialect::sniff(["type;client;tx;amount", "deposit;1;1;2,5", "dispute;1;1;"]);
+        assert_eq!(semicolon.delimiter, ';');
+        let tx = semicolon
+            .parse(Schema::V1, "deposit; 1; 2; 1.234,5")
+            .unwrap();
+        assert_eq!(
+            (tx.tx_id(), tx.amount()),
+            (2, Some("1234.5".parse::<Amount>().unwrap()))
+        );
+        assert!(semicolon.parse(Schema::V1, "deposit, 1, 3, 1.0").is_err());
+
+        let tab = Dialect::sniff(["type\tclient\ttx\tamount", "deposit\t1\t1\t2.5"]);
+        let tx = tab
+            .parse(Schema::V2, "hold\t1\t2\t1.0\t\t\t\tlegal")
+            .unwrap();
+        assert_eq!(tx.reason(), Some("legal"));
+
+        let comma = Dialect::sniff(["type, client, tx, amount", "hold, 1, 7, 2.5, risk-42"]);
+        assert_eq!(comma, Dialect::default());
+        let hold = comma.parse(Schema::V1, "hold, 1, 7, 2.5, risk-42").unwrap();
+        assert_eq!(hold.reason(), Some("risk-42"));
+        assert!(comma.parse(Schema::V1, "deposit;1;4;2,5").is_err());
+        let mixed = comma.parse(Schema::V2, "deposit, 1; 5, 2.5").unwrap_err();
+        assert_eq!(
+            mixed.to_string(),
+            "record delimited by ';' in a file delimited by ','"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! Draining the stream server before it exits, for rolling deploys.
+//!
+//! On SIGTERM, SIGINT or SIGHUP (sent after a configuration change, so the
+//! supervisor restarts the server with the new one) the server stops
+//! accepting connections and sends `GOAWAY` to every connected producer,
+//! which should reconnect elsewhere once it has its acks. Records still
+//! arriving are applied until the producers close their connections or
+//! `--drain-grace` runs out; then the final snapshot is written, when
+//! snapshots are on, and the server exits. A successor taking over through
+//! `--handoff` starts the same drain, see `handoff`.
+
+use crate::loadgen::parse_duration;
+use crate::log;
+use crate::metrics::Metrics;
+use anyhow::Result;
+use clap::Args;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+
+const POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct DrainArgs {
+    /// How long connected producers may keep sending after a drain starts
+    #[arg(long, global = true, value_parser = parse_duration, default_value = "30s")]
+    drain_grace: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Serving,
+    Draining,
+    Drained,
+}
+
+/// A task's view of the drain.
+#[derive(Debug, Clone)]
+pub(crate) struct Draining(watch::Receiver<Phase>);
+
+impl Draining {
+    /// A drain that never starts, for tasks outside the server.
+    pub(crate) fn never() -> Self {
+        Self(watch::channel(Phase::Serving).1)
+    }
+
+    async fn reached(&mut self, phase: Phase) {
+        if self.0.wait_for(|p| *p >= phase).await.is_err() {
+            std::future::pending().await
+        }
+    }
+
+    /// Resolves once the server stops accepting connections.
+    pub(crate) async fn started(&mut self) {
+        self.reached(Phase::Draining).await
+    }
+
+    /// Resolves once the grace period is over, or no producer is left.
+    pub(crate) async fn finished(&mut self) {
+        self.reached(Phase::Drained).await
+    }
+}
+
+pub(crate) struct Drain {
+    phase: watch::Sender<Phase>,
+    grace: Duration,
+}
+
+impl Drain {
+    pub(crate) fn new(args: &DrainArgs) -> Self {
+        Self {
+            phase: watch::channel(Phase::Serving).0,
+            grace: args.drain_grace,
+        }
+    }
+
+    pub(crate) fn draining(&self) -> Draining {
+        Draining(self.phase.subscribe())
+    }
+
+    /// Waits for a shutdown signal, then drains the connections counted in
+    /// `metrics`. When `successor` yields first, producers are sent away the
+    /// same way but the drain is left to complete once the successor has taken
+    /// over, see `handoff`.
+    pub(crate) async fn run<S>(
+        &self,
+        metrics: &Metrics,
+        successor: impl Future<Output = Result<S>>,
+    ) -> Result<Option<S>> {
+        tokio::select! {
+            signal = shutdown_signal() => {
+                log::info!("{}: draining, grace period {:?}", signal?, self.grace);
+                self.drain(metrics).await;
+                Ok(None)
+            }
+            successor = successor => {
+                let successor = successor?;
+                log::info!("handoff: draining, grace period {:?}", self.grace);
+                self.phase.send_replace(Phase::Draining);
+                self.wait(metrics).await;
+                Ok(Some(successor))
+            }
+        }
+    }
+
+    async fn drain(&self, metrics: &Metrics) {
+        self.phase.send_replace(Phase::Draining);
+        self.wait(metrics).await;
+        self.phase.send_replace(Phase::Drained);
+    }
+
+    // until the producers left or the grace period is over
+    async fn wait(&self, metrics: &Metrics) {
+        let drained = async {
+            while metrics.snapshot().connections > 0 {
+                tokio::time::sleep(POLL).await;
+            }
+        };
+        if tokio::time::timeout(self.grace, drained).await.is_err() {
+            let left = metrics.snapshot().connections;
+            log::info!("drain: grace period over, closing {left} connections");
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = hangup.recv() => "SIGHUP",
+        _ = interrupt.recv() => "SIGINT",
+    })
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("ctrl-c")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_connections() {
+        let drain = Drain {
+            phase: watch::channel(Phase::Serving).0,
+            grace: Duration::from_secs(5),
+        };
+        let metrics = Metrics::default();
+        let mut draining = drain.draining();
+        let connection = metrics.connection();
+
+        let (_, ()) = tokio::join!(drain.drain(&metrics), async {
+            draining.started().await;
+            assert!(*draining.0.borrow() == Phase::Draining);
+            drop(connection);
+            draining.finished().await;
+        });
+        assert_eq!(metrics.snapshot().connections, 0);
+    }
+}
@@ -1,277 +1,3257 @@
+use crate::amount::Amount;
+use crate::archive::{Archive, ArchivedAccount};
+use crate::clock::{Clock, SystemClock};
+use crate::error::{EngineError, TxError};
+use crate::events::{Balances, Event, Observer};
+use crate::limits::{MinBalance, MinBalancePolicy, Overdraft};
+use crate::log;
+use crate::recent::{self, Decision};
+use crate::{redact, rejects};
+use crate::store::{MemoryStore, TxStore};
+use crate::wal::Wal;
 use anyhow::{Context, Error, Result};
-use std::collections::HashMap;
-use std::io::BufWriter;
-use std::io::Write;
+use serde::ser::{Error as _, SerializeMap};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::future::Future;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-#[derive(Debug, Clone)]
-enum TxType {
+/// The kind of a transaction record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    /// Closes an account that holds nothing in dispute.
+    Close,
+    /// Administrative hold moving an amount from available to held, with a
+    /// reason code, independent of any prior transaction.
+    Hold,
+    /// Releases an amount placed on hold by `Hold` records.
+    Release,
+    /// Amends the amount of an earlier deposit or withdrawal, `tx` naming it.
+    Correction,
+    /// Moves an amount from the client's available funds to those of another
+    /// client, the counterparty, at once or not at all.
+    Transfer,
+    /// Re-enables a locked account. Stream producers may only send it, like
+    /// `Close`, `Hold` and `Release`, with `--stream-admin-records`.
+    Unlock,
+    /// A type string the engine doesn't know natively; routed to the handler
+    /// registered for it, see `TxEngine::register_handler`.
+    Custom,
+    #[default]
     Noop,
 }
 
-impl Default for TxType {
-    fn default() -> Self {
-        Self::Noop
+impl TxType {
+    /// Every type a record can carry, in declaration order.
+    pub(crate) const ALL: [TxType; 11] = [
+        Self::Deposit,
+        Self::Withdrawal,
+        Self::Dispute,
+        Self::Resolve,
+        Self::Chargeback,
+        Self::Close,
+        Self::Hold,
+        Self::Release,
+        Self::Correction,
+        Self::Transfer,
+        Self::Unlock,
+    ];
+
+    /// The type string of records of this type, `custom` for custom ones.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Close => "close",
+            Self::Hold => "hold",
+            Self::Release => "release",
+            Self::Correction => "correction",
+            Self::Transfer => "transfer",
+            Self::Unlock => "unlock",
+            Self::Custom => "custom",
+            Self::Noop => "noop",
+        }
     }
 }
 
-impl From<&str> for TxType {
-    fn from(value: &str) -> Self {
-        match value {
+/// Parses a type string, names the engine doesn't know being `Custom`.
+impl TryFrom<&str> for TxType {
+    type Error = TxError;
+
+    fn try_from(value: &str) -> Result<Self, TxError> {
+        Ok(match value {
+            "" => return Err(TxError::MissingType),
             "deposit" => Self::Deposit,
             "withdrawal" => Self::Withdrawal,
             "dispute" => Self::Dispute,
             "resolve" => Self::Resolve,
             "chargeback" => Self::Chargeback,
-            _ => unreachable!("invalid Tx type"),
-        }
+            "close" => Self::Close,
+            "hold" => Self::Hold,
+            "release" => Self::Release,
+            "correction" => Self::Correction,
+            "transfer" => Self::Transfer,
+            "unlock" => Self::Unlock,
+            _ => Self::Custom,
+        })
     }
 }
 
-#[derive(Debug, Clone, Default)]
-pub(crate) struct Tx {
+/// A transaction record: its type, client, transaction id and amount.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Tx {
+    #[serde(rename = "type")]
     tx_type: TxType,
+    #[serde(rename = "tx")]
     tx_id: u32,
     client: u16,
-    amount: Option<f64>,
+    amount: Option<Amount>,
+    // original type string of `TxType::Custom` records
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    custom_type: Option<Box<str>>,
+    // extra columns of schema 2 and later inputs, boxed to keep legacy records small
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    meta: Option<Box<TxMeta>>,
+    // position in the input, attached at parse time
+    #[serde(skip)]
+    seq: Option<u64>,
+}
+
+/// Columns added after the original four, see `crate::schema`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TxMeta {
+    /// Seconds since the unix epoch at which the producer recorded the tx.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) timestamp: Option<u64>,
+    /// Currency of the amount, see [`Account::currencies`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) currency: Option<Box<str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) correlation_id: Option<Box<str>>,
+    /// Reason code of administrative `hold` and `release` records.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<Box<str>>,
+    /// Bucket of the client's account the record applies to, see
+    /// [`Account::sub_accounts`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sub_account: Option<Box<str>>,
+    /// Client a `transfer` record credits.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) counterparty: Option<u16>,
 }
 
 impl Tx {
-    pub(crate) fn from_str(v: &str) -> Result<Self> {
-        let d: Vec<&str> = v
+    /// The parsed type, `Custom` for type strings the engine doesn't know.
+    pub fn tx_type(&self) -> TxType {
+        self.tx_type
+    }
+
+    /// The type string as it appeared in the input.
+    pub fn type_name(&self) -> &str {
+        self.custom_type
+            .as_deref()
+            .unwrap_or_else(|| self.tx_type.as_str())
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// The id of this transaction, or of the one a dispute, resolve or
+    /// chargeback refers to.
+    pub fn tx_id(&self) -> u32 {
+        self.tx_id
+    }
+
+    /// `None` for records that carry no amount, such as disputes.
+    pub fn amount(&self) -> Option<Amount> {
+        self.amount
+    }
+
+    pub(crate) fn meta(&self) -> Option<&TxMeta> {
+        self.meta.as_deref()
+    }
+
+    pub(crate) fn reason(&self) -> Option<&str> {
+        self.meta().and_then(|m| m.reason.as_deref())
+    }
+
+    pub(crate) fn sub_account(&self) -> Option<&str> {
+        self.meta().and_then(|m| m.sub_account.as_deref())
+    }
+
+    pub(crate) fn currency(&self) -> Option<&str> {
+        self.meta().and_then(|m| m.currency.as_deref())
+    }
+
+    pub(crate) fn counterparty(&self) -> Option<u16> {
+        self.meta().and_then(|m| m.counterparty)
+    }
+
+    pub(crate) fn with_meta(mut self, meta: TxMeta) -> Self {
+        self.meta = (meta != TxMeta::default()).then(|| Box::new(meta));
+        self
+    }
+
+    /// The position in the input attached at parse time, the line of file
+    /// records.
+    pub(crate) fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+
+    pub(crate) fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    pub(crate) fn from_str(v: &str) -> Result<Self, TxError> {
+        let mut d: Vec<&str> = v
             .splitn(4, &[',', ';'])
-            .filter_map(|chunk| Some(chunk.trim()))
+            .map(|chunk| chunk.trim())
             .collect();
+        // administrative records carry their reason code in a fifth column,
+        // transfers their counterparty
+        let tx_type = TxType::try_from(d[0])?;
+        let fifth = match tx_type {
+            TxType::Hold | TxType::Release | TxType::Transfer => d.get_mut(3).and_then(|rest| {
+                let (amount, fifth) = rest.split_once([',', ';'])?;
+                *rest = amount.trim_end();
+                Some(fifth.trim())
+            }),
+            _ => None,
+        };
+        let fifth = fifth.filter(|v| !v.is_empty());
+        let tx = Self::from_fields(&d)?;
+        Ok(tx.with_meta(match tx_type {
+            TxType::Transfer => TxMeta {
+                counterparty: fifth.map(parse_counterparty).transpose()?,
+                ..Default::default()
+            },
+            _ => TxMeta {
+                reason: fifth.map(Into::into),
+                ..Default::default()
+            },
+        }))
+    }
 
-        let tx_type = d
-            .get(0)
-            .ok_or_else(|| Error::msg("missing transaction type"))?
-            .to_owned()
-            .into();
+    /// Parses the original `type, client, tx, amount` columns.
+    pub(crate) fn from_fields(d: &[&str]) -> Result<Self, TxError> {
+        let type_name = d.first().ok_or(TxError::MissingType)?.to_owned();
         let client = d
             .get(1)
-            .ok_or_else(|| Error::msg("missing client"))?
+            .ok_or(TxError::MissingColumn("client"))?
             .parse::<u16>()
-            .context("could not parse client to u16")?;
+            .map_err(TxError::invalid::<u16>("client"))?;
         let tx_id = d
             .get(2)
-            .ok_or_else(|| Error::msg("missing transaction"))?
+            .ok_or(TxError::MissingColumn("transaction"))?
             .parse::<u32>()
-            .context("could not parse tx to u32")?;
-        let amount = d
-            .get(3)
-            .and_then(|v| Some(v.parse::<f64>().unwrap_or(0.)));
+            .map_err(TxError::invalid::<u32>("tx"))?;
+        let amount = match d.get(3) {
+            None | Some(&"") => None,
+            Some(v) => Some(v.parse::<Amount>().map_err(TxError::InvalidAmount)?),
+        };
+        Self::new(type_name, client, tx_id, amount)
+    }
+
+    /// A record of the type named `type_name`, custom when it is no built-in
+    /// one; fails for an empty name, and for deposits and withdrawals of zero
+    /// or less.
+    pub fn new(
+        type_name: &str,
+        client: u16,
+        tx_id: TxId,
+        amount: Option<Amount>,
+    ) -> Result<Self, TxError> {
+        let tx_type = TxType::try_from(type_name)?;
+        if matches!(tx_type, TxType::Deposit | TxType::Withdrawal) {
+            if let Some(amount) = amount.filter(|a| *a <= Amount::ZERO) {
+                return Err(TxError::NonPositiveAmount(amount));
+            }
+        }
         Ok(Self {
             tx_type,
             client,
             tx_id,
             amount,
+            custom_type: (tx_type == TxType::Custom).then(|| type_name.into()),
+            meta: None,
+            seq: None,
         })
     }
 }
 
-#[derive(Debug, Clone, Default)]
-struct Account {
-    client: u16,
-    available: f64,
-    held: f64,
-    total: f64,
-    locked: bool,
+/// Parses the counterparty column of `transfer` records.
+pub(crate) fn parse_counterparty(v: &str) -> Result<u16, TxError> {
+    v.parse().map_err(TxError::invalid::<u16>("counterparty"))
+}
+
+/// Parses a `type, client, tx, amount` line, the amount being optional.
+impl std::str::FromStr for Tx {
+    type Err = TxError;
+
+    fn from_str(s: &str) -> Result<Self, TxError> {
+        Tx::from_str(s)
+    }
+}
+
+/// Handles records of a custom type on behalf of the engine, see
+/// [`TxEngine::register_handler`]. Closures taking the record and the
+/// account implement it.
+pub trait TxHandler: Send {
+    fn handle(&mut self, tx: &Tx, account: &mut AccountHandle<'_>) -> Result<()>;
+}
+
+impl<F> TxHandler for F
+where
+    F: FnMut(&Tx, &mut AccountHandle<'_>) -> Result<()> + Send,
+{
+    fn handle(&mut self, tx: &Tx, account: &mut AccountHandle<'_>) -> Result<()> {
+        self(tx, account)
+    }
+}
+
+/// Runs around every transaction: `filter` may veto a record before it is
+/// applied and `after` observes it once applied. Both see the affected
+/// account, if any, read-only.
+pub(crate) trait TxHook: Send {
+    fn filter(&mut self, tx: &Tx, account: Option<&Account>) -> Result<bool>;
+    fn after(&mut self, tx: &Tx, account: Option<&Account>) -> Result<()>;
+}
+
+/// The constrained view of an account handed to custom handlers. Every
+/// mutation keeps `available + held == total`, applies to the main
+/// sub-account and refuses to touch locked accounts; debits and holds may
+/// draw on the client's overdraft limit like withdrawals.
+pub struct AccountHandle<'a> {
+    account: &'a mut Account,
+    overdraft: Amount,
+}
+
+impl<'a> AccountHandle<'a> {
+    pub(crate) fn new(account: &'a mut Account, overdraft: Amount) -> Self {
+        Self { account, overdraft }
+    }
+
+    /// The account, with the changes made so far.
+    pub fn account(&self) -> &Account {
+        self.account
+    }
+
+    /// The client's overdraft limit debits and holds may draw on.
+    pub fn overdraft(&self) -> Amount {
+        self.overdraft
+    }
+
+    fn check(&self, amount: Amount) -> Result<()> {
+        anyhow::ensure!(
+            !self.account.locked,
+            "account {} is locked",
+            redact::client(self.account.client)
+        );
+        anyhow::ensure!(
+            !amount.is_negative(),
+            "amount must not be negative, got {}",
+            redact::amount(amount)
+        );
+        Ok(())
+    }
+
+    /// Adds funds to the available balance.
+    pub fn credit(&mut self, amount: Amount) -> Result<()> {
+        self.check(amount)?;
+        self.account.shift(None, None, amount, Amount::ZERO)
+    }
+
+    /// Removes funds from the available balance.
+    pub fn debit(&mut self, amount: Amount) -> Result<()> {
+        self.check(amount)?;
+        anyhow::ensure!(
+            self.account.can_debit(None, None, amount, self.overdraft),
+            "insufficient available funds"
+        );
+        self.account.shift(None, None, -amount, Amount::ZERO)
+    }
+
+    /// Moves funds from available to held.
+    pub fn hold(&mut self, amount: Amount) -> Result<()> {
+        self.check(amount)?;
+        anyhow::ensure!(
+            self.account.can_debit(None, None, amount, self.overdraft),
+            "insufficient available funds"
+        );
+        self.account.shift(None, None, -amount, amount)
+    }
+
+    /// Moves funds from held back to available.
+    pub fn release(&mut self, amount: Amount) -> Result<()> {
+        self.check(amount)?;
+        anyhow::ensure!(
+            self.account.bucket(None, None).held >= amount,
+            "insufficient held funds"
+        );
+        self.account.shift(None, None, amount, -amount)
+    }
+
+    /// Locks the account, as a chargeback would.
+    pub fn lock(&mut self) {
+        self.account.locked = true;
+    }
+}
+
+/// The balances of a client. `available`, `held` and `total` are those in
+/// the unnamed currency, that of records naming none; the balances in other
+/// currencies are kept apart in `currencies` and never added to them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Account {
+    pub(crate) client: u16,
+    pub(crate) available: Amount,
+    pub(crate) held: Amount,
+    pub(crate) total: Amount,
+    pub(crate) locked: bool,
+    /// Part of `held` placed by administrative `hold` records rather than
+    /// disputes; only this part can be released by `release` records.
+    #[serde(default)]
+    pub(crate) admin_held: Amount,
+    /// Set by a successful `close` record; closed accounts take no further
+    /// transactions.
+    #[serde(default)]
+    pub(crate) closed: bool,
+    /// Balances of every sub-account in each currency, keyed by sub-account
+    /// then currency, those of a currency summing to its balances. Empty
+    /// until a record names a sub-account; until then everything is in the
+    /// main one. A record naming both draws on that pair alone.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) sub_accounts: BTreeMap<Box<str>, BTreeMap<Box<str>, Balances>>,
+    /// Balances in each currency records named, the unnamed one excepted.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) currencies: BTreeMap<Box<str>, Balances>,
+    /// Why, when and by which transaction the account was locked; `None` for
+    /// unlocked accounts and those locked before this was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) lock: Option<LockInfo>,
+}
+
+/// How disputes of withdrawals move funds, see `--withdrawal-disputes`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum WithdrawalDisputes {
+    /// Hold the withdrawn amount pending its return: a resolve lets the
+    /// withdrawal stand, a chargeback returns the amount to `available`
+    #[default]
+    Hold,
+    /// Leave withdrawals undisputable
+    Ignore,
+}
+
+/// What happens to deposits and withdrawals of locked accounts, see
+/// `--locked-policy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum LockedPolicy {
+    /// Drop them
+    #[default]
+    RejectAll,
+    /// Apply deposits, drop withdrawals
+    DepositsOnly,
+    /// Keep them, in order, until the account is unlocked
+    Queue,
+}
+
+/// What locked an account.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LockRule {
+    /// A `chargeback` record.
+    #[default]
+    Chargeback,
+    /// An operator, through `POST /api/accounts/{client}/lock`.
+    Admin,
+    /// A plugin handling a custom transaction type, see `wasm`.
+    Risk,
+}
+
+impl LockRule {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Chargeback => "chargeback",
+            Self::Admin => "admin",
+            Self::Risk => "risk",
+        }
+    }
+}
+
+impl std::str::FromStr for LockRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "chargeback" => Ok(Self::Chargeback),
+            "admin" => Ok(Self::Admin),
+            "risk" => Ok(Self::Risk),
+            _ => anyhow::bail!("unknown lock rule {s:?}"),
+        }
+    }
+}
+
+/// Provenance of an account lock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct LockInfo {
+    /// The transaction that caused the lock; `None` for admin locks.
+    #[serde(default)]
+    pub(crate) tx: Option<TxId>,
+    /// Engine clock time of the lock, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub(crate) at: u64,
+    #[serde(default)]
+    pub(crate) rule: LockRule,
 }
 
+/// Sub-account of records that name none.
+pub(crate) const MAIN_SUB_ACCOUNT: &str = "main";
+/// Currency of records that name none, an empty column in summaries.
+pub(crate) const NO_CURRENCY: &str = "";
+
 impl Account {
-    fn to_csv_line(&self) -> String {
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// Funds that can be withdrawn, in the unnamed currency.
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    /// Funds held by disputes and administrative holds, in the unnamed
+    /// currency.
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    /// `available` plus `held`.
+    pub fn total(&self) -> Amount {
+        self.total
+    }
+
+    /// Set by a chargeback, or by any other rule locking the account.
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Balances of one sub-account in one currency, the main one and the
+    /// unnamed one for `None`.
+    pub(crate) fn bucket(&self, sub: Option<&str>, currency: Option<&str>) -> Balances {
+        let sub = sub.unwrap_or(MAIN_SUB_ACCOUNT);
+        if self.sub_accounts.is_empty() && sub == MAIN_SUB_ACCOUNT {
+            return self.in_currency(currency);
+        }
+        self.sub_accounts
+            .get(sub)
+            .and_then(|currencies| currencies.get(currency.unwrap_or(NO_CURRENCY)))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Balances in one currency, the unnamed one for `None`.
+    pub(crate) fn in_currency(&self, currency: Option<&str>) -> Balances {
+        match currency.filter(|c| *c != NO_CURRENCY) {
+            Some(currency) => self.currencies.get(currency).copied().unwrap_or_default(),
+            None => Balances::from(self),
+        }
+    }
+
+    /// Available funds of a record naming `sub` and `currency`.
+    pub(crate) fn spendable(&self, sub: Option<&str>, currency: Option<&str>) -> Amount {
+        self.bucket(sub, currency).available
+    }
+
+    /// Whether funds are held in any currency, by disputes or holds.
+    pub(crate) fn holds_funds(&self) -> bool {
+        self.currency_balances()
+            .values()
+            .any(|balances| balances.held != Amount::ZERO)
+    }
+
+    /// What the account owes below zero, which a single overdraft limit
+    /// covers: the overdrawn available balances of its currencies, or of its
+    /// sub-accounts in each currency when they owe more; `None` when out of
+    /// range.
+    pub(crate) fn overdrawn(&self) -> Option<Amount> {
+        let currencies = owed(self.currency_balances().values())?;
+        let subs = owed(self.sub_accounts.values().flat_map(BTreeMap::values))?;
+        Some(currencies.max(subs))
+    }
+
+    /// Whether the funds available to a record naming `sub` and `currency`
+    /// cover a debit of `amount`, once they run out drawing on what the
+    /// account's `overdraft` limit has left.
+    pub(crate) fn can_debit(
+        &self,
+        sub: Option<&str>,
+        currency: Option<&str>,
+        amount: Amount,
+        overdraft: Amount,
+    ) -> bool {
+        let spendable = self.spendable(sub, currency);
+        if spendable >= amount {
+            return true;
+        }
+        let shortfall = amount.checked_sub(spendable.max(Amount::ZERO));
+        // the shortfall adds to what the account already owes
+        let owed = match (self.overdrawn(), shortfall) {
+            (Some(owed), Some(shortfall)) => owed.checked_add(shortfall),
+            _ => None,
+        };
+        owed.is_some_and(|owed| owed <= overdraft)
+    }
+
+    /// Balances per currency, the unnamed one under [`NO_CURRENCY`], kept
+    /// even when emptied, for telling which currencies a record moved.
+    pub(crate) fn currency_balances(&self) -> BTreeMap<Box<str>, Balances> {
+        let mut balances = self.currencies.clone();
+        balances.insert(NO_CURRENCY.into(), Balances::from(self));
+        balances
+    }
+
+    /// Sets the balances in `currency`, the unnamed one for `None`; for
+    /// accounts rebuilt from summary rows and change records rather than
+    /// transactions.
+    pub(crate) fn set_currency(&mut self, currency: Option<&str>, balances: Balances) {
+        match currency.filter(|c| *c != NO_CURRENCY) {
+            Some(currency) => {
+                self.currencies.insert(currency.into(), balances);
+            }
+            None => {
+                (self.available, self.held, self.total) =
+                    (balances.available, balances.held, balances.total);
+            }
+        }
+    }
+
+    /// The account split per currency, each part holding the balances in
+    /// one currency; accounts that never named one are a single part in the
+    /// unnamed currency, which others only have when it holds funds.
+    pub(crate) fn per_currency(&self) -> Vec<(&str, Account)> {
+        let unnamed = (NO_CURRENCY, Balances::from(self));
+        let named = self
+            .currencies
+            .iter()
+            .map(|(currency, b)| (&**currency, *b));
+        self.parts(std::iter::once(unnamed).chain(named).collect())
+    }
+
+    /// The account split per sub-account, then per currency as
+    /// [`Account::per_currency`] splits it; accounts that never named a
+    /// sub-account only have the main one.
+    pub(crate) fn per_sub_account(&self) -> Vec<(&str, &str, Account)> {
+        if self.sub_accounts.is_empty() {
+            let parts = self.per_currency().into_iter();
+            return parts
+                .map(|(currency, part)| (MAIN_SUB_ACCOUNT, currency, part))
+                .collect();
+        }
+        let mut parts = Vec::new();
+        for (sub, currencies) in &self.sub_accounts {
+            let currencies = currencies.iter().map(|(currency, b)| (&**currency, *b));
+            for (currency, part) in self.parts(currencies.collect()) {
+                parts.push((&**sub, currency, part));
+            }
+        }
+        parts
+    }
+
+    // an account of the client per currency of `balances`, leaving out the
+    // unnamed one when empty and another holds funds
+    fn parts<'a>(&self, balances: Vec<(&'a str, Balances)>) -> Vec<(&'a str, Account)> {
+        let named = balances
+            .iter()
+            .any(|&(currency, _)| currency != NO_CURRENCY);
+        balances
+            .into_iter()
+            .filter(|&(currency, balances)| {
+                !named || currency != NO_CURRENCY || balances != Balances::default()
+            })
+            .map(|(currency, balances)| {
+                let part = Account {
+                    client: self.client,
+                    available: balances.available,
+                    held: balances.held,
+                    total: balances.total,
+                    locked: self.locked,
+                    closed: self.closed,
+                    lock: self.lock,
+                    ..Default::default()
+                };
+                (currency, part)
+            })
+            .collect()
+    }
+
+    // locks the account, keeping the provenance of an earlier lock
+    fn set_locked(&mut self, tx: Option<TxId>, at: u64, rule: LockRule) {
+        if !self.locked {
+            self.lock = Some(LockInfo { tx, at, rule });
+        }
+        self.locked = true;
+    }
+
+    // moves available and held of the account's `sub` and `currency` pair
+    // and of that currency alike, total following their sum; moves nothing
+    // when any of those balances would go out of range
+    fn shift(
+        &mut self,
+        sub: Option<&str>,
+        currency: Option<&str>,
+        available: Amount,
+        held: Amount,
+    ) -> Result<()> {
+        let moved =
+            |balances: Balances| shifted(balances, available, held).context("balance out of range");
+        let currency_after = moved(self.in_currency(currency))?;
+        let sub_after = moved(self.bucket(sub, currency))?;
+        let sub = sub.unwrap_or(MAIN_SUB_ACCOUNT);
+        if self.sub_accounts.is_empty() && sub != MAIN_SUB_ACCOUNT {
+            // the first other sub-account splits what the account holds so
+            // far off into the main one
+            let main = self.currency_balances();
+            self.sub_accounts.insert(MAIN_SUB_ACCOUNT.into(), main);
+        }
+        if !self.sub_accounts.is_empty() {
+            let currencies = self.sub_accounts.entry(sub.into()).or_default();
+            currencies.insert(currency.unwrap_or(NO_CURRENCY).into(), sub_after);
+        }
+        self.set_currency(currency, currency_after);
+        Ok(())
+    }
+
+    /// Header of [`Account::summary_lines`].
+    pub(crate) fn summary_header(with_closed: bool, with_currency: bool) -> String {
+        let header = Self::csv_header(with_closed);
+        match header.split_once(',') {
+            Some((client, balances)) if with_currency => format!("{client},currency,{balances}"),
+            _ => header.to_owned(),
+        }
+    }
+
+    /// The summary rows of the account: one, or with `with_currency` one per
+    /// currency with a `currency` column after the client.
+    pub(crate) fn summary_lines(&self, with_closed: bool, with_currency: bool) -> Vec<String> {
+        if !with_currency {
+            return vec![self.to_summary_line(with_closed)];
+        }
+        self.per_currency()
+            .into_iter()
+            .map(|(currency, part)| {
+                let line = part.to_summary_line(with_closed);
+                let (client, balances) = line.split_once(',').expect("csv line has columns");
+                format!("{client},{currency},{balances}")
+            })
+            .collect()
+    }
+
+    pub(crate) fn to_csv_line(&self) -> String {
         format!(
             "{},{},{},{},{}",
             self.client, self.available, self.held, self.total, self.locked
         )
     }
+
+    /// Summary header; the `closed` column is only written once some account
+    /// was closed, so summaries of inputs without `close` records keep the
+    /// original five columns.
+    pub(crate) fn csv_header(with_closed: bool) -> &'static str {
+        if with_closed {
+            "client,available,held,total,locked,closed"
+        } else {
+            "client,available,held,total,locked"
+        }
+    }
+
+    pub(crate) fn to_summary_line(&self, with_closed: bool) -> String {
+        if with_closed {
+            format!("{},{}", self.to_csv_line(), self.closed)
+        } else {
+            self.to_csv_line()
+        }
+    }
+
+    /// `lock_tx,lock_rule,locked_at` columns of the extended summary, empty
+    /// when the account's lock has no recorded provenance.
+    pub(crate) fn lock_columns(&self) -> String {
+        match self.lock {
+            Some(lock) => format!(
+                "{},{},{}",
+                lock.tx.map(|tx| tx.to_string()).unwrap_or_default(),
+                lock.rule.as_str(),
+                lock.at
+            ),
+            None => ",,".to_owned(),
+        }
+    }
+}
+
+// reports `tx` as refused for `reason`
+fn refused(tx: &Tx, reason: &'static str) -> EngineError {
+    rejects::refused(tx, reason);
+    EngineError::Refused(reason.into())
+}
+
+// reports `tx` as refused with `err`
+fn refused_with(tx: &Tx, err: impl std::fmt::Display) -> EngineError {
+    let reason = err.to_string();
+    rejects::refused_with(tx, &reason);
+    EngineError::Refused(reason)
+}
+
+// refuses `tx` for the store failing with `err`
+fn store_failed(tx: &Tx, err: Error) -> EngineError {
+    log::error!("tx {}: {} refused: {err:#}", tx.tx_id, tx.tx_type.as_str());
+    rejects::refused(tx, "could not use the transaction store");
+    EngineError::Store(err)
+}
+
+// `balances` with `available` and `held` moved, `None` when out of range
+fn shifted(balances: Balances, available: Amount, held: Amount) -> Option<Balances> {
+    Some(Balances {
+        available: balances.available.checked_add(available)?,
+        held: balances.held.checked_add(held)?,
+        total: balances.total.checked_add(available.checked_add(held)?)?,
+    })
+}
+
+// what the overdrawn available balances of `parts` owe, `None` when out of
+// range
+fn owed<'a>(mut parts: impl Iterator<Item = &'a Balances>) -> Option<Amount> {
+    parts.try_fold(Amount::ZERO, |sum, part| {
+        sum.checked_add(Amount::ZERO.checked_sub(part.available.min(Amount::ZERO))?)
+    })
+}
+
+// the currencies whose balances differ between two `currency_balances` of
+// an account, `None` for the unnamed one, with their balances before and after
+fn changed_currencies(
+    before: &BTreeMap<Box<str>, Balances>,
+    after: &BTreeMap<Box<str>, Balances>,
+) -> Vec<(Option<Box<str>>, Balances, Balances)> {
+    let currencies: BTreeSet<&Box<str>> = before.keys().chain(after.keys()).collect();
+    currencies
+        .into_iter()
+        .filter_map(|currency| {
+            let before = before.get(currency).copied().unwrap_or_default();
+            let after = after.get(currency).copied().unwrap_or_default();
+            let currency = Some(currency.clone()).filter(|c| &**c != NO_CURRENCY);
+            (before != after).then_some((currency, before, after))
+        })
+        .collect()
+}
+
+/// Header of the columns `Account::lock_columns` writes.
+pub(crate) const LOCK_COLUMNS: &str = "lock_tx,lock_rule,locked_at";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Retained {
+    pub(crate) accounts: usize,
+    pub(crate) txs: usize,
+    pub(crate) disputes: usize,
 }
 
 type ClientId = u16;
 type TxId = u32;
 
-pub(crate) struct TxEngine {
+// records between two looks for idle accounts to archive
+const ARCHIVE_SWEEP_EVERY: u64 = 1024;
+
+/// Transactions parked by `TxEngine::defer_future_dated`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Pending {
+    pub count: usize,
+    /// Effective date of the earliest one, in seconds since the unix epoch.
+    pub next_effective: Option<u64>,
+}
+
+/// Records of locked accounts held back by `TxEngine::locked_policy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct LockedRecords {
+    /// Deposits and withdrawals dropped so far.
+    pub dropped: u64,
+    /// Deposits and withdrawals queued until their account is unlocked.
+    pub queued: usize,
+}
+
+/// Deletion report of `TxEngine::erase_client`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Erasure {
+    pub client: ClientId,
+    /// Seconds since the unix epoch, by the engine clock.
+    pub erased_at: u64,
+    pub account: bool,
+    pub txs: usize,
+    pub disputes: usize,
+}
+
+/// A stored transaction with its dispute state and the account it affected,
+/// see `TxEngine::transaction`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxLookup {
+    pub tx: Tx,
+    /// Whether the transaction is among the engine's disputes.
+    pub disputed: bool,
+    /// `None` once the client was erased.
+    pub account: Option<Account>,
+}
+
+/// What the engine has learned from the records applied so far, handed to a
+/// successor on upgrade, see `crate::handoff`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct EngineState {
     accounts: HashMap<ClientId, Account>,
     txs: HashMap<TxId, Tx>,
+    disputes: HashMap<TxId, Tx>,
+    tx_times: HashMap<TxId, SystemTime>,
+    seq: u64,
+    last_seq: HashMap<ClientId, u64>,
+    // keyed by effective timestamp and sequence number, as in the engine
+    pending: Vec<((u64, u64), Tx)>,
+    #[serde(default)]
+    locked_queue: HashMap<ClientId, Vec<Tx>>,
+    #[serde(default)]
+    locked_dropped: u64,
+}
+
+// `EngineState` borrowed from a running engine, written by `TxEngine::snapshot`
+#[derive(Serialize)]
+struct EngineStateRef<'a> {
+    accounts: &'a HashMap<ClientId, Account>,
+    txs: StoredTxs<'a>,
+    disputes: &'a HashMap<TxId, Tx>,
+    tx_times: &'a HashMap<TxId, SystemTime>,
+    seq: u64,
+    last_seq: &'a HashMap<ClientId, u64>,
+    pending: Vec<(&'a (u64, u64), &'a Tx)>,
+    locked_queue: &'a HashMap<ClientId, Vec<Tx>>,
+    locked_dropped: u64,
+}
+
+// the engine's store written as the map `EngineState` reads back
+struct StoredTxs<'a>(&'a dyn TxStore);
+
+impl Serialize for StoredTxs<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for tx in self.0.iter() {
+            let tx = tx.map_err(|err| S::Error::custom(format!("{err:#}")))?;
+            map.serialize_entry(&tx.tx_id, &tx)?;
+        }
+        map.end()
+    }
+}
+
+/// Applies transaction records to the accounts of their clients.
+pub struct TxEngine {
+    accounts: HashMap<ClientId, Account>,
+    // deposits and withdrawals by id, see `crate::store`
+    txs: Box<dyn TxStore>,
     desputes: HashMap<TxId, Tx>,
+    clock: Arc<dyn Clock>,
+    // when set, disputes referencing a tx older than this window are ignored
+    pub(crate) dispute_window: Option<Duration>,
+    // only populated while a dispute window is configured
+    tx_times: HashMap<TxId, SystemTime>,
+    // when set, stored transactions past the dispute window are dropped
+    // unless disputed
+    pub(crate) evict_expired: bool,
+    // stored transactions by the time they were recorded, oldest first; only
+    // populated while `evict_expired` is set
+    expiry: VecDeque<(SystemTime, TxId)>,
+    observers: Vec<Observer>,
+    handlers: HashMap<String, Box<dyn TxHandler>>,
+    hooks: Vec<Box<dyn TxHook>>,
+    // records consumed so far, valid or not
+    seq: u64,
+    // when set, records whose input position is not after the last one applied
+    // for their client are rejected, see `--deterministic`
+    pub(crate) deterministic: bool,
+    // only populated while `deterministic` is set
+    last_seq: HashMap<ClientId, u64>,
+    // when set, idle accounts are moved out of the maps, see `crate::archive`
+    archive: Option<Archive>,
+    // when set, withdrawals are held to a floor on `available`, see `crate::limits`
+    pub(crate) min_balance: Option<MinBalance>,
+    // when set, debits may take `available` below zero up to a limit per
+    // account, see `crate::limits`
+    overdraft: Option<Overdraft>,
+    // when set, records timestamped after the engine clock wait in `pending`
+    // until it reaches their timestamp
+    pub(crate) defer_future_dated: bool,
+    pub(crate) withdrawal_disputes: WithdrawalDisputes,
+    pub(crate) locked_policy: LockedPolicy,
+    // deposits and withdrawals of locked accounts, by client in input order;
+    // only populated under `LockedPolicy::Queue`
+    locked_queue: HashMap<ClientId, Vec<Tx>>,
+    // deposits and withdrawals of locked accounts dropped so far
+    locked_dropped: u64,
+    // clients unlocked with records still in `locked_queue`, applied once the
+    // record unlocking them is
+    unlocked: Vec<ClientId>,
+    // parked records by effective timestamp, then sequence number
+    pending: BTreeMap<(u64, u64), Tx>,
+    // when set, records and admin locks are logged before they apply, see
+    // `crate::wal`
+    wal: Option<Wal>,
+}
+
+impl Default for TxEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TxEngine {
+    /// An engine without accounts, reading time from the system clock.
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub(crate) fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Self {
             accounts: HashMap::new(),
-            txs: HashMap::default(),
+            txs: Box::<MemoryStore>::default(),
             desputes: HashMap::new(),
+            clock,
+            dispute_window: None,
+            tx_times: HashMap::new(),
+            evict_expired: false,
+            expiry: VecDeque::new(),
+            observers: Vec::new(),
+            handlers: HashMap::new(),
+            hooks: Vec::new(),
+            seq: 0,
+            deterministic: false,
+            last_seq: HashMap::new(),
+            archive: None,
+            min_balance: None,
+            overdraft: None,
+            defer_future_dated: false,
+            withdrawal_disputes: WithdrawalDisputes::default(),
+            locked_policy: LockedPolicy::default(),
+            locked_queue: HashMap::new(),
+            locked_dropped: 0,
+            unlocked: Vec::new(),
+            pending: BTreeMap::new(),
+            wal: None,
         }
     }
 
-    pub fn process_tx(&mut self, tx: Tx) {
-        match tx.tx_type {
-            TxType::Deposit | TxType::Withdrawal => {
-                self.process_deposit_and_withdrawal(tx);
-            }
-            TxType::Dispute => {
-                self.process_dispute(tx.tx_id);
-            }
-            TxType::Resolve => {
-                self.process_resolve(tx.tx_id);
-            }
-            TxType::Chargeback => {
-                self.process_chargeback(tx.tx_id);
-            }
-            _ => unreachable!("unidentified transaction type"),
-        }
+    /// Routes records whose type string is `type_name` to `handler`, replacing
+    /// any handler registered for it before. Records of a type nobody handles
+    /// are refused like any other invalid record.
+    ///
+    /// A handler that fails leaves the account as it was:
+    ///
+    /// ```
+    /// use roinstxs::{AccountHandle, Amount, Tx, TxEngine};
+    ///
+    /// let mut engine = TxEngine::new();
+    /// engine.register_handler("fee", |tx: &Tx, account: &mut AccountHandle<'_>| {
+    ///     account.debit(tx.amount().unwrap_or_default())
+    /// });
+    /// engine.process_tx("deposit, 1, 1, 10.0".parse()?)?;
+    /// engine.process_tx("fee, 1, 2, 1.5".parse()?)?;
+    /// assert!(engine.process_tx("fee, 1, 3, 100".parse()?).is_err());
+    ///
+    /// let account = engine.account(1).unwrap();
+    /// assert_eq!(account.available(), "8.5".parse::<Amount>()?);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    ///
+    /// Built-in types are never handed to handlers, so a handler registered
+    /// under one of their names is never called.
+    pub fn register_handler(&mut self, type_name: &str, handler: impl TxHandler + 'static) {
+        self.handlers.insert(type_name.to_owned(), Box::new(handler));
     }
 
-    fn process_deposit_and_withdrawal(&mut self, tx: Tx) {
-        let account = self.accounts.entry(tx.client).or_insert_with(|| Account {
-            client: tx.client,
-            ..Default::default()
-        });
+    /// Adds a hook run around every subsequent transaction.
+    #[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+    pub(crate) fn add_hook(&mut self, hook: Box<dyn TxHook>) {
+        self.hooks.push(hook);
+    }
 
-        if account.locked {
-            return;
+    /// Moves idle accounts without held funds, and their transactions, into
+    /// `archive`, restoring them on their client's next record.
+    pub(crate) fn set_archive(&mut self, archive: Archive) {
+        self.archive = Some(archive);
+    }
+
+    /// Keeps transactions in `store` from now on; set before any record is
+    /// applied, the transactions stored so far staying behind.
+    pub(crate) fn set_store(&mut self, store: Box<dyn TxStore>) {
+        self.txs = store;
+    }
+
+    pub(crate) fn set_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
+    }
+
+    /// Lets withdrawals, transfers, holds and custom handlers' debits take
+    /// an account below zero by up to its limit in `overdraft`.
+    pub(crate) fn set_overdraft(&mut self, overdraft: Overdraft) {
+        self.overdraft = Some(overdraft);
+    }
+
+    /// Lets debits take the account of `client`, or with `None` every account
+    /// without a limit of its own, below zero by up to `limit`, across all
+    /// its currencies and sub-accounts.
+    pub fn set_overdraft_limit(&mut self, client: Option<u16>, limit: Amount) -> Result<()> {
+        anyhow::ensure!(
+            !limit.is_negative(),
+            "overdraft limit must not be negative, got {limit}"
+        );
+        let overdraft = self.overdraft.get_or_insert_with(Overdraft::default);
+        overdraft.set_limit(client, limit);
+        Ok(())
+    }
+
+    // how far below zero the client's account may go
+    fn overdraft_limit(&self, client: u16) -> Amount {
+        let overdraft = self.overdraft.as_ref();
+        overdraft.map_or(Amount::ZERO, |o| o.limit(client))
+    }
+
+    /// Empties the write-ahead log, once a snapshot holds the whole state.
+    pub(crate) fn truncate_wal(&mut self) -> Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.truncate(),
+            None => Ok(()),
         }
+    }
 
-        match tx.tx_type {
-            TxType::Deposit => {
-                if let Some(amount) = tx.amount {
-                    account.available += amount;
-                    account.total += amount;
-                    self.txs.insert(tx.tx_id, tx);
-                }
-            }
-            TxType::Withdrawal => {
-                if let Some(amount) = tx.amount {
-                    if account.available >= amount {
-                        account.available -= amount;
-                        account.total -= amount;
-                    }
-                    self.txs.insert(tx.tx_id, tx);
-                }
-            }
-            _ => unreachable!(),
+    /// Runs `f` on the engine with no observers, for replaying records whose
+    /// events went out before.
+    pub(crate) fn unobserved<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let observers = std::mem::take(&mut self.observers);
+        let result = f(self);
+        self.observers = observers;
+        result
+    }
+
+    /// Registers `observer` to be called with every event the engine emits.
+    pub(crate) fn subscribe(&mut self, observer: impl FnMut(&Event) + Send + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    fn emit(&mut self, event: Event) {
+        for observer in &mut self.observers {
+            observer(&event);
         }
     }
-    fn process_dispute(&mut self, tx_id: TxId) {
-        if let Some(tx) = self.txs.get(&tx_id) {
-            if let Some(amount) = tx.amount {
-                // we do know she/he has account;
-                let account = self.accounts.get_mut(&tx.client).unwrap();
-                account.available -= amount;
-                account.held += amount;
-                self.desputes.insert(tx_id, tx.clone());
+
+    fn record_tx(&mut self, tx: Tx) -> Result<()> {
+        let tx_id = tx.tx_id;
+        // prefer the producer's timestamp so replays age disputes correctly
+        let at = self
+            .dispute_window
+            .map(|_| match tx.meta().and_then(|m| m.timestamp) {
+                Some(secs) => SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                None => self.clock.now(),
+            });
+        self.store_tx(tx)?;
+        if let Some(at) = at {
+            self.tx_times.insert(tx_id, at);
+            if self.evict_expired {
+                self.expiry.push_back((at, tx_id));
             }
         }
+        Ok(())
     }
-    fn process_resolve(&mut self, tx_id: TxId) {
-        if let Some(tx) = self.txs.get(&tx_id) {
-            if let Some(amount) = tx.amount {
-                // we do know she/he has account;
-                let account = self.accounts.get_mut(&tx.client).unwrap();
-                account.available += amount;
-                account.held -= amount;
-                self.desputes.insert(tx_id, tx.clone());
-            }
+
+    fn store_tx(&mut self, tx: Tx) -> Result<()> {
+        let tx_id = tx.tx_id;
+        self.txs
+            .insert(tx)
+            .with_context(|| format!("could not store tx {tx_id}"))
+    }
+
+    fn stored_tx(&self, tx_id: TxId) -> Result<Option<Tx>> {
+        self.txs
+            .get(tx_id)
+            .with_context(|| format!("could not read tx {tx_id} from the store"))
+    }
+
+    // the stored transaction a dispute, resolve or chargeback names, as if
+    // missing when it belongs to another client than the record's
+    fn referenced_tx(&self, record: &Tx) -> Result<Tx, EngineError> {
+        let stored = self.stored_tx(record.tx_id);
+        let Some(tx) = stored.map_err(|err| store_failed(record, err))? else {
+            return Err(refused(record, "unknown transaction"));
+        };
+        if tx.client != record.client {
+            log::warn!(
+                "tx {}: {} refused: transaction belongs to another client",
+                record.tx_id,
+                record.tx_type.as_str()
+            );
+            return Err(refused(record, "transaction belongs to another client"));
         }
+        Ok(tx)
     }
-    fn process_chargeback(&mut self, tx_id: TxId) {
-        if let Some(tx) = self.txs.get(&tx_id) {
-            if let Some(amount) = tx.amount {
-                // we do know she/he has account;
-                let account = self.accounts.get_mut(&tx.client).unwrap();
-                account.total -= amount;
-                account.held -= amount;
-                account.locked = true;
+
+    // drops the stored transactions too old to be disputed, keeping those
+    // under dispute for their resolve or chargeback
+    fn evict_expired_txs(&mut self) {
+        let Some(window) = self.dispute_window else {
+            return;
+        };
+        let now = self.clock.now();
+        while let Some(&(at, tx_id)) = self.expiry.front() {
+            if !now.duration_since(at).is_ok_and(|age| age > window) {
+                break;
+            }
+            self.expiry.pop_front();
+            if self.desputes.contains_key(&tx_id) {
+                continue;
+            }
+            self.tx_times.remove(&tx_id);
+            if let Err(err) = self.txs.remove(tx_id) {
+                log::error!("tx {tx_id}: could not evict from the store: {err:#}");
             }
         }
     }
 
-    pub(crate) fn summarize_accounts(&self, w: impl Write) -> Result<()> {
-        let mut writer = BufWriter::new(w);
-        writeln!(writer, "{}", "client,available,held,total,locked")?;
-        for client in self.accounts.values() {
-            writeln!(writer, "{}", client.to_csv_line())?;
-        }
-        Ok(())
+    fn dispute_expired(&self, tx_id: TxId) -> bool {
+        let (Some(window), Some(at)) = (self.dispute_window, self.tx_times.get(&tx_id)) else {
+            return false;
+        };
+        self.clock
+            .now()
+            .duration_since(*at)
+            .map(|age| age > window)
+            .unwrap_or(false)
     }
-}
+
+    /// Applies `tx`. Invalid records, such as withdrawals exceeding the
+    /// available funds or disputes of unknown transactions, change nothing
+    /// and are returned as [`EngineError::Refused`]; records the engine
+    /// cannot take at all as its other errors, all having been logged.
+    /// Records parked or queued for later count as taken.
+    pub fn process_tx(&mut self, tx: Tx) -> Result<(), EngineError> {
+        if tx.tx_type == TxType::Noop {
+            log::warn!("tx {}: refused: record has no transaction type", tx.tx_id);
+            rejects::refused(&tx, "record has no transaction type");
+            return Err(EngineError::Untyped);
+        }
+        if let Some(wal) = &mut self.wal {
+            if let Err(err) = wal.record(&tx) {
+                log::error!(
+                    "tx {}: could not write the WAL, not applied: {err:#}",
+                    tx.tx_id
+                );
+                rejects::refused(&tx, "could not write the WAL");
+                return Err(EngineError::Wal(err));
+            }
+        }
+        self.seq += 1;
+        if self.evict_expired {
+            self.evict_expired_txs();
+        }
+        if self.deterministic && !self.in_sequence(&tx) {
+            log::warn!(
+                "tx {}: out of input order for client {}, rejected",
+                tx.tx_id,
+                redact::client(tx.client)
+            );
+            rejects::refused(&tx, "out of input order");
+            self.note(tx.client, &tx, Decision::OutOfOrder);
+            return Err(EngineError::OutOfOrder);
+        }
+        if self.defer_future_dated {
+            self.release_due();
+            let effective = tx.meta().and_then(|m| m.timestamp);
+            if let Some(at) = effective.filter(|at| *at > self.now_secs()) {
+                self.note(tx.client, &tx, Decision::Deferred);
+                self.pending.insert((at, self.seq), tx);
+                return Ok(());
+            }
+        }
+        let applied = self.apply_record(tx);
+        self.release_unlocked();
+        applied
+    }
+
+    // applies the records queued for accounts unlocked since, in input order
+    fn release_unlocked(&mut self) {
+        while let Some(client) = self.unlocked.pop() {
+            for tx in self.locked_queue.remove(&client).unwrap_or_default() {
+                // refused and logged as it was applied
+                let _ = self.apply_record(tx);
+            }
+        }
+    }
+
+    /// Applies every parked record whose effective date the engine clock has
+    /// reached, in effective date order.
+    pub(crate) fn release_due(&mut self) {
+        let now = self.now_secs();
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let tx = entry.remove();
+            // refused and logged as it was applied
+            let _ = self.apply_record(tx);
+            self.release_unlocked();
+        }
+    }
+
+    /// Records parked until their effective date.
+    pub(crate) fn pending(&self) -> Pending {
+        Pending {
+            count: self.pending.len(),
+            next_effective: self.pending.keys().next().map(|(at, _)| *at),
+        }
+    }
+
+    fn now_secs(&self) -> u64 {
+        self.clock
+            .now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
+    // keeps the decision on `tx` for `--recent`, with the client's balances
+    fn note(&self, client: ClientId, tx: &Tx, decision: Decision) {
+        if recent::enabled() {
+            let after = self.accounts.get(&client).map(Balances::from);
+            recent::decided(client, tx, self.now_secs(), decision, after);
+        }
+    }
+
+    // applies a record that is due, around hooks and observers
+    fn apply_record(&mut self, tx: Tx) -> Result<(), EngineError> {
+        if self.archive.is_some() {
+            self.track_activity(tx.client);
+            if let Some(counterparty) = tx.counterparty() {
+                self.track_activity(counterparty);
+            }
+        }
+        if self.observers.is_empty() && self.hooks.is_empty() && !recent::enabled() {
+            return self.apply_tx(tx);
+        }
+
+        // dispute-family records act on the client of the referenced tx
+        let client = match tx.tx_type {
+            TxType::Deposit
+            | TxType::Withdrawal
+            | TxType::Close
+            | TxType::Hold
+            | TxType::Release
+            | TxType::Transfer
+            | TxType::Unlock
+            | TxType::Custom => Some(tx.client),
+            // a store failing here fails the record itself, below
+            _ => self.stored_tx(tx.tx_id).ok().flatten().map(|t| t.client),
+        };
+        let snapshot = |engine: &Self| {
+            client
+                .and_then(|c| engine.accounts.get(&c))
+                .map(|a| (a.currency_balances(), a.lock, a.closed))
+        };
+        // transfers credit their counterparty as well
+        let counterparty = tx.counterparty().filter(|_| tx.tx_type == TxType::Transfer);
+        let credited = |engine: &Self| {
+            counterparty
+                .and_then(|c| engine.accounts.get(&c))
+                .map(Account::currency_balances)
+        };
+
+        if !self.run_filters(&tx, client) {
+            self.note(client.unwrap_or(tx.client), &tx, Decision::Filtered);
+            return Ok(());
+        }
+        let hooked = (!self.hooks.is_empty()).then(|| tx.clone());
+        let noted = recent::enabled().then(|| tx.clone());
+
+        let (tx_type, tx_id) = (tx.tx_type, tx.tx_id);
+        let before = snapshot(self);
+        let credited_before = credited(self);
+        let applied = self.apply_tx(tx);
+        if let Some(tx) = hooked {
+            self.run_after(&tx, client);
+        }
+        if let Some(tx) = noted {
+            // an account opened by a refused record is no effect either
+            let after = snapshot(self).unwrap_or_default();
+            let decision = match after == before.clone().unwrap_or_default() {
+                true => Decision::NoEffect,
+                false => Decision::Applied,
+            };
+            self.note(client.unwrap_or(tx.client), &tx, decision);
+        }
+        let (Some(client), Some((after, lock, closed))) = (client, snapshot(self)) else {
+            return applied;
+        };
+        let (before, was_locked, was_closed) = before.unwrap_or_default();
+
+        for (currency, before, after) in changed_currencies(&before, &after) {
+            self.emit(Event::BalanceChanged {
+                client,
+                tx: tx_id,
+                cause: tx_type,
+                currency,
+                before,
+                after,
+            });
+            if tx_type == TxType::Chargeback {
+                self.emit(Event::Chargeback {
+                    client,
+                    tx: tx_id,
+                    amount: before.total - after.total,
+                });
+            }
+        }
+        if let (Some(lock), None) = (lock, was_locked) {
+            self.emit(Event::AccountLocked { client, lock });
+        }
+        if closed && !was_closed {
+            self.emit(Event::AccountClosed { client, tx: tx_id });
+        }
+        if let Some(counterparty) = counterparty {
+            let before = credited_before.unwrap_or_default();
+            let after = credited(self).unwrap_or_default();
+            for (currency, before, after) in changed_currencies(&before, &after) {
+                self.emit(Event::BalanceChanged {
+                    client: counterparty,
+                    tx: tx_id,
+                    cause: tx_type,
+                    currency,
+                    before,
+                    after,
+                });
+            }
+        }
+        applied
+    }
+
+    // per-client sequencing by the input position attached at parse time;
+    // records without one, e.g. from the stream, are not sequenced
+    fn in_sequence(&mut self, tx: &Tx) -> bool {
+        let Some(seq) = tx.seq else {
+            return true;
+        };
+        let last = self.last_seq.entry(tx.client).or_default();
+        if seq <= *last {
+            return false;
+        }
+        *last = seq;
+        true
+    }
+
+    // brings the client back from the archive before its record is applied,
+    // and every so often archives whoever has gone idle
+    fn track_activity(&mut self, client: ClientId) {
+        if self.seq.is_multiple_of(ARCHIVE_SWEEP_EVERY) {
+            self.archive_idle();
+        }
+        let now = self.clock.now();
+        let Some(archive) = self.archive.as_mut() else {
+            return;
+        };
+        archive.touch(client, self.seq, now);
+        if self.accounts.contains_key(&client) {
+            return;
+        }
+        let restored = match archive.take(client) {
+            Ok(Some(archived)) => self.restore_archived(archived),
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = restored {
+            log::error!(
+                "could not restore client {}: {err:#}",
+                redact::client(client)
+            );
+        }
+    }
+
+    // the account comes back even when its transactions cannot, which
+    // leaves them undisputable
+    fn restore_archived(&mut self, archived: ArchivedAccount) -> Result<()> {
+        self.accounts.insert(archived.account.client, archived.account);
+        for (tx, at) in archived.txs {
+            let tx_id = tx.tx_id;
+            self.store_tx(tx)?;
+            if let Some(at) = at {
+                self.tx_times.insert(tx_id, at);
+                if self.evict_expired {
+                    self.expiry.push_back((at, tx_id));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn archive_idle(&mut self) {
+        let now = self.clock.now();
+        let Some(archive) = self.archive.as_mut() else {
+            return;
+        };
+        // held funds mean open disputes, which need the account at hand
+        let idle: Vec<ClientId> = archive
+            .idle(self.seq, now)
+            .into_iter()
+            .filter(|c| self.accounts.get(c).is_none_or(|a| !a.holds_funds()))
+            .collect();
+        if idle.is_empty() {
+            return;
+        }
+
+        let extracted = match self.txs.extract(&mut |tx| idle.contains(&tx.client)) {
+            Ok(extracted) => extracted,
+            Err(err) => {
+                log::error!("could not archive idle clients: {err:#}");
+                return;
+            }
+        };
+        let mut owned: HashMap<ClientId, Vec<(Tx, Option<SystemTime>)>> = HashMap::new();
+        for tx in extracted {
+            let at = self.tx_times.remove(&tx.tx_id);
+            owned.entry(tx.client).or_default().push((tx, at));
+        }
+        for client in idle {
+            let archive = self.archive.as_mut().expect("checked above");
+            // clients whose records were all rejected have nothing to keep
+            let Some(account) = self.accounts.remove(&client) else {
+                archive.forget(client);
+                continue;
+            };
+            let archived = ArchivedAccount {
+                account,
+                txs: owned.remove(&client).unwrap_or_default(),
+            };
+            if let Err(err) = archive.store(&archived) {
+                log::error!("could not archive client {}: {err:#}", redact::client(client));
+                if let Err(err) = self.restore_archived(archived) {
+                    log::error!("could not keep client {}: {err:#}", redact::client(client));
+                }
+            }
+        }
+    }
+
+    // a failing filter rejects the record, the engine fails closed
+    fn run_filters(&mut self, tx: &Tx, client: Option<ClientId>) -> bool {
+        let account = client.and_then(|c| self.accounts.get(&c));
+        self.hooks.iter_mut().all(|hook| {
+            hook.filter(tx, account).unwrap_or_else(|err| {
+                log::warn!("tx {}: filter failed: {err:#}", tx.tx_id);
+                false
+            })
+        })
+    }
+
+    fn run_after(&mut self, tx: &Tx, client: Option<ClientId>) {
+        let account = client.and_then(|c| self.accounts.get(&c));
+        for hook in &mut self.hooks {
+            if let Err(err) = hook.after(tx, account) {
+                log::warn!("tx {}: post-apply hook failed: {err:#}", tx.tx_id);
+            }
+        }
+    }
+
+    fn apply_tx(&mut self, tx: Tx) -> Result<(), EngineError> {
+        match tx.tx_type {
+            TxType::Deposit | TxType::Withdrawal => self.process_deposit_and_withdrawal(tx)?,
+            TxType::Dispute => self.process_dispute(&tx)?,
+            TxType::Resolve => self.process_resolve(&tx)?,
+            TxType::Chargeback => self.process_chargeback(&tx)?,
+            TxType::Close => self.process_close(tx.client).map_err(|err| {
+                log::warn!("tx {}: close refused: {err}", tx.tx_id);
+                refused_with(&tx, err)
+            })?,
+            TxType::Hold | TxType::Release => self.process_admin_hold(&tx).map_err(|err| {
+                log::warn!("tx {}: {} refused: {err}", tx.tx_id, tx.tx_type.as_str());
+                refused_with(&tx, err)
+            })?,
+            TxType::Correction => self.process_correction(&tx).map_err(|err| {
+                log::warn!("tx {}: correction refused: {err}", tx.tx_id);
+                refused_with(&tx, err)
+            })?,
+            TxType::Transfer => self.process_transfer(&tx).map_err(|err| {
+                log::warn!("tx {}: transfer refused: {err}", tx.tx_id);
+                refused_with(&tx, err)
+            })?,
+            TxType::Unlock => self.process_unlock(tx.client).map_err(|err| {
+                log::warn!("tx {}: unlock refused: {err}", tx.tx_id);
+                refused_with(&tx, err)
+            })?,
+            TxType::Custom => self.process_custom(tx)?,
+            // refused by `process_tx` before it reaches here
+            TxType::Noop => {}
+        }
+        Ok(())
+    }
+
+    // reverses the original amount and applies the corrected one in a single
+    // shift, so the account is never seen half-corrected
+    fn process_correction(&mut self, correction: &Tx) -> Result<()> {
+        let tx_id = correction.tx_id;
+        let amount = correction
+            .amount
+            .filter(|a| !a.is_negative())
+            .context("a correction needs a non-negative amount")?;
+        let original = self
+            .stored_tx(tx_id)?
+            .with_context(|| format!("no transaction {tx_id} to correct"))?;
+        anyhow::ensure!(
+            original.client == correction.client,
+            "transaction {tx_id} belongs to another client"
+        );
+        let delta = match (original.tx_type, original.amount) {
+            (TxType::Deposit, Some(was)) => amount.checked_sub(was),
+            (TxType::Withdrawal, Some(was)) => was.checked_sub(amount),
+            _ => anyhow::bail!("only deposits and withdrawals can be corrected"),
+        };
+        let delta = delta.context("balance out of range")?;
+        // disputes, resolves and chargebacks act on the amount held, which
+        // the correction would no longer match
+        anyhow::ensure!(
+            !self.desputes.contains_key(&tx_id),
+            "transaction {tx_id} is disputed"
+        );
+        let client = correction.client;
+        let overdraft = self.overdraft_limit(client);
+        let account = self
+            .accounts
+            .get_mut(&client)
+            .with_context(|| format!("client {} has no account", redact::client(client)))?;
+        anyhow::ensure!(!account.locked, "account {} is locked", redact::client(client));
+        anyhow::ensure!(!account.closed, "account {} is closed", redact::client(client));
+        let (sub, currency) = (original.sub_account(), original.currency());
+        // a correction lowering the balance debits the difference
+        anyhow::ensure!(
+            !delta.is_negative() || account.can_debit(sub, currency, -delta, overdraft),
+            "insufficient available funds"
+        );
+        account.shift(sub, currency, delta, Amount::ZERO)?;
+        let corrected = Tx {
+            amount: Some(amount),
+            ..original.clone()
+        };
+        if let Err(err) = self.store_tx(corrected) {
+            // later disputes would act on the amount before
+            let account = self.accounts.get_mut(&client).expect("checked above");
+            account
+                .shift(sub, currency, -delta, Amount::ZERO)
+                .expect("moving back to the balances before is in range");
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // fails with the condition blocking the close
+    fn process_close(&mut self, client: ClientId) -> Result<()> {
+        let account = self
+            .accounts
+            .get_mut(&client)
+            .with_context(|| format!("client {} has no account", redact::client(client)))?;
+        anyhow::ensure!(!account.closed, "account {} is already closed", redact::client(client));
+        anyhow::ensure!(!account.locked, "account {} is locked", redact::client(client));
+        anyhow::ensure!(
+            !account.holds_funds(),
+            "account {} holds funds in open disputes",
+            redact::client(client)
+        );
+        account.closed = true;
+        Ok(())
+    }
+
+    // fails with the condition blocking the unlock
+    fn process_unlock(&mut self, client: ClientId) -> Result<()> {
+        let account = self
+            .accounts
+            .get_mut(&client)
+            .with_context(|| format!("client {} has no account", redact::client(client)))?;
+        anyhow::ensure!(account.locked, "account {} is not locked", redact::client(client));
+        account.locked = false;
+        account.lock = None;
+        if self.locked_queue.contains_key(&client) {
+            self.unlocked.push(client);
+        }
+        self.emit(Event::AccountUnlocked { client });
+        Ok(())
+    }
+
+    // fails with the condition blocking the hold or release
+    fn process_admin_hold(&mut self, tx: &Tx) -> Result<()> {
+        let reason = tx.reason().context("missing reason code")?;
+        let amount = tx.amount.unwrap_or_default();
+        anyhow::ensure!(
+            amount > Amount::ZERO,
+            "amount must be positive, got {}",
+            redact::amount(amount)
+        );
+        let overdraft = self.overdraft_limit(tx.client);
+        let account = self
+            .accounts
+            .get_mut(&tx.client)
+            .with_context(|| format!("client {} has no account", redact::client(tx.client)))?;
+        anyhow::ensure!(!account.closed, "account {} is closed", redact::client(tx.client));
+        let moved = match tx.tx_type {
+            TxType::Hold => {
+                anyhow::ensure!(
+                    account.can_debit(tx.sub_account(), tx.currency(), amount, overdraft),
+                    "insufficient available funds"
+                );
+                amount
+            }
+            TxType::Release => {
+                anyhow::ensure!(
+                    account.admin_held >= amount
+                        && account.bucket(tx.sub_account(), tx.currency()).held >= amount,
+                    "insufficient funds on hold"
+                );
+                -amount
+            }
+            _ => unreachable!(),
+        };
+        let admin_held = account
+            .admin_held
+            .checked_add(moved)
+            .context("balance out of range")?;
+        account.shift(tx.sub_account(), tx.currency(), -moved, moved)?;
+        account.admin_held = admin_held;
+        self.emit(Event::AdminHold {
+            client: tx.client,
+            tx: tx.tx_id,
+            cause: tx.tx_type,
+            amount,
+            reason: reason.into(),
+        });
+        Ok(())
+    }
+
+    // fails with the condition blocking the transfer, leaving both accounts
+    // untouched
+    fn process_transfer(&mut self, tx: &Tx) -> Result<()> {
+        let amount = tx.amount.unwrap_or_default();
+        anyhow::ensure!(
+            amount > Amount::ZERO,
+            "amount must be positive, got {}",
+            redact::amount(amount)
+        );
+        let to = tx.counterparty().context("missing counterparty")?;
+        anyhow::ensure!(to != tx.client, "cannot transfer to the same client");
+        let from = self
+            .accounts
+            .get(&tx.client)
+            .with_context(|| format!("client {} has no account", redact::client(tx.client)))?;
+        anyhow::ensure!(!from.locked, "account {} is locked", redact::client(tx.client));
+        anyhow::ensure!(!from.closed, "account {} is closed", redact::client(tx.client));
+        let overdraft = self.overdraft_limit(tx.client);
+        anyhow::ensure!(
+            from.can_debit(tx.sub_account(), tx.currency(), amount, overdraft),
+            "insufficient available funds"
+        );
+        if let Some(dest) = self.accounts.get(&to) {
+            anyhow::ensure!(!dest.locked, "account {} is locked", redact::client(to));
+            anyhow::ensure!(!dest.closed, "account {} is closed", redact::client(to));
+        }
+        let from = self.accounts.get_mut(&tx.client).expect("checked above");
+        from.shift(tx.sub_account(), tx.currency(), -amount, Amount::ZERO)?;
+        let dest = self.accounts.entry(to).or_insert_with(|| Account {
+            client: to,
+            ..Default::default()
+        });
+        if let Err(err) = dest.shift(None, tx.currency(), amount, Amount::ZERO) {
+            let from = self.accounts.get_mut(&tx.client).expect("checked above");
+            from.shift(tx.sub_account(), tx.currency(), amount, Amount::ZERO)
+                .expect("moving back to the balances before is in range");
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn process_custom(&mut self, tx: Tx) -> Result<(), EngineError> {
+        let now = self.now_secs();
+        let overdraft = self.overdraft_limit(tx.client);
+        let Some(handler) = self.handlers.get_mut(tx.type_name()) else {
+            return Err(refused(&tx, "unknown transaction type"));
+        };
+        let account = self.accounts.entry(tx.client).or_insert_with(|| Account {
+            client: tx.client,
+            ..Default::default()
+        });
+        if account.closed {
+            return Err(refused(&tx, "account closed"));
+        }
+        // a failed handler leaves the account untouched, like any other
+        // rejected transaction
+        let mut scratch = account.clone();
+        match handler.handle(&tx, &mut AccountHandle::new(&mut scratch, overdraft)) {
+            Ok(()) => {
+                if scratch.locked && !account.locked {
+                    scratch.set_locked(Some(tx.tx_id), now, LockRule::Risk);
+                }
+                *account = scratch;
+                Ok(())
+            }
+            Err(err) => Err(refused_with(&tx, format_args!("{err:#}"))),
+        }
+    }
+
+    fn process_deposit_and_withdrawal(&mut self, tx: Tx) -> Result<(), EngineError> {
+        // a reused id would replace the stored transaction, and with it the
+        // amount later disputes act on
+        let stored = self.stored_tx(tx.tx_id);
+        if stored.map_err(|err| store_failed(&tx, err))?.is_some() {
+            log::warn!(
+                "tx {}: {} refused: duplicate transaction id",
+                tx.tx_id,
+                tx.tx_type.as_str()
+            );
+            return Err(refused(&tx, "duplicate transaction id"));
+        }
+        let overdraft = self.overdraft_limit(tx.client);
+        let account = self.accounts.entry(tx.client).or_insert_with(|| Account {
+            client: tx.client,
+            ..Default::default()
+        });
+
+        if account.closed {
+            return Err(refused(&tx, "account closed"));
+        }
+        if account.locked {
+            match (self.locked_policy, tx.tx_type) {
+                (LockedPolicy::DepositsOnly, TxType::Deposit) => {}
+                (LockedPolicy::Queue, _) => {
+                    self.locked_queue.entry(tx.client).or_default().push(tx);
+                    return Ok(());
+                }
+                _ => {
+                    self.locked_dropped += 1;
+                    return Err(refused(&tx, "account locked"));
+                }
+            }
+        }
+
+        let Some(amount) = tx.amount else {
+            return Err(refused(&tx, "missing amount"));
+        };
+        // records not built by `Tx::new`, e.g. read back from JSON
+        if amount <= Amount::ZERO {
+            return Err(refused(&tx, "amount must be positive"));
+        }
+        let mut breach = None;
+        let moved = match tx.tx_type {
+            TxType::Deposit => {
+                account
+                    .shift(tx.sub_account(), tx.currency(), amount, Amount::ZERO)
+                    .map_err(|_| refused(&tx, "balance out of range"))?;
+                amount
+            }
+            TxType::Withdrawal => {
+                let minimum = self.min_balance.as_ref().and_then(|m| {
+                    let available = account.in_currency(tx.currency()).available;
+                    let left = available.checked_sub(amount);
+                    m.minimum(tx.client)
+                        .filter(|min| left.is_none_or(|left| left < *min))
+                        .map(|min| (min, m.policy))
+                });
+                match minimum {
+                    Some((min, MinBalancePolicy::Reject)) => {
+                        log::warn!(
+                            "tx {}: withdrawal would take client {} below its minimum of {}, rejected",
+                            tx.tx_id,
+                            redact::client(tx.client),
+                            redact::amount(min)
+                        );
+                        return Err(refused(&tx, "below the minimum balance"));
+                    }
+                    _ if account.can_debit(tx.sub_account(), tx.currency(), amount, overdraft) => {
+                        account
+                            .shift(tx.sub_account(), tx.currency(), -amount, Amount::ZERO)
+                            .map_err(|_| refused(&tx, "balance out of range"))?;
+                        breach = minimum
+                            .map(|(min, _)| (min, account.in_currency(tx.currency()).available));
+                        -amount
+                    }
+                    _ => return Err(refused(&tx, "insufficient funds")),
+                }
+            }
+            _ => unreachable!(),
+        };
+        if let Err(err) = self.record_tx(tx.clone()) {
+            // a record disputes could not find is not applied either
+            let account = self.accounts.get_mut(&tx.client).expect("checked above");
+            account
+                .shift(tx.sub_account(), tx.currency(), -moved, Amount::ZERO)
+                .expect("moving back to the balances before is in range");
+            return Err(store_failed(&tx, err));
+        }
+        if let Some((minimum, available)) = breach {
+            log::warn!(
+                "tx {}: withdrawal took client {} below its minimum of {}, flagged",
+                tx.tx_id,
+                redact::client(tx.client),
+                redact::amount(minimum)
+            );
+            self.emit(Event::MinimumBreached {
+                client: tx.client,
+                tx: tx.tx_id,
+                currency: tx.currency().map(Into::into),
+                available,
+                minimum,
+            });
+        }
+        Ok(())
+    }
+
+    // how a dispute, resolve or chargeback of `tx` moves available and held
+    // funds, `None` when withdrawal disputes are ignored
+    fn dispute_shift(&self, tx: &Tx, amount: Amount, stage: TxType) -> Option<(Amount, Amount)> {
+        let withdrawal = match (tx.tx_type, self.withdrawal_disputes) {
+            (TxType::Withdrawal, WithdrawalDisputes::Ignore) => return None,
+            (tx_type, _) => tx_type == TxType::Withdrawal,
+        };
+        // a disputed deposit holds funds still available, a disputed
+        // withdrawal funds already gone
+        Some(match (stage, withdrawal) {
+            (TxType::Dispute, false) => (-amount, amount),
+            (TxType::Dispute, true) => (Amount::ZERO, amount),
+            (TxType::Resolve, false) => (amount, -amount),
+            (TxType::Resolve, true) => (Amount::ZERO, -amount),
+            (TxType::Chargeback, false) => (Amount::ZERO, -amount),
+            (TxType::Chargeback, true) => (amount, -amount),
+            _ => unreachable!(),
+        })
+    }
+    fn process_dispute(&mut self, record: &Tx) -> Result<(), EngineError> {
+        let tx_id = record.tx_id;
+        if self.dispute_expired(tx_id) {
+            return Err(refused(record, "dispute window expired"));
+        }
+        let tx = self.referenced_tx(record)?;
+        // a second dispute would hold the amount twice
+        if self.desputes.contains_key(&tx_id) {
+            return Err(refused(record, "transaction already disputed"));
+        }
+        if let Some(amount) = tx.amount {
+            let Some((available, held)) = self.dispute_shift(&tx, amount, TxType::Dispute) else {
+                return Err(refused(record, "withdrawal disputes are ignored"));
+            };
+            // we do know she/he has account;
+            let account = self.accounts.get_mut(&tx.client).unwrap();
+            if account.closed {
+                return Err(refused(record, "account closed"));
+            }
+            account
+                .shift(tx.sub_account(), tx.currency(), available, held)
+                .map_err(|_| refused(record, "balance out of range"))?;
+            self.desputes.insert(tx_id, tx);
+        }
+        Ok(())
+    }
+    // the disputed transaction a resolve or chargeback refers to
+    fn disputed_tx(&self, record: &Tx) -> Result<Tx, EngineError> {
+        let tx = self.referenced_tx(record)?;
+        if !self.desputes.contains_key(&record.tx_id) {
+            return Err(refused(record, "transaction not disputed"));
+        }
+        Ok(tx)
+    }
+
+    fn process_resolve(&mut self, record: &Tx) -> Result<(), EngineError> {
+        let tx_id = record.tx_id;
+        let tx = self.disputed_tx(record)?;
+        if let Some(amount) = tx.amount {
+            let Some((available, held)) = self.dispute_shift(&tx, amount, TxType::Resolve) else {
+                return Err(refused(record, "withdrawal disputes are ignored"));
+            };
+            // we do know she/he has account;
+            let account = self.accounts.get_mut(&tx.client).unwrap();
+            if account.closed {
+                return Err(refused(record, "account closed"));
+            }
+            account
+                .shift(tx.sub_account(), tx.currency(), available, held)
+                .map_err(|_| refused(record, "balance out of range"))?;
+            self.desputes.remove(&tx_id);
+        }
+        Ok(())
+    }
+    fn process_chargeback(&mut self, record: &Tx) -> Result<(), EngineError> {
+        let tx_id = record.tx_id;
+        let now = self.now_secs();
+        let tx = self.disputed_tx(record)?;
+        if let Some(amount) = tx.amount {
+            let Some((available, held)) = self.dispute_shift(&tx, amount, TxType::Chargeback)
+            else {
+                return Err(refused(record, "withdrawal disputes are ignored"));
+            };
+            // we do know she/he has account;
+            let account = self.accounts.get_mut(&tx.client).unwrap();
+            if account.closed {
+                return Err(refused(record, "account closed"));
+            }
+            account
+                .shift(tx.sub_account(), tx.currency(), available, held)
+                .map_err(|_| refused(record, "balance out of range"))?;
+            account.set_locked(Some(tx_id), now, LockRule::Chargeback);
+            self.desputes.remove(&tx_id);
+        }
+        Ok(())
+    }
+
+    /// Removes the client's account and every transaction and dispute of it,
+    /// for right-to-erasure requests. Observers get a `ClientErased` event to
+    /// delete their own copies, even when the engine knew nothing about the
+    /// client, since downstream copies may outlive the engine's.
+    pub(crate) fn erase_client(&mut self, client: ClientId) -> Erasure {
+        let account = self.accounts.remove(&client).is_some();
+        let erased = self
+            .txs
+            .extract(&mut |tx| tx.client == client)
+            .unwrap_or_else(|err| {
+                log::error!("could not erase client from the store: {err:#}");
+                Vec::new()
+            });
+        for tx in &erased {
+            self.tx_times.remove(&tx.tx_id);
+        }
+        let disputes = self.desputes.len();
+        self.desputes.retain(|_, tx| tx.client != client);
+        self.pending.retain(|_, tx| tx.client != client);
+        self.locked_queue.remove(&client);
+        self.last_seq.remove(&client);
+        let (mut account, mut txs) = (account, erased.len());
+        if let Some(archive) = &mut self.archive {
+            archive.forget(client);
+            match archive.take(client) {
+                Ok(Some(archived)) => {
+                    account = true;
+                    txs += archived.txs.len();
+                }
+                Ok(None) => {}
+                Err(err) => log::error!("could not erase archived client: {err:#}"),
+            }
+        }
+        if let Some(wal) = &mut self.wal {
+            if let Err(err) = wal.scrub(client) {
+                log::error!("could not erase client from the WAL: {err:#}");
+            }
+        }
+
+        self.emit(Event::ClientErased { client });
+        Erasure {
+            client,
+            erased_at: self.now_secs(),
+            account,
+            txs,
+            disputes: disputes - self.desputes.len(),
+        }
+    }
+
+    /// Locks the client's account on an operator's request, returning the
+    /// account, or `None` when the client has none. Locking a locked account
+    /// keeps the provenance of its first lock.
+    pub(crate) fn lock_account(&mut self, client: ClientId) -> Option<Account> {
+        let now = self.now_secs();
+        let account = self.accounts.get_mut(&client)?;
+        if account.locked {
+            return Some(account.clone());
+        }
+        if let Some(wal) = &mut self.wal {
+            // the lock stands regardless; a replay misses it
+            if let Err(err) = wal.lock(client) {
+                log::error!("could not write the WAL: {err:#}");
+            }
+        }
+        account.set_locked(None, now, LockRule::Admin);
+        let account = account.clone();
+        if let Some(lock) = account.lock {
+            self.emit(Event::AccountLocked { client, lock });
+        }
+        Some(account)
+    }
+
+    /// Unlocks the client's account on an operator's request and applies the
+    /// records queued while it was locked, returning the account, or `None`
+    /// when the client has none.
+    pub(crate) fn unlock_account(&mut self, client: ClientId) -> Option<Account> {
+        if !self.accounts.get(&client)?.locked {
+            return self.accounts.get(&client).cloned();
+        }
+        if let Some(wal) = &mut self.wal {
+            // the unlock stands regardless; a replay misses it
+            if let Err(err) = wal.unlock(client) {
+                log::error!("could not write the WAL: {err:#}");
+            }
+        }
+        self.process_unlock(client).expect("checked above");
+        self.release_unlocked();
+        self.accounts.get(&client).cloned()
+    }
+
+    /// Replaces the client's account with `account` wholesale, or drops it for
+    /// `None`, as of the primary's sequence number `seq`; for read replicas,
+    /// see `crate::replication`.
+    pub(crate) fn mirror(&mut self, client: ClientId, account: Option<Account>, seq: u64) {
+        match account {
+            Some(account) => {
+                self.accounts.insert(client, account);
+            }
+            None => {
+                self.accounts.remove(&client);
+            }
+        }
+        self.seq = self.seq.max(seq);
+    }
+
+    /// Moves the accounts, transactions, disputes and parked records out of
+    /// the engine, leaving it empty; fails, taking nothing, when the store
+    /// cannot be read.
+    pub(crate) fn take_state(&mut self) -> Result<EngineState> {
+        let txs = self
+            .txs
+            .extract(&mut |_| true)
+            .context("could not read the store")?;
+        self.expiry.clear();
+        Ok(EngineState {
+            accounts: std::mem::take(&mut self.accounts),
+            txs: txs.into_iter().map(|tx| (tx.tx_id, tx)).collect(),
+            disputes: std::mem::take(&mut self.desputes),
+            tx_times: std::mem::take(&mut self.tx_times),
+            seq: std::mem::take(&mut self.seq),
+            last_seq: std::mem::take(&mut self.last_seq),
+            pending: std::mem::take(&mut self.pending).into_iter().collect(),
+            locked_queue: std::mem::take(&mut self.locked_queue),
+            locked_dropped: std::mem::take(&mut self.locked_dropped),
+        })
+    }
+
+    /// Adds `state`, taken from an engine of other clients with
+    /// [`TxEngine::take_state`], to the engine's. No events are emitted.
+    /// Fails on a transaction the engine holds already, which would replace
+    /// it.
+    pub(crate) fn merge_state(&mut self, state: EngineState) -> Result<()> {
+        for tx in state.txs.into_values() {
+            anyhow::ensure!(
+                self.stored_tx(tx.tx_id)?.is_none(),
+                "tx {} was taken by two shards, transaction ids must be unique across clients",
+                tx.tx_id
+            );
+            self.store_tx(tx)?;
+        }
+        self.accounts.extend(state.accounts);
+        self.desputes.extend(state.disputes);
+        self.tx_times.extend(state.tx_times);
+        self.last_seq.extend(state.last_seq);
+        // numbered after the engine's own, keeping their order among themselves
+        for ((at, seq), tx) in state.pending {
+            self.pending.insert((at, self.seq + seq), tx);
+        }
+        self.locked_queue.extend(state.locked_queue);
+        self.locked_dropped += state.locked_dropped;
+        self.seq += state.seq;
+        Ok(())
+    }
+
+    /// Replaces the engine's state with `state`, taken from a predecessor
+    /// with [`TxEngine::take_state`]. No events are emitted.
+    pub(crate) fn restore_state(&mut self, state: EngineState) -> Result<()> {
+        // custom records wait for handlers of their type, which must exist
+        let parked = state.pending.iter().map(|(_, tx)| tx);
+        let queued = state.locked_queue.values().flatten();
+        let stored = state.txs.values().chain(state.disputes.values());
+        for tx in stored.chain(parked).chain(queued) {
+            if tx.tx_type == TxType::Custom && !self.handlers.contains_key(tx.type_name()) {
+                anyhow::bail!(
+                    "tx {} is of type {:?}, which has no registered handler",
+                    tx.tx_id,
+                    tx.type_name()
+                );
+            }
+        }
+        // whatever the store held is replaced
+        self.txs
+            .extract(&mut |_| true)
+            .context("could not clear the store")?;
+        for tx in state.txs.into_values() {
+            self.store_tx(tx)?;
+        }
+        self.accounts = state.accounts;
+        self.desputes = state.disputes;
+        self.tx_times = state.tx_times;
+        if self.evict_expired {
+            let mut expiry: Vec<_> = self.tx_times.iter().map(|(id, at)| (*at, *id)).collect();
+            expiry.sort_unstable();
+            self.expiry = expiry.into();
+        }
+        self.seq = state.seq;
+        self.last_seq = state.last_seq;
+        self.pending = state.pending.into_iter().collect();
+        self.locked_queue = state.locked_queue;
+        self.locked_dropped = state.locked_dropped;
+        Ok(())
+    }
+
+    /// Writes the accounts, stored transactions, open disputes and parked
+    /// records to `w` as JSON, for [`TxEngine::restore`] to read back, e.g.
+    /// in the next run of a stream server.
+    pub fn snapshot(&self, w: impl Write) -> Result<()> {
+        let state = EngineStateRef {
+            accounts: &self.accounts,
+            txs: StoredTxs(self.txs.as_ref()),
+            disputes: &self.desputes,
+            tx_times: &self.tx_times,
+            seq: self.seq,
+            last_seq: &self.last_seq,
+            pending: self.pending.iter().collect(),
+            locked_queue: &self.locked_queue,
+            locked_dropped: self.locked_dropped,
+        };
+        let mut writer = BufWriter::new(w);
+        serde_json::to_writer(&mut writer, &state).context("could not write snapshot")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Replaces the engine's state with a snapshot [`TxEngine::snapshot`]
+    /// wrote. No events are emitted. Records of custom types keep their
+    /// type, which must have a handler registered beforehand.
+    pub fn restore(&mut self, r: impl Read) -> Result<()> {
+        let state =
+            serde_json::from_reader(BufReader::new(r)).context("could not read snapshot")?;
+        self.restore_state(state)
+    }
+
+    /// Applies `tx`, returning why it was refused, if it was.
+    #[cfg(test)]
+    pub(crate) fn try_apply(&mut self, tx: Tx) -> Option<String> {
+        match self.process_tx(tx) {
+            Ok(()) => None,
+            Err(EngineError::Refused(reason)) => Some(reason),
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// Applies the record `line`, returning why it was refused, if it was.
+    #[cfg(test)]
+    pub(crate) fn apply_line(&mut self, line: &str) -> Option<String> {
+        self.try_apply(line.parse().unwrap())
+    }
+
+    /// All known accounts, in no particular order.
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    /// Transactions that have been disputed, in no particular order.
+    pub(crate) fn disputes(&self) -> impl Iterator<Item = &Tx> {
+        self.desputes.values()
+    }
+
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// The deposit or withdrawal `tx_id` as stored, `None` if the engine
+    /// keeps none by that id.
+    pub(crate) fn transaction(&self, tx_id: TxId) -> Result<Option<TxLookup>> {
+        let Some(tx) = self.stored_tx(tx_id)? else {
+            return Ok(None);
+        };
+        Ok(Some(TxLookup {
+            disputed: self.desputes.contains_key(&tx_id),
+            account: self.accounts.get(&tx.client).cloned(),
+            tx,
+        }))
+    }
+
+    /// Deposits and withdrawals of locked accounts held back so far.
+    pub(crate) fn locked_records(&self) -> LockedRecords {
+        LockedRecords {
+            dropped: self.locked_dropped,
+            queued: self.locked_queue.values().map(Vec::len).sum(),
+        }
+    }
+
+    /// Number of entries held in each of the engine's maps.
+    pub(crate) fn retained(&self) -> Retained {
+        Retained {
+            accounts: self.accounts.len(),
+            txs: self.txs.len(),
+            disputes: self.desputes.len(),
+        }
+    }
+
+    /// Checks the bookkeeping invariants that must hold after every applied
+    /// transaction, returning a description of the first violation found.
+    pub(crate) fn check_invariants(&self) -> Result<()> {
+        for account in self.accounts.values() {
+            for (currency, part) in account.per_currency() {
+                if part.available + part.held != part.total {
+                    anyhow::bail!(
+                        "client {}: available {} + held {} != total {} in currency {currency:?}",
+                        redact::client(account.client),
+                        redact::amount(part.available),
+                        redact::amount(part.held),
+                        redact::amount(part.total)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Current time by the engine clock.
+    pub(crate) fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    /// Number of records consumed so far. Two summaries carrying the same
+    /// sequence number were taken at the same logical point of the input.
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// `summarize_accounts` for async writers such as sockets, flushing the
+    /// summary once written.
+    pub fn summarize_accounts_async<'a>(
+        &'a self,
+        w: impl AsyncWrite + Unpin + 'a,
+    ) -> impl Future<Output = std::io::Result<()>> + 'a {
+        // borrowing the accounts alone keeps the future `Send` for `Send`
+        // writers, the engine itself is not `Sync`
+        let accounts = &self.accounts;
+        async move {
+            let mut writer = tokio::io::BufWriter::new(w);
+            let with_closed = accounts.values().any(|a| a.closed);
+            let with_currency = accounts.values().any(|a| !a.currencies.is_empty());
+            let header = Account::summary_header(with_closed, with_currency);
+            writer.write_all(format!("{header}\n").as_bytes()).await?;
+            for client in accounts.values() {
+                for line in client.summary_lines(with_closed, with_currency) {
+                    writer.write_all(format!("{line}\n").as_bytes()).await?;
+                }
+            }
+            writer.flush().await
+        }
+    }
+
+    /// Writes the `client,available,held,total,locked` summary of every
+    /// account, in no particular order. Once a record named a currency every
+    /// account has a row per currency, after a `currency` column.
+    pub fn summarize_accounts(&self, w: impl Write) -> Result<()> {
+        let mut writer = BufWriter::new(w);
+        let with_closed = self.accounts.values().any(|a| a.closed);
+        let with_currency = self.accounts.values().any(|a| !a.currencies.is_empty());
+        let header = Account::summary_header(with_closed, with_currency);
+        writeln!(writer, "{header}")?;
+        for client in self.accounts.values() {
+            for line in client.summary_lines(with_closed, with_currency) {
+                writeln!(writer, "{line}")?;
+            }
+        }
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
 
     #[test]
     fn test_dispute_resolve_and_chargeback_flow() {
         let mut engine = TxEngine::new();
 
-        engine.process_tx(Tx {
-            tx_type: TxType::Deposit,
-            client: 1,
-            tx_id: 1,
-            amount: Some(1000.0),
-        });
-        engine.process_tx(Tx {
-            tx_type: TxType::Deposit,
-            client: 1,
-            tx_id: 2,
-            amount: Some(500.0),
-        });
+        engine
+            .process_tx(Tx {
+                tx_type: TxType::Deposit,
+                client: 1,
+                tx_id: 1,
+                amount: Some(Amount::from_units(1000)),
+                custom_type: None,
+                meta: None,
+                seq: None,
+            })
+            .unwrap();
+        engine
+            .process_tx(Tx {
+                tx_type: TxType::Deposit,
+                client: 1,
+                tx_id: 2,
+                amount: Some(Amount::from_units(500)),
+                custom_type: None,
+                meta: None,
+                seq: None,
+            })
+            .unwrap();
 
-        engine.process_tx(Tx {
-            tx_type: TxType::Dispute,
-            client: 1,
-            tx_id: 1,
-            amount: None,
-        });
+        engine
+            .process_tx(Tx {
+                tx_type: TxType::Dispute,
+                client: 1,
+                tx_id: 1,
+                amount: None,
+                custom_type: None,
+                meta: None,
+                seq: None,
+            })
+            .unwrap();
 
         {
             let account = engine.accounts.get(&1).unwrap();
-            assert_eq!(account.available, 500.0); 
-            assert_eq!(account.held, 1000.0); 
-            assert_eq!(account.total, 1500.0);
+            assert_eq!(account.available, Amount::from_units(500)); 
+            assert_eq!(account.held, Amount::from_units(1000)); 
+            assert_eq!(account.total, Amount::from_units(1500));
             assert!(!account.locked);
         }
 
-        engine.process_tx(Tx {
-            tx_type: TxType::Resolve,
-            client: 1,
-            tx_id: 1,
-            amount: None,
-        });
+        engine
+            .process_tx(Tx {
+                tx_type: TxType::Resolve,
+                client: 1,
+                tx_id: 1,
+                amount: None,
+                custom_type: None,
+                meta: None,
+                seq: None,
+            })
+            .unwrap();
 
         {
             let account = engine.accounts.get(&1).unwrap();
-            assert_eq!(account.available, 1500.0); 
-            assert_eq!(account.held, 0.0); 
-            assert_eq!(account.total, 1500.0); 
+            assert_eq!(account.available, Amount::from_units(1500)); 
+            assert_eq!(account.held, Amount::ZERO); 
+            assert_eq!(account.total, Amount::from_units(1500)); 
             assert!(!account.locked);
         }
 
-        engine.process_tx(Tx {
+        engine
+            .process_tx(Tx {
+                tx_type: TxType::Dispute,
+                client: 1,
+                tx_id: 2,
+                amount: None,
+                custom_type: None,
+                meta: None,
+                seq: None,
+            })
+            .unwrap();
+        engine
+            .process_tx(Tx {
+                tx_type: TxType::Chargeback,
+                client: 1,
+                tx_id: 2,
+                amount: None,
+                custom_type: None,
+                meta: None,
+                seq: None,
+            })
+            .unwrap();
+
+        {
+            let account = engine.accounts.get(&1).unwrap();
+            assert_eq!(account.available, Amount::from_units(1000));
+            assert_eq!(account.held, Amount::ZERO); 
+            assert_eq!(account.total, Amount::from_units(1000)); 
+            assert!(account.locked); 
+        }
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_policies() {
+        let run = |policy, last: &str| {
+            let mut engine = TxEngine::new();
+            engine.withdrawal_disputes = policy;
+            for line in [
+                "deposit, 1, 1, 10.0",
+                "withdrawal, 1, 2, 4.0",
+                "withdrawal, 1, 3, 50.0",
+                "dispute, 1, 3,",
+                "dispute, 1, 2,",
+                last,
+            ] {
+                engine.apply_line(line);
+            }
+            let account = engine.account(1).unwrap().clone();
+            (account.available, account.held, account.total, account.locked)
+        };
+        let units = Amount::from_units;
+
+        // the withdrawn 4 is held pending its return; the refused withdrawal
+        // is no transaction to dispute
+        assert_eq!(
+            run(WithdrawalDisputes::Hold, "noop, 1, 2,"),
+            (units(6), units(4), units(10), false)
+        );
+        assert_eq!(
+            run(WithdrawalDisputes::Hold, "resolve, 1, 2,"),
+            (units(6), Amount::ZERO, units(6), false)
+        );
+        assert_eq!(
+            run(WithdrawalDisputes::Hold, "chargeback, 1, 2,"),
+            (units(10), Amount::ZERO, units(10), true)
+        );
+        for last in ["resolve, 1, 2,", "chargeback, 1, 2,"] {
+            assert_eq!(
+                run(WithdrawalDisputes::Ignore, last),
+                (units(6), Amount::ZERO, units(6), false)
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolves_and_chargebacks_need_an_open_dispute() {
+        let run = |lines: &[&str]| {
+            let mut engine = TxEngine::new();
+            let opening = ["deposit, 1, 1, 10.0", "withdrawal, 1, 2, 4.0"];
+            for line in opening.iter().chain(lines) {
+                engine.apply_line(line);
+            }
+            let account = engine.account(1).unwrap().clone();
+            (account.available, account.held, account.locked)
+        };
+        let units = Amount::from_units;
+
+        // without a dispute nothing moves, and a second dispute holds nothing
+        // more
+        for tx in [1, 2] {
+            for last in ["resolve", "chargeback"] {
+                let line = format!("{last}, 1, {tx},");
+                assert_eq!(run(&[&line]), (units(6), Amount::ZERO, false));
+            }
+        }
+        let twice = ["dispute, 1, 1,", "dispute, 1, 1,"];
+        assert_eq!(run(&twice), (units(-4), units(10), false));
+        let twice = ["dispute, 1, 2,", "dispute, 1, 2,"];
+        assert_eq!(run(&twice), (units(6), units(4), false));
+
+        // a dispute ends with its resolve or chargeback
+        let ended = ["dispute, 1, 1,", "resolve, 1, 1,", "resolve, 1, 1,"];
+        assert_eq!(run(&ended), (units(6), Amount::ZERO, false));
+        let ended = ["dispute, 1, 2,", "chargeback, 1, 2,", "chargeback, 1, 2,"];
+        assert_eq!(run(&ended), (units(10), Amount::ZERO, true));
+        let ended = ["dispute, 1, 2,", "resolve, 1, 2,", "chargeback, 1, 2,"];
+        assert_eq!(run(&ended), (units(6), Amount::ZERO, false));
+    }
+
+    #[test]
+    fn test_balances_out_of_range_are_refused() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 900000000000000",
+            "deposit, 1, 2, 900000000000000",
+            "deposit, 2, 3, 900000000000000",
+            "transfer, 1, 4, 900000000000000, 2",
+            "dispute, 1, 1,",
+        ] {
+            engine.apply_line(line);
+        }
+        let most = Amount::from_units(900_000_000_000_000);
+        let balances = |client| {
+            let account = engine.account(client).unwrap();
+            (account.available, account.held, account.total)
+        };
+        // the dispute went through, the second deposit and the transfer did
+        // not, leaving both sides as they were
+        assert_eq!(balances(1), (Amount::ZERO, most, most));
+        assert_eq!(balances(2), (most, Amount::ZERO, most));
+    }
+
+    #[test]
+    fn test_non_positive_amounts_are_refused() {
+        // a parse error, which --lenient skips and strict runs fail on
+        for line in ["withdrawal, 1, 2, -1000", "deposit, 2, 3, -5"] {
+            let err = Tx::from_str(line).unwrap_err();
+            assert!(matches!(err, TxError::NonPositiveAmount(_)), "{line}");
+        }
+        let err = Tx::from_str("deposit, 2, 4, 0").unwrap_err();
+        assert!(matches!(err, TxError::NonPositiveAmount(_)));
+
+        let mut engine = TxEngine::new();
+        engine.apply_line("deposit, 1, 1, 10");
+        // records that never went through `Tx::new`
+        let negative = |line: &str| Tx {
+            amount: Some("-1000".parse().unwrap()),
+            ..Tx::from_str(line).unwrap()
+        };
+        let refusal = engine.try_apply(negative("withdrawal, 1, 2, 1"));
+        assert_eq!(refusal.as_deref(), Some("amount must be positive"));
+        let refusal = engine.try_apply(negative("deposit, 2, 3, 1"));
+        assert_eq!(refusal.as_deref(), Some("amount must be positive"));
+        assert_eq!(engine.account(1).unwrap().total, Amount::from_units(10));
+        assert!(engine.account(2).is_none_or(|a| a.total == Amount::ZERO));
+    }
+
+    #[test]
+    fn test_corrections_and_holds_out_of_range_are_refused() {
+        let mut engine = TxEngine::new();
+        engine.apply_line("deposit, 1, 1, 1");
+        let low = Tx {
+            amount: Some("-922337203685477.5807".parse().unwrap()),
+            ..Tx::from_str("deposit, 1, 1, 1").unwrap()
+        };
+        engine.txs.insert(low).unwrap();
+        let correction = Tx::from_str("correction, 1, 1, 1").unwrap();
+        let err = engine.process_correction(&correction).unwrap_err();
+        assert_eq!(err.to_string(), "balance out of range");
+
+        engine.accounts.get_mut(&1).unwrap().admin_held = "922337203685477.5807".parse().unwrap();
+        let hold = Tx::from_str("hold, 1, 2, 1; legal").unwrap();
+        let err = engine.process_admin_hold(&hold).unwrap_err();
+        assert_eq!(err.to_string(), "balance out of range");
+        assert_eq!(engine.account(1).unwrap().available, Amount::from_units(1));
+    }
+
+    #[test]
+    fn test_locked_policies() {
+        let run = |policy| {
+            let mut engine = TxEngine::new();
+            engine.locked_policy = policy;
+            for line in [
+                "deposit, 1, 1, 10.0",
+                "deposit, 1, 2, 5.0",
+                "dispute, 1, 2,",
+                "chargeback, 1, 2,",
+                "deposit, 1, 3, 3.0",
+                "withdrawal, 1, 4, 1.0",
+            ] {
+                engine.apply_line(line);
+            }
+            let locked = engine.locked_records();
+            (engine.account(1).unwrap().total, locked.dropped, locked.queued)
+        };
+
+        let units = Amount::from_units;
+        assert_eq!(run(LockedPolicy::RejectAll), (units(10), 2, 0));
+        assert_eq!(run(LockedPolicy::DepositsOnly), (units(13), 1, 0));
+        assert_eq!(run(LockedPolicy::Queue), (units(10), 0, 2));
+    }
+
+    #[test]
+    fn test_unlock_applies_queued_records() {
+        let mut engine = TxEngine::new();
+        engine.locked_policy = LockedPolicy::Queue;
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = events.clone();
+        engine.subscribe(move |event: &Event| seen.lock().unwrap().push(event.clone()));
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 5.0",
+            "dispute, 1, 2,",
+            "chargeback, 1, 2,",
+            "withdrawal, 1, 3, 4.0",
+            "deposit, 1, 4, 1.0",
+            "unlock, 2, 5,",
+            "unlock, 1, 6,",
+        ] {
+            engine.apply_line(line);
+        }
+
+        let account = engine.account(1).unwrap();
+        assert!(!account.locked && account.lock.is_none());
+        assert_eq!(account.total, Amount::from_units(7));
+        assert_eq!(engine.locked_records().queued, 0);
+        // the unlock, then the balance changes of the queued records
+        let events = std::mem::take(&mut *events.lock().unwrap());
+        let unlocked = events
+            .iter()
+            .position(|e| *e == Event::AccountUnlocked { client: 1 })
+            .unwrap();
+        assert_eq!(events.len(), unlocked + 3);
+
+        // operators unlock the same way
+        engine
+            .process_tx(Tx::from_str("deposit, 2, 7, 1.0").unwrap())
+            .unwrap();
+        engine.lock_account(2);
+        engine
+            .process_tx(Tx::from_str("deposit, 2, 8, 1.0").unwrap())
+            .unwrap();
+        assert_eq!(
+            engine.unlock_account(2).unwrap().total,
+            Amount::from_units(2)
+        );
+        assert!(engine.unlock_account(3).is_none());
+    }
+
+    #[test]
+    fn test_dispute_of_another_clients_tx_is_ignored() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "dispute, 2, 1,",
+            "chargeback, 2, 1,",
+            "dispute, 1, 2,",
+        ] {
+            engine.apply_line(line);
+        }
+
+        for (client, available) in [(1, 10), (2, 5)] {
+            let account = engine.account(client).unwrap();
+            assert_eq!(account.available, Amount::from_units(available));
+            assert_eq!(account.held, Amount::ZERO);
+            assert!(!account.locked);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_tx_ids_are_refused() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 1, 100.0",
+            "withdrawal, 2, 1, 5.0",
+            "dispute, 1, 1,",
+        ] {
+            engine.apply_line(line);
+        }
+
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, Amount::ZERO);
+        assert_eq!(account.held, Amount::from_units(10));
+        assert!(engine.account(2).is_none());
+    }
+
+    #[test]
+    fn test_dispute_expires_after_window() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        let mut engine = TxEngine::with_clock(clock.clone());
+        engine.dispute_window = Some(Duration::from_secs(60));
+
+        for tx_id in [1, 2] {
+            engine
+                .process_tx(Tx {
+                    tx_type: TxType::Deposit,
+                    client: 1,
+                    tx_id,
+                    amount: Some(Amount::from_units(10)),
+                    custom_type: None,
+                    meta: None,
+                    seq: None,
+                })
+                .unwrap();
+        }
+
+        clock.advance(Duration::from_secs(30));
+        engine
+            .process_tx(Tx {
+                tx_type: TxType::Dispute,
+                client: 1,
+                tx_id: 1,
+                amount: None,
+                custom_type: None,
+                meta: None,
+                seq: None,
+            })
+            .unwrap();
+
+        clock.advance(Duration::from_secs(31));
+        let refused = engine.try_apply(Tx {
             tx_type: TxType::Dispute,
             client: 1,
             tx_id: 2,
             amount: None,
+            custom_type: None,
+            meta: None,
+            seq: None,
         });
-        engine.process_tx(Tx {
-            tx_type: TxType::Chargeback,
-            client: 1,
-            tx_id: 2,
-            amount: None,
+        assert_eq!(refused.as_deref(), Some("dispute window expired"));
+
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.held, Amount::from_units(10));
+        assert_eq!(account.available, Amount::from_units(10));
+    }
+
+    #[test]
+    fn test_compact_store_evicts_expired() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        let mut engine = TxEngine::with_clock(clock.clone());
+        engine.set_store(Box::<crate::store::CompactStore>::default());
+        engine.dispute_window = Some(Duration::from_secs(60));
+        engine.evict_expired = true;
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 5.0",
+            "dispute, 1, 2,",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        clock.advance(Duration::from_secs(61));
+        engine
+            .process_tx(Tx::from_str("deposit, 1, 3, 1.0").unwrap())
+            .unwrap();
+
+        // the disputed one stays for its resolve
+        assert_eq!(engine.retained().txs, 2);
+        assert!(engine.transaction(1).unwrap().is_none());
+        engine
+            .process_tx(Tx::from_str("resolve, 1, 2,").unwrap())
+            .unwrap();
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.held, Amount::ZERO);
+        assert_eq!(account.available, Amount::from_units(16));
+    }
+
+    #[test]
+    fn test_registered_handler_for_custom_type() {
+        let mut engine = TxEngine::new();
+        engine.register_handler("bonus", |tx: &Tx, account: &mut AccountHandle<'_>| {
+            account.credit(tx.amount().unwrap_or_default())?;
+            account.debit(Amount::from_units(1000))
         });
 
-        {
-            let account = engine.accounts.get(&1).unwrap();
-            assert_eq!(account.available, 1000.0);
-            assert_eq!(account.held, 0.0); 
-            assert_eq!(account.total, 1000.0); 
-            assert!(account.locked); 
+        engine
+            .process_tx(Tx::from_str("deposit, 1, 1, 2000.0").unwrap())
+            .unwrap();
+        engine
+            .process_tx(Tx::from_str("bonus, 1, 2, 5.0").unwrap())
+            .unwrap();
+        let refused = engine.apply_line("bonus, 2, 3, 5.0");
+        assert_eq!(refused.as_deref(), Some("insufficient available funds"));
+        let refused = engine.apply_line("mystery, 3, 4, 5.0");
+        assert_eq!(refused.as_deref(), Some("unknown transaction type"));
+
+        // client 2 could not cover the debit, so its credit was rolled back
+        assert_eq!(engine.accounts.get(&1).unwrap().total, Amount::from_units(1005));
+        assert_eq!(engine.accounts.get(&2).unwrap().total, Amount::ZERO);
+        assert!(!engine.accounts.contains_key(&3));
+        // and the record of no registered type was counted as refused
+        assert!(rejects::refusals()["unknown transaction type"] >= 1);
+    }
+
+    #[test]
+    fn test_unregistered_custom_type_is_refused_until_registered() {
+        let mut engine = TxEngine::new();
+        let refused = engine.apply_line("cashback, 1, 1, 5.0");
+        assert_eq!(refused.as_deref(), Some("unknown transaction type"));
+        assert!(engine.account(1).is_none());
+
+        engine.register_handler("cashback", |tx: &Tx, account: &mut AccountHandle<'_>| {
+            account.credit(tx.amount().unwrap_or_default())
+        });
+        assert_eq!(engine.apply_line("cashback, 1, 1, 5.0"), None);
+        // type names are matched as written
+        let refused = engine.apply_line("Cashback, 1, 2, 5.0");
+        assert_eq!(refused.as_deref(), Some("unknown transaction type"));
+        assert_eq!(engine.account(1).unwrap().total(), Amount::from_units(5));
+    }
+
+    #[test]
+    fn test_handlers_do_not_shadow_built_in_types() {
+        let mut engine = TxEngine::new();
+        engine.register_handler("deposit", |_: &Tx, account: &mut AccountHandle<'_>| {
+            account.credit(Amount::from_units(1000))
+        });
+        assert_eq!(engine.apply_line("deposit, 1, 1, 5.0"), None);
+        assert_eq!(engine.account(1).unwrap().total(), Amount::from_units(5));
+    }
+
+    #[test]
+    fn test_custom_records_cannot_be_disputed() {
+        let mut engine = TxEngine::new();
+        engine.register_handler("bonus", |tx: &Tx, account: &mut AccountHandle<'_>| {
+            account.credit(tx.amount().unwrap_or_default())
+        });
+        assert_eq!(engine.apply_line("bonus, 1, 1, 5.0"), None);
+        // custom records are not stored, so there is nothing to dispute
+        assert!(engine.apply_line("dispute, 1, 1,").is_some());
+        assert!(engine.apply_line("chargeback, 1, 1,").is_some());
+        let account = engine.account(1).unwrap();
+        let balances = (account.available(), account.held());
+        assert_eq!(balances, (Amount::from_units(5), Amount::ZERO));
+        assert!(!account.locked());
+    }
+
+    #[test]
+    fn test_deterministic_rejects_out_of_order_records() {
+        let mut engine = TxEngine::new();
+        engine.deterministic = true;
+        let tx = |line: &str, seq| Tx::from_str(line).unwrap().with_seq(seq);
+
+        engine.process_tx(tx("deposit, 1, 1, 10.0", 2)).unwrap();
+        engine.process_tx(tx("deposit, 2, 2, 5.0", 1)).unwrap();
+        assert!(matches!(
+            engine.process_tx(tx("deposit, 1, 3, 7.0", 1)),
+            Err(EngineError::OutOfOrder)
+        ));
+        engine.process_tx(tx("deposit, 1, 4, 1.0", 3)).unwrap();
+        engine
+            .process_tx(Tx::from_str("deposit, 1, 5, 2.0").unwrap())
+            .unwrap();
+
+        // other clients keep their own sequence, unsequenced records pass
+        assert_eq!(engine.accounts.get(&1).unwrap().total, Amount::from_units(13));
+        assert_eq!(engine.accounts.get(&2).unwrap().total, Amount::from_units(5));
+        assert_eq!(engine.seq(), 5);
+    }
+
+    #[test]
+    fn test_untyped_records_are_errors() {
+        assert!(matches!(
+            Tx::from_str(", 1, 2, 3.0"),
+            Err(TxError::MissingType)
+        ));
+        let err = Tx::from_str("deposit, 1, x, 3.0").unwrap_err();
+        assert_eq!(
+            format!("{:#}", anyhow::Error::from(err)),
+            "could not parse tx to u32: invalid digit found in string"
+        );
+
+        let err = Tx::from_str("deposit, 1, 1, ten").unwrap_err();
+        assert!(matches!(err, TxError::InvalidAmount(_)));
+        assert!(matches!(
+            Tx::new("", 1, 1, Some(Amount::from_units(3))),
+            Err(TxError::MissingType)
+        ));
+
+        // such records only come from code, and no longer panic the engine
+        let mut engine = TxEngine::new();
+        assert!(matches!(
+            engine.process_tx(Tx::default()),
+            Err(EngineError::Untyped)
+        ));
+        assert!(engine.accounts.is_empty());
+        assert_eq!(engine.seq(), 0);
+    }
+
+    #[test]
+    fn test_correction_amends_earlier_amount() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "withdrawal, 1, 2, 4.0",
+            "deposit, 1, 3, 5.0",
+            "correction, 1, 1, 12.0",
+            "correction, 1, 2, 3.0",
+            // refused: another client's tx, more than is available, disputed
+            "correction, 2, 1, 1.0",
+            "correction, 1, 2, 20.0",
+            "dispute, 1, 3,",
+            "correction, 1, 3, 1.0",
+        ] {
+            engine.apply_line(line);
+        }
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (Amount::from_units(9), Amount::from_units(5), Amount::from_units(14))
+        );
+
+        // later disputes act on the corrected amount
+        engine
+            .process_tx(Tx::from_str("dispute, 1, 1,").unwrap())
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().held, Amount::from_units(17));
+    }
+
+    #[test]
+    fn test_close_account() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "dispute, 2, 2,",
+            "close, 1, 3,",
+            "close, 2, 4,",
+            "close, 3, 5,",
+            "deposit, 1, 6, 1.0",
+            "dispute, 1, 1,",
+        ] {
+            engine.apply_line(line);
+        }
+
+        // closed accounts ignore later records, disputed ones cannot close
+        let closed = engine.account(1).unwrap();
+        assert!(closed.closed);
+        assert_eq!((closed.available, closed.held), (Amount::from_units(10), Amount::ZERO));
+        assert!(!engine.account(2).unwrap().closed);
+        assert!(engine.account(3).is_none());
+        assert!(engine.process_close(1).is_err());
+        assert!(engine
+            .process_close(2)
+            .unwrap_err()
+            .to_string()
+            .contains("open disputes"));
+
+        let mut summary = Vec::new();
+        engine.summarize_accounts(&mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        assert!(summary.starts_with("client,available,held,total,locked,closed\n"));
+        assert!(summary.contains("1,10,0,10,false,true\n"));
+    }
+
+    #[test]
+    fn test_admin_hold_and_release() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "hold, 1, 2, 4.0, legal",
+            "dispute, 1, 1,",
+            "deposit, 1, 3, 10.0",
+            "release, 1, 4, 5.0, legal",
+            "hold, 1, 5, 1.0",
+            "release, 1, 6, 3.0, legal",
+        ] {
+            engine.apply_line(line);
+        }
+
+        // the dispute's 10 stays held, only the admin hold can be released
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (Amount::from_units(9), Amount::from_units(11))
+        );
+        assert_eq!(account.admin_held, Amount::from_units(1));
+        assert_eq!(Tx::from_str("hold, 1, 7, 2.5; risk-42").unwrap().reason(), Some("risk-42"));
+    }
+
+    #[test]
+    fn test_transfer_between_clients() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 3, 2, 1.0",
+            "dispute, 3, 2,",
+            "chargeback, 3, 2,",
+            "transfer, 1, 3, 4.0, 2",
+            "transfer, 1, 4, 7.0, 2",
+            "transfer, 1, 5, 1.0, 3",
+            "transfer, 2, 6, 1.0, 2",
+        ] {
+            engine.apply_line(line);
+        }
+
+        // only the first has the funds and an unlocked counterparty other than
+        // the client
+        let available = |client| engine.account(client).unwrap().available;
+        assert_eq!(available(1), Amount::from_units(6));
+        assert_eq!(available(2), Amount::from_units(4));
+        assert_eq!(engine.account(2).unwrap().total, Amount::from_units(4));
+        assert!(Tx::from_str("transfer, 1, 7, 1.0, x").is_err());
+    }
+
+    #[test]
+    fn test_balances_kept_per_currency() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 5.0,, EUR",
+            "withdrawal, 1, 3, 8.0,, EUR",
+            "withdrawal, 1, 4, 2.0,, USD",
+            "withdrawal, 1, 5, 1.0,, EUR",
+            "dispute, 1, 2,",
+            "deposit, 2, 6, 1.0",
+        ] {
+            engine.try_apply(crate::schema::Schema::V2.parse(line).unwrap());
+        }
+
+        // neither withdrawal in EUR nor the one in USD could draw on the
+        // unnamed currency; the dispute held what was left of the EUR deposit
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.in_currency(None).available, Amount::from_units(10));
+        assert_eq!(
+            account.in_currency(Some("EUR")).available,
+            Amount::from_units(-1)
+        );
+        assert_eq!(account.in_currency(Some("EUR")).held, Amount::from_units(5));
+        assert_eq!(account.in_currency(Some("USD")).total, Amount::ZERO);
+        // what the account itself holds is in the unnamed currency, not a sum
+        assert_eq!(account.available(), Amount::from_units(10));
+        assert_eq!(account.held(), Amount::ZERO);
+
+        let mut summary = Vec::new();
+        engine.summarize_accounts(&mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        let mut rows: Vec<&str> = summary.lines().collect();
+        rows.sort();
+        assert_eq!(
+            rows,
+            [
+                "1,,10,0,10,false",
+                "1,EUR,-1,5,4,false",
+                "2,,1,0,1,false",
+                "client,currency,available,held,total,locked"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_future_dated_records_wait_for_the_clock() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(100)));
+        let mut engine = TxEngine::with_clock(clock.clone());
+        engine.defer_future_dated = true;
+        let tx = |line: &str, at| {
+            Tx::from_str(line).unwrap().with_meta(TxMeta {
+                timestamp: Some(at),
+                ..Default::default()
+            })
+        };
+
+        engine.process_tx(tx("deposit, 1, 1, 10.0", 50)).unwrap();
+        engine.process_tx(tx("withdrawal, 1, 2, 4.0", 300)).unwrap();
+        engine.process_tx(tx("deposit, 1, 3, 5.0", 200)).unwrap();
+        assert_eq!(engine.account(1).unwrap().total, Amount::from_units(10));
+        assert_eq!(
+            engine.pending(),
+            Pending {
+                count: 2,
+                next_effective: Some(200)
+            }
+        );
+
+        clock.advance(Duration::from_secs(200));
+        engine.release_due();
+        assert_eq!(engine.account(1).unwrap().total, Amount::from_units(11));
+        assert_eq!(engine.pending().count, 0);
+    }
+
+    #[test]
+    fn test_erase_client() {
+        let mut engine = TxEngine::new();
+        let erased = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = erased.clone();
+        engine.subscribe(move |event: &Event| {
+            if let Event::ClientErased { client } = event {
+                seen.lock().unwrap().push(*client);
+            }
+        });
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 5.0",
+            "deposit, 2, 3, 1.0",
+            "dispute, 1, 2,",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+
+        let report = engine.erase_client(1);
+        assert!(report.account);
+        assert_eq!((report.txs, report.disputes), (2, 1));
+        assert!(engine.account(1).is_none() && engine.account(2).is_some());
+        assert_eq!(engine.retained().txs, 1);
+        assert_eq!(*erased.lock().unwrap(), [1]);
+
+        // later references to the erased transactions find nothing
+        let refused = engine.apply_line("dispute, 1, 1,");
+        assert_eq!(refused.as_deref(), Some("unknown transaction"));
+        assert!(engine.account(1).is_none());
+        assert!(!engine.erase_client(1).account);
+    }
+
+    #[test]
+    fn test_lock_provenance() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(100)));
+        let mut engine = TxEngine::with_clock(clock.clone());
+        let locks = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = locks.clone();
+        engine.subscribe(move |event: &Event| {
+            if let Event::AccountLocked { client, lock } = event {
+                seen.lock().unwrap().push((*client, *lock));
+            }
+        });
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "dispute, 1, 1,",
+            "chargeback, 1, 1,",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        clock.advance(Duration::from_secs(50));
+        engine.lock_account(2).unwrap();
+        // a second lock keeps the provenance of the first
+        assert_eq!(engine.lock_account(2).unwrap().lock.unwrap().at, 150);
+        assert!(engine.lock_account(3).is_none());
+
+        let chargeback = LockInfo {
+            tx: Some(1),
+            at: 100,
+            rule: LockRule::Chargeback,
+        };
+        let admin = LockInfo {
+            tx: None,
+            at: 150,
+            rule: LockRule::Admin,
+        };
+        assert_eq!(*locks.lock().unwrap(), [(1, chargeback), (2, admin)]);
+        assert_eq!(engine.account(1).unwrap().lock_columns(), "1,chargeback,100");
+        assert_eq!(engine.account(2).unwrap().lock_columns(), ",admin,150");
+    }
+
+    #[test]
+    fn test_snapshot_restores_disputes() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 5.0",
+            "dispute, 1, 2,",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
         }
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+
+        let mut restored = TxEngine::new();
+        restored.restore(&snapshot[..]).unwrap();
+        assert_eq!(restored.seq(), 3);
+        assert_eq!(restored.account(1).unwrap().held, Amount::from_units(5));
+        // the dispute and the deposit it holds survived the restart
+        restored
+            .process_tx(Tx::from_str("chargeback, 1, 2,").unwrap())
+            .unwrap();
+        let account = restored.account(1).unwrap();
+        assert!(account.locked);
+        assert_eq!(account.total, Amount::from_units(10));
+        assert!(restored.restore(&b"{}"[..]).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_keeps_the_type_of_custom_records() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let clock = Arc::new(MockClock::new(start));
+        let engine = |clock: &Arc<MockClock>, bonus: bool| {
+            let mut engine = TxEngine::with_clock(clock.clone());
+            engine.defer_future_dated = true;
+            if bonus {
+                engine.register_handler("bonus", |tx: &Tx, account: &mut AccountHandle<'_>| {
+                    account.credit(tx.amount().unwrap_or_default())
+                });
+            }
+            engine
+        };
+        let mut original = engine(&clock, true);
+        let bonus = Tx::from_str("bonus, 1, 1, 5.0").unwrap().with_meta(TxMeta {
+            timestamp: Some(200),
+            ..Default::default()
+        });
+        original.process_tx(bonus).unwrap();
+        assert_eq!(original.pending().count, 1);
+        let mut snapshot = Vec::new();
+        original.snapshot(&mut snapshot).unwrap();
+
+        // a parked record of a type nothing handles cannot be restored
+        let err = engine(&clock, false).restore(&snapshot[..]).unwrap_err();
+        let unknown = r#"tx 1 is of type "bonus", which has no registered handler"#;
+        assert_eq!(err.to_string(), unknown);
+
+        let mut restored = engine(&clock, true);
+        restored.restore(&snapshot[..]).unwrap();
+        clock.advance(Duration::from_secs(200));
+        restored.release_due();
+        assert_eq!(restored.account(1).unwrap().total, Amount::from_units(5));
     }
 }
@@ -0,0 +1,75 @@
+//! Errors of parsing records and of applying them to the engine.
+//!
+//! [`TxError`] is why a line could not become a [`crate::Tx`], and
+//! [`EngineError`] why the engine did not apply one: refused, such as a
+//! withdrawal exceeding the available funds, or not taken at all. Both
+//! convert into `anyhow::Error` for the command line, which tags them with an
+//! exit code, see `crate::exit`.
+
+use crate::amount::Amount;
+use std::num::ParseIntError;
+
+/// A record that could not be parsed.
+#[derive(Debug, thiserror::Error)]
+pub enum TxError {
+    #[error("missing transaction type")]
+    MissingType,
+    #[error("missing {0}")]
+    MissingColumn(&'static str),
+    #[error("could not parse {column} to {target}")]
+    InvalidColumn {
+        column: &'static str,
+        target: &'static str,
+        #[source]
+        source: ParseIntError,
+    },
+    #[error("could not parse amount: {0:#}")]
+    InvalidAmount(anyhow::Error),
+    /// A deposit or withdrawal of zero or less, which would move money the
+    /// wrong way.
+    #[error("amount must be positive, got {0}")]
+    NonPositiveAmount(Amount),
+}
+
+impl TxError {
+    pub(crate) fn invalid<T>(column: &'static str) -> impl FnOnce(ParseIntError) -> Self {
+        move |source| Self::InvalidColumn {
+            column,
+            target: std::any::type_name::<T>(),
+            source,
+        }
+    }
+}
+
+/// A record the engine did not apply.
+#[derive(Debug, thiserror::Error)]
+pub enum EngineError {
+    /// The engine looked at the record and refused it for the reason given,
+    /// changing nothing, e.g. `insufficient funds`.
+    #[error("{0}")]
+    Refused(String),
+    /// Records without a type, such as `Tx::default()`, mean nothing to the
+    /// engine.
+    #[error("record has no transaction type")]
+    Untyped,
+    /// Under `--deterministic`, a record of a client positioned before one
+    /// already applied.
+    #[error("out of input order")]
+    OutOfOrder,
+    /// The write-ahead log could not take the record, so applying it would
+    /// lose it on a crash.
+    #[error("could not write the WAL: {0:#}")]
+    Wal(anyhow::Error),
+    /// The transaction store could not be read or written, so the record
+    /// was not applied.
+    #[error("could not use the transaction store: {0:#}")]
+    Store(anyhow::Error),
+}
+
+impl EngineError {
+    /// Whether the record was refused, which leaves the engine as it was,
+    /// rather than not taken at all.
+    pub fn is_refusal(&self) -> bool {
+        matches!(self, Self::Refused(_))
+    }
+}
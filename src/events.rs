@@ -0,0 +1,78 @@
+//! Events describing what the engine did, delivered to registered observers.
+
+use crate::amount::Amount;
+use crate::engine::{Account, LockInfo, TxType};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Balances {
+    pub(crate) available: Amount,
+    pub(crate) held: Amount,
+    pub(crate) total: Amount,
+}
+
+impl From<&Account> for Balances {
+    fn from(account: &Account) -> Self {
+        Self {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    /// A transaction changed an account's balances in `currency`, `None`
+    /// for the unnamed one; the balances are those in that currency.
+    BalanceChanged {
+        client: u16,
+        tx: u32,
+        cause: TxType,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        currency: Option<Box<str>>,
+        before: Balances,
+        after: Balances,
+    },
+    /// A chargeback reversed a disputed transaction.
+    Chargeback {
+        client: u16,
+        tx: u32,
+        amount: Amount,
+    },
+    /// The account was frozen; `lock` tells by which rule and transaction.
+    AccountLocked {
+        client: u16,
+        #[serde(flatten)]
+        lock: LockInfo,
+    },
+    /// A withdrawal took `available` in `currency` below the client's
+    /// minimum, allowed by the `flag` policy.
+    MinimumBreached {
+        client: u16,
+        tx: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        currency: Option<Box<str>>,
+        available: Amount,
+        minimum: Amount,
+    },
+    /// An administrative `hold` or `release` record moved `amount` between
+    /// available and held.
+    AdminHold {
+        client: u16,
+        tx: u32,
+        cause: TxType,
+        amount: Amount,
+        reason: Box<str>,
+    },
+    /// The account was unlocked, by an `unlock` record or an operator.
+    AccountUnlocked { client: u16 },
+    /// The account was closed by the `close` record `tx`.
+    AccountClosed { client: u16, tx: u32 },
+    /// Everything the engine held about the client was deleted on request;
+    /// copies kept downstream must be deleted too.
+    ClientErased { client: u16 },
+}
+
+pub(crate) type Observer = Box<dyn FnMut(&Event) + Send>;
@@ -0,0 +1,127 @@
+//! Process exit codes and error reporting for orchestration tooling.
+//!
+//! | code | meaning                                              |
+//! |------|------------------------------------------------------|
+//! | 0    | success                                              |
+//! | 1    | unclassified failure                                 |
+//! | 2    | invalid command line (reported by clap)              |
+//! | 3    | input could not be parsed                            |
+//! | 4    | I/O failure reading input or writing output          |
+//! | 5    | engine invariant or resource bound violated          |
+//! | 6    | partial success: output written, some input rejected |
+//! | 7    | reconciliation found discrepancies                   |
+
+use anyhow::Error;
+use clap::ValueEnum;
+use std::fmt;
+use std::process::ExitCode;
+
+/// Attached to an error with `.context(Failure::..)` to select its exit code.
+/// Errors without a tag are classified as I/O when an [`std::io::Error`] is in
+/// their chain and as unclassified otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Failure {
+    Parse,
+    Io,
+    Invariant,
+    Partial,
+    Mismatch,
+}
+
+impl Failure {
+    fn code(self) -> u8 {
+        match self {
+            Self::Parse => 3,
+            Self::Io => 4,
+            Self::Invariant => 5,
+            Self::Partial => 6,
+            Self::Mismatch => 7,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Parse => "parse",
+            Self::Io => "io",
+            Self::Invariant => "invariant",
+            Self::Partial => "partial",
+            Self::Mismatch => "mismatch",
+        }
+    }
+
+    fn classify(err: &Error) -> Option<Self> {
+        if let Some(failure) = err.downcast_ref::<Self>() {
+            return Some(*failure);
+        }
+        err.chain()
+            .any(|e| e.is::<std::io::Error>())
+            .then_some(Self::Io)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Self::Parse => "parse failure",
+            Self::Io => "i/o failure",
+            Self::Invariant => "invariant violation",
+            Self::Partial => "partial success",
+            Self::Mismatch => "balances do not reconcile",
+        };
+        f.write_str(msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub(crate) enum ErrorFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Prints `err` to stderr in the requested format and returns the exit code
+/// matching its classification.
+pub(crate) fn report(err: &Error, format: ErrorFormat) -> ExitCode {
+    let failure = Failure::classify(err);
+    let code = failure.map_or(1, Failure::code);
+    match format {
+        ErrorFormat::Text => eprintln!("Error: {err:?}"),
+        ErrorFormat::Json => {
+            let causes: Vec<String> = err
+                .chain()
+                .skip(1)
+                .map(ToString::to_string)
+                .collect();
+            let object = serde_json::json!({
+                "error": {
+                    "kind": failure.map_or("other", Failure::name),
+                    "code": code,
+                    "message": err.to_string(),
+                    "causes": causes,
+                }
+            });
+            eprintln!("{object}");
+        }
+    }
+    ExitCode::from(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_classify() {
+        let parse = Err::<(), _>(anyhow::anyhow!("bad amount"))
+            .context("line 3")
+            .context(Failure::Parse)
+            .unwrap_err();
+        assert_eq!(Failure::classify(&parse), Some(Failure::Parse));
+
+        let io = Error::new(std::io::Error::other("disk on fire")).context("could not write");
+        assert_eq!(Failure::classify(&io), Some(Failure::Io));
+
+        assert_eq!(Failure::classify(&anyhow::anyhow!("???")), None);
+    }
+}
@@ -0,0 +1,534 @@
+//! gRPC API of the server, with the `grpc` feature.
+//!
+//! `--grpc ADDR` serves the `Accounts` service of `proto/roinstxs.proto`.
+//! `WatchAccounts` streams the changes of accounts to the caller as the
+//! engine applies records, optionally only those of some clients, so
+//! dashboards follow the book instead of polling summaries. Changes are the
+//! engine's events, delivered from the moment of the call on. A watcher
+//! falling more than `BACKLOG` events behind has its stream ended with
+//! `DATA_LOSS` and should call again.
+//!
+//! `SubmitTx` and `SubmitTxStream` take typed records in place of the text
+//! lines of the stream listeners and answer each one with its tx id once it
+//! reached the engine, like `#ack`; records that cannot be applied, e.g. a
+//! client id above 65535 or a malformed amount, are answered with
+//! `INVALID_ARGUMENT`, or with an error reply that leaves the stream open.
+//! Records carry no `--stream-key` signature, so with one set submissions are
+//! refused with `UNAUTHENTICATED`; with `--stream-token` they must carry
+//! `authorization: Bearer <token>` metadata, see `auth`. `GetAccount` and `ListAccounts` read the
+//! current balances. With `--stream-token` every call, reads and watches
+//! included, must carry the token.
+
+use crate::amount::Amount;
+use crate::auth;
+use crate::engine::{Account, Tx, TxEngine, TxMeta, TxType};
+use crate::error::EngineError;
+use crate::events::{self, Event};
+use crate::log;
+use crate::metrics::Metrics;
+use crate::{batch, input, net, recent, redact, rejects};
+use anyhow::{Context, Result};
+use clap::Args;
+use proto::account_event::Kind;
+use proto::accounts_server::{Accounts, AccountsServer};
+use proto::{
+    AccountEvent, GetAccountRequest, ListAccountsRequest, SubmitTxReply, Transaction,
+    WatchAccountsRequest,
+};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status, Streaming};
+
+mod proto {
+    tonic::include_proto!("roinstxs.v1");
+}
+
+// events a watcher may fall behind by before its stream is ended
+const BACKLOG: usize = 1024;
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct GrpcArgs {
+    /// Serve the gRPC API on this address, e.g. 127.0.0.1:50051
+    #[arg(long = "grpc", global = true, value_name = "ADDR")]
+    addr: Option<SocketAddr>,
+}
+
+impl From<events::Balances> for proto::Balances {
+    fn from(b: events::Balances) -> Self {
+        Self {
+            available: b.available.to_f64(),
+            held: b.held.to_f64(),
+            total: b.total.to_f64(),
+        }
+    }
+}
+
+fn account_event(event: &Event) -> AccountEvent {
+    let (client, kind) = match *event {
+        Event::BalanceChanged {
+            client,
+            tx,
+            cause,
+            ref currency,
+            before,
+            after,
+        } => (
+            client,
+            Kind::BalanceChanged(proto::BalanceChanged {
+                tx,
+                cause: cause.as_str().to_owned(),
+                before: Some(before.into()),
+                after: Some(after.into()),
+                currency: currency.as_deref().unwrap_or_default().to_owned(),
+            }),
+        ),
+        Event::Chargeback { client, tx, amount } => (
+            client,
+            Kind::Chargeback(proto::Chargeback {
+                tx,
+                amount: amount.to_f64(),
+            }),
+        ),
+        Event::AccountLocked { client, lock } => (
+            client,
+            Kind::AccountLocked(proto::AccountLocked {
+                tx: lock.tx.unwrap_or_default(),
+                rule: lock.rule.as_str().to_owned(),
+                at: lock.at,
+            }),
+        ),
+        Event::MinimumBreached {
+            client,
+            tx,
+            ref currency,
+            available,
+            minimum,
+        } => (
+            client,
+            Kind::MinimumBreached(proto::MinimumBreached {
+                tx,
+                available: available.to_f64(),
+                minimum: minimum.to_f64(),
+                currency: currency.as_deref().unwrap_or_default().to_owned(),
+            }),
+        ),
+        Event::AdminHold {
+            client,
+            tx,
+            cause,
+            amount,
+            ref reason,
+        } => (
+            client,
+            Kind::AdminHold(proto::AdminHold {
+                tx,
+                cause: cause.as_str().to_owned(),
+                amount: amount.to_f64(),
+                reason: reason.to_string(),
+            }),
+        ),
+        Event::AccountUnlocked { client } => {
+            (client, Kind::AccountUnlocked(proto::AccountUnlocked {}))
+        }
+        Event::AccountClosed { client, tx } => {
+            (client, Kind::AccountClosed(proto::AccountClosed { tx }))
+        }
+        Event::ClientErased { client } => (client, Kind::ClientErased(proto::ClientErased {})),
+    };
+    AccountEvent {
+        client: client.into(),
+        kind: Some(kind),
+    }
+}
+
+fn account(account: &Account) -> proto::Account {
+    proto::Account {
+        client: account.client.into(),
+        balances: Some(events::Balances::from(account).into()),
+        locked: account.locked,
+        closed: account.closed,
+        currencies: account
+            .currencies
+            .iter()
+            .map(|(currency, &balances)| (currency.to_string(), balances.into()))
+            .collect(),
+    }
+}
+
+// unset optional fields are their zero values in proto3
+fn transaction(record: Transaction) -> Result<Tx> {
+    TxType::try_from(record.r#type.as_str())?;
+    let client = u16::try_from(record.client)
+        .with_context(|| format!("client {} is out of range", record.client))?;
+    let amount = match record.amount.as_str() {
+        "" => None,
+        amount => Some(amount.parse::<Amount>()?),
+    };
+    let counterparty = record
+        .counterparty
+        .map(|c| u16::try_from(c).with_context(|| format!("counterparty {c} is out of range")))
+        .transpose()?;
+    let text = |field: String| (!field.is_empty()).then(|| field.into_boxed_str());
+    let meta = TxMeta {
+        timestamp: (record.timestamp != 0).then_some(record.timestamp),
+        currency: text(record.currency),
+        correlation_id: text(record.correlation_id),
+        reason: text(record.reason),
+        sub_account: text(record.sub_account),
+        counterparty,
+    };
+    let tx = Tx::new(&record.r#type, client, record.tx, amount)?.with_meta(meta);
+    input::check_streamed(&tx)?;
+    Ok(tx)
+}
+
+/// The `Accounts` server, fed by an engine observer.
+pub(crate) struct Api {
+    addr: SocketAddr,
+    events: broadcast::Sender<Event>,
+}
+
+impl Api {
+    pub(crate) fn new(args: &GrpcArgs) -> Option<Self> {
+        Some(Self {
+            addr: args.addr?,
+            events: broadcast::channel(BACKLOG).0,
+        })
+    }
+
+    /// Engine observer passing every event on to the watchers.
+    pub(crate) fn observer(&self) -> impl FnMut(&Event) + Send + 'static {
+        let events = self.events.clone();
+        move |event: &Event| {
+            // no receivers just means nobody is watching
+            let _ = events.send(event.clone());
+        }
+    }
+
+    /// Serves the API, applying submitted records to `engine`; `signed`
+    /// tells that producers must sign their records, `token` the stream
+    /// token they must present.
+    pub(crate) async fn run(
+        self,
+        engine: Arc<Mutex<TxEngine>>,
+        metrics: Arc<Metrics>,
+        signed: bool,
+        token: Option<Arc<str>>,
+    ) -> Result<()> {
+        let listener = net::bind_tcp(self.addr)?;
+        let service = Service {
+            events: self.events,
+            engine,
+            metrics: metrics.listener(format!("grpc://{}", self.addr)),
+            signed,
+            token,
+        };
+        tonic::transport::Server::builder()
+            .add_service(AccountsServer::new(service))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Service {
+    events: broadcast::Sender<Event>,
+    engine: Arc<Mutex<TxEngine>>,
+    metrics: Arc<Metrics>,
+    signed: bool,
+    token: Option<Arc<str>>,
+}
+
+impl Service {
+    /// Applies one record, returning its tx id once the engine applied it.
+    /// Rejections are logged and recorded in `metrics`.
+    async fn submit(&self, record: Transaction) -> Result<u32> {
+        // as a CSV line, for rejections and `--recent`
+        let line = format!(
+            "{}, {}, {}, {}",
+            record.r#type, record.client, record.tx, record.amount
+        );
+        let tx = transaction(record).inspect_err(|err| {
+            log::warn!("rejecting gRPC record: {err:#}");
+            rejects::unparsed(None, &line, err);
+            let line = redact::record(&line);
+            self.metrics.record_rejected(format!("{line}: {err:#}"));
+        })?;
+        recent::received(tx.client(), &line);
+        batch::apply_one(tx, &self.engine, &self.metrics).await
+    }
+
+    // a submission, which must also be signed when producers have to
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        if self.signed {
+            return Err(Status::unauthenticated(
+                "records must be signed with --stream-key, which gRPC does not support",
+            ));
+        }
+        self.authenticate(request)
+    }
+
+    fn authenticate<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        let sent = request.metadata().get("authorization");
+        match auth::is_bearer(sent.and_then(|v| v.to_str().ok()), token) {
+            true => Ok(()),
+            false => {
+                let reason = format!("request: {}", auth::UNAUTHENTICATED);
+                self.metrics.record_rejected(reason);
+                Err(Status::unauthenticated(auth::UNAUTHENTICATED))
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Accounts for Service {
+    type WatchAccountsStream = ReceiverStream<Result<AccountEvent, Status>>;
+    type SubmitTxStreamStream = ReceiverStream<Result<SubmitTxReply, Status>>;
+    type ListAccountsStream =
+        tokio_stream::Iter<std::vec::IntoIter<Result<proto::Account, Status>>>;
+
+    async fn watch_accounts(
+        &self,
+        request: Request<WatchAccountsRequest>,
+    ) -> Result<Response<Self::WatchAccountsStream>, Status> {
+        self.authenticate(&request)?;
+        let clients: HashSet<u32> = request.into_inner().clients.into_iter().collect();
+        let mut events = self.events.subscribe();
+        let (stream, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    received = events.recv() => received,
+                    // the watcher hung up
+                    _ = stream.closed() => return,
+                };
+                let event = match event {
+                    Ok(event) => account_event(&event),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        let status = Status::data_loss(format!("fell {missed} events behind"));
+                        let _ = stream.send(Err(status)).await;
+                        return;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let watched = clients.is_empty() || clients.contains(&event.client);
+                if watched && stream.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn submit_tx(
+        &self,
+        request: Request<Transaction>,
+    ) -> Result<Response<SubmitTxReply>, Status> {
+        self.authorize(&request)?;
+        match self.submit(request.into_inner()).await {
+            Ok(tx) => Ok(Response::new(SubmitTxReply {
+                tx,
+                error: String::new(),
+            })),
+            Err(err) if err.downcast_ref().is_some_and(EngineError::is_refusal) => {
+                Err(Status::failed_precondition(format!("{err:#}")))
+            }
+            Err(err) => Err(Status::invalid_argument(format!("{err:#}"))),
+        }
+    }
+
+    async fn submit_tx_stream(
+        &self,
+        request: Request<Streaming<Transaction>>,
+    ) -> Result<Response<Self::SubmitTxStreamStream>, Status> {
+        self.authorize(&request)?;
+        let mut records = request.into_inner();
+        let service = self.clone();
+        let (replies, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let record = match records.message().await {
+                    Ok(Some(record)) => record,
+                    Ok(None) => return,
+                    Err(status) => {
+                        let _ = replies.send(Err(status)).await;
+                        return;
+                    }
+                };
+                let tx = record.tx;
+                let reply = match service.submit(record).await {
+                    Ok(tx) => SubmitTxReply {
+                        tx,
+                        error: String::new(),
+                    },
+                    Err(err) => SubmitTxReply {
+                        tx,
+                        error: format!("{err:#}"),
+                    },
+                };
+                // the producer hung up
+                if replies.send(Ok(reply)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        self.authenticate(&request)?;
+        let client = request.into_inner().client;
+        let engine = self.engine.lock().await;
+        u16::try_from(client)
+            .ok()
+            .and_then(|client| engine.account(client))
+            .map(|found| Response::new(account(found)))
+            .ok_or_else(|| Status::not_found(format!("no account for client {client}")))
+    }
+
+    async fn list_accounts(
+        &self,
+        request: Request<ListAccountsRequest>,
+    ) -> Result<Response<Self::ListAccountsStream>, Status> {
+        self.authenticate(&request)?;
+        let engine = self.engine.lock().await;
+        let mut accounts: Vec<&Account> = engine.accounts().collect();
+        accounts.sort_unstable_by_key(|a| a.client);
+        let accounts: Vec<_> = accounts.into_iter().map(|a| Ok(account(a))).collect();
+        Ok(Response::new(tokio_stream::iter(accounts)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    fn new_service(signed: bool, token: Option<&str>) -> Service {
+        let api = Api::new(&GrpcArgs {
+            addr: Some("127.0.0.1:0".parse().unwrap()),
+        })
+        .unwrap();
+        let mut engine = TxEngine::new();
+        engine.subscribe(api.observer());
+        Service {
+            events: api.events,
+            engine: Arc::new(Mutex::new(engine)),
+            metrics: Arc::new(Metrics::default()),
+            signed,
+            token: token.map(Arc::from),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch_filters_by_client() {
+        let service = new_service(false, None);
+        let request = Request::new(WatchAccountsRequest { clients: vec![2] });
+        let mut stream = service.watch_accounts(request).await.unwrap().into_inner();
+
+        let mut engine = service.engine.lock().await;
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "dispute, 2, 2,",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        drop(engine);
+        let deposit = stream.next().await.unwrap().unwrap();
+        assert_eq!(deposit.client, 2);
+        let Some(Kind::BalanceChanged(change)) = deposit.kind else {
+            panic!("expected a balance change, got {deposit:?}");
+        };
+        assert_eq!((change.tx, change.cause.as_str()), (2, "deposit"));
+        assert_eq!(change.after.unwrap().available, 5.0);
+        let dispute = stream.next().await.unwrap().unwrap();
+        let Some(Kind::BalanceChanged(change)) = dispute.kind else {
+            panic!("expected a balance change, got {dispute:?}");
+        };
+        assert_eq!(change.after.unwrap().held, 5.0);
+    }
+
+    #[tokio::test]
+    async fn test_submitted_records_reach_the_engine() {
+        let service = new_service(false, None);
+        let record = |tx_type: &str, tx: u32, amount: &str| Transaction {
+            r#type: tx_type.to_owned(),
+            client: 7,
+            tx,
+            amount: amount.to_owned(),
+            ..Default::default()
+        };
+        let reply = service.submit_tx(Request::new(record("deposit", 1, "10.5")));
+        assert_eq!(reply.await.unwrap().into_inner().tx, 1);
+        let status = service
+            .submit_tx(Request::new(record("deposit", 2, "ten")))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let out_of_range = Transaction {
+            client: 70_000,
+            ..record("deposit", 3, "1")
+        };
+        assert!(service.submit_tx(Request::new(out_of_range)).await.is_err());
+        service
+            .submit_tx(Request::new(record("withdrawal", 4, "0.5")))
+            .await
+            .unwrap();
+
+        let request = Request::new(GetAccountRequest { client: 7 });
+        let account = service.get_account(request).await.unwrap().into_inner();
+        assert_eq!(account.balances.unwrap().available, 10.0);
+        let status = service
+            .get_account(Request::new(GetAccountRequest { client: 8 }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let request = Request::new(ListAccountsRequest {});
+        let listed: Vec<_> = service
+            .list_accounts(request)
+            .await
+            .unwrap()
+            .into_inner()
+            .collect()
+            .await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(service.metrics.snapshot().rejected, 2);
+
+        let signed = new_service(true, None);
+        let status = signed
+            .submit_tx(Request::new(record("deposit", 1, "1")))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let tokened = new_service(false, Some("s3cret"));
+        let status = tokened
+            .submit_tx(Request::new(record("deposit", 1, "1")))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let mut request = Request::new(record("deposit", 1, "1"));
+        let bearer = "Bearer s3cret".parse().unwrap();
+        request.metadata_mut().insert("authorization", bearer);
+        assert!(tokened.submit_tx(request).await.is_ok());
+        // reads need the token as well
+        let request = Request::new(GetAccountRequest { client: 7 });
+        let status = tokened.get_account(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let mut request = Request::new(GetAccountRequest { client: 7 });
+        let bearer = "Bearer s3cret".parse().unwrap();
+        request.metadata_mut().insert("authorization", bearer);
+        assert!(tokened.get_account(request).await.is_ok());
+    }
+}
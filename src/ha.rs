@@ -0,0 +1,103 @@
+//! Active/standby operation of the stream server.
+//!
+//! Instances started with the same `--ha-lock` file elect a leader through an
+//! exclusive lock on it: only the instance holding the lock binds the
+//! listeners and applies transactions, the others wait as standbys and poll
+//! for the lock. The operating system releases the lock when the leader
+//! exits or dies, so a standby takes over within one poll interval. The lock
+//! file names the current leader's process id for operators; it must live on
+//! a filesystem with working advisory locks shared by all instances.
+//!
+//! Engine state moves with the leadership through the files the leader
+//! leaves: an instance loads `--restore-from` and replays `--wal` only once it
+//! leads, before it binds the listeners, so a standby taking over resumes
+//! from the last snapshot and every record logged after it rather than from
+//! what the files held when it started. Both must live on a filesystem all
+//! instances share, like the lock file.
+
+use crate::log;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// how often a standby tries to take the lock
+const POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct HaArgs {
+    /// Serve only while holding an exclusive lock on this file, waiting as standby otherwise
+    #[arg(long, global = true, value_name = "PATH")]
+    ha_lock: Option<PathBuf>,
+}
+
+/// Proof of leadership; dropping it hands leadership to a standby.
+pub(crate) struct Leadership {
+    _lock: File,
+}
+
+fn try_lead(path: &Path) -> Result<Option<Leadership>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("could not open {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => return Ok(None),
+        Err(TryLockError::Error(err)) => {
+            return Err(err).with_context(|| format!("could not lock {}", path.display()))
+        }
+    }
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(Some(Leadership { _lock: file }))
+}
+
+impl HaArgs {
+    /// Waits until this instance leads; returns at once without `--ha-lock`.
+    pub(crate) async fn lead(&self) -> Result<Option<Leadership>> {
+        let Some(path) = &self.ha_lock else {
+            return Ok(None);
+        };
+        let mut announced = false;
+        loop {
+            if let Some(leadership) = try_lead(path)? {
+                log::info!("ha: leading, holding {}", path.display());
+                return Ok(Some(leadership));
+            }
+            if !announced {
+                log::info!(
+                    "ha: standby, {} is held by another instance",
+                    path.display()
+                );
+                announced = true;
+            }
+            tokio::time::sleep(POLL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_leader_until_it_steps_down() {
+        let path = std::env::temp_dir().join(format!("roinstxs-ha-{}.lock", std::process::id()));
+        let leader = try_lead(&path).unwrap().expect("first instance leads");
+        assert!(try_lead(&path).unwrap().is_none());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().trim(),
+            std::process::id().to_string()
+        );
+
+        drop(leader);
+        assert!(try_lead(&path).unwrap().is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+}
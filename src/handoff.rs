@@ -0,0 +1,325 @@
+//! Upgrading the stream server without dropping producers or balances.
+//!
+//! A server started with `--handoff PATH` waits for its successor on a Unix
+//! socket at PATH. The new binary is started with the same listeners and
+//! `--take-over PATH`, usually with `--handoff PATH` as well for the next
+//! upgrade. Each side checks with `SO_PEERCRED` that the other runs as the
+//! same user, the connection being dropped otherwise.
+//!
+//! The old server then drains as on SIGTERM, but keeps its listening sockets
+//! open. Once the producers left or `--drain-grace` ran out, it stops applying
+//! records for good and sends the successor its listening sockets
+//! (`SCM_RIGHTS`) and every account, transaction, dispute and parked record,
+//! then exits without a final snapshot. The successor accepts nothing before
+//! it has them, so producers reconnecting after `GOAWAY` wait in the listen
+//! backlog rather than being refused, and binds its other sockets, e.g.
+//! `--http`, only once the old server has exited. Inherited sockets are matched
+//! to the successor's listeners by address; listeners the old server lacked
+//! are bound afresh and inherited sockets nobody listens on are closed.
+
+use crate::engine::{EngineState, TxEngine};
+use crate::log;
+use anyhow::{Context, Result};
+use clap::Args;
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use socket2::{SockAddr, SockRef};
+use std::io::{IoSlice, IoSliceMut, Read, Write};
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+
+// most listening sockets handed over
+const MAX_SOCKETS: usize = 64;
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct HandoffArgs {
+    /// Hand the listeners and accounts to a successor connecting to this Unix socket
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "raft_id")]
+    handoff: Option<PathBuf>,
+    /// Take the listeners and accounts over from the server handing off on this Unix socket
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "raft_id")]
+    take_over: Option<PathBuf>,
+}
+
+// duplicates of the listening sockets, kept while a successor may take them
+static LISTENING: Mutex<Option<Vec<OwnedFd>>> = Mutex::new(None);
+// sockets taken over from the predecessor, until a listener claims them
+static INHERITED: Mutex<Vec<OwnedFd>> = Mutex::new(Vec::new());
+
+fn local_addr(fd: &OwnedFd) -> Option<SockAddr> {
+    SockRef::from(fd).local_addr().ok()
+}
+
+fn inherit(matches: impl Fn(&SockAddr) -> bool) -> Option<OwnedFd> {
+    let mut inherited = INHERITED.lock().unwrap();
+    let idx = inherited
+        .iter()
+        .position(|fd| local_addr(fd).is_some_and(|a| matches(&a)))?;
+    Some(inherited.swap_remove(idx))
+}
+
+fn keep(listener: &impl AsFd) -> Result<()> {
+    if let Some(listening) = LISTENING.lock().unwrap().as_mut() {
+        let fd = listener
+            .as_fd()
+            .try_clone_to_owned()
+            .context("could not keep the listening socket for a successor")?;
+        listening.push(fd);
+    }
+    Ok(())
+}
+
+/// The TCP listener on `addr` taken over from the predecessor, else the one
+/// `bind` creates.
+pub(crate) fn tcp_listener(
+    addr: SocketAddr,
+    bind: impl FnOnce() -> Result<TcpListener>,
+) -> Result<TcpListener> {
+    let listener = match inherit(|a| a.as_socket() == Some(addr)) {
+        Some(fd) => {
+            let listener = std::net::TcpListener::from(fd);
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => bind()?,
+    };
+    keep(&listener)?;
+    Ok(listener)
+}
+
+/// The Unix listener on `path` taken over from the predecessor, else the one
+/// `bind` creates.
+pub(crate) fn unix_listener(
+    path: &Path,
+    bind: impl FnOnce() -> Result<UnixListener>,
+) -> Result<UnixListener> {
+    let listener = match inherit(|a| a.as_pathname() == Some(path)) {
+        Some(fd) => {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            listener.set_nonblocking(true)?;
+            UnixListener::from_std(listener)?
+        }
+        None => bind()?,
+    };
+    keep(&listener)?;
+    Ok(listener)
+}
+
+/// Closes the inherited sockets none of `listening` names, `Err` addresses
+/// standing for Unix socket paths.
+pub(crate) fn close_unclaimed(listening: &[Result<SocketAddr, &Path>]) {
+    INHERITED.lock().unwrap().retain(|fd| {
+        let Some(addr) = local_addr(fd) else {
+            return false;
+        };
+        listening.iter().any(|l| match l {
+            Ok(a) => addr.as_socket() == Some(*a),
+            Err(path) => addr.as_pathname() == Some(*path),
+        })
+    });
+}
+
+fn check_peer(stream: &UnixStream) -> Result<Option<i32>> {
+    let cred = stream
+        .peer_cred()
+        .context("could not read peer credentials")?;
+    let uid = nix::unistd::geteuid().as_raw();
+    if cred.uid() != uid {
+        anyhow::bail!("peer runs as uid {}, not {uid}", cred.uid());
+    }
+    Ok(cred.pid())
+}
+
+pub(crate) struct Handoff {
+    path: Option<PathBuf>,
+}
+
+impl Handoff {
+    pub(crate) fn new(args: &HandoffArgs) -> Self {
+        if args.handoff.is_some() {
+            *LISTENING.lock().unwrap() = Some(Vec::new());
+        }
+        Self {
+            path: args.handoff.clone(),
+        }
+    }
+
+    /// Waits for a successor to connect, pending forever without `--handoff`.
+    pub(crate) async fn successor(&self) -> Result<Successor> {
+        let Some(path) = &self.path else {
+            return std::future::pending().await;
+        };
+        if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path)
+                .with_context(|| format!("could not remove stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("could not bind {}", path.display()))?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            match check_peer(&stream) {
+                Ok(pid) => {
+                    let pid = pid.map_or("?".to_owned(), |p| p.to_string());
+                    log::info!("handoff: successor pid {pid} connected");
+                    return Ok(Successor { stream });
+                }
+                Err(err) => log::warn!("handoff: rejecting successor: {err:#}"),
+            }
+        }
+    }
+}
+
+/// A successor waiting for the listening sockets and the engine's state.
+pub(crate) struct Successor {
+    stream: UnixStream,
+}
+
+impl Successor {
+    /// Hands the listening sockets and everything in `engine` to the
+    /// successor. The engine stays locked, so that no record is applied after
+    /// its state is gone, and the connection open until the process exits,
+    /// which the successor waits for.
+    pub(crate) async fn hand_over(self, engine: &tokio::sync::Mutex<TxEngine>) -> Result<()> {
+        let mut engine = engine.lock().await;
+        let state = serde_json::to_vec(&engine.take_state()?).context("could not encode state")?;
+        let sockets = LISTENING.lock().unwrap().take().unwrap_or_default();
+        let count = sockets.len();
+        let stream = self.stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        let stream = tokio::task::spawn_blocking(move || send(stream, &sockets, &state))
+            .await?
+            .context("could not hand over to the successor")?;
+        log::info!("handoff: handed over {count} sockets, exiting");
+        std::mem::forget(engine);
+        std::mem::forget(stream);
+        Ok(())
+    }
+}
+
+// the sockets ride on the length of the state, which follows
+fn send(
+    mut stream: std::os::unix::net::UnixStream,
+    sockets: &[OwnedFd],
+    state: &[u8],
+) -> Result<std::os::unix::net::UnixStream> {
+    if sockets.len() > MAX_SOCKETS {
+        anyhow::bail!("more than {MAX_SOCKETS} listening sockets");
+    }
+    let len = (state.len() as u64).to_le_bytes();
+    let fds: Vec<RawFd> = sockets.iter().map(|fd| fd.as_raw_fd()).collect();
+    let rights = [ControlMessage::ScmRights(&fds)];
+    let sent = sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&len)],
+        &rights,
+        MsgFlags::empty(),
+        None,
+    )?;
+    stream.write_all(&len[sent..])?;
+    stream.write_all(state)?;
+    stream.flush()?;
+    Ok(stream)
+}
+
+fn receive(mut stream: std::os::unix::net::UnixStream) -> Result<(Vec<OwnedFd>, EngineState)> {
+    let mut len = [0; 8];
+    let mut cmsgs = nix::cmsg_space!([RawFd; MAX_SOCKETS]);
+    let (received, fds) = {
+        let mut iov = [IoSliceMut::new(&mut len)];
+        let msg = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsgs),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        let mut fds = Vec::new();
+        for cmsg in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(rights) = cmsg {
+                // SAFETY: the kernel installed these descriptors for us alone
+                fds.extend(
+                    rights
+                        .into_iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                );
+            }
+        }
+        (msg.bytes, fds)
+    };
+    if received == 0 {
+        anyhow::bail!("the server closed the connection before handing over");
+    }
+    stream.read_exact(&mut len[received..])?;
+    let mut state = vec![0; u64::from_le_bytes(len) as usize];
+    stream.read_exact(&mut state)?;
+    let state = serde_json::from_slice(&state).context("invalid state")?;
+    // the server keeps the connection open until it exits
+    stream.read_to_end(&mut Vec::new())?;
+    Ok((fds, state))
+}
+
+/// Takes the listening sockets and the engine's state over from the server
+/// handing off on `--take-over`, once it exited. `None` without the option.
+pub(crate) async fn take_over(args: &HandoffArgs) -> Result<Option<EngineState>> {
+    let Some(path) = &args.take_over else {
+        return Ok(None);
+    };
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("could not connect to {}", path.display()))?;
+    let pid = check_peer(&stream).context("refusing to take over")?;
+    let pid = pid.map_or("?".to_owned(), |p| p.to_string());
+    log::info!("handoff: waiting for pid {pid} to drain");
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    let (fds, state) = tokio::task::spawn_blocking(move || receive(stream))
+        .await?
+        .context("could not take over")?;
+    log::info!("handoff: took over {} sockets from pid {pid}", fds.len());
+    *INHERITED.lock().unwrap() = fds;
+    Ok(Some(state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::engine::Tx;
+
+    #[tokio::test]
+    async fn test_hand_over_sockets_and_state() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 5.0",
+            "dispute, 1, 2,",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+
+        let (old, new) = std::os::unix::net::UnixStream::pair().unwrap();
+        let state = serde_json::to_vec(&engine.take_state().unwrap()).unwrap();
+        let sockets = [OwnedFd::from(listener)];
+        let sender = std::thread::spawn(move || drop(send(old, &sockets, &state).unwrap()));
+        let (fds, state) = receive(new).unwrap();
+        sender.join().unwrap();
+
+        *INHERITED.lock().unwrap() = fds;
+        let inherited = tcp_listener(addr, || unreachable!("the socket is inherited")).unwrap();
+        assert_eq!(inherited.local_addr().unwrap(), addr);
+        let mut successor = TxEngine::new();
+        successor.restore_state(state).unwrap();
+        successor
+            .process_tx(Tx::from_str("resolve, 1, 2,").unwrap())
+            .unwrap();
+        let account = successor.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (Amount::from_units(15), Amount::ZERO)
+        );
+    }
+}
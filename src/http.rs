@@ -0,0 +1,455 @@
+//! HTTP mode: a small JSON API over the live engine plus an embedded dashboard.
+//!
+//! Routes:
+//! - `GET /` dashboard page, refreshing itself from the API below
+//! - `GET /api/accounts` every account
+//! - `DELETE /api/accounts/{client}` erase everything held about a client,
+//!   answering with the deletion report
+//! - `POST /api/accounts/{client}/lock` lock an account as an operator,
+//!   answering with the account and the provenance of its lock
+//! - `POST /api/accounts/{client}/unlock` unlock an account as an operator,
+//!   applying what was queued while it was locked, answering with the account
+//! - `GET /api/disputes` disputed transactions
+//! - `GET /api/transactions/{tx}` a stored transaction, whether it is
+//!   disputed and the account it affected
+//! - `GET /api/recent/{client}` the client's last lines and engine decisions,
+//!   with `--recent`
+//! - `GET /api/metrics` ingest counters, in total and by `--listen` listener
+//! - `GET /metrics` the same counters and engine gauges in the Prometheus text
+//!   format, see `prometheus`
+//! - `GET /api/shards?count=N&by=range|hash` per-shard summaries and their rollup
+//! - `GET /api/shards/{shard}?count=N&by=range|hash` a single shard
+//!
+//! Erasures, locks and unlocks are only served with `--stream-token`, and
+//! must carry it as an `Authorization: Bearer <token>` header, being answered
+//! with `401` otherwise, see `auth`; without a token they are not routed at
+//! all. Under `--raft-id` they go through the raft log, answered once
+//! committed, or with `503` by a member not leading.
+
+use crate::auth;
+use crate::engine::{Account, Erasure, LockedRecords, Pending, Tx, TxEngine, TxLookup};
+use crate::log;
+use crate::metrics::Metrics;
+use crate::net;
+use crate::partition::{self, PartitionBy, Rollup, ShardSummary};
+use crate::prometheus::{self, EngineGauges};
+use crate::raft;
+use crate::recent::{self, Entry};
+use crate::rejects;
+use anyhow::{Context, Result};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+#[derive(Clone)]
+struct AppState {
+    engine: Arc<Mutex<TxEngine>>,
+    metrics: Arc<Metrics>,
+    token: Option<Arc<str>>,
+}
+
+impl AppState {
+    // an admin request, which must carry the stream token
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let sent = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        let authorized = self
+            .token
+            .as_ref()
+            .is_some_and(|t| auth::is_bearer(sent, t));
+        if !authorized {
+            let reason = format!("request: {}", auth::UNAUTHENTICATED);
+            self.metrics.record_rejected(reason);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        Ok(())
+    }
+}
+
+async fn get_dashboard() -> Html<&'static str> {
+    Html(DASHBOARD)
+}
+
+async fn get_accounts(State(state): State<AppState>) -> Json<Vec<Account>> {
+    let engine = state.engine.lock().await;
+    let mut accounts: Vec<Account> = engine.accounts().cloned().collect();
+    accounts.sort_unstable_by_key(|a| a.client);
+    Json(accounts)
+}
+
+// an admin change the raft log did not take, e.g. on a member not leading
+fn not_committed(err: anyhow::Error) -> StatusCode {
+    log::error!("http: {err:#}");
+    StatusCode::SERVICE_UNAVAILABLE
+}
+
+async fn delete_account(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(client): Path<u16>,
+) -> Result<Json<Erasure>, StatusCode> {
+    state.authorize(&headers)?;
+    if let Some(raft) = raft::proposer() {
+        return raft.erase(client).await.map(Json).map_err(not_committed);
+    }
+    let mut engine = state.engine.lock().await;
+    Ok(Json(engine.erase_client(client)))
+}
+
+async fn lock_account(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(client): Path<u16>,
+) -> Result<Json<Account>, StatusCode> {
+    state.authorize(&headers)?;
+    let account = match raft::proposer() {
+        Some(raft) => raft.lock(client).await.map_err(not_committed)?,
+        None => state.engine.lock().await.lock_account(client),
+    };
+    account.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn unlock_account(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(client): Path<u16>,
+) -> Result<Json<Account>, StatusCode> {
+    state.authorize(&headers)?;
+    let account = match raft::proposer() {
+        Some(raft) => raft.unlock(client).await.map_err(not_committed)?,
+        None => state.engine.lock().await.unlock_account(client),
+    };
+    account.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_transaction(
+    State(state): State<AppState>,
+    Path(tx): Path<u32>,
+) -> Result<Json<TxLookup>, StatusCode> {
+    let engine = state.engine.lock().await;
+    match engine.transaction(tx) {
+        Ok(lookup) => lookup.map(Json).ok_or(StatusCode::NOT_FOUND),
+        Err(err) => {
+            log::error!("tx {tx}: {err:#}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_recent(Path(client): Path<u16>) -> Result<Json<Vec<Entry>>, StatusCode> {
+    recent::of(client).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_disputes(State(state): State<AppState>) -> Json<Vec<Tx>> {
+    let engine = state.engine.lock().await;
+    Json(engine.disputes().cloned().collect())
+}
+
+/// Body of `GET /api/metrics`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MetricsReport {
+    /// Records applied, by transaction type.
+    pub processed: BTreeMap<String, u64>,
+    pub total_processed: u64,
+    pub rejected: u64,
+    pub connections: u64,
+    #[serde(default)]
+    pub accepted_ipv4: u64,
+    #[serde(default)]
+    pub accepted_ipv6: u64,
+    /// Batches of stream records applied under one engine lock.
+    #[serde(default)]
+    pub batches: u64,
+    #[serde(default)]
+    pub mean_batch_size: f64,
+    #[serde(default)]
+    pub largest_batch: u64,
+    /// Most recent first.
+    pub recent_rejections: Vec<String>,
+    pub pending: Pending,
+    /// Deposits and withdrawals of locked accounts dropped or queued.
+    #[serde(default)]
+    pub locked: LockedRecords,
+    #[serde(default)]
+    pub listeners: Vec<ListenerReport>,
+}
+
+/// Counters of one `--listen` listener, within `MetricsReport`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListenerReport {
+    pub listener: String,
+    pub total_processed: u64,
+    pub rejected: u64,
+    pub connections: u64,
+    pub accepted_ipv4: u64,
+    pub accepted_ipv6: u64,
+}
+
+async fn get_metrics(State(state): State<AppState>) -> Json<MetricsReport> {
+    let snapshot = state.metrics.snapshot();
+    let (pending, locked) = {
+        let engine = state.engine.lock().await;
+        (engine.pending(), engine.locked_records())
+    };
+    Json(MetricsReport {
+        processed: snapshot
+            .processed
+            .iter()
+            .map(|(kind, n)| (kind.as_str().to_owned(), *n))
+            .collect(),
+        total_processed: snapshot.total_processed(),
+        rejected: snapshot.rejected,
+        connections: snapshot.connections,
+        accepted_ipv4: snapshot.accepted_ipv4,
+        accepted_ipv6: snapshot.accepted_ipv6,
+        batches: snapshot.batches,
+        mean_batch_size: snapshot.mean_batch(),
+        largest_batch: snapshot.largest_batch,
+        listeners: snapshot
+            .listeners
+            .iter()
+            .map(|(listener, counters)| ListenerReport {
+                listener: listener.clone(),
+                total_processed: counters.total_processed(),
+                rejected: counters.rejected,
+                connections: counters.connections,
+                accepted_ipv4: counters.accepted_ipv4,
+                accepted_ipv6: counters.accepted_ipv6,
+            })
+            .collect(),
+        recent_rejections: snapshot.recent_rejections,
+        pending,
+        locked,
+    })
+}
+
+async fn get_prometheus(State(state): State<AppState>) -> impl IntoResponse {
+    let gauges = EngineGauges::of(&*state.engine.lock().await);
+    let page = prometheus::render(&state.metrics.snapshot(), &gauges, &rejects::refusals());
+    ([(header::CONTENT_TYPE, prometheus::CONTENT_TYPE)], page)
+}
+
+/// Query string of the shard routes.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ShardQuery {
+    #[serde(default = "one_shard")]
+    pub count: u16,
+    #[serde(default)]
+    pub by: PartitionBy,
+}
+
+fn one_shard() -> u16 {
+    1
+}
+
+async fn get_shards(
+    State(state): State<AppState>,
+    Query(query): Query<ShardQuery>,
+) -> Result<Json<Rollup>, StatusCode> {
+    if query.count == 0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let engine = state.engine.lock().await;
+    let shards = partition::shard_summaries(&engine, query.count, query.by);
+    Ok(Json(partition::rollup(shards)))
+}
+
+async fn get_shard(
+    State(state): State<AppState>,
+    Path(shard): Path<usize>,
+    Query(query): Query<ShardQuery>,
+) -> Result<Json<ShardSummary>, StatusCode> {
+    if shard >= usize::from(query.count) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let engine = state.engine.lock().await;
+    let mut shards = partition::shard_summaries(&engine, query.count, query.by);
+    Ok(Json(shards.swap_remove(shard)))
+}
+
+pub(crate) fn router(
+    engine: Arc<Mutex<TxEngine>>,
+    metrics: Arc<Metrics>,
+    token: Option<Arc<str>>,
+) -> Router {
+    let router = Router::new()
+        .route("/", get(get_dashboard))
+        .route("/api/accounts", get(get_accounts))
+        .route("/api/disputes", get(get_disputes))
+        .route("/api/transactions/{tx}", get(get_transaction))
+        .route("/api/recent/{client}", get(get_recent))
+        .route("/api/metrics", get(get_metrics))
+        .route("/metrics", get(get_prometheus))
+        .route("/api/shards", get(get_shards))
+        .route("/api/shards/{shard}", get(get_shard));
+    // no admin routes without a token to check them against
+    let router = match token {
+        Some(_) => router
+            .route("/api/accounts/{client}", delete(delete_account))
+            .route("/api/accounts/{client}/lock", post(lock_account))
+            .route("/api/accounts/{client}/unlock", post(unlock_account)),
+        None => router,
+    };
+    router.with_state(AppState {
+        engine,
+        metrics,
+        token,
+    })
+}
+
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    engine: Arc<Mutex<TxEngine>>,
+    metrics: Arc<Metrics>,
+    token: Option<Arc<str>>,
+) -> Result<()> {
+    let listener =
+        net::bind_tcp(addr).with_context(|| format!("could not bind http listener on {addr}"))?;
+    if token.is_none() {
+        log::info!("http: erase, lock and unlock routes need --stream-token, not serving them");
+    }
+    axum::serve(listener, router(engine, metrics, token)).await?;
+    Ok(())
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct TransactionArgs {
+    /// Transactions to print
+    #[arg(required = true, num_args = 1..)]
+    txs: Vec<u32>,
+    /// HTTP API of the server, as given to its --http
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    target: SocketAddr,
+}
+
+/// Prints the transactions as the server holds them, one JSON object per
+/// line.
+pub(crate) async fn transaction(args: TransactionArgs) -> Result<()> {
+    let client = reqwest::Client::new();
+    for tx in args.txs {
+        let response = client
+            .get(format!("http://{}/api/transactions/{tx}", args.target))
+            .send()
+            .await
+            .context("could not reach the server")?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            log::warn!("tx {tx}: not found");
+            continue;
+        }
+        let body = response.error_for_status()?.bytes().await?;
+        let lookup: TxLookup = serde_json::from_slice(&body)
+            .with_context(|| format!("tx {tx}: unexpected response"))?;
+        println!("{}", serde_json::to_string(&lookup)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn engine() -> Arc<Mutex<TxEngine>> {
+        let mut engine = TxEngine::new();
+        engine
+            .process_tx(Tx::from_str("deposit, 1, 1, 10.0").unwrap())
+            .unwrap();
+        Arc::new(Mutex::new(engine))
+    }
+
+    // the status of `request` sent to `app`
+    async fn status(app: &Router, request: Request<Body>) -> StatusCode {
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    fn admin(uri: &str, token: Option<&str>) -> Request<Body> {
+        let mut request = Request::post(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    // the body of the `200` answering a `GET` of `uri`
+    async fn body(app: &Router, uri: &str) -> String {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX);
+        String::from_utf8(body.await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_reads_accounts_disputes_and_metrics() {
+        let engine = engine();
+        for line in ["deposit, 2, 2, 5.0", "dispute, 2, 2,"] {
+            engine
+                .lock()
+                .await
+                .process_tx(Tx::from_str(line).unwrap())
+                .unwrap();
+        }
+        let app = router(engine, Arc::default(), None);
+
+        let page = body(&app, "/").await;
+        assert!(page.contains("fetch('/api/' + p)"));
+        let accounts: Vec<Account> =
+            serde_json::from_str(&body(&app, "/api/accounts").await).unwrap();
+        let summary: Vec<_> = accounts
+            .iter()
+            .map(|a| (a.client(), a.available(), a.held()))
+            .collect();
+        let units = Amount::from_units;
+        assert_eq!(summary, [(1, units(10), units(0)), (2, units(0), units(5))]);
+        let disputes: Vec<Tx> = serde_json::from_str(&body(&app, "/api/disputes").await).unwrap();
+        assert_eq!(disputes.iter().map(Tx::tx_id).collect::<Vec<_>>(), [2]);
+        let metrics: MetricsReport =
+            serde_json::from_str(&body(&app, "/api/metrics").await).unwrap();
+        assert_eq!(metrics.total_processed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes_need_the_token() {
+        // not routed at all without a token to check
+        let app = router(engine(), Arc::default(), None);
+        let lock = admin("/api/accounts/1/lock", None);
+        assert_eq!(status(&app, lock).await, StatusCode::NOT_FOUND);
+        let erase = Request::delete("/api/accounts/1")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(&app, erase).await, StatusCode::NOT_FOUND);
+
+        let engine = engine();
+        let metrics = Arc::new(Metrics::default());
+        let app = router(engine.clone(), metrics.clone(), Some(Arc::from("s3cret")));
+        for token in [None, Some("guess")] {
+            let lock = admin("/api/accounts/1/lock", token);
+            assert_eq!(status(&app, lock).await, StatusCode::UNAUTHORIZED);
+        }
+        assert!(!engine.lock().await.account(1).unwrap().locked());
+        assert_eq!(metrics.snapshot().rejected, 2);
+
+        let lock = admin("/api/accounts/1/lock", Some("s3cret"));
+        assert_eq!(status(&app, lock).await, StatusCode::OK);
+        assert!(engine.lock().await.account(1).unwrap().locked());
+        let unlock = admin("/api/accounts/1/unlock", Some("s3cret"));
+        assert_eq!(status(&app, unlock).await, StatusCode::OK);
+        assert!(!engine.lock().await.account(1).unwrap().locked());
+        let unknown = admin("/api/accounts/2/lock", Some("s3cret"));
+        assert_eq!(status(&app, unknown).await, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,180 @@
+//! Typed client for the HTTP API of `--http`, with the `http-client` feature.
+//!
+//! [`HttpTxClient`] has one method per route of `src/http.rs`, answering with
+//! the same structs the server serializes, so a service reads accounts,
+//! disputes, transactions, metrics and shard rollups or erases a client
+//! without handling JSON itself, presenting the server's `--stream-token`
+//! when given one. Error statuses come back as errors, except
+//! a shard outside the requested count or an unknown transaction, which are
+//! `None`.
+
+use crate::engine::{Account, Erasure, Tx, TxLookup};
+use crate::http::{MetricsReport, ShardQuery};
+use crate::partition::{Rollup, ShardSummary};
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use std::net::SocketAddr;
+
+/// Client of the HTTP API served with `--http`, one method per route.
+/// Error statuses come back as errors, except where a method answers `None`.
+pub struct HttpTxClient {
+    base: String,
+    client: reqwest::Client,
+    token: Option<String>,
+}
+
+impl HttpTxClient {
+    /// A client of the API served on `addr`.
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            base: format!("http://{addr}/api"),
+            client: reqwest::Client::new(),
+            token: None,
+        }
+    }
+
+    /// Presents `token`, the server's stream token, which erasing a client
+    /// needs once the server has one.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_owned());
+        self
+    }
+
+    async fn send<T: DeserializeOwned>(&self, mut request: reqwest::RequestBuilder) -> Result<T> {
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.context("could not reach the server")?;
+        let url = response.url().clone();
+        response
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("unexpected response from {url}"))
+    }
+
+    /// Every account, ordered by client.
+    pub async fn accounts(&self) -> Result<Vec<Account>> {
+        self.send(self.client.get(format!("{}/accounts", self.base)))
+            .await
+    }
+
+    /// Erases everything held about `client` and returns the deletion report.
+    pub async fn erase(&self, client: u16) -> Result<Erasure> {
+        self.send(
+            self.client
+                .delete(format!("{}/accounts/{client}", self.base)),
+        )
+        .await
+    }
+
+    /// The transactions under dispute.
+    pub async fn disputes(&self) -> Result<Vec<Tx>> {
+        self.send(self.client.get(format!("{}/disputes", self.base)))
+            .await
+    }
+
+    /// The transaction `tx` with its dispute state and account, `None` when
+    /// the server keeps none by that id.
+    pub async fn transaction(&self, tx: u32) -> Result<Option<TxLookup>> {
+        let request = self.client.get(format!("{}/transactions/{tx}", self.base));
+        match self.send(request).await {
+            Err(err) if status_of(&err) == Some(StatusCode::NOT_FOUND) => Ok(None),
+            res => res.map(Some),
+        }
+    }
+
+    /// Ingest counters of the stream listener and the deferred backlog.
+    pub async fn metrics(&self) -> Result<MetricsReport> {
+        self.send(self.client.get(format!("{}/metrics", self.base)))
+            .await
+    }
+
+    /// Every shard of the book split as `query` says, with their rollup.
+    pub async fn shards(&self, query: ShardQuery) -> Result<Rollup> {
+        self.send(
+            self.client
+                .get(format!("{}/shards", self.base))
+                .query(&query),
+        )
+        .await
+    }
+
+    /// One shard of the book split as `query` says, `None` past the last one.
+    pub async fn shard(&self, shard: usize, query: ShardQuery) -> Result<Option<ShardSummary>> {
+        let request = self
+            .client
+            .get(format!("{}/shards/{shard}", self.base))
+            .query(&query);
+        match self.send(request).await {
+            Err(err) if status_of(&err) == Some(StatusCode::NOT_FOUND) => Ok(None),
+            res => res.map(Some),
+        }
+    }
+}
+
+fn status_of(err: &anyhow::Error) -> Option<StatusCode> {
+    err.downcast_ref::<reqwest::Error>()?.status()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::engine::TxEngine;
+    use crate::partition::PartitionBy;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_client_reads_and_erases() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "dispute, 2, 2,",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let token = Some(Arc::from("s3cret"));
+        let app = crate::http::router(Arc::new(Mutex::new(engine)), Arc::default(), token);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = HttpTxClient::new(addr);
+        let accounts = client.accounts().await.unwrap();
+        assert_eq!(
+            accounts.iter().map(|a| a.client).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(client.disputes().await.unwrap().len(), 1);
+        let disputed = client.transaction(2).await.unwrap().unwrap();
+        assert!(disputed.disputed);
+        assert_eq!(disputed.account.unwrap().held, Amount::from_units(5));
+        assert!(client.transaction(9).await.unwrap().is_none());
+        assert_eq!(client.metrics().await.unwrap().total_processed, 0);
+
+        let query = ShardQuery {
+            count: 2,
+            by: PartitionBy::Hash,
+        };
+        let rollup = client.shards(query).await.unwrap();
+        assert_eq!(rollup.totals.accounts, 2);
+        assert_eq!(rollup.shards.len(), 2);
+        assert!(client.shard(1, query).await.unwrap().is_some());
+        assert!(client.shard(2, query).await.unwrap().is_none());
+
+        // erasing needs the token, reads do not
+        assert_eq!(
+            status_of(&client.erase(1).await.unwrap_err()),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        let client = client.with_token("s3cret");
+        let erasure = client.erase(1).await.unwrap();
+        assert!(erasure.account);
+        assert_eq!(client.accounts().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,589 @@
+//! Transaction files in any of the supported formats.
+//!
+//! With `--input-format auto`, the default, the format of each file is
+//! detected: gzip is recognized by its magic bytes and decompressed first,
+//! then a `PAR1` header or a `.parquet` extension means Parquet, `.jsonl` or
+//! `.ndjson` JSON lines and `.csv` CSV; a file with none of these is JSON
+//! lines when it starts with `{` and CSV otherwise. An explicit format skips
+//! the detection, gzip still being decompressed. Lines streamed to the server
+//! are JSON objects when they start with `{`, or always and never with
+//! `--input-format jsonl` and `csv`.
+//!
+//! CSV files are read with the schema directive and header, in the dialect
+//! sniffed from their first lines (see `dialect`). Schema 1 files with the
+//! plain comma-separated `type, client, tx, amount` header are deserialized
+//! into typed rows by the `csv` crate, a malformed field failing its row with
+//! the column it is in. JSON objects and Parquet
+//! rows name their fields after the schema 2 columns (`type`, `client`, `tx`,
+//! `amount`, `timestamp`, `currency`, `correlation_id`, `reason`,
+//! `sub_account`), missing or null ones being empty, and their columns are
+//! parsed like those of schema 2 CSV records so every format is validated the
+//! same way. Parquet needs the `parquet` feature and cannot be
+//! gzip-compressed, as it compresses its own pages.
+//!
+//! With the `io-uring` feature on Linux, CSV and JSON lines files are read
+//! ahead through io_uring (see `uring`), falling back to plain reads where
+//! io_uring is unavailable. With `--parse-threads`, CSV and JSON lines
+//! records are parsed on a pool of threads (see `chunked`).
+
+use crate::amount::Amount;
+use crate::chunked::{self, Chunked};
+use crate::dialect::{Dialect, SNIFF_LINES};
+use crate::engine::{parse_counterparty, Tx, TxMeta, TxType};
+use crate::exit::Failure;
+use crate::schema::Schema;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use flate2::read::MultiGzDecoder;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+use std::path::Path;
+use std::sync::OnceLock;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+const COLUMNS: [&str; 10] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "timestamp",
+    "currency",
+    "correlation_id",
+    "reason",
+    "sub_account",
+    "counterparty",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum InputFormat {
+    #[default]
+    Auto,
+    Csv,
+    #[value(alias = "json")]
+    Jsonl,
+    Parquet,
+}
+
+// only set once the command line is parsed
+static FORMAT: OnceLock<InputFormat> = OnceLock::new();
+
+pub(crate) fn set_format(format: InputFormat) {
+    FORMAT.get_or_init(|| format);
+}
+
+fn format() -> InputFormat {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+// set once the parser threads are started
+static PARALLEL: OnceLock<()> = OnceLock::new();
+
+/// Parses the records of files on `threads` threads.
+pub(crate) fn set_parse_threads(threads: usize) -> Result<()> {
+    chunked::configure(threads)?;
+    PARALLEL.get_or_init(|| ());
+    Ok(())
+}
+
+fn parallel() -> bool {
+    PARALLEL.get().is_some()
+}
+
+// set when stream producers may send administrative records
+static ADMIN_RECORDS: OnceLock<()> = OnceLock::new();
+
+/// Lets stream producers send administrative records.
+pub(crate) fn allow_admin_records() {
+    ADMIN_RECORDS.get_or_init(|| ());
+}
+
+/// Refuses administrative records, `close`, `hold`, `release` and `unlock`,
+/// streamed without `--stream-admin-records`; operators send them in files
+/// or unlock accounts through the HTTP API otherwise.
+pub(crate) fn check_streamed(tx: &Tx) -> Result<()> {
+    let admin = matches!(
+        tx.tx_type(),
+        TxType::Close | TxType::Hold | TxType::Release | TxType::Unlock
+    );
+    anyhow::ensure!(
+        !admin || ADMIN_RECORDS.get().is_some(),
+        "{} records need --stream-admin-records",
+        tx.tx_type().as_str()
+    );
+    Ok(())
+}
+
+/// One record of a transaction file.
+pub(crate) struct Record {
+    /// 1-based line, or row, of the record in the file.
+    pub(crate) line_no: usize,
+    /// The parsed transaction, or why the record could not be parsed.
+    pub(crate) tx: Result<Tx>,
+    /// The input of records that could not be parsed, for `--rejects`.
+    pub(crate) input: Option<String>,
+}
+
+impl Record {
+    /// The record at `line_no` parsed into `tx`, keeping the `input` it was
+    /// parsed from if that failed.
+    pub(crate) fn new(line_no: usize, tx: Result<Tx>, input: impl FnOnce() -> String) -> Self {
+        let input = tx.is_err().then(input);
+        Self { line_no, tx, input }
+    }
+}
+
+/// Records of a file; an `Err` item is a read error ending the file.
+pub(crate) type Records = Box<dyn Iterator<Item = Result<Record>>>;
+
+/// The format of a file named `name`, whose content starts with `head`.
+fn detect(name: &str, head: &[u8]) -> InputFormat {
+    if head.starts_with(PARQUET_MAGIC) {
+        return InputFormat::Parquet;
+    }
+    match Path::new(name).extension().and_then(|e| e.to_str()) {
+        Some("parquet") => InputFormat::Parquet,
+        Some("jsonl" | "ndjson") => InputFormat::Jsonl,
+        Some("csv") => InputFormat::Csv,
+        _ => match head.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        },
+    }
+}
+
+/// Opens `path` and reads its records in the format given by
+/// `--input-format`.
+pub(crate) fn records(path: &Path) -> Result<Records> {
+    let open = || File::open(path).with_context(|| format!("could not open {}", path.display()));
+    let mut reader: Box<dyn BufRead> = Box::new(BufReader::new(read_ahead(open()?)));
+    let mut name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let gzip = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+    if gzip {
+        reader = Box::new(BufReader::new(MultiGzDecoder::new(reader)));
+        name = name.strip_suffix(".gz").unwrap_or(name);
+    }
+    let format = match format() {
+        InputFormat::Auto => {
+            let head = reader
+                .fill_buf()
+                .with_context(|| format!("could not read {}", path.display()))?;
+            detect(name, head)
+        }
+        format => format,
+    };
+    match format {
+        InputFormat::Csv | InputFormat::Auto => csv_records(reader),
+        InputFormat::Jsonl => Ok(jsonl_records(reader)),
+        InputFormat::Parquet if gzip => {
+            anyhow::bail!(
+                "{} is gzip-compressed Parquet, which is not supported",
+                path.display()
+            )
+        }
+        InputFormat::Parquet => parquet_records(open()?),
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn read_ahead(file: File) -> Box<dyn Read> {
+    static FALLBACK: std::sync::Once = std::sync::Once::new();
+    match file.try_clone().and_then(crate::uring::UringReader::new) {
+        Ok(reader) => Box::new(reader),
+        Err(err) => {
+            FALLBACK.call_once(|| crate::log::warn!("io_uring unavailable, reading files without it: {err}"));
+            Box::new(file)
+        }
+    }
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn read_ahead(file: File) -> Box<dyn Read> {
+    Box::new(file)
+}
+
+fn csv_records(mut reader: Box<dyn BufRead>) -> Result<Records> {
+    // the directive, the header and enough records to sniff the dialect
+    let mut head = Vec::new();
+    for _ in 0..=SNIFF_LINES {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        head.push(line);
+    }
+    let schema = match head.first() {
+        Some(first) => Schema::from_directive(first)
+            .transpose()
+            .context("line 1")
+            .context(Failure::Parse)?,
+        None => None,
+    };
+    let skipped = usize::from(schema.is_some());
+    let schema = schema.unwrap_or_default();
+    let dialect = Dialect::sniff(head[skipped..].iter().map(|l| trim_newline(l)));
+    let header = head.get(skipped).map(|l| trim_newline(l));
+    if schema == Schema::V1 && dialect == Dialect::default() && header.is_some_and(standard_header)
+    {
+        if !parallel() {
+            return Ok(typed_records(head, skipped, reader));
+        }
+        let columns = csv::StringRecord::from(&TYPED_COLUMNS[..]);
+        let lines = numbered_lines(head, skipped, reader);
+        return Ok(Box::new(Chunked::new(lines, move |line| {
+            parse_typed_line(line, &columns)
+        })));
+    }
+
+    let lines = numbered_lines(head, skipped, reader);
+    if parallel() {
+        return Ok(Box::new(Chunked::new(lines, move |line| {
+            dialect.parse(schema, line)
+        })));
+    }
+    let records = lines.map(move |line| {
+        let (line_no, line) = line?;
+        let tx = dialect.parse(schema, &line);
+        Ok(Record::new(line_no, tx, || line))
+    });
+    Ok(Box::new(records))
+}
+
+// the record lines below the header, numbered from 1 like the file's lines,
+// blank ones skipped
+fn numbered_lines(
+    head: Vec<String>,
+    skipped: usize,
+    reader: Box<dyn BufRead>,
+) -> impl Iterator<Item = io::Result<(usize, String)>> {
+    let head: Vec<_> = head
+        .into_iter()
+        .map(|line| Ok(trim_newline(&line).to_owned()))
+        .collect();
+    head.into_iter()
+        .chain(reader.lines())
+        .enumerate()
+        .skip(skipped + 1)
+        .filter_map(|(i, line)| match line {
+            Err(err) => Some(Err(err)),
+            Ok(line) if line.is_empty() => None,
+            Ok(line) => Some(Ok((i + 1, line))),
+        })
+}
+
+// a line as `BufRead::lines` yields it
+fn trim_newline(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// Schema 1 records of files with the standard header, deserialized field by
+/// field so that a malformed column fails its record by name. The reason code
+/// of administrative records, or the counterparty of transfers, may follow in
+/// a fifth column.
+#[derive(Debug, Deserialize)]
+struct Row {
+    #[serde(rename = "type")]
+    type_name: String,
+    client: u16,
+    tx: u32,
+    // read as text, as csv would hand a number column over as an `f64`
+    #[serde(default)]
+    amount: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+const TYPED_COLUMNS: [&str; 5] = ["type", "client", "tx", "amount", "reason"];
+
+fn standard_header(line: &str) -> bool {
+    line.split(',')
+        .map(str::trim)
+        .eq(TYPED_COLUMNS.into_iter().take(4))
+}
+
+fn typed_records(head: Vec<String>, skipped: usize, reader: Box<dyn BufRead>) -> Records {
+    let head = head[skipped..].concat();
+    // every record ends in a newline, so that the reader's position after one
+    // is the line below it; a record's own position may point at blank lines
+    // skipped before it
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(Cursor::new(head).chain(reader).chain(&b"\n"[..]));
+    let columns = csv::StringRecord::from(&TYPED_COLUMNS[..]);
+    let records = std::iter::from_fn(move || {
+        let mut record = csv::StringRecord::new();
+        match reader.read_record(&mut record) {
+            Ok(false) => None,
+            Err(err) => Some(Err(err.into())),
+            Ok(true) => {
+                let line_no = reader.position().line() as usize - 1 + skipped;
+                let tx = parse_row(&mut record, &columns);
+                let input = || record.iter().collect::<Vec<_>>().join(", ");
+                Some(Ok(Record::new(line_no, tx, input)))
+            }
+        }
+    });
+    Box::new(records)
+}
+
+// names the column a record of `fields` columns failed on
+fn row_error(err: csv::Error, fields: usize) -> anyhow::Error {
+    let csv::ErrorKind::Deserialize { err, .. } = err.kind() else {
+        return err.into();
+    };
+    if let csv::DeserializeErrorKind::UnexpectedEndOfRow = err.kind() {
+        return anyhow::anyhow!("missing column {}", TYPED_COLUMNS[fields]);
+    }
+    match err.field().and_then(|i| TYPED_COLUMNS.get(i as usize)) {
+        Some(column) => anyhow::anyhow!("column {column}: {}", err.kind()),
+        None => anyhow::anyhow!("{}", err.kind()),
+    }
+}
+
+fn parse_row(record: &mut csv::StringRecord, columns: &csv::StringRecord) -> Result<Tx> {
+    // the legacy `hold, 1, 7, 2.5; reason` form
+    if let (Some("hold" | "release"), Some((amount, reason)), None) = (
+        record.get(0),
+        record.get(3).and_then(|a| a.split_once(';')),
+        record.get(4),
+    ) {
+        let (amount, reason) = (amount.trim().to_owned(), reason.trim().to_owned());
+        record.truncate(3);
+        record.push_field(&amount);
+        record.push_field(&reason);
+    }
+    let row: Row = record
+        .deserialize(Some(columns))
+        .map_err(|err| row_error(err, record.len()))?;
+    let amount = row.amount.as_deref().filter(|a| !a.is_empty());
+    let amount = amount.map(str::parse::<Amount>).transpose()?;
+    let tx = Tx::new(&row.type_name, row.client, row.tx, amount)?;
+    Ok(match tx.tx_type() {
+        TxType::Hold | TxType::Release => tx.with_meta(TxMeta {
+            reason: row.reason.map(Into::into),
+            ..Default::default()
+        }),
+        TxType::Transfer => tx.with_meta(TxMeta {
+            counterparty: row.reason.as_deref().map(parse_counterparty).transpose()?,
+            ..Default::default()
+        }),
+        _ => tx,
+    })
+}
+
+// a line of `typed_records` read on its own, for parsing in parallel
+fn parse_typed_line(line: &str, columns: &csv::StringRecord) -> Result<Tx> {
+    // without quotes the fields are the trimmed columns, sparing the reader
+    // built for quoted ones
+    if !line.contains('"') {
+        let fields = line
+            .split(',')
+            .map(|field| field.trim_matches(|c: char| c.is_ascii_whitespace()));
+        return parse_row(&mut fields.collect(), columns);
+    }
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(line.as_bytes());
+    let mut record = csv::StringRecord::new();
+    reader.read_record(&mut record)?;
+    parse_row(&mut record, columns)
+}
+
+// parses a JSON object or Parquet row from its schema 2 columns, without
+// joining them into a line that would have to be split again
+fn schema2_record(column: impl Fn(&str) -> Option<String>) -> Result<Tx> {
+    let fields = COLUMNS.map(|name| column(name).unwrap_or_default());
+    let fields = fields.each_ref().map(|value| value.trim());
+    Schema::V2.parse_fields(&fields)
+}
+
+fn parse_json(line: &str) -> Result<Tx> {
+    use serde_json::Value;
+    let object: serde_json::Map<String, Value> =
+        serde_json::from_str(line).context("invalid JSON record")?;
+    schema2_record(|name| match object.get(name)? {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        value => Some(value.to_string()),
+    })
+}
+
+/// Parses a line streamed to the server: a JSON object with `--input-format
+/// jsonl`, or with `auto` when the line starts with `{`, and a record of
+/// `schema` otherwise.
+pub(crate) fn parse_streamed(line: &str, schema: Schema) -> Result<Tx> {
+    let tx = parse_unchecked(line, schema)?;
+    check_streamed(&tx)?;
+    Ok(tx)
+}
+
+/// `parse_streamed` without `check_streamed`, for reading records that a
+/// server checks itself, e.g. to route them.
+pub(crate) fn parse_unchecked(line: &str, schema: Schema) -> Result<Tx> {
+    let json = match format() {
+        InputFormat::Jsonl => true,
+        InputFormat::Auto => line.trim_start().starts_with('{'),
+        InputFormat::Csv | InputFormat::Parquet => false,
+    };
+    match json {
+        true => parse_json(line),
+        false => schema.parse(line),
+    }
+}
+
+fn jsonl_records(reader: Box<dyn BufRead>) -> Records {
+    let lines = reader
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| match line {
+            Err(err) => Some(Err(err)),
+            Ok(line) if line.trim().is_empty() => None,
+            Ok(line) => Some(Ok((i + 1, line))),
+        });
+    if parallel() {
+        return Box::new(Chunked::new(lines, parse_json));
+    }
+    let records = lines.map(|line| {
+        let (line_no, line) = line?;
+        let tx = parse_json(&line);
+        Ok(Record::new(line_no, tx, || line))
+    });
+    Box::new(records)
+}
+
+#[cfg(feature = "parquet")]
+fn parquet_records(file: File) -> Result<Records> {
+    use parquet::file::reader::SerializedFileReader;
+    use parquet::record::{Field, Row};
+
+    fn parse_row(row: &Row) -> Result<Tx> {
+        schema2_record(|name| {
+            let (_, field) = row.get_column_iter().find(|(n, _)| n.as_str() == name)?;
+            match field {
+                Field::Null => None,
+                Field::Str(s) => Some(s.clone()),
+                field => Some(field.to_string()),
+            }
+        })
+    }
+
+    let reader = SerializedFileReader::new(file).context("could not read Parquet metadata")?;
+    let records = reader.into_iter().enumerate().map(|(i, row)| {
+        let row = row.context("could not read Parquet row")?;
+        Ok(Record::new(i + 1, parse_row(&row), || row.to_string()))
+    });
+    Ok(Box::new(records))
+}
+
+#[cfg(not(feature = "parquet"))]
+fn parquet_records(_: File) -> Result<Records> {
+    anyhow::bail!("Parquet input needs the `parquet` feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    #[test]
+    fn test_detect_and_read_gzip_jsonl() {
+        assert_eq!(detect("a.csv", b"type, client"), InputFormat::Csv);
+        assert_eq!(detect("settlement", b"  {\"type\""), InputFormat::Jsonl);
+        assert_eq!(detect("a.bin", b"PAR1\x15"), InputFormat::Parquet);
+        assert_eq!(detect("a.parquet", b""), InputFormat::Parquet);
+
+        let path = std::env::temp_dir().join(format!("roinstxs-input-{}.gz", std::process::id()));
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let body = concat!(
+            "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 2.5}\n",
+            "\n",
+            "{\"type\": \"hold\", \"client\": 1, \"tx\": 2, \"amount\": 1, \"reason\": \"legal, KYC\"}\n",
+            "{\"type\": \"deposit\", \"client\": \"x\", \"tx\": 3}\n",
+        );
+        gz.write_all(body.as_bytes()).unwrap();
+        std::fs::write(&path, gz.finish().unwrap()).unwrap();
+
+        let records: Vec<Record> = records(&path).unwrap().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.line_no).collect::<Vec<_>>(),
+            [1, 3, 4]
+        );
+        let deposit = records[0].tx.as_ref().unwrap();
+        assert_eq!(
+            (deposit.client(), deposit.amount()),
+            (1, Some("2.5".parse::<Amount>().unwrap()))
+        );
+        let hold = records[1].tx.as_ref().unwrap();
+        let reason = hold.meta().and_then(|m| m.reason.as_deref());
+        assert_eq!(reason, Some("legal, KYC"));
+        assert!(records[2].tx.is_err());
+    }
+
+    #[test]
+    fn test_typed_amounts_are_read_as_text() {
+        let path = std::env::temp_dir().join(format!("roinstxs-exact-{}.csv", std::process::id()));
+        let body = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1234567890123.4567\n\
+                    deposit, 1, 2, 1e3\n\
+                    deposit, 1, 3, 0.00015\n";
+        std::fs::write(&path, body).unwrap();
+        let records: Vec<Record> = records(&path).unwrap().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        let amount = records[0].tx.as_ref().unwrap().amount().unwrap();
+        assert_eq!(amount.to_string(), "1234567890123.4567");
+        // refused like the `;` dialect refuses them
+        assert!(records[1..].iter().all(|r| r.tx.is_err()));
+        let semicolon = Dialect::sniff(["type;client;tx;amount"]);
+        assert!(semicolon.parse(Schema::V1, "deposit;1;2;1e3").is_err());
+    }
+
+    #[test]
+    fn test_typed_csv_rows_fail_by_column() {
+        let path = std::env::temp_dir().join(format!("roinstxs-typed-{}.csv", std::process::id()));
+        let body = "type, client, tx, amount\n\
+                    deposit, 1, 1, 2.5\n\
+                    withdrawal, 1, 2, 1O.0\n\
+                    deposit, x, 3, 1.0\n\
+                    dispute, 1, 1,\n\
+                    hold, 1, 4, 1.0; legal\n\
+                    \"release\", 1, 5, 1.0, risk\n\n\
+                    resolve, 1\n";
+        std::fs::write(&path, body).unwrap();
+        let records: Vec<Record> = records(&path).unwrap().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<usize> = records.iter().map(|r| r.line_no).collect();
+        assert_eq!(lines, [2, 3, 4, 5, 6, 7, 9]);
+        let error = |i: usize| format!("{:#}", records[i].tx.as_ref().unwrap_err());
+        assert_eq!(error(1), "invalid amount \"1O.0\"");
+        assert!(error(2).starts_with("column client: "), "{}", error(2));
+        assert_eq!(records[3].tx.as_ref().unwrap().amount(), None);
+        let reasons: Vec<_> = records[4..6]
+            .iter()
+            .map(|r| r.tx.as_ref().unwrap().reason().unwrap())
+            .collect();
+        assert_eq!(reasons, ["legal", "risk"]);
+        assert_eq!(error(6), "missing column tx");
+
+        // read line by line, as with --parse-threads
+        let columns = csv::StringRecord::from(&TYPED_COLUMNS[..]);
+        let lines = body.lines().skip(1).filter(|l| !l.is_empty());
+        for (record, line) in records.iter().zip(lines) {
+            let show = |tx: &Result<Tx>| format!("{:?}", tx.as_ref().map_err(|e| format!("{e:#}")));
+            let parsed = parse_typed_line(line, &columns);
+            assert_eq!(show(&parsed), show(&record.tx), "{line}");
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Kafka as a transaction source for the stream server, with the `kafka`
+//! feature.
+//!
+//! With `--kafka-source` every message of `--kafka-source-topic` is one
+//! record in the schema 2 layout (legacy four-column records included),
+//! signed like TCP records when a stream key is set. Consumption resumes from
+//! the offsets committed for `--kafka-group`; `--seek-to` instead starts every
+//! partition at `offset:N`, at the first message at or after
+//! `timestamp:SECS`, or at the `beginning` or `end`, so the engine state can
+//! be rebuilt from that point of the topic. Snapshots cannot be loaded back
+//! into the engine yet, so a rebuild has to replay from the beginning.
+//!
+//! Ingestion scales out by running N instances in the same group with
+//! `--kafka-instance 0/N` to `N-1/N`: instance I owns the partitions whose id
+//! is I modulo N. Ownership is static rather than left to group rebalancing,
+//! because a partition moved to another instance would arrive there without
+//! its clients' history. Producers must write a client's records to the
+//! partition `--kafka-affinity` maps it to (contiguous client ranges by
+//! default, as `--partition-by` does for summaries); records on any other
+//! partition are rejected, so no client's state is split across instances.
+//! The instances' summaries are disjoint and `merge` rolls them up into one.
+
+use crate::csv_stream::ingest_record;
+use crate::log;
+use crate::metrics::Metrics;
+use crate::partition::PartitionBy;
+use crate::schema::{client_of, Schema};
+use crate::signing::Verifier;
+use crate::TxEngine;
+use anyhow::{Context, Result};
+use clap::Args;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::{ClientConfig, Message, Offset, TopicPartitionList};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where consumption starts in every partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SeekTo {
+    Beginning,
+    End,
+    Offset(i64),
+    /// Milliseconds since the unix epoch, Kafka's timestamp unit.
+    Timestamp(i64),
+}
+
+fn parse_seek_to(s: &str) -> Result<SeekTo> {
+    let seek = match s.split_once(':') {
+        None if s == "beginning" => SeekTo::Beginning,
+        None if s == "end" => SeekTo::End,
+        Some(("offset", n)) => SeekTo::Offset(n.parse().context("could not parse offset")?),
+        Some(("timestamp", secs)) => {
+            let secs: i64 = secs.parse().context("could not parse timestamp")?;
+            SeekTo::Timestamp(secs.checked_mul(1000).context("timestamp out of range")?)
+        }
+        _ => anyhow::bail!("expected offset:N, timestamp:SECS, beginning or end, got {s}"),
+    };
+    Ok(seek)
+}
+
+/// This process's share of the topic, `I/N`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Instance {
+    index: u16,
+    count: u16,
+}
+
+fn parse_instance(s: &str) -> Result<Instance> {
+    let (index, count) = s.split_once('/').context("expected I/N, e.g. 0/3")?;
+    let index = index.parse().context("could not parse instance index")?;
+    let count = count.parse().context("could not parse instance count")?;
+    anyhow::ensure!(index < count, "instance index must be below the instance count");
+    Ok(Instance { index, count })
+}
+
+impl Instance {
+    fn owns(self, partition: i32) -> bool {
+        partition.rem_euclid(i32::from(self.count)) == i32::from(self.index)
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct KafkaSourceArgs {
+    /// Consume transaction records from Kafka at these brokers, e.g. localhost:9092
+    #[arg(long, global = true, value_name = "BROKERS")]
+    kafka_source: Option<String>,
+    /// Topic transaction records are consumed from
+    #[arg(
+        long,
+        global = true,
+        default_value = "transactions",
+        requires = "kafka_source"
+    )]
+    kafka_source_topic: String,
+    /// Consumer group whose committed offsets consumption resumes from
+    #[arg(
+        long,
+        global = true,
+        default_value = "roinstxs",
+        requires = "kafka_source"
+    )]
+    kafka_group: String,
+    /// Start every partition at offset:N, timestamp:SECS, beginning or end instead
+    #[arg(long, global = true, value_parser = parse_seek_to, requires = "kafka_source")]
+    seek_to: Option<SeekTo>,
+    /// Consume only the partitions of instance I of N sharing the group, e.g. 0/3
+    #[arg(long, global = true, value_name = "I/N", value_parser = parse_instance, requires = "kafka_source")]
+    kafka_instance: Option<Instance>,
+    /// How producers assign clients to partitions, checked with --kafka-instance
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        requires = "kafka_instance"
+    )]
+    kafka_affinity: PartitionBy,
+}
+
+impl KafkaSourceArgs {
+    pub(crate) fn enabled(&self) -> bool {
+        self.kafka_source.is_some()
+    }
+
+    fn partitions(&self, consumer: &StreamConsumer) -> Result<Vec<i32>> {
+        let topic = &self.kafka_source_topic;
+        let metadata = consumer
+            .fetch_metadata(Some(topic), METADATA_TIMEOUT)
+            .with_context(|| format!("could not fetch metadata of {topic}"))?;
+        let partitions: Vec<i32> = metadata
+            .topics()
+            .iter()
+            .find(|t| t.name() == topic)
+            .map(|t| t.partitions().iter().map(|p| p.id()).collect())
+            .unwrap_or_default();
+        anyhow::ensure!(!partitions.is_empty(), "topic {topic} has no partitions");
+        Ok(partitions)
+    }
+
+    // the owned partitions of the topic, starting where --seek-to says
+    fn assignment(&self, consumer: &StreamConsumer, partitions: &[i32]) -> Result<TopicPartitionList> {
+        let offset = match self.seek_to {
+            None => Offset::Stored,
+            Some(SeekTo::Beginning) => Offset::Beginning,
+            Some(SeekTo::End) => Offset::End,
+            Some(SeekTo::Offset(n)) => Offset::Offset(n),
+            // resolved by the broker below
+            Some(SeekTo::Timestamp(millis)) => Offset::Offset(millis),
+        };
+        let mut assignment = TopicPartitionList::new();
+        for &partition in partitions {
+            if self.kafka_instance.is_none_or(|i| i.owns(partition)) {
+                assignment.add_partition_offset(&self.kafka_source_topic, partition, offset)?;
+            }
+        }
+        anyhow::ensure!(
+            assignment.count() > 0,
+            "no partition of {} is left for this instance",
+            self.kafka_source_topic
+        );
+        if let Some(SeekTo::Timestamp(_)) = self.seek_to {
+            assignment = consumer
+                .offsets_for_times(assignment, METADATA_TIMEOUT)
+                .context("could not look up offsets for the timestamp")?;
+        }
+        Ok(assignment)
+    }
+}
+
+/// Applies every record consumed from the owned partitions to the shared
+/// engine.
+pub(crate) async fn run(
+    args: &KafkaSourceArgs,
+    engine: Arc<Mutex<TxEngine>>,
+    metrics: Arc<Metrics>,
+    verifier: Option<Arc<Verifier>>,
+) -> Result<()> {
+    let brokers = args.kafka_source.as_deref().context("no kafka brokers given")?;
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("group.id", &args.kafka_group)
+        .set("enable.auto.commit", "true")
+        .set("auto.offset.reset", "earliest")
+        .create()
+        .with_context(|| format!("could not create kafka consumer for {brokers}"))?;
+    let partitions = args.partitions(&consumer)?;
+    consumer.assign(&args.assignment(&consumer, &partitions)?)?;
+    let partition_count = u16::try_from(partitions.len()).context("too many partitions")?;
+
+    loop {
+        let message = consumer.recv().await.context("could not consume from kafka")?;
+        let line = match message.payload_view::<str>() {
+            Some(Ok(line)) => line.trim(),
+            Some(Err(err)) => {
+                log::warn!("rejecting record: {err}");
+                metrics.record_rejected(format!("offset {}: {err}", message.offset()));
+                continue;
+            }
+            None => continue,
+        };
+        if line.is_empty() {
+            continue;
+        }
+        if args.kafka_instance.is_some() {
+            let owner = client_of(line, Schema::V2).map(|c| args.kafka_affinity.partition_of(c, partition_count));
+            if let Some(owner) = owner.filter(|&p| p != message.partition() as usize) {
+                let reason = format!(
+                    "partition {} offset {}: client belongs to partition {owner}",
+                    message.partition(),
+                    message.offset(),
+                );
+                log::warn!("rejecting record: {reason}");
+                metrics.record_rejected(reason);
+                continue;
+            }
+        }
+        // rejections are logged and counted by ingest_record
+        let _ = ingest_record(line, Schema::V2, &engine, &metrics, verifier.as_deref()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_seek_to() {
+        assert_eq!(parse_seek_to("offset:42").unwrap(), SeekTo::Offset(42));
+        assert_eq!(
+            parse_seek_to("timestamp:1700000000").unwrap(),
+            SeekTo::Timestamp(1_700_000_000_000)
+        );
+        assert_eq!(parse_seek_to("beginning").unwrap(), SeekTo::Beginning);
+        assert!(parse_seek_to("offset:soon").is_err());
+        assert!(parse_seek_to("latest").is_err());
+    }
+
+    #[test]
+    fn test_instances_split_partitions() {
+        let instances: Vec<Instance> = ["0/3", "1/3", "2/3"]
+            .into_iter()
+            .map(|s| parse_instance(s).unwrap())
+            .collect();
+        for partition in 0..8 {
+            assert_eq!(instances.iter().filter(|i| i.owns(partition)).count(), 1);
+        }
+        assert!(instances[1].owns(4));
+        assert!(parse_instance("3/3").is_err());
+        assert_eq!(client_of("deposit, 7, 1, 2.0", Schema::V2), Some(7));
+    }
+}
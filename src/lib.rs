@@ -0,0 +1,127 @@
+//! A transaction engine applying deposits, withdrawals and disputes to
+//! client accounts, and the `roinstxs` command line built on it.
+//!
+//! Services embedding the engine build a [`TxEngine`], hand it [`Tx`]
+//! records, parsed from `type, client, tx, amount` lines or built with
+//! [`Tx::new`], and read the resulting [`Account`]s back or write them as
+//! the CSV summary the binary prints:
+//!
+//! ```
+//! use roinstxs::{Amount, Tx, TxEngine};
+//!
+//! let mut engine = TxEngine::new();
+//! engine.process_tx("deposit, 1, 1, 10.0".parse()?)?;
+//! engine.process_tx(Tx::new("withdrawal", 1, 2, Some("2.5".parse()?))?)?;
+//!
+//! let account = engine.account(1).unwrap();
+//! assert_eq!(account.available(), "7.5".parse::<Amount>()?);
+//! assert!(!account.locked());
+//!
+//! let mut summary = Vec::new();
+//! engine.summarize_accounts(&mut summary)?;
+//! assert_eq!(summary, b"client,available,held,total,locked\n1,7.5,0,7.5,false\n");
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Records of types the engine doesn't know go to the [`TxHandler`] registered
+//! for them with [`TxEngine::register_handler`], which changes the account
+//! through an [`AccountHandle`].
+//!
+//! Producers feed a running server through [`TxClient`], and services read
+//! its HTTP API through `HttpTxClient`, with the `http-client` feature.
+//! Everything else, the file formats, servers and their options, is reached
+//! through [`main`], which runs the command line on the process arguments.
+
+mod aggregate;
+mod amount;
+mod anonymize;
+mod archive;
+mod auth;
+mod batch;
+mod cdc;
+mod chunked;
+mod client;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod cli;
+mod clock;
+mod crypt;
+mod daily;
+mod dialect;
+mod drain;
+mod engine;
+mod error;
+mod events;
+mod csv_stream;
+mod exit;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod ha;
+#[cfg(unix)]
+mod handoff;
+mod http;
+#[cfg(feature = "http-client")]
+mod http_client;
+mod input;
+#[cfg(feature = "kafka")]
+mod kafka_source;
+mod limits;
+mod listen;
+mod loadgen;
+mod log;
+mod manifest;
+mod merge;
+mod metrics;
+mod net;
+mod notify;
+mod order;
+mod output;
+mod partition;
+mod prometheus;
+mod raft;
+mod repl;
+mod replay;
+mod reconcile;
+mod recent;
+mod replication;
+mod router;
+mod redact;
+mod rejects;
+mod report;
+mod rng;
+mod schedule;
+mod schema;
+mod signing;
+mod snapshot;
+mod snapshot_diff;
+#[cfg(feature = "scripting")]
+mod script;
+mod shard;
+mod soak;
+mod statsd;
+mod store;
+mod template;
+#[cfg(test)]
+mod sim;
+mod tls;
+mod tui;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod wal;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod webhook;
+
+pub use amount::Amount;
+pub use cli::main;
+pub use client::{Ack, TxClient};
+pub use engine::{Account, AccountHandle, Tx, TxEngine, TxHandler, TxType};
+pub use error::{EngineError, TxError};
+#[cfg(feature = "http-client")]
+pub use engine::{Erasure, LockedRecords, Pending, TxLookup};
+#[cfg(feature = "http-client")]
+pub use http::{ListenerReport, MetricsReport, ShardQuery};
+#[cfg(feature = "http-client")]
+pub use http_client::HttpTxClient;
+#[cfg(feature = "http-client")]
+pub use partition::{PartitionBy, Rollup, ShardSummary, Totals};
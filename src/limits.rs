@@ -0,0 +1,282 @@
+//! Minimum available balances enforced on withdrawals.
+//!
+//! A floor can be set for every account with `--min-available` and per
+//! account in the account metadata sidecar given with `--account-meta`: a CSV
+//! file whose header names its columns, of which `client` and `min_available`
+//! are read; an empty `min_available` leaves the client on the global floor.
+//! A withdrawal that would take `available` in its currency below the
+//! client's floor is rejected, or with `--min-balance-policy flag` applied,
+//! logged and reported as a `MinimumBreached` event (a `minimum_breached`
+//! notification in server mode); each currency an account holds is kept
+//! above the floor on its own.
+//!
+//! Credit accounts are modelled with an overdraft limit, set for every
+//! account with `--overdraft-limit` and per account in the `overdraft_limit`
+//! column of the same sidecar: withdrawals, transfers, holds and the debits
+//! of custom handlers may then take `available` below zero, rather than only
+//! spend what is available. The limit is the account's, not each currency's
+//! or sub-account's: what they owe together stays within it. A floor still
+//! applies on top of it.
+
+use crate::amount::Amount;
+use crate::exit::Failure;
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum MinBalancePolicy {
+    /// Refuse the withdrawal
+    #[default]
+    Reject,
+    /// Apply the withdrawal and raise a below-minimum event
+    Flag,
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct LimitArgs {
+    /// Smallest available balance a withdrawal may leave on any account
+    #[arg(long, global = true, value_name = "AMOUNT")]
+    min_available: Option<Amount>,
+    /// Account metadata CSV with per-client `min_available` overrides
+    #[arg(long, global = true, value_name = "PATH")]
+    account_meta: Option<PathBuf>,
+    /// What to do with withdrawals that would go below the minimum
+    #[arg(long, global = true, value_enum, default_value_t)]
+    min_balance_policy: MinBalancePolicy,
+    /// How far below zero withdrawals may take any account's available
+    /// balance, overridden per client by the account metadata's
+    /// `overdraft_limit`
+    #[arg(long, global = true, value_name = "AMOUNT")]
+    overdraft_limit: Option<Amount>,
+}
+
+impl LimitArgs {
+    // the per-client amounts of the sidecar's `column`
+    fn account_meta(&self, column: &str) -> Result<HashMap<u16, Amount>> {
+        let Some(path) = &self.account_meta else {
+            return Ok(HashMap::new());
+        };
+        let body = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        parse_account_meta(&body, column)
+            .with_context(|| format!("invalid account metadata {}", path.display()))
+            .context(Failure::Parse)
+    }
+
+    pub(crate) fn min_balance(&self) -> Result<Option<MinBalance>> {
+        let per_client = self.account_meta("min_available")?;
+        if self.min_available.is_none() && per_client.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(MinBalance {
+            global: self.min_available,
+            per_client,
+            policy: self.min_balance_policy,
+        }))
+    }
+
+    pub(crate) fn overdraft(&self) -> Result<Option<Overdraft>> {
+        let per_client = self.account_meta("overdraft_limit")?;
+        if self.overdraft_limit.is_none() && per_client.is_empty() {
+            return Ok(None);
+        }
+        let limits = self.overdraft_limit.iter().chain(per_client.values());
+        if let Some(limit) = limits.copied().find(|l| l.is_negative()) {
+            anyhow::bail!("overdraft limit must not be negative, got {limit}");
+        }
+        Ok(Some(Overdraft {
+            global: self.overdraft_limit,
+            per_client,
+        }))
+    }
+}
+
+// the `client` and `name` columns of the account metadata; an empty `name`
+// leaves the client on the global setting
+fn parse_account_meta(body: &str, name: &str) -> Result<HashMap<u16, Amount>> {
+    let mut lines = body.lines().enumerate();
+    let header: Vec<&str> = lines
+        .next()
+        .context("missing header")?
+        .1
+        .split(',')
+        .map(str::trim)
+        .collect();
+    let column = |name| header.iter().position(|c| *c == name);
+    let client_col = column("client").context("missing client column")?;
+    let Some(value_col) = column(name) else {
+        return Ok(HashMap::new());
+    };
+
+    let mut values = HashMap::new();
+    for (line_no, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let d: Vec<&str> = line.split(',').map(str::trim).collect();
+        let parse = || -> Result<Option<(u16, Amount)>> {
+            let client = d
+                .get(client_col)
+                .context("missing client")?
+                .parse()
+                .context("could not parse client to u16")?;
+            match d.get(value_col).copied().unwrap_or_default() {
+                "" => Ok(None),
+                value => Ok(Some((
+                    client,
+                    value
+                        .parse()
+                        .with_context(|| format!("could not parse {name}"))?,
+                ))),
+            }
+        };
+        if let Some((client, value)) = parse().with_context(|| format!("line {}", line_no + 1))? {
+            values.insert(client, value);
+        }
+    }
+    Ok(values)
+}
+
+/// Resolved minimum balance configuration of the engine.
+#[derive(Debug, Clone)]
+pub(crate) struct MinBalance {
+    global: Option<Amount>,
+    per_client: HashMap<u16, Amount>,
+    pub(crate) policy: MinBalancePolicy,
+}
+
+impl MinBalance {
+    /// The client's floor: its own when the sidecar sets one, the global otherwise.
+    pub(crate) fn minimum(&self, client: u16) -> Option<Amount> {
+        self.per_client.get(&client).copied().or(self.global)
+    }
+}
+
+/// Resolved overdraft limits of the engine.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Overdraft {
+    global: Option<Amount>,
+    per_client: HashMap<u16, Amount>,
+}
+
+impl Overdraft {
+    /// How far below zero the client's available balance may go: its own
+    /// limit when the sidecar sets one, the global otherwise.
+    pub(crate) fn limit(&self, client: u16) -> Amount {
+        let limit = self.per_client.get(&client).copied().or(self.global);
+        limit.unwrap_or_default()
+    }
+
+    /// Sets the client's own limit, or the global one for `None`.
+    pub(crate) fn set_limit(&mut self, client: Option<u16>, limit: Amount) {
+        match client {
+            Some(client) => {
+                self.per_client.insert(client, limit);
+            }
+            None => self.global = Some(limit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TxEngine;
+
+    #[test]
+    fn test_minimum_available_per_client_and_policy() {
+        let per_client = parse_account_meta(
+            "client,name,min_available\n1,acme,50\n2,globex,\n",
+            "min_available",
+        )
+        .unwrap();
+        assert_eq!(per_client, HashMap::from([(1, Amount::from_units(50))]));
+
+        let run = |policy| {
+            let mut engine = TxEngine::new();
+            engine.min_balance = Some(MinBalance {
+                global: Some(Amount::from_units(10)),
+                per_client: per_client.clone(),
+                policy,
+            });
+            for line in [
+                "deposit, 1, 1, 100.0",
+                "deposit, 2, 2, 100.0",
+                "withdrawal, 1, 3, 60.0",
+                "withdrawal, 2, 4, 60.0",
+                "withdrawal, 2, 5, 35.0",
+            ] {
+                engine.apply_line(line);
+            }
+            [1, 2].map(|c| engine.account(c).unwrap().available.to_f64())
+        };
+        assert_eq!(run(MinBalancePolicy::Reject), [100.0, 40.0]);
+        assert_eq!(run(MinBalancePolicy::Flag), [40.0, 5.0]);
+
+        // the floor holds in each currency, not only across them
+        let mut engine = TxEngine::new();
+        engine.min_balance = Some(MinBalance {
+            global: Some(Amount::from_units(10)),
+            per_client: HashMap::new(),
+            policy: MinBalancePolicy::Reject,
+        });
+        for line in [
+            "deposit, 3, 6, 100.0,, EUR",
+            "deposit, 3, 7, 5.0,, USD",
+            "withdrawal, 3, 8, 5.0,, USD",
+        ] {
+            engine.try_apply(crate::schema::Schema::V2.parse(line).unwrap());
+        }
+        let usd = engine.account(3).unwrap().in_currency(Some("USD"));
+        assert_eq!(usd.available, Amount::from_units(5));
+    }
+
+    #[test]
+    fn test_overdraft_limit_per_client() {
+        let meta = "client,overdraft_limit\n1,50\n2,\n";
+        let mut engine = TxEngine::new();
+        engine.set_overdraft(Overdraft {
+            global: Some(Amount::from_units(10)),
+            per_client: parse_account_meta(meta, "overdraft_limit").unwrap(),
+        });
+        for line in [
+            "deposit, 1, 1, 20.0",
+            "deposit, 2, 2, 20.0",
+            "withdrawal, 1, 3, 60.0",
+            "withdrawal, 1, 4, 20.0",
+            "withdrawal, 2, 5, 25.0",
+            "withdrawal, 2, 6, 10.0",
+            "deposit, 3, 7, 1.0",
+            "withdrawal, 3, 8, 11.0",
+        ] {
+            engine.apply_line(line);
+        }
+        let available = |c| engine.account(c).unwrap().available.to_f64();
+        assert_eq!([1, 2, 3].map(available), [-40.0, -5.0, -10.0]);
+
+        // the limit covers the account's currencies together, and transfers
+        for line in [
+            "withdrawal, 4, 9, 6.0,, EUR",
+            "withdrawal, 4, 10, 6.0,, USD",
+            "withdrawal, 4, 11, 4.0,, USD",
+            "transfer, 4, 12, 1.0,, EUR,,,, 5",
+        ] {
+            engine.try_apply(crate::schema::Schema::V2.parse(line).unwrap());
+        }
+        let available = |c| engine.account(4).unwrap().in_currency(Some(c)).available;
+        assert_eq!(available("EUR"), Amount::from_units(-6));
+        assert_eq!(available("USD"), Amount::from_units(-4));
+        assert!(engine.account(5).is_none());
+
+        // as set through the engine
+        let (negative, limit) = (Amount::from_units(-1), Amount::from_units(11));
+        assert!(engine.set_overdraft_limit(Some(4), negative).is_err());
+        engine.set_overdraft_limit(Some(4), limit).unwrap();
+        engine.apply_line("withdrawal, 4, 13, 1.0");
+        engine.apply_line("withdrawal, 4, 14, 1.0");
+        let available = engine.account(4).unwrap().available;
+        assert_eq!(available, Amount::from_units(-1));
+    }
+}
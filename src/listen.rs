@@ -0,0 +1,324 @@
+//! Ingest listeners of the stream server.
+//!
+//! `--listen` takes a URL and may be repeated; every listener feeds the same
+//! engine and records into its own counters, shown per listener under
+//! `listeners` in `/api/metrics`:
+//! - `tcp://ADDR` the line protocol of `csv_stream` on a TCP socket, the
+//!   default being `tcp://127.0.0.1:6969`
+//! - `tcp+proxy://ADDR` the same behind a load balancer sending the PROXY
+//!   protocol header, see `net`
+//! - `tls://ADDR` the same over TLS with the certificate of `--tls-cert`, see
+//!   `tls`
+//! - `unix://PATH` the same protocol on a Unix domain socket
+//! - `http://ADDR` `POST /ingest` with a body of records in the line
+//!   protocol, `#schema=N` directive included, answered with one `OK <tx>`
+//!   or `ERR <reason>` line per record as on `#ack` connections
+//!
+//! With `--stream-token` every listener authenticates producers, see `auth`.
+
+use crate::auth::{self, AuthArgs};
+use crate::csv_stream::{self, Handshake, ACK_DIRECTIVE};
+use crate::drain::Draining;
+use crate::metrics::Metrics;
+use crate::net::{self, TcpArgs};
+use crate::signing::Verifier;
+use crate::tls::TlsArgs;
+use crate::TxEngine;
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::Router;
+use clap::Args;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Listener {
+    Tcp(SocketAddr),
+    /// A TCP listener whose connections open with a PROXY protocol header.
+    TcpProxy(SocketAddr),
+    /// A TCP listener whose connections open with a TLS handshake.
+    Tls(SocketAddr),
+    Unix(PathBuf),
+    Http(SocketAddr),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "tcp://{addr}"),
+            Listener::TcpProxy(addr) => write!(f, "tcp+proxy://{addr}"),
+            Listener::Tls(addr) => write!(f, "tls://{addr}"),
+            Listener::Unix(path) => write!(f, "unix://{}", path.display()),
+            Listener::Http(addr) => write!(f, "http://{addr}"),
+        }
+    }
+}
+
+fn parse_listener(s: &str) -> Result<Listener> {
+    let (scheme, rest) = s
+        .split_once("://")
+        .context("expected tcp://ADDR, tcp+proxy://ADDR, tls://ADDR, unix://PATH or http://ADDR")?;
+    let addr = || {
+        rest.parse()
+            .with_context(|| format!("could not parse address {rest}"))
+    };
+    let listener = match scheme {
+        "tcp" => Listener::Tcp(addr()?),
+        "tcp+proxy" => Listener::TcpProxy(addr()?),
+        "tls" => Listener::Tls(addr()?),
+        "http" => Listener::Http(addr()?),
+        "unix" if cfg!(unix) => Listener::Unix(PathBuf::from(rest)),
+        "unix" => anyhow::bail!("unix sockets are not supported on this platform"),
+        _ => anyhow::bail!("unknown listener scheme {scheme}"),
+    };
+    Ok(listener)
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct ListenArgs {
+    /// Accept records on tcp://ADDR, tcp+proxy://ADDR, tls://ADDR, unix://PATH or http://ADDR;
+    /// repeat to serve several
+    #[arg(
+        long = "listen",
+        value_name = "URL",
+        value_parser = parse_listener,
+        default_value = "tcp://127.0.0.1:6969"
+    )]
+    listeners: Vec<Listener>,
+
+    #[command(flatten)]
+    tcp: TcpArgs,
+
+    #[command(flatten)]
+    tls: TlsArgs,
+
+    #[command(flatten)]
+    auth: AuthArgs,
+}
+
+impl ListenArgs {
+    /// The token producers authenticate with, see `auth`.
+    pub(crate) fn token(&self) -> Option<Arc<str>> {
+        self.auth.token()
+    }
+}
+
+#[derive(Clone)]
+struct IngestState {
+    engine: Arc<Mutex<TxEngine>>,
+    metrics: Arc<Metrics>,
+    verifier: Option<Arc<Verifier>>,
+    token: Option<Arc<str>>,
+    draining: Draining,
+}
+
+async fn post_ingest(
+    State(state): State<IngestState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<String, StatusCode> {
+    if let Some(token) = &state.token {
+        let sent = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        if !auth::is_bearer(sent, token) {
+            let reason = format!("request: {}", auth::UNAUTHENTICATED);
+            state.metrics.record_rejected(reason);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+    let _active = state.metrics.connection();
+    let directive = format!("{ACK_DIRECTIVE}\n");
+    let reader = directive.as_bytes().chain(&body[..]);
+    let mut replies = Vec::new();
+    let verifier = state.verifier.as_deref();
+    csv_stream::serve_lines(
+        reader,
+        &mut replies,
+        &state.engine,
+        &state.metrics,
+        verifier,
+        // a request's body is applied whole; graceful shutdown waits for it
+        Draining::never(),
+    )
+    .await;
+    Ok(String::from_utf8_lossy(&replies).into_owned())
+}
+
+fn ingest_router(state: IngestState) -> Router {
+    Router::new()
+        .route("/ingest", post(post_ingest))
+        .with_state(state)
+}
+
+async fn serve_http(addr: SocketAddr, state: IngestState) -> Result<()> {
+    #[cfg(unix)]
+    let listener = crate::handoff::tcp_listener(addr, || net::bind_tcp(addr))?;
+    #[cfg(not(unix))]
+    let listener = net::bind_tcp(addr)?;
+    let mut draining = state.draining.clone();
+    axum::serve(listener, ingest_router(state))
+        .with_graceful_shutdown(async move { draining.started().await })
+        .await?;
+    Ok(())
+}
+
+async fn serve(
+    listener: Listener,
+    state: IngestState,
+    tcp: TcpArgs,
+    tls: Option<TlsAcceptor>,
+) -> Result<()> {
+    let IngestState {
+        engine,
+        metrics,
+        verifier,
+        token,
+        draining,
+    } = state.clone();
+    match listener {
+        Listener::Tcp(addr) | Listener::TcpProxy(addr) | Listener::Tls(addr) => {
+            let handshake = Handshake {
+                proxy: matches!(listener, Listener::TcpProxy(_)),
+                tls: match listener {
+                    Listener::Tls(_) => tls,
+                    _ => None,
+                },
+                token,
+            };
+            csv_stream::handle_stream(addr, handshake, tcp, engine, metrics, verifier, draining)
+                .await
+        }
+        #[cfg(unix)]
+        Listener::Unix(path) => {
+            csv_stream::handle_unix_stream(&path, token, engine, metrics, verifier, draining).await
+        }
+        #[cfg(not(unix))]
+        Listener::Unix(_) => unreachable!("rejected by parse_listener"),
+        Listener::Http(addr) => serve_http(addr, state).await,
+    }
+}
+
+/// Serves every listener until one of them fails. Listeners stop accepting
+/// when the server drains, which ends the server itself.
+pub(crate) async fn run(
+    args: &ListenArgs,
+    engine: Arc<Mutex<TxEngine>>,
+    metrics: Arc<Metrics>,
+    verifier: Option<Arc<Verifier>>,
+    draining: Draining,
+) -> Result<()> {
+    #[cfg(unix)]
+    crate::handoff::close_unclaimed(
+        &args
+            .listeners
+            .iter()
+            .map(|listener| match listener {
+                Listener::Tcp(addr)
+                | Listener::TcpProxy(addr)
+                | Listener::Tls(addr)
+                | Listener::Http(addr) => Ok(*addr),
+                Listener::Unix(path) => Err(path.as_path()),
+            })
+            .collect::<Vec<_>>(),
+    );
+    let tls = args.tls.acceptor()?;
+    let serves_tls = args.listeners.iter().any(|l| matches!(l, Listener::Tls(_)));
+    match (serves_tls, tls.is_some()) {
+        (true, false) => anyhow::bail!("tls:// listeners need --tls-cert and --tls-key"),
+        (false, true) => anyhow::bail!("--tls-cert needs a tls:// listener"),
+        _ => {}
+    }
+    let token = args.auth.token();
+    let mut listeners = JoinSet::new();
+    for listener in args.listeners.iter().cloned() {
+        let name = listener.to_string();
+        let state = IngestState {
+            engine: engine.clone(),
+            metrics: metrics.listener(name.clone()),
+            verifier: verifier.clone(),
+            token: token.clone(),
+            draining: draining.clone(),
+        };
+        let (tcp, tls) = (args.tcp, tls.clone());
+        listeners.spawn(async move { serve(listener, state, tcp, tls).await.context(name) });
+    }
+    while let Some(res) = listeners.join_next().await {
+        res??;
+    }
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_listener() {
+        assert_eq!(
+            parse_listener("tcp://127.0.0.1:6969").unwrap(),
+            Listener::Tcp("127.0.0.1:6969".parse().unwrap())
+        );
+        assert_eq!(
+            parse_listener("unix:///run/roinstxs.sock")
+                .unwrap()
+                .to_string(),
+            "unix:///run/roinstxs.sock"
+        );
+        assert!(parse_listener("127.0.0.1:6969").is_err());
+        assert!(parse_listener("udp://127.0.0.1:6969").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_ingest_counts_per_listener() {
+        let engine = Arc::new(Mutex::new(TxEngine::new()));
+        let metrics = Arc::new(Metrics::default());
+        let state = IngestState {
+            engine: engine.clone(),
+            metrics: metrics.listener("http://127.0.0.1:0".to_owned()),
+            verifier: None,
+            token: None,
+            draining: Draining::never(),
+        };
+        let _other = metrics.listener("tcp://127.0.0.1:0".to_owned());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, ingest_router(state)).await });
+
+        let replies = reqwest::Client::new()
+            .post(format!("http://{addr}/ingest"))
+            .body("#schema=2\ndeposit, 1, 1, 10.0\ndeposit, x, 2, 1.0\n")
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(replies.lines().next(), Some("OK 1"));
+        assert!(replies.lines().nth(1).unwrap().starts_with("ERR "));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.total_processed(), snapshot.rejected), (1, 1));
+        let (name, http) = &snapshot.listeners[0];
+        assert_eq!(name, "http://127.0.0.1:0");
+        assert_eq!((http.total_processed(), http.rejected), (1, 1));
+        assert_eq!(snapshot.listeners[1].1.total_processed(), 0);
+        assert_eq!(
+            engine.lock().await.account(1).unwrap().available,
+            Amount::from_units(10)
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Synthetic load against a running stream server.
+//!
+//! Transactions are generated from a seeded RNG and written in small bursts so
+//! the achieved rate tracks `--tps` closely. Latency is measured per burst as
+//! the time taken for the server to accept the bytes on the socket.
+
+use crate::rng::XorShift;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const TICK: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Args)]
+pub(crate) struct LoadgenArgs {
+    /// Server to load, e.g. tcp://127.0.0.1:6969
+    #[arg(long, default_value = "tcp://127.0.0.1:6969")]
+    target: String,
+    /// Transactions per second to sustain across all connections
+    #[arg(long, default_value_t = 10_000)]
+    tps: u64,
+    /// How long to run, e.g. 500ms, 60s, 5m
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    duration: Duration,
+    /// Number of parallel producer connections
+    #[arg(long, default_value_t = 1)]
+    connections: u64,
+    /// Seed for the synthetic transaction stream
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+pub(crate) fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("invalid duration {s}"))?;
+    let duration = match unit {
+        "ms" => Duration::from_millis(value),
+        "" | "s" => Duration::from_secs(value),
+        "m" => Duration::from_secs(value * 60),
+        "h" => Duration::from_secs(value * 3600),
+        "d" => Duration::from_secs(value * 86400),
+        _ => anyhow::bail!("unknown duration unit {unit} in {s}"),
+    };
+    Ok(duration)
+}
+
+/// Produces a plausible mix of deposits, withdrawals and dispute-family
+/// records. Tx ids are partitioned by connection so producers never collide.
+pub(crate) struct TxGenerator {
+    rng: XorShift,
+    next_tx_id: u32,
+    stride: u32,
+}
+
+impl TxGenerator {
+    pub(crate) fn new(seed: u64, connection: u32, connections: u32) -> Self {
+        Self {
+            rng: XorShift::new(seed ^ u64::from(connection)),
+            next_tx_id: connection + 1,
+            stride: connections,
+        }
+    }
+
+    fn write_line(&mut self, buf: &mut Vec<u8>) {
+        use std::io::Write;
+
+        let client = 1 + self.rng.below(u64::from(u16::MAX));
+        let roll = self.rng.below(100);
+        if roll >= 95 && self.next_tx_id > self.stride {
+            let tx_id = 1 + self.rng.below(u64::from(self.next_tx_id - 1));
+            let kind = match roll {
+                95..=97 => "dispute",
+                98 => "resolve",
+                _ => "chargeback",
+            };
+            let _ = writeln!(buf, "{kind},{client},{tx_id},");
+            return;
+        }
+
+        let kind = if roll < 70 { "deposit" } else { "withdrawal" };
+        let cents = 1 + self.rng.below(1_000_000);
+        let _ = writeln!(
+            buf,
+            "{kind},{client},{},{}.{:02}",
+            self.next_tx_id,
+            cents / 100,
+            cents % 100
+        );
+        self.next_tx_id += self.stride;
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Report {
+    pub(crate) sent: u64,
+    latencies: Vec<Duration>,
+}
+
+async fn produce(addr: String, tps: u64, duration: Duration, gen: TxGenerator) -> Result<Report> {
+    let socket = TcpStream::connect(&addr)
+        .await
+        .with_context(|| format!("could not connect to {addr}"))?;
+    produce_into(socket, tps, duration, gen).await
+}
+
+/// Writes generated lines into `sink` at `tps` until `duration` elapses, then
+/// shuts the sink down so the reading side sees end of stream.
+pub(crate) async fn produce_into<W: AsyncWrite + Unpin>(
+    mut sink: W,
+    tps: u64,
+    duration: Duration,
+    mut gen: TxGenerator,
+) -> Result<Report> {
+    let mut report = Report::default();
+    let mut buf = Vec::new();
+    let mut ticker = tokio::time::interval(TICK);
+    let start = Instant::now();
+
+    while start.elapsed() < duration {
+        ticker.tick().await;
+        let due = (start.elapsed().as_secs_f64() * tps as f64) as u64;
+        let burst = due.saturating_sub(report.sent);
+        if burst == 0 {
+            continue;
+        }
+
+        buf.clear();
+        for _ in 0..burst {
+            gen.write_line(&mut buf);
+        }
+        let begin = Instant::now();
+        sink.write_all(&buf).await?;
+        report.latencies.push(begin.elapsed());
+        report.sent += burst;
+    }
+    sink.shutdown().await?;
+    Ok(report)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[rank]
+}
+
+pub(crate) async fn run(args: LoadgenArgs) -> Result<()> {
+    let addr = args
+        .target
+        .strip_prefix("tcp://")
+        .unwrap_or(&args.target)
+        .to_owned();
+    let connections = args.connections.max(1);
+    let per_connection = args.tps / connections;
+
+    let start = Instant::now();
+    let mut tasks = tokio::task::JoinSet::new();
+    for i in 0..connections {
+        let gen = TxGenerator::new(args.seed, i as u32, connections as u32);
+        tasks.spawn(produce(addr.clone(), per_connection, args.duration, gen));
+    }
+
+    let mut total = Report::default();
+    while let Some(res) = tasks.join_next().await {
+        let report = res??;
+        total.sent += report.sent;
+        total.latencies.extend(report.latencies);
+    }
+    let elapsed = start.elapsed();
+    total.latencies.sort_unstable();
+
+    println!("target:      {addr}");
+    println!("sent:        {} txs in {:.2?}", total.sent, elapsed);
+    println!(
+        "throughput:  {:.0} tx/s (target {})",
+        total.sent as f64 / elapsed.as_secs_f64(),
+        args.tps
+    );
+    println!(
+        "write p50/p90/p99/max: {:.2?} / {:.2?} / {:.2?} / {:.2?}",
+        percentile(&total.latencies, 0.50),
+        percentile(&total.latencies, 0.90),
+        percentile(&total.latencies, 0.99),
+        total.latencies.last().copied().unwrap_or_default()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("60s").unwrap(), Duration::from_secs(60));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("2").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+}
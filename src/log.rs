@@ -0,0 +1,73 @@
+//! Log lines on stderr, filtered by `--log-level`.
+//!
+//! Everything the engine and servers report goes through `error!`, `warn!`
+//! and `info!`, which print like `eprintln!` when the level is enabled:
+//! `error` for failures to store, write or reach something, `warn` for
+//! records and connections refused along the way, `info` for what servers do
+//! on their own, such as restoring, draining or taking over. The default
+//! `info` prints all of them. Output commands print anyway, the summary, the
+//! `submit` report and fatal errors included.
+
+use clap::ValueEnum;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub(crate) enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Prints the lines of `level` and the more severe ones from now on.
+pub(crate) fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub(crate) fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+macro_rules! error_ {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Error) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! warn_ {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! info_ {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+// renamed on export, as `warn` alone is also a built-in attribute
+pub(crate) use {error_ as error, info_ as info, warn_ as warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels_include_the_more_severe() {
+        assert!(Level::Error < Level::Warn && Level::Warn < Level::Info);
+        set_level(Level::Warn);
+        assert!(enabled(Level::Error) && enabled(Level::Warn));
+        assert!(!enabled(Level::Info));
+        set_level(Level::Info);
+        assert!(enabled(Level::Info));
+    }
+}
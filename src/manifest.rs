@@ -0,0 +1,148 @@
+//! Run manifests for verifying file-mode results downstream.
+//!
+//! With `--manifest PATH` a JSON document is written next to the summary
+//! holding the SHA-256 of every input, the engine version, a hash of the
+//! effective configuration, row counts and the SHA-256 of the exact bytes
+//! written to stdout. A consumer that recomputes the output hash can tell a
+//! truncated or altered summary from the one this run produced.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Args)]
+pub(crate) struct ManifestArgs {
+    /// Write input/output hashes, row counts and config hash to this JSON file
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        conflicts_with = "partitions"
+    )]
+    manifest: Option<PathBuf>,
+}
+
+impl ManifestArgs {
+    /// `config` is any stable rendering of the options the run was started with.
+    pub(crate) fn manifest(&self, config: &str) -> Option<Manifest> {
+        Some(Manifest {
+            path: self.manifest.clone()?,
+            config_sha256: hex::encode(Sha256::digest(config.as_bytes())),
+        })
+    }
+}
+
+pub(crate) struct Manifest {
+    path: PathBuf,
+    config_sha256: String,
+}
+
+/// What a file-mode run consumed and produced.
+pub(crate) struct RunCounts {
+    /// Records handed to the engine, valid or not.
+    pub(crate) records: u64,
+    /// Unparsable lines skipped under `--lenient`.
+    pub(crate) skipped: usize,
+}
+
+impl Manifest {
+    pub(crate) fn write(
+        &self,
+        input: &Path,
+        counts: RunCounts,
+        output: &HashingWriter<impl Write>,
+    ) -> Result<()> {
+        let manifest = json!({
+            "engine_version": env!("CARGO_PKG_VERSION"),
+            "config_sha256": self.config_sha256,
+            "inputs": [{
+                "path": input.display().to_string(),
+                "sha256": hash_file(input)?,
+            }],
+            "rows": {
+                "input": counts.records,
+                "skipped": counts.skipped,
+                "output": output.lines,
+            },
+            "output": {
+                "bytes": output.bytes,
+                "sha256": hex::encode(output.hasher.clone().finalize()),
+            },
+        });
+        let file = File::create(&self.path)
+            .with_context(|| format!("could not create {}", self.path.display()))?;
+        serde_json::to_writer_pretty(&file, &manifest)?;
+        writeln!(&file)?;
+        Ok(())
+    }
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Passes writes through to `inner` while hashing and counting them.
+pub(crate) struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    bytes: u64,
+    lines: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+            lines: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // only what the inner writer accepted ends up in the hash
+        let n = self.inner.write(buf)?;
+        let written = &buf[..n];
+        self.hasher.update(written);
+        self.bytes += n as u64;
+        self.lines += written.iter().filter(|&&b| b == b'\n').count() as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashing_writer_matches_digest_of_output() {
+        let mut out = HashingWriter::new(Vec::new());
+        write!(out, "client,available\n1,2\n").unwrap();
+        assert_eq!(out.lines, 2);
+        assert_eq!(out.bytes, 21);
+        assert_eq!(
+            hex::encode(out.hasher.clone().finalize()),
+            hex::encode(Sha256::digest(&out.inner))
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Rollup of the summaries of several instances into one.
+//!
+//! Instances that split the input by client, e.g. Kafka consumers started
+//! with `--kafka-instance`, each summarize a disjoint set of accounts. `merge`
+//! reads their CSV summaries and prints the combined summary, sorted by
+//! client. A client summarized by two inputs means the split was broken and
+//! its state diverged, so the merge fails instead of picking one.
+
+use crate::engine::Account;
+use crate::exit::Failure;
+use crate::output::Output;
+use crate::reconcile::parse_expected;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Args)]
+pub(crate) struct MergeArgs {
+    /// Summary CSVs of the instances
+    #[arg(required = true, num_args = 1..)]
+    summaries: Vec<PathBuf>,
+}
+
+fn merge<'a>(summaries: impl IntoIterator<Item = (&'a Path, &'a str)>) -> Result<Vec<Account>> {
+    let mut merged: BTreeMap<u16, (&Path, Account)> = BTreeMap::new();
+    for (path, body) in summaries {
+        let accounts =
+            parse_expected(body).with_context(|| format!("invalid summary {}", path.display()))?;
+        for (client, account) in accounts {
+            match merged.entry(client) {
+                Entry::Vacant(e) => {
+                    e.insert((path, account));
+                }
+                Entry::Occupied(e) => {
+                    return Err(anyhow::anyhow!(
+                        "client {client} is summarized by both {} and {}",
+                        e.get().0.display(),
+                        path.display()
+                    ))
+                    .context(Failure::Invariant);
+                }
+            }
+        }
+    }
+    Ok(merged.into_values().map(|(_, account)| account).collect())
+}
+
+pub(crate) fn run(args: MergeArgs) -> Result<()> {
+    let mut bodies = Vec::with_capacity(args.summaries.len());
+    for path in &args.summaries {
+        let body = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        bodies.push(body);
+    }
+    let merged = merge(
+        args.summaries
+            .iter()
+            .map(PathBuf::as_path)
+            .zip(bodies.iter().map(String::as_str)),
+    )?;
+    let accounts: Vec<&Account> = merged.iter().collect();
+    Output::default().write_accounts(&accounts, std::io::stdout().lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_disjoint_summaries() {
+        let header = "client,available,held,total,locked";
+        let a = format!("{header}\n3,1,0,1,false\n1,2,0,2,false\n");
+        let b = format!("{header},closed\n2,0,0,0,false,true\n");
+        let merged = merge([(Path::new("a"), a.as_str()), (Path::new("b"), b.as_str())]).unwrap();
+        assert_eq!(
+            merged.iter().map(|a| a.client).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert!(merged[1].closed);
+
+        let err = merge([(Path::new("a"), a.as_str()), (Path::new("c"), a.as_str())]).unwrap_err();
+        assert_eq!(err.downcast_ref::<Failure>(), Some(&Failure::Invariant));
+    }
+}
@@ -0,0 +1,281 @@
+//! Lock-free counters describing the stream server's activity.
+//!
+//! Connection handlers record into a shared [`Metrics`] and observers (the
+//! dashboard, exporters) read consistent-enough copies via [`Metrics::snapshot`].
+//! Each ingest listener records into its own child from [`Metrics::listener`],
+//! which also counts into the shared one, so the snapshot holds the totals and
+//! a breakdown by listener.
+
+use crate::engine::TxType;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const RECENT_REJECTIONS: usize = 32;
+/// Upper bounds of the buckets of the apply latency histogram, the last
+/// bucket holding the slower records.
+pub(crate) const LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_micros(1),
+    Duration::from_micros(5),
+    Duration::from_micros(10),
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+];
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    processed: [AtomicU64; TxType::ALL.len()],
+    rejected: AtomicU64,
+    connections: AtomicU64,
+    accepted_ipv4: AtomicU64,
+    accepted_ipv6: AtomicU64,
+    batches: AtomicU64,
+    batched: AtomicU64,
+    largest_batch: AtomicU64,
+    batch_nanos: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_nanos: AtomicU64,
+    recent_rejections: Mutex<VecDeque<String>>,
+    // the shared counters a listener's counters also record into
+    parent: Option<Arc<Metrics>>,
+    listeners: Mutex<Vec<(String, Arc<Metrics>)>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MetricsSnapshot {
+    pub(crate) processed: Vec<(TxType, u64)>,
+    pub(crate) rejected: u64,
+    pub(crate) connections: u64,
+    /// TCP connections accepted so far, by the producer's address family.
+    pub(crate) accepted_ipv4: u64,
+    pub(crate) accepted_ipv6: u64,
+    /// Batches of stream records applied, see `batch`, and the records
+    /// they held.
+    pub(crate) batches: u64,
+    pub(crate) batched: u64,
+    pub(crate) largest_batch: u64,
+    /// Time spent applying batches under the engine lock.
+    pub(crate) batch_time: Duration,
+    /// Records applied by the time the engine took on each, by bucket of
+    /// `LATENCY_BUCKETS`, and that time summed.
+    pub(crate) latency: Vec<u64>,
+    pub(crate) latency_sum: Duration,
+    /// Most recent first.
+    pub(crate) recent_rejections: Vec<String>,
+    /// The counters of each listener, in the order they were created.
+    pub(crate) listeners: Vec<(String, MetricsSnapshot)>,
+}
+
+impl MetricsSnapshot {
+    pub(crate) fn total_processed(&self) -> u64 {
+        self.processed.iter().map(|(_, n)| n).sum()
+    }
+
+    pub(crate) fn mean_batch(&self) -> f64 {
+        match self.batches {
+            0 => 0.,
+            batches => self.batched as f64 / batches as f64,
+        }
+    }
+}
+
+impl Metrics {
+    pub(crate) fn record_processed(&self, kind: TxType) {
+        if let Some(slot) = TxType::ALL.iter().position(|k| *k == kind) {
+            self.processed[slot].fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(parent) = &self.parent {
+            parent.record_processed(kind);
+        }
+    }
+
+    pub(crate) fn record_rejected(&self, reason: String) {
+        if let Some(parent) = &self.parent {
+            parent.record_rejected(reason.clone());
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        let mut recent = self.recent_rejections.lock().unwrap();
+        if recent.len() == RECENT_REJECTIONS {
+            recent.pop_back();
+        }
+        recent.push_front(reason);
+    }
+
+    /// Counts the connection as active until the returned guard is dropped.
+    pub(crate) fn connection(&self) -> ConnectionGuard<'_> {
+        self.add_connections(1);
+        ConnectionGuard(self)
+    }
+
+    fn add_connections(&self, delta: i64) {
+        // wrapping, so a negative delta decrements
+        self.connections.fetch_add(delta as u64, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.add_connections(delta);
+        }
+    }
+
+    /// Counts a TCP connection accepted from `peer`, an IPv4-mapped address
+    /// being IPv4.
+    pub(crate) fn record_accepted(&self, peer: SocketAddr) {
+        let family = match peer.ip().to_canonical() {
+            IpAddr::V4(_) => &self.accepted_ipv4,
+            IpAddr::V6(_) => &self.accepted_ipv6,
+        };
+        family.fetch_add(1, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.record_accepted(peer);
+        }
+    }
+
+    /// Counts a record the engine applied in `took`.
+    pub(crate) fn record_latency(&self, took: Duration) {
+        let bucket = LATENCY_BUCKETS.partition_point(|bound| *bound < took);
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_nanos
+            .fetch_add(took.as_nanos() as u64, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.record_latency(took);
+        }
+    }
+
+    /// Counts a batch of `size` records applied under one engine lock in
+    /// `took`.
+    pub(crate) fn record_batch(&self, size: usize, took: Duration) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.batched.fetch_add(size as u64, Ordering::Relaxed);
+        self.largest_batch.fetch_max(size as u64, Ordering::Relaxed);
+        self.batch_nanos.fetch_add(took.as_nanos() as u64, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.record_batch(size, took);
+        }
+    }
+
+    /// Counters for the listener `name`, recording into these as well.
+    pub(crate) fn listener(self: &Arc<Self>, name: String) -> Arc<Metrics> {
+        let child = Arc::new(Metrics {
+            parent: Some(self.clone()),
+            ..Default::default()
+        });
+        self.listeners.lock().unwrap().push((name, child.clone()));
+        child
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            processed: TxType::ALL
+                .iter()
+                .zip(&self.processed)
+                .map(|(kind, n)| (*kind, n.load(Ordering::Relaxed)))
+                .collect(),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            accepted_ipv4: self.accepted_ipv4.load(Ordering::Relaxed),
+            accepted_ipv6: self.accepted_ipv6.load(Ordering::Relaxed),
+            batches: self.batches.load(Ordering::Relaxed),
+            batched: self.batched.load(Ordering::Relaxed),
+            largest_batch: self.largest_batch.load(Ordering::Relaxed),
+            batch_time: Duration::from_nanos(self.batch_nanos.load(Ordering::Relaxed)),
+            latency: self
+                .latency
+                .iter()
+                .map(|n| n.load(Ordering::Relaxed))
+                .collect(),
+            latency_sum: Duration::from_nanos(self.latency_nanos.load(Ordering::Relaxed)),
+            recent_rejections: self
+                .recent_rejections
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect(),
+            listeners: self
+                .listeners
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, metrics)| (name.clone(), metrics.snapshot()))
+                .collect(),
+        }
+    }
+}
+
+pub(crate) struct ConnectionGuard<'a>(&'a Metrics);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.add_connections(-1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listeners_count_into_the_totals() {
+        let metrics = Arc::new(Metrics::default());
+        let tcp = metrics.listener("tcp".to_owned());
+        let http = metrics.listener("http".to_owned());
+        tcp.record_processed(TxType::Deposit);
+        http.record_processed(TxType::Withdrawal);
+        metrics.record_processed(TxType::Deposit);
+        tcp.record_accepted("[::ffff:127.0.0.1]:1".parse().unwrap());
+        http.record_accepted("[::1]:1".parse().unwrap());
+        let guard = tcp.connection();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.total_processed(), 3);
+        assert_eq!((snapshot.accepted_ipv4, snapshot.accepted_ipv6), (1, 1));
+        assert_eq!(snapshot.connections, 1);
+        let listeners: Vec<(&str, u64)> = snapshot
+            .listeners
+            .iter()
+            .map(|(name, counters)| (name.as_str(), counters.total_processed()))
+            .collect();
+        assert_eq!(listeners, [("tcp", 1), ("http", 1)]);
+        drop(guard);
+        assert_eq!(metrics.snapshot().connections, 0);
+    }
+
+    #[test]
+    fn test_rejections_keep_the_most_recent() {
+        let metrics = Metrics::default();
+        for n in 0..RECENT_REJECTIONS + 2 {
+            metrics.record_rejected(format!("line {n}"));
+        }
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.rejected, RECENT_REJECTIONS as u64 + 2);
+        assert_eq!(snapshot.recent_rejections.len(), RECENT_REJECTIONS);
+        assert_eq!(snapshot.recent_rejections[0], "line 33");
+        assert_eq!(snapshot.recent_rejections.last().unwrap(), "line 2");
+    }
+
+    #[test]
+    fn test_latency_buckets_and_batches() {
+        let metrics = Metrics::default();
+        metrics.record_latency(Duration::from_micros(1));
+        metrics.record_latency(Duration::from_micros(7));
+        metrics.record_latency(Duration::from_secs(1));
+        metrics.record_batch(4, Duration::from_millis(1));
+        metrics.record_batch(2, Duration::from_millis(1));
+
+        let snapshot = metrics.snapshot();
+        // bounds are inclusive, the last bucket takes what is slower than all
+        assert_eq!(snapshot.latency[0], 1);
+        assert_eq!(snapshot.latency[2], 1);
+        assert_eq!(snapshot.latency[LATENCY_BUCKETS.len()], 1);
+        assert_eq!(snapshot.latency.iter().sum::<u64>(), 3);
+        assert_eq!((snapshot.batches, snapshot.largest_batch), (2, 4));
+        assert_eq!(snapshot.mean_batch(), 3.0);
+        assert_eq!(snapshot.batch_time, Duration::from_millis(2));
+        assert_eq!(MetricsSnapshot::default().mean_batch(), 0.0);
+    }
+}
@@ -0,0 +1,256 @@
+//! Socket setup shared by the server's TCP listeners.
+//!
+//! Listeners bind through [`bind_tcp`] rather than `TcpListener::bind` so the
+//! IPv6 wildcard `[::]` is dual-stack whatever the host's `bindv6only`
+//! default, and IPv4 producers reach an IPv6-only network's listener as
+//! IPv4-mapped addresses. [`peer_addr`] turns those back into plain IPv4 so
+//! logs and metrics name the family the producer actually used.
+//!
+//! Behind HAProxy or a network load balancer the peer is the balancer;
+//! listeners given as `tcp+proxy://` read the producer's address from the
+//! PROXY protocol header (v1 text or v2 binary) the balancer sends first.
+//! `UNKNOWN` and `LOCAL` headers, such as health checks, keep the peer's.
+//!
+//! Ingest connections get the socket options of [`TcpArgs`]. Keepalive is on
+//! by default, so a producer whose host vanished without closing its
+//! connection is detected within about two minutes instead of the kernel's
+//! default of over two hours.
+
+use crate::loadgen::parse_duration;
+use anyhow::{Context, Result};
+use clap::Args;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const BACKLOG: i32 = 1024;
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// longest v1 header, CRLF included
+const PROXY_V1_MAX: usize = 107;
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Args)]
+pub(crate) struct TcpArgs {
+    /// Idle time before keepalive probes start on ingest connections; 0s disables them
+    #[arg(long, global = true, value_parser = parse_duration, default_value = "60s")]
+    tcp_keepalive: Duration,
+    /// Time between keepalive probes
+    #[arg(long, global = true, value_parser = parse_duration, default_value = "10s")]
+    tcp_keepalive_interval: Duration,
+    /// Unanswered keepalive probes before a connection is dropped
+    #[arg(long, global = true, default_value_t = 5)]
+    tcp_keepalive_retries: u32,
+    /// Send replies without waiting to coalesce them (TCP_NODELAY)
+    #[arg(long, global = true)]
+    tcp_nodelay: bool,
+    /// Receive buffer size of ingest sockets in bytes (SO_RCVBUF)
+    #[arg(long, global = true, value_name = "BYTES")]
+    tcp_recv_buffer: Option<usize>,
+}
+
+impl TcpArgs {
+    /// `bind_tcp`, with the receive buffer set before listening so accepted
+    /// connections inherit it and the window scale is chosen for it.
+    pub(crate) fn bind(&self, addr: SocketAddr) -> Result<TcpListener> {
+        bind(addr, self.tcp_recv_buffer)
+    }
+
+    /// Applies the keepalive and nodelay options to an accepted connection.
+    pub(crate) fn tune(&self, stream: &TcpStream) -> std::io::Result<()> {
+        let socket = SockRef::from(stream);
+        socket.set_tcp_nodelay(self.tcp_nodelay)?;
+        if self.tcp_keepalive.is_zero() {
+            return socket.set_keepalive(false);
+        }
+        let keepalive = TcpKeepalive::new().with_time(self.tcp_keepalive);
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+        let keepalive = keepalive
+            .with_interval(self.tcp_keepalive_interval)
+            .with_retries(self.tcp_keepalive_retries);
+        socket.set_tcp_keepalive(&keepalive)
+    }
+}
+
+/// Binds a listener on `addr`, accepting both families on `[::]`.
+pub(crate) fn bind_tcp(addr: SocketAddr) -> Result<TcpListener> {
+    bind(addr, None)
+}
+
+fn bind(addr: SocketAddr, recv_buffer: Option<usize>) -> Result<TcpListener> {
+    let bind = || -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if addr.ip() == Ipv6Addr::UNSPECIFIED {
+            socket.set_only_v6(false)?;
+        }
+        if let Some(size) = recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(BACKLOG)?;
+        TcpListener::from_std(socket.into())
+    };
+    bind().with_context(|| format!("could not bind {addr}"))
+}
+
+/// `peer` with an IPv4-mapped IPv6 address given as the IPv4 address.
+pub(crate) fn peer_addr(peer: SocketAddr) -> SocketAddr {
+    SocketAddr::new(peer.ip().to_canonical(), peer.port())
+}
+
+/// Reads the PROXY protocol header opening a connection and returns the
+/// producer address it carries, `None` when it names none. Fails when the
+/// connection does not open with a valid header.
+pub(crate) async fn read_proxy_header<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<SocketAddr>> {
+    let read = async {
+        // both versions are at least this long, so no payload byte is read
+        let mut start = [0u8; 12];
+        reader.read_exact(&mut start).await?;
+        if start == PROXY_V2_SIGNATURE {
+            read_proxy_v2(reader).await
+        } else if start.starts_with(b"PROXY ") {
+            read_proxy_v1(reader, &start).await
+        } else {
+            anyhow::bail!("connection did not open with a PROXY protocol header")
+        }
+    };
+    tokio::time::timeout(PROXY_HEADER_TIMEOUT, read)
+        .await
+        .context("timed out waiting for the PROXY protocol header")?
+}
+
+async fn read_proxy_v1<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    start: &[u8],
+) -> Result<Option<SocketAddr>> {
+    let mut header = start.to_vec();
+    while !header.ends_with(b"\r\n") {
+        anyhow::ensure!(header.len() < PROXY_V1_MAX, "PROXY v1 header too long");
+        header.push(reader.read_u8().await?);
+    }
+    let header = std::str::from_utf8(&header[..header.len() - 2])?;
+    let fields: Vec<&str> = header.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().context("invalid PROXY v1 source address")?;
+            let port = port.parse().context("invalid PROXY v1 source port")?;
+            Ok(Some(peer_addr(SocketAddr::new(ip, port))))
+        }
+        _ => anyhow::bail!("malformed PROXY v1 header {header:?}"),
+    }
+}
+
+async fn read_proxy_v2<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut fixed = [0u8; 4];
+    reader.read_exact(&mut fixed).await?;
+    let [version_command, family, len @ ..] = fixed;
+    anyhow::ensure!(
+        version_command >> 4 == 2,
+        "unsupported PROXY protocol version"
+    );
+    let mut addresses = vec![0u8; usize::from(u16::from_be_bytes(len))];
+    reader.read_exact(&mut addresses).await?;
+    // LOCAL: the balancer's own connection, e.g. a health check
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+    let addr = match (family >> 4, addresses.as_slice()) {
+        (1, [a, b, c, d, _, _, _, _, p0, p1, ..]) => {
+            let ip = Ipv4Addr::new(*a, *b, *c, *d);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([*p0, *p1]))
+        }
+        (2, bytes) if bytes.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[..16])?);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([bytes[32], bytes[33]]))
+        }
+        // unspecified or unix addresses
+        (0 | 3, _) => return Ok(None),
+        _ => anyhow::bail!("malformed PROXY v2 address block"),
+    };
+    Ok(Some(peer_addr(addr)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wildcard_is_dual_stack() {
+        let listener = match bind_tcp("[::]:0".parse().unwrap()) {
+            Ok(listener) => listener,
+            // no IPv6 on this host
+            Err(_) => return,
+        };
+        let port = listener.local_addr().unwrap().port();
+        let connect = tokio::net::TcpStream::connect(("127.0.0.1", port));
+        let (accepted, connected) = tokio::join!(listener.accept(), connect);
+        connected.unwrap();
+        let (_, peer) = accepted.unwrap();
+        assert!(peer.is_ipv6());
+        assert_eq!(
+            peer_addr(peer),
+            SocketAddr::new([127, 0, 0, 1].into(), peer.port())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tune_sets_keepalive() {
+        let args = TcpArgs {
+            tcp_keepalive: Duration::from_secs(30),
+            tcp_keepalive_interval: Duration::from_secs(5),
+            tcp_keepalive_retries: 3,
+            tcp_nodelay: true,
+            tcp_recv_buffer: Some(1 << 16),
+        };
+        let listener = args.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accepted, connected) = tokio::join!(listener.accept(), TcpStream::connect(addr));
+        let (_producer, (stream, _)) = (connected.unwrap(), accepted.unwrap());
+        args.tune(&stream).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert!(socket.tcp_nodelay().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(
+                socket.tcp_keepalive_time().unwrap(),
+                Duration::from_secs(30)
+            );
+            assert_eq!(socket.tcp_keepalive_retries().unwrap(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_headers() {
+        let mut v1: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 6969\r\ndeposit, 1, 1, 1.0\n";
+        let producer = read_proxy_header(&mut v1).await.unwrap();
+        assert_eq!(producer, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(v1, b"deposit, 1, 1, 1.0\n");
+
+        let mut v2 = PROXY_V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x21, 0, 36]);
+        let source: Ipv6Addr = "2001:db8::7".parse().unwrap();
+        v2.extend(source.octets());
+        v2.extend([0; 16]);
+        v2.extend(51234u16.to_be_bytes());
+        v2.extend(6969u16.to_be_bytes());
+        v2.extend(b"deposit");
+        let mut v2 = v2.as_slice();
+        let producer = read_proxy_header(&mut v2).await.unwrap();
+        assert_eq!(producer, Some("[2001:db8::7]:51234".parse().unwrap()));
+        assert_eq!(v2, b"deposit");
+
+        let mut local: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut local).await.unwrap(), None);
+        let mut plain: &[u8] = b"deposit, 1, 1, 1.0\n";
+        assert!(read_proxy_header(&mut plain).await.is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! Routing of account alerts to pluggable notification channels.
+//!
+//! The engine observer only turns events into [`Notification`]s and queues
+//! them; a background task hands each one to every configured [`Notifier`] in
+//! order, so slow channels never stall transaction processing. New channels
+//! (chat, pagers, queues) only need to implement the [`Notifier`] trait.
+
+use crate::amount::Amount;
+use crate::events::Event;
+use crate::log;
+use crate::webhook::WebhookNotifier;
+use anyhow::{Context, Result};
+use clap::Args;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::pin::Pin;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+pub(crate) type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// An alert worth telling someone about.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Notification {
+    /// `account_locked`, `chargeback`, `minimum_breached` or `threshold_crossed`
+    pub(crate) kind: &'static str,
+    /// JSON object describing the alert, including an `event` field equal to `kind`
+    pub(crate) payload: Value,
+}
+
+pub(crate) trait Notifier: Send + Sync {
+    /// Short description used in error messages.
+    fn name(&self) -> String;
+
+    /// Delivers one notification. Errors are logged by the dispatcher and do
+    /// not prevent delivery to other notifiers.
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Prints each notification as a JSON line on stdout.
+pub(crate) struct StdoutNotifier;
+
+impl Notifier for StdoutNotifier {
+    fn name(&self) -> String {
+        "stdout".to_owned()
+    }
+
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut line = notification.payload.to_string();
+            line.push('\n');
+            tokio::io::stdout().write_all(line.as_bytes()).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Runs a shell command per notification with the JSON payload on stdin and
+/// the kind in `ROINSTXS_EVENT`.
+pub(crate) struct CommandNotifier {
+    command: String,
+}
+
+impl Notifier for CommandNotifier {
+    fn name(&self) -> String {
+        format!("exec `{}`", self.command)
+    }
+
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut child = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(&self.command)
+                .env("ROINSTXS_EVENT", notification.kind)
+                .stdin(Stdio::piped())
+                .spawn()
+                .context("could not spawn command")?;
+            if let Some(mut stdin) = child.stdin.take() {
+                let written = stdin
+                    .write_all(notification.payload.to_string().as_bytes())
+                    .await;
+                // commands are free to ignore the payload
+                match written {
+                    Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+                        return Err(err.into())
+                    }
+                    _ => {}
+                }
+            }
+            let status = child.wait().await?;
+            anyhow::ensure!(status.success(), "command exited with {status}");
+            Ok(())
+        })
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct NotifyArgs {
+    /// POST account alerts (locks, chargebacks, threshold crossings) to this URL; repeatable
+    #[arg(long = "webhook", global = true)]
+    webhooks: Vec<String>,
+    /// Shared secret used to sign webhook payloads
+    #[arg(
+        long,
+        global = true,
+        env = "ROINSTXS_WEBHOOK_SECRET",
+        hide_env_values = true
+    )]
+    webhook_secret: Option<String>,
+    /// Delivery attempts per webhook before a notification is dropped
+    #[arg(long, global = true, default_value_t = 5)]
+    webhook_attempts: u32,
+    /// Print account alerts as JSON lines on stdout
+    #[arg(long, global = true)]
+    notify_stdout: bool,
+    /// Run this shell command per alert, payload on stdin; repeatable
+    #[arg(long = "notify-exec", global = true)]
+    commands: Vec<String>,
+    /// Alert when an account's available balance crosses this amount; repeatable
+    #[arg(long = "balance-threshold", global = true)]
+    thresholds: Vec<Amount>,
+}
+
+impl NotifyArgs {
+    pub(crate) fn notifiers(&self) -> Vec<Box<dyn Notifier>> {
+        let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+        for url in &self.webhooks {
+            notifiers.push(Box::new(WebhookNotifier::new(
+                url.clone(),
+                self.webhook_secret.clone(),
+                self.webhook_attempts,
+            )));
+        }
+        if self.notify_stdout {
+            notifiers.push(Box::new(StdoutNotifier));
+        }
+        for command in &self.commands {
+            notifiers.push(Box::new(CommandNotifier {
+                command: command.clone(),
+            }));
+        }
+        notifiers
+    }
+}
+
+/// Maps an engine event to the notifications it should raise.
+fn notifications(event: &Event, thresholds: &[Amount]) -> Vec<Notification> {
+    match event {
+        Event::AccountLocked { .. } => vec![Notification {
+            kind: "account_locked",
+            payload: json!(event),
+        }],
+        Event::Chargeback { .. } => vec![Notification {
+            kind: "chargeback",
+            payload: json!(event),
+        }],
+        Event::MinimumBreached { .. } => vec![Notification {
+            kind: "minimum_breached",
+            payload: json!(event),
+        }],
+        Event::AccountUnlocked { .. }
+        | Event::AccountClosed { .. }
+        | Event::AdminHold { .. }
+        | Event::ClientErased { .. } => Vec::new(),
+        Event::BalanceChanged {
+            client,
+            tx,
+            before,
+            after,
+            ..
+        } => thresholds
+            .iter()
+            .filter_map(|&threshold| {
+                let direction = if before.available < threshold && after.available >= threshold {
+                    "above"
+                } else if before.available >= threshold && after.available < threshold {
+                    "below"
+                } else {
+                    return None;
+                };
+                Some(Notification {
+                    kind: "threshold_crossed",
+                    payload: json!({
+                        "event": "threshold_crossed",
+                        "client": client,
+                        "tx": tx,
+                        "threshold": threshold,
+                        "direction": direction,
+                        "available": after.available,
+                    }),
+                })
+            })
+            .collect(),
+    }
+}
+
+/// Starts the dispatch task and returns the engine observer feeding it, or
+/// `None` when no notifier is configured. Must be called from within the
+/// tokio runtime.
+pub(crate) fn observer(args: &NotifyArgs) -> Option<impl FnMut(&Event) + Send + 'static> {
+    let notifiers = args.notifiers();
+    if notifiers.is_empty() {
+        return None;
+    }
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Notification>();
+    let thresholds = args.thresholds.clone();
+
+    tokio::spawn(async move {
+        while let Some(notification) = receiver.recv().await {
+            for notifier in &notifiers {
+                if let Err(err) = notifier.notify(&notification).await {
+                    log::error!("notifier {}: {err:#}", notifier.name());
+                }
+            }
+        }
+    });
+
+    Some(move |event: &Event| {
+        for notification in notifications(event, &thresholds) {
+            // the receiver only goes away with the runtime
+            let _ = sender.send(notification);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{LockInfo, LockRule, TxType};
+    use crate::events::Balances;
+
+    fn changed(before: i64, after: i64) -> Event {
+        let (before, after) = (Amount::from_units(before), Amount::from_units(after));
+        Event::BalanceChanged {
+            client: 7,
+            tx: 1,
+            cause: TxType::Deposit,
+            currency: None,
+            before: Balances {
+                available: before,
+                held: Amount::ZERO,
+                total: before,
+            },
+            after: Balances {
+                available: after,
+                held: Amount::ZERO,
+                total: after,
+            },
+        }
+    }
+
+    #[test]
+    fn test_threshold_crossings() {
+        let thresholds = [Amount::from_units(100), Amount::from_units(1000)];
+        assert!(notifications(&changed(50, 99), &thresholds).is_empty());
+
+        let up = notifications(&changed(50, 150), &thresholds);
+        assert_eq!(up.len(), 1);
+        assert_eq!(up[0].payload["direction"], "above");
+        assert_eq!(up[0].payload["threshold"], "100");
+
+        let down = notifications(&changed(2000, 10), &thresholds);
+        assert_eq!(down.len(), 2);
+        assert!(down.iter().all(|n| n.payload["direction"] == "below"));
+
+        let lock = LockInfo {
+            tx: Some(3),
+            at: 1_700_000_000,
+            rule: LockRule::Chargeback,
+        };
+        let locked = notifications(&Event::AccountLocked { client: 7, lock }, &thresholds);
+        assert_eq!(locked[0].kind, "account_locked");
+        assert_eq!(
+            locked[0].payload,
+            json!({"event": "account_locked", "client": 7, "tx": 3, "at": 1_700_000_000, "rule": "chargeback"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_notifier_receives_payload() {
+        let dir = std::env::temp_dir().join(format!("roinstxs-notify-{}", std::process::id()));
+        let notifier = CommandNotifier {
+            command: format!(
+                "cat > {}; test \"$ROINSTXS_EVENT\" = chargeback",
+                dir.display()
+            ),
+        };
+        let notification = Notification {
+            kind: "chargeback",
+            payload: json!({"event": "chargeback", "client": 1}),
+        };
+        notifier.notify(&notification).await.unwrap();
+        let written = std::fs::read_to_string(&dir).unwrap();
+        std::fs::remove_file(&dir).unwrap();
+        assert_eq!(written, notification.payload.to_string());
+    }
+}
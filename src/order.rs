@@ -0,0 +1,123 @@
+//! Order-invariance checking.
+//!
+//! Accounts are independent, so any interleaving of the input that keeps each
+//! client's transactions in their original order must produce the same
+//! summary. `check-order` replays a file under several such seeded shuffles
+//! and fails if any summary differs from the in-order run, catching hidden
+//! cross-client ordering dependencies in the engine.
+
+use crate::engine::{Tx, TxEngine};
+use crate::exit::Failure;
+use crate::rng::XorShift;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+
+#[derive(Debug, Args)]
+pub(crate) struct CheckOrderArgs {
+    /// Transactions CSV to replay
+    file: PathBuf,
+    /// Number of shuffled replays to compare against the in-order run
+    #[arg(long, default_value_t = 8)]
+    shuffles: u64,
+    /// Seed of the first shuffle; shuffle i uses seed + i
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// A uniformly random interleaving of the per-client sequences of `txs`.
+fn shuffle(txs: &[Tx], rng: &mut XorShift) -> Vec<Tx> {
+    let mut queues: HashMap<u16, VecDeque<&Tx>> = HashMap::new();
+    for tx in txs {
+        queues.entry(tx.client()).or_default().push_back(tx);
+    }
+    // one slot per transaction labelled with its client; shuffling the labels
+    // and draining each client's queue in slot order keeps per-client order
+    let mut slots: Vec<u16> = txs.iter().map(Tx::client).collect();
+    for i in (1..slots.len()).rev() {
+        let j = rng.below(i as u64 + 1) as usize;
+        slots.swap(i, j);
+    }
+    slots
+        .into_iter()
+        .filter_map(|client| queues.get_mut(&client)?.pop_front().cloned())
+        .collect()
+}
+
+fn sorted_summary(txs: impl IntoIterator<Item = Tx>) -> Result<Vec<String>> {
+    let mut engine = TxEngine::new();
+    for tx in txs {
+        // refused records change nothing, whatever their position
+        match engine.process_tx(tx) {
+            Err(err) if !err.is_refusal() => return Err(err.into()),
+            _ => {}
+        }
+    }
+    let mut out = Vec::new();
+    engine.summarize_accounts(&mut out)?;
+    let mut lines: Vec<String> = String::from_utf8(out)?.lines().map(str::to_owned).collect();
+    lines.sort_unstable();
+    Ok(lines)
+}
+
+pub(crate) fn run(args: CheckOrderArgs) -> Result<()> {
+    let mut txs = Vec::new();
+    crate::cli::for_each_tx(&args.file, false, |tx| txs.push(tx))?;
+    let expected = sorted_summary(txs.iter().cloned())?;
+
+    for i in 0..args.shuffles {
+        let seed = args.seed.wrapping_add(i);
+        let actual = sorted_summary(shuffle(&txs, &mut XorShift::new(seed)))?;
+        if actual != expected {
+            let diff: Vec<String> = expected
+                .iter()
+                .filter(|l| !actual.contains(l))
+                .map(|l| format!("- {l}"))
+                .chain(actual.iter().filter(|l| !expected.contains(l)).map(|l| format!("+ {l}")))
+                .collect();
+            return Err(anyhow::anyhow!(
+                "summary depends on cross-client order (shuffle seed {seed}):\n{}",
+                diff.join("\n")
+            ))
+            .context(Failure::Invariant);
+        }
+    }
+    println!(
+        "{} transactions, {} shuffles: summary is order-invariant",
+        txs.len(),
+        args.shuffles
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shuffle_preserves_per_client_order() {
+        let lines = [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "withdrawal, 1, 3, 4.0",
+            "dispute, 2, 2,",
+            "deposit, 3, 4, 1.0",
+            "chargeback, 2, 2,",
+        ];
+        let txs: Vec<Tx> = lines.iter().map(|l| Tx::from_str(l).unwrap()).collect();
+        let expected = sorted_summary(txs.iter().cloned()).unwrap();
+
+        for seed in 0..16 {
+            let shuffled = shuffle(&txs, &mut XorShift::new(seed));
+            assert_eq!(shuffled.len(), txs.len());
+            for client in 1..=3 {
+                let ids = |txs: &[Tx]| -> Vec<u32> {
+                    txs.iter().filter(|t| t.client() == client).map(Tx::tx_id).collect()
+                };
+                assert_eq!(ids(&shuffled), ids(&txs));
+            }
+            assert_eq!(sorted_summary(shuffled).unwrap(), expected);
+        }
+    }
+}
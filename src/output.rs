@@ -0,0 +1,669 @@
+//! Rendering of account summaries in the supported output formats.
+
+use crate::amount::Amount;
+use crate::anonymize::Anonymizer;
+use crate::engine::{Account, LockInfo, TxEngine, LOCK_COLUMNS, NO_CURRENCY};
+use crate::partition::{self, Partitioning};
+use crate::report::Report;
+use crate::template::Template;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufWriter, IsTerminal, StdoutLock, Write};
+use std::path::{Path, PathBuf};
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// `client,available,held,total,locked` rows, plus `closed` once an account was closed and
+    /// `currency` once a record named one
+    #[default]
+    Csv,
+    /// Aligned table sorted by client, colorized on terminals
+    Human,
+    /// One row per account rendered from --template, sorted by client
+    Template,
+    /// JSON array of account objects, sorted by client
+    Json,
+    /// One JSON account object per line, sorted by client
+    Ndjson,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Output {
+    pub(crate) format: OutputFormat,
+    pub(crate) color: bool,
+    // when set, the CSV summary goes to partition files instead of the writer
+    pub(crate) partitioning: Option<Partitioning>,
+    // when set, the report is written instead of the summary
+    pub(crate) report: Option<Report>,
+    // CSV rows ordered by client instead of the engine's unspecified order
+    pub(crate) sorted: bool,
+    // when set, client ids are written as salted hashes
+    pub(crate) anonymizer: Option<Anonymizer>,
+    // one row per sub-account instead of per client
+    pub(crate) sub_accounts: bool,
+    // rows of the template format
+    pub(crate) template: Option<Template>,
+    // CSV rows extended with the provenance of account locks
+    pub(crate) lock_details: bool,
+}
+
+impl Output {
+    /// Color is used only when writing to a terminal, `--no-color` was not
+    /// given, and `NO_COLOR` is unset or empty (https://no-color.org).
+    pub(crate) fn new(format: OutputFormat, no_color: bool) -> Self {
+        let env_allows = std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+        Self {
+            format,
+            color: !no_color && env_allows && std::io::stdout().is_terminal(),
+            partitioning: None,
+            report: None,
+            sorted: false,
+            anonymizer: None,
+            sub_accounts: false,
+            template: None,
+            lock_details: false,
+        }
+    }
+
+    pub(crate) fn write(&self, engine: &TxEngine, w: impl Write) -> Result<()> {
+        if let Some(report) = &self.report {
+            return report.write(engine, self, w);
+        }
+        if let Some(p) = &self.partitioning {
+            partition::write_partitions(engine, &p.dir, p.partitions, p.by)?;
+            return Ok(());
+        }
+        if self.sub_accounts {
+            return self.write_sub_accounts(engine, w);
+        }
+        match self.format {
+            OutputFormat::Csv
+                if !self.sorted && self.anonymizer.is_none() && !self.lock_details =>
+            {
+                engine.summarize_accounts(w)
+            }
+            OutputFormat::Human
+                if self.anonymizer.is_none() && !with_currency(engine.accounts()) =>
+            {
+                write_human(engine, w, self.color)
+            }
+            _ => {
+                let mut accounts: Vec<&Account> = engine.accounts().collect();
+                match &self.anonymizer {
+                    // ordering by id would leak how the hidden ids compare
+                    Some(a) => accounts.sort_by_cached_key(|account| a.client(account.client)),
+                    None => accounts.sort_unstable_by_key(|a| a.client),
+                }
+                self.write_accounts(&accounts, w)
+            }
+        }
+    }
+
+    /// A client id as written to the summary and reports.
+    pub(crate) fn client_label(&self, client: u16) -> String {
+        match &self.anonymizer {
+            Some(a) => a.client(client),
+            None => client.to_string(),
+        }
+    }
+
+    /// Writes `accounts` in the given order using the configured format.
+    /// Once a record named a currency every account is written per currency.
+    pub(crate) fn write_accounts(&self, accounts: &[&Account], w: impl Write) -> Result<()> {
+        let with_currency = with_currency(accounts.iter().copied());
+        match self.format {
+            OutputFormat::Csv => {
+                let mut writer = BufWriter::new(w);
+                let with_closed = accounts.iter().any(|a| a.closed);
+                let header = Account::summary_header(with_closed, with_currency);
+                match self.lock_details {
+                    true => writeln!(writer, "{header},{LOCK_COLUMNS}")?,
+                    false => writeln!(writer, "{header}")?,
+                }
+                for account in accounts {
+                    for mut line in account.summary_lines(with_closed, with_currency) {
+                        if self.lock_details {
+                            line = format!("{line},{}", account.lock_columns());
+                        }
+                        match &self.anonymizer {
+                            Some(a) => {
+                                let (_, rest) = line.split_once(',').expect("csv line has columns");
+                                writeln!(writer, "{},{rest}", a.client(account.client))?;
+                            }
+                            None => writeln!(writer, "{line}")?,
+                        }
+                    }
+                }
+                writer.flush()?;
+                Ok(())
+            }
+            _ if with_currency => {
+                let parts: Vec<(String, &str, Account)> = accounts
+                    .iter()
+                    .flat_map(|a| a.per_currency())
+                    .map(|(currency, part)| (self.client_label(part.client), currency, part))
+                    .collect();
+                self.write_parts(&parts, w)
+            }
+            OutputFormat::Human => {
+                let rows: Vec<(String, &Account)> = accounts
+                    .iter()
+                    .map(|a| (self.client_label(a.client), *a))
+                    .collect();
+                write_human_rows(&rows, w, self.color)
+            }
+            OutputFormat::Template => {
+                let mut writer = BufWriter::new(w);
+                for account in accounts {
+                    let label = self.client_label(account.client);
+                    let row = self.template().render(&label, None, None, account);
+                    writeln!(writer, "{row}")?;
+                }
+                writer.flush()?;
+                Ok(())
+            }
+            OutputFormat::Json | OutputFormat::Ndjson => {
+                let rows = accounts
+                    .iter()
+                    .map(|a| self.json_row(a, None, None))
+                    .collect();
+                self.write_json(rows, w)
+            }
+        }
+    }
+
+    // writes the per currency `parts` of accounts, each with its client
+    // label, in the formats other than CSV
+    fn write_parts(&self, parts: &[(String, &str, Account)], w: impl Write) -> Result<()> {
+        match self.format {
+            OutputFormat::Human => {
+                let rows: Vec<(String, &Account)> = parts
+                    .iter()
+                    .map(|(label, currency, part)| match *currency {
+                        NO_CURRENCY => (label.clone(), part),
+                        currency => (format!("{label} {currency}"), part),
+                    })
+                    .collect();
+                write_human_rows(&rows, w, self.color)
+            }
+            OutputFormat::Template => {
+                let mut writer = BufWriter::new(w);
+                for (label, currency, part) in parts {
+                    let row = self.template().render(label, None, Some(currency), part);
+                    writeln!(writer, "{row}")?;
+                }
+                writer.flush()?;
+                Ok(())
+            }
+            _ => {
+                let rows = parts
+                    .iter()
+                    .map(|(_, currency, part)| {
+                        let currency = Some(*currency).filter(|c| *c != NO_CURRENCY);
+                        self.json_row(part, None, currency)
+                    })
+                    .collect();
+                self.write_json(rows, w)
+            }
+        }
+    }
+
+    /// The object of `account` in the JSON formats; anonymized client ids
+    /// are strings.
+    fn json_row<'a>(
+        &self,
+        account: &'a Account,
+        sub: Option<&'a str>,
+        currency: Option<&'a str>,
+    ) -> JsonAccount<'a> {
+        let client = match &self.anonymizer {
+            Some(a) => json!(a.client(account.client)),
+            None => json!(account.client),
+        };
+        JsonAccount {
+            client,
+            sub_account: sub,
+            currency,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+            closed: account.closed,
+            lock: self.lock_details.then_some(account.lock),
+        }
+    }
+
+    fn write_json(&self, rows: Vec<JsonAccount>, w: impl Write) -> Result<()> {
+        let mut writer = BufWriter::new(w);
+        match self.format {
+            OutputFormat::Ndjson => {
+                for row in rows {
+                    serde_json::to_writer(&mut writer, &row)?;
+                    writeln!(writer)?;
+                }
+            }
+            _ => {
+                serde_json::to_writer(&mut writer, &rows)?;
+                writeln!(writer)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn template(&self) -> &Template {
+        self.template
+            .as_ref()
+            .expect("--template is required by --format template")
+    }
+
+    /// Writes one row per sub-account and currency, ordered by client, then
+    /// sub-account, then currency. Clients that never named a sub-account
+    /// get `main` rows. The currency is a column once any account names one.
+    fn write_sub_accounts(&self, engine: &TxEngine, w: impl Write) -> Result<()> {
+        let with_currency = with_currency(engine.accounts());
+        let mut buckets: Vec<(String, &str, Option<&str>, Account)> = Vec::new();
+        for account in engine.accounts() {
+            for (sub, currency, bucket) in account.per_sub_account() {
+                let currency = Some(currency).filter(|c| *c != NO_CURRENCY);
+                buckets.push((self.client_label(account.client), sub, currency, bucket));
+            }
+        }
+        match &self.anonymizer {
+            Some(_) => buckets.sort_unstable_by(|a, b| (&a.0, a.1, a.2).cmp(&(&b.0, b.1, b.2))),
+            None => {
+                buckets.sort_unstable_by_key(|(_, sub, currency, a)| (a.client, *sub, *currency))
+            }
+        }
+
+        match self.format {
+            OutputFormat::Csv => {
+                let mut writer = BufWriter::new(w);
+                let columns = match with_currency {
+                    true => "client,currency,sub_account",
+                    false => "client,sub_account",
+                };
+                writeln!(writer, "{columns},available,held,total,locked")?;
+                for (label, sub, currency, a) in &buckets {
+                    let label = match with_currency {
+                        true => format!("{label},{}", currency.unwrap_or(NO_CURRENCY)),
+                        false => label.clone(),
+                    };
+                    writeln!(
+                        writer,
+                        "{label},{sub},{},{},{},{}",
+                        a.available, a.held, a.total, a.locked
+                    )?;
+                }
+                writer.flush()?;
+                Ok(())
+            }
+            OutputFormat::Human => {
+                let rows: Vec<(String, &Account)> = buckets
+                    .iter()
+                    .map(|(label, sub, currency, a)| match currency {
+                        Some(currency) => (format!("{label}/{sub} {currency}"), a),
+                        None => (format!("{label}/{sub}"), a),
+                    })
+                    .collect();
+                write_human_rows(&rows, w, self.color)
+            }
+            OutputFormat::Template => {
+                let mut writer = BufWriter::new(w);
+                for (label, sub, currency, a) in &buckets {
+                    let row = self.template().render(label, Some(sub), *currency, a);
+                    writeln!(writer, "{row}")?;
+                }
+                writer.flush()?;
+                Ok(())
+            }
+            OutputFormat::Json | OutputFormat::Ndjson => {
+                let rows = buckets
+                    .iter()
+                    .map(|(_, sub, currency, a)| self.json_row(a, Some(sub), *currency))
+                    .collect();
+                self.write_json(rows, w)
+            }
+        }
+    }
+}
+
+// an account in the JSON formats, fields in the order of the CSV columns
+#[derive(Serialize)]
+struct JsonAccount<'a> {
+    client: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub_account: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<&'a str>,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+    closed: bool,
+    // with --lock-details, null for accounts that are not locked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lock: Option<Option<LockInfo>>,
+}
+
+/// Where the summary goes: stdout, or with `--output PATH` a file that only
+/// replaces PATH, complete, once committed. A file sink dropped uncommitted
+/// removes its temporary file.
+pub(crate) enum Sink {
+    Stdout(StdoutLock<'static>),
+    File {
+        // closed before `tmp` removes it
+        file: BufWriter<File>,
+        tmp: TmpFile,
+        path: PathBuf,
+    },
+}
+
+/// A temporary file, removed on drop unless it was moved into place.
+pub(crate) struct TmpFile {
+    path: PathBuf,
+    committed: bool,
+}
+
+impl TmpFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            committed: false,
+        }
+    }
+
+    // once moved into place, the file is no longer ours to remove
+    fn keep(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Sink {
+    /// Whether `--output` names stdout: when it is not given or `-`.
+    pub(crate) fn is_stdout(path: Option<&Path>) -> bool {
+        path.is_none_or(|p| p == Path::new("-"))
+    }
+
+    /// Stdout, or `path` written through a temporary file next to it.
+    pub(crate) fn open(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) if !Self::is_stdout(Some(path)) => path,
+            _ => return Ok(Self::Stdout(std::io::stdout().lock())),
+        };
+        // next to the target, so the rename stays on its file system
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let file =
+            File::create(&tmp).with_context(|| format!("could not create {}", tmp.display()))?;
+        Ok(Self::File {
+            file: BufWriter::new(file),
+            tmp: TmpFile::new(tmp),
+            path: path.to_owned(),
+        })
+    }
+
+    /// Flushes the summary and moves a file into place.
+    pub(crate) fn commit(self) -> Result<()> {
+        match self {
+            Self::Stdout(mut stdout) => Ok(stdout.flush()?),
+            Self::File { file, tmp, path } => {
+                let file = file.into_inner().map_err(|err| err.into_error())?;
+                file.sync_all()?;
+                drop(file);
+                std::fs::rename(&tmp.path, &path)
+                    .with_context(|| format!("could not replace {}", path.display()))?;
+                tmp.keep();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Stdout(stdout) => stdout.write(buf),
+            Self::File { file, .. } => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Stdout(stdout) => stdout.flush(),
+            Self::File { file, .. } => file.flush(),
+        }
+    }
+}
+
+fn paint(text: String, color: Option<&str>) -> String {
+    match color {
+        Some(code) => format!("{code}{text}{RESET}"),
+        None => text,
+    }
+}
+
+// whether a record named a currency, splitting the summary per currency
+fn with_currency<'a>(mut accounts: impl Iterator<Item = &'a Account>) -> bool {
+    accounts.any(|a| !a.currencies.is_empty())
+}
+
+fn write_human(engine: &TxEngine, w: impl Write, color: bool) -> Result<()> {
+    let mut accounts: Vec<&Account> = engine.accounts().collect();
+    accounts.sort_unstable_by_key(|a| a.client);
+    let rows: Vec<(String, &Account)> = accounts.iter().map(|a| (a.client.to_string(), *a)).collect();
+    write_human_rows(&rows, w, color)
+}
+
+/// Writes the table of `rows`, each an account with the label of its row.
+fn write_human_rows(rows: &[(String, &Account)], w: impl Write, color: bool) -> Result<()> {
+    let width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or_default().max(6);
+    let mut writer = BufWriter::new(w);
+    writeln!(
+        writer,
+        "{:>width$}  {:>16}  {:>16}  {:>16}  status",
+        "client", "available", "held", "total"
+    )?;
+    for (label, account) in rows {
+        let row_color = (color && account.locked).then_some(RED);
+        let available_color = (color && account.available.is_negative()).then_some(BOLD_RED);
+        let held_color = (color && account.held != Amount::ZERO).then_some(YELLOW);
+        let status = match (account.locked, account.closed) {
+            (true, _) => "LOCKED",
+            (false, true) => "CLOSED",
+            (false, false) => "ok",
+        };
+
+        let available = paint(
+            format!("{:>16}", account.available),
+            available_color.or(row_color),
+        );
+        let held = paint(format!("{:>16}", account.held), held_color.or(row_color));
+        writeln!(
+            writer,
+            "{}  {}  {}  {}  {}",
+            paint(format!("{label:>width$}"), row_color),
+            available,
+            held,
+            paint(format!("{:>16}", account.total), row_color),
+            paint(status.to_owned(), row_color),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Tx;
+
+    fn engine(lines: &[&str]) -> TxEngine {
+        let mut engine = TxEngine::new();
+        for line in lines {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        engine
+    }
+
+    #[test]
+    fn test_human_output_colors_only_when_enabled() {
+        let engine = engine(&[
+            "deposit, 2, 1, 5.0",
+            "deposit, 1, 2, 3.0",
+            "dispute, 1, 2,",
+            "chargeback, 1, 2,",
+        ]);
+
+        let mut plain = Vec::new();
+        write_human(&engine, &mut plain, false).unwrap();
+        let plain = String::from_utf8(plain).unwrap();
+        assert!(!plain.contains('\x1b'));
+        let rows: Vec<&str> = plain.lines().skip(1).collect();
+        assert!(rows[0].trim_start().starts_with("1 ") && rows[0].ends_with("LOCKED"));
+        assert!(rows[1].trim_start().starts_with("2 ") && rows[1].ends_with("ok"));
+
+        let mut colored = Vec::new();
+        write_human(&engine, &mut colored, true).unwrap();
+        let colored = String::from_utf8(colored).unwrap();
+        assert!(colored.contains(&format!("{RED}LOCKED{RESET}")));
+    }
+
+    #[test]
+    fn test_sub_accounts_summarized_separately_and_rolled_up() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 5.0,,,,, savings",
+            "withdrawal, 1, 3, 8.0,,,,, savings",
+            "dispute, 1, 2,",
+            "deposit, 2, 4, 1.0",
+        ] {
+            engine.try_apply(crate::schema::Schema::V2.parse(line).unwrap());
+        }
+
+        // the withdrawal could not draw on main, the dispute held the savings deposit
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (Amount::from_units(10), Amount::from_units(5), Amount::from_units(15))
+        );
+        let savings = account.bucket(Some("savings"), None);
+        assert_eq!(savings.held, Amount::from_units(5));
+
+        let output = Output {
+            sub_accounts: true,
+            ..Default::default()
+        };
+        let mut csv = Vec::new();
+        output.write(&engine, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,sub_account,available,held,total,locked\n\
+             1,main,10,0,10,false\n\
+             1,savings,0,5,5,false\n\
+             2,main,1,0,1,false\n"
+        );
+
+        // the currency is a column, each sub-account a row per currency
+        let parse = |line| crate::schema::Schema::V2.parse(line).unwrap();
+        engine.try_apply(parse("deposit, 3, 5, 2.0,, EUR,,, savings"));
+        engine.try_apply(parse("deposit, 3, 6, 1.0,, USD"));
+        engine.try_apply(parse("deposit, 3, 7, 4.0,, EUR"));
+        let mut csv = Vec::new();
+        output.write(&engine, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert!(lines[0].starts_with("client,currency,sub_account,"));
+        assert_eq!(lines[1], "1,,main,10,0,10,false");
+        assert_eq!(
+            lines[4..],
+            [
+                "3,EUR,main,4,0,4,false",
+                "3,USD,main,1,0,1,false",
+                "3,EUR,savings,2,0,2,false"
+            ]
+        );
+        // a withdrawal draws on its pair alone
+        engine.try_apply(parse("withdrawal, 3, 8, 3.0,, EUR,,, savings"));
+        let account = engine.account(3).unwrap();
+        let savings = account.bucket(Some("savings"), Some("EUR"));
+        assert_eq!(savings.available, Amount::from_units(2));
+        let eur = account.in_currency(Some("EUR"));
+        assert_eq!(eur.available, Amount::from_units(6));
+    }
+
+    #[test]
+    fn test_json_formats_write_account_objects() {
+        let engine = engine(&["deposit, 2, 1, 5.0", "deposit, 1, 2, 2.5"]);
+        let write = |format| {
+            let mut out = Vec::new();
+            Output::new(format, true).write(&engine, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let array: Value = serde_json::from_str(&write(OutputFormat::Json)).unwrap();
+        assert_eq!(
+            array[0],
+            json!({
+                "client": 1,
+                "available": "2.5",
+                "held": "0",
+                "total": "2.5",
+                "locked": false,
+                "closed": false,
+            })
+        );
+        assert_eq!(array[1]["client"], 2);
+        let lines: Vec<Value> = write(OutputFormat::Ndjson)
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(Value::Array(lines), array);
+    }
+
+    #[test]
+    fn test_file_sink_replaces_target_on_commit() {
+        let engine = engine(&["deposit, 1, 1, 2.5"]);
+        let dir = std::env::temp_dir().join(format!("roinstxs-sink-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("accounts.csv");
+        std::fs::write(&path, "previous\n").unwrap();
+
+        let mut sink = Sink::open(Some(&path)).unwrap();
+        Output::default().write(&engine, &mut sink).unwrap();
+        // the previous summary stays whole until the new one is complete
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous\n");
+        sink.commit().unwrap();
+        let summary = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            summary,
+            "client,available,held,total,locked\n1,2.5,0,2.5,false\n"
+        );
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // a summary that failed halfway leaves the target and no temporary
+        let mut sink = Sink::open(Some(&path)).unwrap();
+        sink.write_all(b"partial").unwrap();
+        drop(sink);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), summary);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(Sink::is_stdout(Some(Path::new("-"))) && Sink::is_stdout(None));
+    }
+}
@@ -0,0 +1,253 @@
+//! Writing the account summary split across several files.
+//!
+//! Each partition file is a complete CSV summary, header included, holding the
+//! accounts assigned to it sorted by client id, so loaders can ingest the
+//! partitions in parallel without coordinating.
+//!
+//! The same assignment backs the per-shard summaries of the HTTP API, which
+//! carry the engine sequence number so a rollup can tell whether every shard
+//! was summarized at the same logical point.
+
+use crate::amount::Amount;
+use crate::engine::{Account, TxEngine};
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionBy {
+    /// Contiguous, equally sized ranges of the client id space
+    #[default]
+    Range,
+    /// A hash of the client id, spreading dense id ranges evenly
+    Hash,
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct PartitionArgs {
+    /// Split the summary across this many files in --out-dir instead of stdout
+    #[arg(long, global = true, requires = "out_dir", value_parser = clap::value_parser!(u16).range(1..))]
+    partitions: Option<u16>,
+    /// Directory the partition files are written to
+    #[arg(long, global = true, requires = "partitions")]
+    out_dir: Option<PathBuf>,
+    /// How clients are assigned to partitions
+    #[arg(long, global = true, value_enum, default_value_t)]
+    partition_by: PartitionBy,
+}
+
+/// A resolved `--partitions` request.
+#[derive(Debug, Clone)]
+pub(crate) struct Partitioning {
+    pub(crate) dir: PathBuf,
+    pub(crate) partitions: u16,
+    pub(crate) by: PartitionBy,
+}
+
+impl PartitionArgs {
+    pub(crate) fn partitioning(&self) -> Option<Partitioning> {
+        Some(Partitioning {
+            dir: self.out_dir.clone()?,
+            partitions: self.partitions?,
+            by: self.partition_by,
+        })
+    }
+}
+
+impl PartitionBy {
+    pub(crate) fn partition_of(self, client: u16, partitions: u16) -> usize {
+        let n = u32::from(partitions);
+        let slot = match self {
+            Self::Range => (u32::from(client) * n) >> 16,
+            Self::Hash => (u32::from(client).wrapping_mul(0x9e37_79b1) >> 16) % n,
+        };
+        slot as usize
+    }
+}
+
+fn partition_path(dir: &Path, index: usize, partitions: u16) -> PathBuf {
+    let width = (partitions - 1).to_string().len();
+    dir.join(format!("accounts-{index:0width$}.csv"))
+}
+
+/// Assigns every account to one of `partitions` buckets, each sorted by client.
+fn buckets(engine: &TxEngine, partitions: u16, by: PartitionBy) -> Vec<Vec<&Account>> {
+    let mut buckets: Vec<Vec<&Account>> = vec![Vec::new(); partitions as usize];
+    for account in engine.accounts() {
+        buckets[by.partition_of(account.client, partitions)].push(account);
+    }
+    for bucket in &mut buckets {
+        bucket.sort_unstable_by_key(|a| a.client);
+    }
+    buckets
+}
+
+/// Writes `partitions` summary files into `dir`, returning their paths in
+/// partition order. Empty partitions still get a file with just the header.
+pub(crate) fn write_partitions(
+    engine: &TxEngine,
+    dir: &Path,
+    partitions: u16,
+    by: PartitionBy,
+) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("could not create {}", dir.display()))?;
+
+    let mut paths = Vec::with_capacity(partitions as usize);
+    for (index, accounts) in buckets(engine, partitions, by).into_iter().enumerate() {
+        let path = partition_path(dir, index, partitions);
+        let file =
+            File::create(&path).with_context(|| format!("could not create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "client,available,held,total,locked")?;
+        for account in accounts {
+            writeln!(writer, "{}", account.to_csv_line())?;
+        }
+        writer
+            .flush()
+            .with_context(|| format!("could not write {}", path.display()))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Totals {
+    pub accounts: usize,
+    pub locked: usize,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+}
+
+impl Totals {
+    fn merge(&mut self, other: &Totals) {
+        self.accounts += other.accounts;
+        self.locked += other.locked;
+        self.available += other.available;
+        self.held += other.held;
+        self.total += other.total;
+    }
+}
+
+impl<'a> FromIterator<&'a Account> for Totals {
+    fn from_iter<I: IntoIterator<Item = &'a Account>>(iter: I) -> Self {
+        let mut totals = Self::default();
+        for account in iter {
+            totals.accounts += 1;
+            totals.locked += usize::from(account.locked);
+            totals.available += account.available;
+            totals.held += account.held;
+            totals.total += account.total;
+        }
+        totals
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardSummary {
+    pub shard: usize,
+    pub seq: u64,
+    pub totals: Totals,
+    pub accounts: Vec<Account>,
+}
+
+/// Per-shard summaries aggregated into one book-wide view.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rollup {
+    /// The common sequence number, absent when shards disagree.
+    pub seq: Option<u64>,
+    pub consistent: bool,
+    pub totals: Totals,
+    pub shards: Vec<ShardSummary>,
+}
+
+pub(crate) fn shard_summaries(engine: &TxEngine, shards: u16, by: PartitionBy) -> Vec<ShardSummary> {
+    buckets(engine, shards, by)
+        .into_iter()
+        .enumerate()
+        .map(|(shard, accounts)| ShardSummary {
+            shard,
+            seq: engine.seq(),
+            totals: accounts.iter().copied().collect(),
+            accounts: accounts.into_iter().cloned().collect(),
+        })
+        .collect()
+}
+
+pub(crate) fn rollup(shards: Vec<ShardSummary>) -> Rollup {
+    let mut totals = Totals::default();
+    for shard in &shards {
+        totals.merge(&shard.totals);
+    }
+    let first = shards.first().map(|s| s.seq);
+    let consistent = shards.iter().all(|s| Some(s.seq) == first);
+    Rollup {
+        seq: first.filter(|_| consistent),
+        consistent,
+        totals,
+        shards,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Tx;
+
+    #[test]
+    fn test_partitions_cover_all_clients_sorted() {
+        let mut engine = TxEngine::new();
+        for (tx_id, client) in [40_000u16, 3, 65_535, 17, 20_000, 1].into_iter().enumerate() {
+            let line = format!("deposit, {client}, {}, 1.0", tx_id + 1);
+            engine.process_tx(Tx::from_str(&line).unwrap()).unwrap();
+        }
+
+        for by in [PartitionBy::Range, PartitionBy::Hash] {
+            let dir = std::env::temp_dir().join(format!("roinstxs-partitions-{by:?}-{}", std::process::id()));
+            let paths = write_partitions(&engine, &dir, 4, by).unwrap();
+            assert_eq!(paths.len(), 4);
+
+            let mut seen = Vec::new();
+            for path in &paths {
+                let body = std::fs::read_to_string(path).unwrap();
+                let clients: Vec<u16> = body
+                    .lines()
+                    .skip(1)
+                    .map(|l| l.split(',').next().unwrap().parse().unwrap())
+                    .collect();
+                assert!(clients.windows(2).all(|w| w[0] < w[1]));
+                seen.extend(clients);
+            }
+            if by == PartitionBy::Range {
+                assert!(seen.windows(2).all(|w| w[0] < w[1]));
+            }
+            seen.sort_unstable();
+            assert_eq!(seen, [1, 3, 17, 20_000, 40_000, 65_535]);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rollup_flags_inconsistent_shards() {
+        let mut engine = TxEngine::new();
+        for line in ["deposit, 1, 1, 5.0", "deposit, 40000, 2, 7.0", "withdrawal, 1, 3, 2.0"] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+
+        let mut shards = shard_summaries(&engine, 2, PartitionBy::Range);
+        assert_eq!(shards[0].accounts.len(), 1);
+        let total = rollup(shards.clone());
+        assert_eq!((total.seq, total.consistent), (Some(3), true));
+        assert_eq!(total.totals.accounts, 2);
+        assert_eq!(total.totals.total, Amount::from_units(10));
+
+        shards[1].seq = 2;
+        let total = rollup(shards);
+        assert_eq!((total.seq, total.consistent), (None, false));
+    }
+}
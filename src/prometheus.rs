@@ -0,0 +1,173 @@
+//! Prometheus exposition of the stream server's metrics.
+//!
+//! `GET /metrics` on the `--http` API answers in the Prometheus text format,
+//! for scrapers alerting on anomalies:
+//!
+//! - `roinstxs_processed_total{type}` records applied, by type
+//! - `roinstxs_rejected_total` lines rejected before reaching the engine, as
+//!   unparsable, badly signed or unauthenticated
+//! - `roinstxs_refused_total{reason}` records the engine refused, by reason,
+//!   see `crate::rejects`
+//! - `roinstxs_apply_seconds` histogram of the time the engine took on each
+//!   streamed record
+//! - `roinstxs_connections` producer connections open
+//! - `roinstxs_accounts`, `roinstxs_locked_accounts`, `roinstxs_open_disputes`
+//!   and `roinstxs_pending` gauges of the engine
+//!
+//! The counters are those of `/api/metrics`, since startup.
+
+use crate::engine::TxEngine;
+use crate::metrics::{MetricsSnapshot, LATENCY_BUCKETS};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+pub(crate) const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// What the gauges read of the engine, taken under its lock.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct EngineGauges {
+    accounts: usize,
+    locked: usize,
+    disputes: usize,
+    pending: usize,
+}
+
+impl EngineGauges {
+    pub(crate) fn of(engine: &TxEngine) -> Self {
+        Self {
+            accounts: engine.retained().accounts,
+            locked: engine.accounts().filter(|a| a.locked()).count(),
+            disputes: engine.retained().disputes,
+            pending: engine.pending().count,
+        }
+    }
+}
+
+// a label value with `\`, `"` and newlines escaped
+fn label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+// the `# HELP` and `# TYPE` lines of the metric `name`
+fn header(page: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(page, "# HELP roinstxs_{name} {help}");
+    let _ = writeln!(page, "# TYPE roinstxs_{name} {kind}");
+}
+
+/// The metrics page of `metrics`, `engine` and the engine's `refusals`.
+pub(crate) fn render(
+    metrics: &MetricsSnapshot,
+    engine: &EngineGauges,
+    refusals: &BTreeMap<String, u64>,
+) -> String {
+    let mut page = String::new();
+    header(
+        &mut page,
+        "processed_total",
+        "counter",
+        "Records applied, by type.",
+    );
+    for (kind, n) in &metrics.processed {
+        let kind = kind.as_str();
+        let _ = writeln!(page, "roinstxs_processed_total{{type=\"{kind}\"}} {n}");
+    }
+    header(
+        &mut page,
+        "rejected_total",
+        "counter",
+        "Lines rejected before reaching the engine.",
+    );
+    let _ = writeln!(page, "roinstxs_rejected_total {}", metrics.rejected);
+    header(
+        &mut page,
+        "refused_total",
+        "counter",
+        "Records the engine refused, by reason.",
+    );
+    for (reason, n) in refusals {
+        let reason = label(reason);
+        let _ = writeln!(page, "roinstxs_refused_total{{reason=\"{reason}\"}} {n}");
+    }
+
+    header(
+        &mut page,
+        "apply_seconds",
+        "histogram",
+        "Time the engine took on each streamed record.",
+    );
+    let mut count = 0;
+    for (bound, n) in LATENCY_BUCKETS.iter().zip(&metrics.latency) {
+        count += n;
+        let le = bound.as_secs_f64();
+        let _ = writeln!(page, "roinstxs_apply_seconds_bucket{{le=\"{le}\"}} {count}");
+    }
+    count += metrics.latency.last().copied().unwrap_or_default();
+    let _ = writeln!(page, "roinstxs_apply_seconds_bucket{{le=\"+Inf\"}} {count}");
+    let sum = metrics.latency_sum.as_secs_f64();
+    let _ = writeln!(page, "roinstxs_apply_seconds_sum {sum}");
+    let _ = writeln!(page, "roinstxs_apply_seconds_count {count}");
+
+    for (name, help, value) in [
+        (
+            "connections",
+            "Producer connections open.",
+            metrics.connections as usize,
+        ),
+        ("accounts", "Accounts held in memory.", engine.accounts),
+        ("locked_accounts", "Accounts locked.", engine.locked),
+        (
+            "open_disputes",
+            "Transactions under dispute.",
+            engine.disputes,
+        ),
+        ("pending", "Future-dated records waiting.", engine.pending),
+    ] {
+        header(&mut page, name, "gauge", help);
+        let _ = writeln!(page, "roinstxs_{name} {value}");
+    }
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Tx, TxType};
+    use crate::metrics::Metrics;
+    use std::time::Duration;
+
+    #[test]
+    fn test_page_holds_counters_histogram_and_gauges() {
+        let metrics = Metrics::default();
+        metrics.record_processed(TxType::Deposit);
+        metrics.record_latency(Duration::from_micros(3));
+        metrics.record_latency(Duration::from_secs(2));
+        let mut engine = TxEngine::new();
+        for line in ["deposit, 1, 1, 10.0", "dispute, 1, 1,", "chargeback, 1, 1,"] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        let refusals = BTreeMap::from([("say \"no\"".to_owned(), 2)]);
+
+        let page = render(&metrics.snapshot(), &EngineGauges::of(&engine), &refusals);
+        for expected in [
+            "# TYPE roinstxs_processed_total counter",
+            "roinstxs_processed_total{type=\"deposit\"} 1",
+            "roinstxs_processed_total{type=\"withdrawal\"} 0",
+            "roinstxs_refused_total{reason=\"say \\\"no\\\"\"} 2",
+            "roinstxs_apply_seconds_bucket{le=\"0.000001\"} 0",
+            "roinstxs_apply_seconds_bucket{le=\"0.000005\"} 1",
+            "roinstxs_apply_seconds_bucket{le=\"0.1\"} 1",
+            "roinstxs_apply_seconds_bucket{le=\"+Inf\"} 2",
+            "roinstxs_apply_seconds_count 2",
+            "roinstxs_locked_accounts 1",
+            "roinstxs_accounts 1",
+        ] {
+            assert!(
+                page.lines().any(|l| l == expected),
+                "{expected} missing in\n{page}"
+            );
+        }
+    }
+}
@@ -0,0 +1,1573 @@
+//! Raft replication of the stream server's engine, for deployments that must
+//! survive the loss of nodes.
+//!
+//! Servers started with `--raft-id`, `--raft-listen`, `--raft-dir`,
+//! `--raft-token` and a `--raft-peer ID=ADDR` for every other member form a
+//! cluster that elects a leader among themselves. Records the leader takes, from the stream
+//! listener, gRPC or Kafka, and the erasures, locks and unlocks of its HTTP
+//! admin routes are appended to a log, replicated to the other members and
+//! applied by every member in log order once a majority stored them. Only
+//! then are producers answered, so an answered record survives the loss of
+//! any minority of the cluster. Members not leading refuse records, naming
+//! the leader they know of, and serve reads from their own engine, which may
+//! lag the leader's.
+//!
+//! Each member keeps its term, vote and log under `--raft-dir`, synced to
+//! disk before it answers anyone. Every `--raft-snapshot-every` applied
+//! entries, and after every erasure, it writes its engine snapshot there and
+//! drops the log the snapshot covers. A member missing entries the leader
+//! dropped is sent the leader's snapshot instead and restores its engine
+//! from it. A restarting member restores its snapshot and applies its log
+//! again once a leader confirms what was committed.
+//!
+//! Members only stay alike if applying a record gives the same everywhere:
+//! the clock-driven `--schedule` and `--defer-future-dated` cannot be used,
+//! `--wal`, `--restore-from` and handoffs are replaced by the raft directory,
+//! and dispute windows are judged by each member's clock, so members' clocks
+//! must agree. Every member emits the events of what it applies to the
+//! observers it was started with, `--cdc` and notifications included.
+//!
+//! Members take messages only on connections opening with an
+//! `AUTH <token>` line of the cluster's `--raft-token`, and drop others
+//! unanswered, as any message may rewrite the log. The token travels in the
+//! clear, so members must reach one another on a trusted network.
+
+use crate::auth;
+use crate::engine::{Account, Erasure, Tx, TxEngine};
+use crate::error::EngineError;
+use crate::log;
+use crate::net;
+use crate::rng::XorShift;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+// how often a leader reaches every member, keeping it from an election
+const HEARTBEAT: Duration = Duration::from_millis(100);
+// how long a member waits for the leader before standing itself, plus up to
+// `ELECTION_SPREAD` so that members rarely stand at once
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(500);
+const ELECTION_SPREAD: u64 = 500;
+// how long an unanswered message is left before it is sent again, short of
+// the election timeout
+const RETRY: Duration = Duration::from_millis(250);
+const TICK: Duration = Duration::from_millis(20);
+// most entries sent to a member in one message
+const MAX_ENTRIES: usize = 512;
+// messages queued for a member before further ones are dropped
+const OUTBOX: usize = 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct RaftArgs {
+    /// Replicate the engine through raft as the cluster member with this id
+    #[arg(
+        long,
+        global = true,
+        value_name = "ID",
+        requires_all = ["raft_listen", "raft_dir", "raft_token"],
+        conflicts_with_all = ["replica_of", "schedule", "defer_future_dated", "wal", "restore_from", "ha_lock"]
+    )]
+    raft_id: Option<u64>,
+    /// Address the other members reach this one on, e.g. 0.0.0.0:7100
+    #[arg(long, global = true, value_name = "ADDR", requires = "raft_id")]
+    raft_listen: Option<SocketAddr>,
+    /// Another member of the cluster, as ID=ADDR; repeat for every one
+    #[arg(long, global = true, value_name = "ID=ADDR", value_parser = parse_peer, requires = "raft_id")]
+    raft_peer: Vec<(u64, SocketAddr)>,
+    /// Shared secret every member of the cluster opens its connections to
+    /// the others with
+    #[arg(
+        long,
+        global = true,
+        env = "ROINSTXS_RAFT_TOKEN",
+        hide_env_values = true,
+        requires = "raft_id"
+    )]
+    raft_token: Option<String>,
+    /// Directory keeping this member's term, vote, log and snapshot
+    #[arg(long, global = true, value_name = "PATH", requires = "raft_id")]
+    raft_dir: Option<PathBuf>,
+    /// Applied log entries between the snapshots the log is compacted to
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        default_value_t = 10_000,
+        requires = "raft_id"
+    )]
+    raft_snapshot_every: u64,
+}
+
+fn parse_peer(v: &str) -> Result<(u64, SocketAddr)> {
+    let (id, addr) = v
+        .split_once('=')
+        .with_context(|| format!("expected ID=ADDR, got {v:?}"))?;
+    let id = id
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid member id {id:?}"))?;
+    let addr = addr
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid address {addr:?}"))?;
+    Ok((id, addr))
+}
+
+impl RaftArgs {
+    /// Opens `--raft-dir`, restoring `engine` from the snapshot in it, and
+    /// routes records through the member from then on.
+    pub(crate) fn open(&self, engine: &mut TxEngine) -> Result<Option<Raft>> {
+        let (Some(id), Some(listen), Some(dir), Some(token)) = (
+            self.raft_id,
+            self.raft_listen,
+            &self.raft_dir,
+            &self.raft_token,
+        ) else {
+            return Ok(None);
+        };
+        let peers: HashMap<u64, SocketAddr> = self.raft_peer.iter().copied().collect();
+        if peers.len() != self.raft_peer.len() {
+            bail!("--raft-peer names a member twice");
+        }
+        if peers.contains_key(&id) {
+            bail!("--raft-peer names this member, {id}");
+        }
+        let (disk, persisted) = Disk::open(dir)?;
+        if let Some(snapshot) = &persisted.snapshot {
+            engine.restore(snapshot.state.as_bytes()).with_context(|| {
+                format!("could not restore the raft snapshot in {}", dir.display())
+            })?;
+            log::info!("raft: restored the snapshot at entry {}", snapshot.index);
+        }
+        let node = Node::new(
+            id,
+            peers.keys().copied().collect(),
+            persisted,
+            Instant::now(),
+        );
+        let (proposer, proposals) = mpsc::channel(OUTBOX);
+        PROPOSER
+            .set(Proposer(proposer))
+            .map_err(|_| anyhow!("raft is already running"))?;
+        Ok(Some(Raft {
+            listen,
+            token: Arc::from(token.as_str()),
+            peers,
+            disk,
+            node,
+            snapshot_every: self.raft_snapshot_every.max(1),
+            proposals,
+        }))
+    }
+}
+
+/// A change to the engine, as the log holds it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Op {
+    /// Appended by every new leader, committing what earlier terms left.
+    Noop,
+    Record {
+        tx: Tx,
+    },
+    Erase {
+        client: u16,
+    },
+    Lock {
+        client: u16,
+    },
+    Unlock {
+        client: u16,
+    },
+}
+
+impl Op {
+    fn record(tx: Tx) -> Self {
+        Self::Record { tx }
+    }
+}
+
+/// What applying an op gave, for the member that proposed it.
+enum Applied {
+    Record(Result<(), EngineError>),
+    Erasure(Erasure),
+    Account(Option<Account>),
+}
+
+// applies a committed op, `None` for a noop
+fn apply(engine: &mut TxEngine, op: Op) -> Result<Option<Applied>> {
+    Ok(Some(match op {
+        Op::Noop => return Ok(None),
+        Op::Record { tx } => Applied::Record(engine.process_tx(tx)),
+        Op::Erase { client } => Applied::Erasure(engine.erase_client(client)),
+        Op::Lock { client } => Applied::Account(engine.lock_account(client)),
+        Op::Unlock { client } => Applied::Account(engine.unlock_account(client)),
+    }))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    index: u64,
+    term: u64,
+    #[serde(flatten)]
+    op: Op,
+}
+
+/// The engine as of a log entry, standing in for the log up to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Snapshot {
+    index: u64,
+    term: u64,
+    // as `TxEngine::snapshot` writes it
+    state: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    /// A candidate asking for a vote.
+    Vote {
+        term: u64,
+        last_index: u64,
+        last_term: u64,
+    },
+    Voted {
+        term: u64,
+        granted: bool,
+    },
+    /// Entries following `prev_index`, none as a heartbeat.
+    Append {
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    /// Answers `Append` and `Snapshot`: how far the member's log matches the
+    /// leader's if `success`, or the index to retry after otherwise.
+    Appended {
+        term: u64,
+        success: bool,
+        matched: u64,
+    },
+    /// The leader's snapshot, for a member missing entries it dropped.
+    Snapshot {
+        term: u64,
+        snapshot: Snapshot,
+    },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match self {
+            Self::Vote { term, .. }
+            | Self::Voted { term, .. }
+            | Self::Append { term, .. }
+            | Self::Appended { term, .. }
+            | Self::Snapshot { term, .. } => *term,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    from: u64,
+    message: Message,
+}
+
+/// The entries since the last snapshot.
+#[derive(Debug, Default)]
+struct Log {
+    // index and term of the last entry the snapshot covers
+    base: u64,
+    base_term: u64,
+    entries: Vec<Entry>,
+}
+
+impl Log {
+    fn last_index(&self) -> u64 {
+        self.base + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.base_term, |e| e.term)
+    }
+
+    /// The term of the entry at `index`, if the log still knows it.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index.checked_sub(self.base)? {
+            0 => Some(self.base_term),
+            n => self.entries.get(n as usize - 1).map(|e| e.term),
+        }
+    }
+
+    /// The entries from `index` on.
+    fn since(&self, index: u64) -> &[Entry] {
+        let start = index.saturating_sub(self.base + 1) as usize;
+        &self.entries[start.min(self.entries.len())..]
+    }
+
+    /// Drops the entries from `index` on.
+    fn truncate(&mut self, index: u64) {
+        self.entries
+            .truncate(index.saturating_sub(self.base + 1) as usize);
+    }
+
+    /// Drops the entries up to `index`, which a snapshot now covers.
+    fn compact(&mut self, index: u64, term: u64) {
+        let covered = (index.saturating_sub(self.base) as usize).min(self.entries.len());
+        self.entries.drain(..covered);
+        self.base = index;
+        self.base_term = term;
+    }
+}
+
+/// What `--raft-dir` held on startup.
+#[derive(Debug, Default)]
+struct Persisted {
+    term: u64,
+    voted_for: Option<u64>,
+    snapshot: Option<Snapshot>,
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<u64>,
+}
+
+// what changed since the member's state last reached the disk
+#[derive(Debug, Default)]
+struct Changes {
+    hard_state: bool,
+    // the first entry appended since
+    appended: Option<u64>,
+    // entries were dropped, so the log file is written anew
+    rewrite: bool,
+    snapshot: bool,
+}
+
+#[derive(Debug)]
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<u64>,
+    },
+    Leader {
+        // per member: the next entry to send, the last one known to match,
+        // and when the last unanswered message went out
+        next: HashMap<u64, u64>,
+        matched: HashMap<u64, u64>,
+        inflight: HashMap<u64, Instant>,
+    },
+}
+
+/// The consensus state of one member, driven by the messages it receives
+/// and the passing of time. It does no I/O: what it sends accumulates in
+/// `outbox` and what must reach the disk first in `changes`.
+struct Node {
+    id: u64,
+    peers: Vec<u64>,
+    term: u64,
+    voted_for: Option<u64>,
+    log: Log,
+    snapshot: Option<Snapshot>,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<u64>,
+    // when to stand for election, or when a leader sends heartbeats
+    deadline: Instant,
+    rng: XorShift,
+    outbox: Vec<(u64, Message)>,
+    changes: Changes,
+    // a snapshot from the leader replaced the log, and must replace the engine
+    installed: bool,
+}
+
+impl Node {
+    fn new(id: u64, peers: Vec<u64>, persisted: Persisted, now: Instant) -> Self {
+        let (base, base_term) = persisted
+            .snapshot
+            .as_ref()
+            .map_or((0, 0), |s| (s.index, s.term));
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let mut node = Self {
+            id,
+            peers,
+            term: persisted.term,
+            voted_for: persisted.voted_for,
+            log: Log {
+                base,
+                base_term,
+                entries: persisted.entries,
+            },
+            snapshot: persisted.snapshot,
+            commit: base,
+            applied: base,
+            role: Role::Follower,
+            leader: None,
+            deadline: now,
+            rng: XorShift::new(seed ^ id),
+            outbox: Vec::new(),
+            changes: Changes::default(),
+            installed: false,
+        };
+        node.wait_for_leader(now);
+        node
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    fn wait_for_leader(&mut self, now: Instant) {
+        let spread = Duration::from_millis(self.rng.below(ELECTION_SPREAD));
+        self.deadline = now + ELECTION_TIMEOUT + spread;
+    }
+
+    fn send(&mut self, to: u64, message: Message) {
+        self.outbox.push((to, message));
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.changes.appended.get_or_insert(entry.index);
+        self.log.entries.push(entry);
+    }
+
+    fn tick(&mut self, now: Instant) {
+        if now < self.deadline {
+            return;
+        }
+        if self.is_leader() {
+            self.replicate(now, true);
+            self.deadline = now + HEARTBEAT;
+        } else {
+            self.campaign(now);
+        }
+    }
+
+    fn campaign(&mut self, now: Instant) {
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.changes.hard_state = true;
+        self.leader = None;
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.id]),
+        };
+        self.wait_for_leader(now);
+        if self.quorum() == 1 {
+            return self.lead(now);
+        }
+        let (last_index, last_term) = (self.log.last_index(), self.log.last_term());
+        for peer in self.peers.clone() {
+            let term = self.term;
+            self.send(
+                peer,
+                Message::Vote {
+                    term,
+                    last_index,
+                    last_term,
+                },
+            );
+        }
+    }
+
+    fn lead(&mut self, now: Instant) {
+        let next = self.log.last_index() + 1;
+        self.role = Role::Leader {
+            next: self.peers.iter().map(|&p| (p, next)).collect(),
+            matched: self.peers.iter().map(|&p| (p, 0)).collect(),
+            inflight: HashMap::new(),
+        };
+        self.leader = Some(self.id);
+        self.push(Entry {
+            index: next,
+            term: self.term,
+            op: Op::Noop,
+        });
+        self.replicate(now, true);
+        self.deadline = now + HEARTBEAT;
+    }
+
+    // follows `leader` for the current term
+    fn follow(&mut self, leader: u64, now: Instant) {
+        self.role = Role::Follower;
+        self.leader = Some(leader);
+        self.wait_for_leader(now);
+    }
+
+    /// Appends `op` to the log of the leader, returning its index, or the
+    /// leader known of when this member is not it.
+    fn propose(&mut self, op: Op) -> Result<u64, Option<u64>> {
+        if !self.is_leader() {
+            return Err(self.leader);
+        }
+        let index = self.log.last_index() + 1;
+        self.push(Entry {
+            index,
+            term: self.term,
+            op,
+        });
+        Ok(index)
+    }
+
+    /// Sends what they miss to the members not waiting on an answer, or to
+    /// every member with `heartbeat`.
+    fn replicate(&mut self, now: Instant, heartbeat: bool) {
+        let Role::Leader { inflight, .. } = &self.role else {
+            return;
+        };
+        let due: Vec<u64> = self
+            .peers
+            .iter()
+            .copied()
+            .filter(|p| {
+                inflight
+                    .get(p)
+                    .is_none_or(|&sent| heartbeat && now.duration_since(sent) >= RETRY)
+            })
+            .collect();
+        for peer in due {
+            self.send_append(peer, now);
+        }
+        self.advance_commit();
+    }
+
+    fn send_append(&mut self, peer: u64, now: Instant) {
+        let Role::Leader { next, inflight, .. } = &mut self.role else {
+            return;
+        };
+        inflight.insert(peer, now);
+        let next = next[&peer];
+        let term = self.term;
+        if next <= self.log.base {
+            let snapshot = self
+                .snapshot
+                .clone()
+                .expect("a compacted log is covered by a snapshot");
+            return self.send(peer, Message::Snapshot { term, snapshot });
+        }
+        let prev_index = next - 1;
+        let prev_term = self
+            .log
+            .term_at(prev_index)
+            .expect("entries after the base are kept");
+        let entries = self
+            .log
+            .since(next)
+            .iter()
+            .take(MAX_ENTRIES)
+            .cloned()
+            .collect();
+        let commit = self.commit;
+        self.send(
+            peer,
+            Message::Append {
+                term,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            },
+        );
+    }
+
+    // commits the last entry of this term a majority stored, and all before
+    fn advance_commit(&mut self) {
+        let Role::Leader { matched, .. } = &self.role else {
+            return;
+        };
+        let mut index = self.log.last_index();
+        while index > self.commit && self.log.term_at(index) == Some(self.term) {
+            if 1 + matched.values().filter(|&&m| m >= index).count() >= self.quorum() {
+                self.commit = index;
+                return;
+            }
+            index -= 1;
+        }
+    }
+
+    fn step(&mut self, from: u64, message: Message, now: Instant) {
+        if !self.peers.contains(&from) {
+            return;
+        }
+        if message.term() > self.term {
+            self.term = message.term();
+            self.voted_for = None;
+            self.changes.hard_state = true;
+            if !matches!(self.role, Role::Follower) {
+                self.wait_for_leader(now);
+            }
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+        let term = self.term;
+        match message {
+            Message::Vote {
+                term: asked,
+                last_index,
+                last_term,
+            } => {
+                let up_to_date =
+                    (last_term, last_index) >= (self.log.last_term(), self.log.last_index());
+                let granted =
+                    asked == term && up_to_date && self.voted_for.is_none_or(|voted| voted == from);
+                if granted {
+                    self.voted_for = Some(from);
+                    self.changes.hard_state = true;
+                    self.wait_for_leader(now);
+                }
+                self.send(from, Message::Voted { term, granted });
+            }
+            Message::Voted {
+                term: voted,
+                granted,
+            } => {
+                let quorum = self.quorum();
+                let Role::Candidate { votes } = &mut self.role else {
+                    return;
+                };
+                if voted == term && granted && votes.insert(from) && votes.len() >= quorum {
+                    self.lead(now);
+                }
+            }
+            Message::Append {
+                term: sent,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                if sent < term {
+                    return self.send(
+                        from,
+                        Message::Appended {
+                            term,
+                            success: false,
+                            matched: 0,
+                        },
+                    );
+                }
+                self.follow(from, now);
+                let (success, matched) = self.append(prev_index, prev_term, entries, commit);
+                self.send(
+                    from,
+                    Message::Appended {
+                        term,
+                        success,
+                        matched,
+                    },
+                );
+            }
+            Message::Appended {
+                term: sent,
+                success,
+                matched,
+            } => {
+                if sent == term {
+                    self.appended(from, success, matched, now);
+                }
+            }
+            Message::Snapshot {
+                term: sent,
+                snapshot,
+            } => {
+                if sent < term {
+                    return self.send(
+                        from,
+                        Message::Appended {
+                            term,
+                            success: false,
+                            matched: 0,
+                        },
+                    );
+                }
+                self.follow(from, now);
+                let matched = self.install(snapshot);
+                self.send(
+                    from,
+                    Message::Appended {
+                        term,
+                        success: true,
+                        matched,
+                    },
+                );
+            }
+        }
+    }
+
+    // takes the leader's entries following `prev_index`, returning whether
+    // the logs matched there and how far they match now, or where the
+    // leader should retry after
+    fn append(
+        &mut self,
+        mut prev_index: u64,
+        mut prev_term: u64,
+        mut entries: Vec<Entry>,
+        commit: u64,
+    ) -> (bool, u64) {
+        // entries a snapshot covers are committed, and so match
+        if prev_index < self.log.base {
+            let covered = (self.log.base - prev_index) as usize;
+            if covered > entries.len() {
+                return (true, prev_index + entries.len() as u64);
+            }
+            if covered > 0 {
+                prev_term = entries[covered - 1].term;
+            }
+            prev_index = self.log.base;
+            entries.drain(..covered);
+        }
+        match self.log.term_at(prev_index) {
+            None => return (false, self.log.last_index()),
+            // skip the whole term the logs disagree on
+            Some(conflicting) if conflicting != prev_term => {
+                let mut retry = prev_index - 1;
+                while retry > self.log.base && self.log.term_at(retry) == Some(conflicting) {
+                    retry -= 1;
+                }
+                return (false, retry);
+            }
+            Some(_) => {}
+        }
+        let matched = prev_index + entries.len() as u64;
+        for entry in entries {
+            match self.log.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.log.truncate(entry.index);
+                    self.changes.rewrite = true;
+                }
+                None => {}
+            }
+            self.push(entry);
+        }
+        if commit > self.commit {
+            self.commit = commit.min(matched).max(self.commit);
+        }
+        (true, matched)
+    }
+
+    fn appended(&mut self, from: u64, success: bool, matched: u64, now: Instant) {
+        let last_index = self.log.last_index();
+        let Role::Leader {
+            next,
+            matched: matches,
+            inflight,
+        } = &mut self.role
+        else {
+            return;
+        };
+        inflight.remove(&from);
+        let (Some(next), Some(matches)) = (next.get_mut(&from), matches.get_mut(&from)) else {
+            return;
+        };
+        if success {
+            *matches = (*matches).max(matched);
+            *next = *matches + 1;
+        } else {
+            *next = (*next).min(matched + 1).max(1);
+        }
+        let more = *next <= last_index;
+        if success {
+            self.advance_commit();
+        }
+        if more || !success {
+            self.send_append(from, now);
+        }
+    }
+
+    // replaces the log up to the snapshot, returning how far the logs match
+    fn install(&mut self, snapshot: Snapshot) -> u64 {
+        if snapshot.index <= self.commit {
+            return snapshot.index;
+        }
+        if self.log.term_at(snapshot.index) == Some(snapshot.term) {
+            self.log.compact(snapshot.index, snapshot.term);
+        } else {
+            self.log = Log {
+                base: snapshot.index,
+                base_term: snapshot.term,
+                entries: Vec::new(),
+            };
+        }
+        self.changes.rewrite = true;
+        self.changes.snapshot = true;
+        self.commit = snapshot.index;
+        self.applied = snapshot.index;
+        self.installed = true;
+        let index = snapshot.index;
+        self.snapshot = Some(snapshot);
+        index
+    }
+
+    /// The committed entries not yet applied, marking them applied.
+    fn take_committed(&mut self) -> Vec<Entry> {
+        let entries = self.log.since(self.applied + 1);
+        let committed = entries[..(self.commit - self.applied) as usize].to_vec();
+        self.applied = self.commit;
+        committed
+    }
+
+    /// Replaces the log up to the last applied entry with the engine's
+    /// `state` as of it.
+    fn compact(&mut self, state: String) {
+        let index = self.applied;
+        let term = self.log.term_at(index).expect("applied entries are known");
+        self.log.compact(index, term);
+        self.snapshot = Some(Snapshot { index, term, state });
+        self.changes.snapshot = true;
+        self.changes.rewrite = true;
+    }
+}
+
+/// The member's files in `--raft-dir`.
+struct Disk {
+    dir: PathBuf,
+    log: File,
+}
+
+impl Disk {
+    const HARD_STATE: &str = "state.json";
+    const SNAPSHOT: &str = "snapshot.json";
+    const LOG: &str = "log.ndjson";
+
+    fn open(dir: &Path) -> Result<(Self, Persisted)> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("could not create {}", dir.display()))?;
+        let hard_state: HardState = read_json(&dir.join(Self::HARD_STATE))?.unwrap_or_default();
+        let snapshot: Option<Snapshot> = read_json(&dir.join(Self::SNAPSHOT))?;
+        let path = dir.join(Self::LOG);
+        let log = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut next = snapshot.as_ref().map_or(0, |s| s.index) + 1;
+        let mut reader = BufReader::new(&log);
+        let (mut line, mut valid) = (String::new(), 0);
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            // a crash cut the last write short; later appends must not
+            // continue it
+            if !line.ends_with('\n') {
+                log::warn!("raft: dropping a partial last line of {}", path.display());
+                log.set_len(valid)?;
+                break;
+            }
+            valid += read as u64;
+            let entry: Entry = serde_json::from_str(&line)
+                .with_context(|| format!("{}: invalid entry", path.display()))?;
+            // entries the snapshot covers, left by a crash before the log
+            // was rewritten
+            if entry.index < next {
+                continue;
+            }
+            if entry.index > next {
+                bail!("{}: entry {next} is missing", path.display());
+            }
+            entries.push(entry);
+            next += 1;
+        }
+        let persisted = Persisted {
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            snapshot,
+            entries,
+        };
+        Ok((
+            Self {
+                dir: dir.to_owned(),
+                log,
+            },
+            persisted,
+        ))
+    }
+
+    /// Writes what changed in `node` since the last call.
+    fn persist(&mut self, node: &mut Node) -> Result<()> {
+        let changes = std::mem::take(&mut node.changes);
+        if changes.snapshot {
+            let snapshot = node.snapshot.as_ref().expect("a snapshot was taken");
+            write_atomically(
+                &self.dir.join(Self::SNAPSHOT),
+                &serde_json::to_vec(snapshot)?,
+            )?;
+        }
+        if changes.hard_state {
+            let hard_state = HardState {
+                term: node.term,
+                voted_for: node.voted_for,
+            };
+            write_atomically(
+                &self.dir.join(Self::HARD_STATE),
+                &serde_json::to_vec(&hard_state)?,
+            )?;
+        }
+        if changes.rewrite {
+            let path = self.dir.join(Self::LOG);
+            write_atomically(&path, &lines(&node.log.entries)?)?;
+            self.log = OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("could not open {}", path.display()))?;
+        } else if let Some(from) = changes.appended {
+            self.log.write_all(&lines(node.log.since(from))?)?;
+            self.log.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+fn lines(entries: &[Entry]) -> Result<Vec<u8>> {
+    let mut lines = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut lines, entry)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+    match std::fs::read(path) {
+        Ok(body) => serde_json::from_slice(&body)
+            .map(Some)
+            .with_context(|| format!("could not read {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("could not read {}", path.display())),
+    }
+}
+
+// replaces `path` so that a crash leaves either the old or the new content
+fn write_atomically(path: &Path, body: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file =
+        File::create(&tmp).with_context(|| format!("could not create {}", tmp.display()))?;
+    file.write_all(body)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path).with_context(|| format!("could not replace {}", path.display()))?;
+    Ok(())
+}
+
+struct Proposal {
+    op: Op,
+    applied: oneshot::Sender<Result<Applied>>,
+}
+
+/// Hands changes to the member, to be applied once committed.
+pub(crate) struct Proposer(mpsc::Sender<Proposal>);
+
+// only set once `RaftArgs::open` ran
+static PROPOSER: OnceLock<Proposer> = OnceLock::new();
+
+/// The member's proposer, if the server replicates through raft.
+pub(crate) fn proposer() -> Option<&'static Proposer> {
+    PROPOSER.get()
+}
+
+impl Proposer {
+    async fn propose(&self, ops: Vec<Op>) -> Vec<Result<Applied>> {
+        let mut waiting = Vec::with_capacity(ops.len());
+        for op in ops {
+            let (applied, receiver) = oneshot::channel();
+            // a stopped member drops the sender, answered below
+            let _ = self.0.send(Proposal { op, applied }).await;
+            waiting.push(receiver);
+        }
+        let mut applied = Vec::with_capacity(waiting.len());
+        for receiver in waiting {
+            applied.push(
+                receiver
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("raft stopped"))),
+            );
+        }
+        applied
+    }
+
+    async fn propose_one(&self, op: Op) -> Result<Applied> {
+        self.propose(vec![op])
+            .await
+            .pop()
+            .expect("one answer per op")
+    }
+
+    /// Applies the records once committed, returning for every record in
+    /// order whether it was applied or why it was refused.
+    pub(crate) async fn records(&self, txs: Vec<Tx>) -> Vec<Result<()>> {
+        let ops = txs.into_iter().map(Op::record).collect();
+        self.propose(ops)
+            .await
+            .into_iter()
+            .map(|applied| match applied? {
+                Applied::Record(res) => Ok(res?),
+                _ => unreachable!("records apply as records"),
+            })
+            .collect()
+    }
+
+    pub(crate) async fn record(&self, tx: Tx) -> Result<()> {
+        self.records(vec![tx])
+            .await
+            .pop()
+            .expect("one answer per record")
+    }
+
+    /// Erases `client` once committed, see `TxEngine::erase_client`.
+    pub(crate) async fn erase(&self, client: u16) -> Result<Erasure> {
+        match self.propose_one(Op::Erase { client }).await? {
+            Applied::Erasure(erasure) => Ok(erasure),
+            _ => unreachable!("erasures apply as erasures"),
+        }
+    }
+
+    /// Locks `client` once committed, see `TxEngine::lock_account`.
+    pub(crate) async fn lock(&self, client: u16) -> Result<Option<Account>> {
+        match self.propose_one(Op::Lock { client }).await? {
+            Applied::Account(account) => Ok(account),
+            _ => unreachable!("locks apply to accounts"),
+        }
+    }
+
+    /// Unlocks `client` once committed, see `TxEngine::unlock_account`.
+    pub(crate) async fn unlock(&self, client: u16) -> Result<Option<Account>> {
+        match self.propose_one(Op::Unlock { client }).await? {
+            Applied::Account(account) => Ok(account),
+            _ => unreachable!("unlocks apply to accounts"),
+        }
+    }
+}
+
+fn not_leader(leader: Option<u64>) -> anyhow::Error {
+    match leader {
+        Some(leader) => anyhow!("not the raft leader, member {leader} is"),
+        None => anyhow!("not the raft leader, and no leader is known"),
+    }
+}
+
+/// A cluster member, ready to run.
+pub(crate) struct Raft {
+    listen: SocketAddr,
+    token: Arc<str>,
+    peers: HashMap<u64, SocketAddr>,
+    disk: Disk,
+    node: Node,
+    snapshot_every: u64,
+    proposals: mpsc::Receiver<Proposal>,
+}
+
+// proposals waiting for their entry to be applied, by index, with the term
+// they were proposed in
+type Waiting = BTreeMap<u64, (u64, oneshot::Sender<Result<Applied>>)>;
+
+impl Raft {
+    /// Takes part in the cluster, applying committed entries to `engine`,
+    /// until the raft listener fails.
+    pub(crate) async fn run(mut self, engine: Arc<Mutex<TxEngine>>) -> Result<()> {
+        let addr = self.listen;
+        let listener = net::bind_tcp(addr)
+            .with_context(|| format!("could not bind raft listener on {addr}"))?;
+        let (inbox, mut received) = mpsc::channel(OUTBOX);
+        let accepting = accept(listener, inbox, self.token.clone());
+        tokio::pin!(accepting);
+        let id = self.node.id;
+        let outboxes: HashMap<u64, mpsc::Sender<Message>> = self
+            .peers
+            .iter()
+            .map(|(&peer, &addr)| (peer, connect(id, addr, self.token.clone())))
+            .collect();
+        let mut waiting = Waiting::new();
+        let mut ticks = tokio::time::interval(TICK);
+        loop {
+            tokio::select! {
+                res = &mut accepting => return res,
+                Some(envelope) = received.recv() => {
+                    self.node.step(envelope.from, envelope.message, Instant::now());
+                }
+                Some(proposal) = self.proposals.recv() => {
+                    self.propose(proposal, &mut waiting);
+                    while let Ok(proposal) = self.proposals.try_recv() {
+                        self.propose(proposal, &mut waiting);
+                    }
+                    self.node.replicate(Instant::now(), false);
+                }
+                _ = ticks.tick() => self.node.tick(Instant::now()),
+            }
+            // nothing goes out before what it promises is on disk
+            self.disk.persist(&mut self.node)?;
+            for (to, message) in self.node.outbox.drain(..) {
+                // the member is down or too slow; the message is retried
+                let _ = outboxes[&to].try_send(message);
+            }
+            self.apply(&engine, &mut waiting).await?;
+        }
+    }
+
+    fn propose(&mut self, proposal: Proposal, waiting: &mut Waiting) {
+        match self.node.propose(proposal.op) {
+            Ok(index) => {
+                waiting.insert(index, (self.node.term, proposal.applied));
+            }
+            Err(leader) => {
+                let _ = proposal.applied.send(Err(not_leader(leader)));
+            }
+        }
+    }
+
+    async fn apply(&mut self, engine: &Mutex<TxEngine>, waiting: &mut Waiting) -> Result<()> {
+        if std::mem::take(&mut self.node.installed) {
+            let snapshot = self
+                .node
+                .snapshot
+                .as_ref()
+                .expect("a snapshot was installed");
+            engine
+                .lock()
+                .await
+                .restore(snapshot.state.as_bytes())
+                .context("could not restore the leader's snapshot")?;
+            log::info!(
+                "raft: restored the leader's snapshot at entry {}",
+                snapshot.index
+            );
+            let later = waiting.split_off(&(snapshot.index + 1));
+            for (_, (_, applied)) in std::mem::replace(waiting, later) {
+                let _ = applied.send(Err(anyhow!(
+                    "replaced by the leader's snapshot before it was applied"
+                )));
+            }
+        }
+        let committed = self.node.take_committed();
+        if committed.is_empty() {
+            return Ok(());
+        }
+        let mut engine = engine.lock().await;
+        let mut erased = false;
+        for entry in committed {
+            erased |= matches!(entry.op, Op::Erase { .. });
+            let applied = apply(&mut engine, entry.op)?;
+            let Some((term, sender)) = waiting.remove(&entry.index) else {
+                continue;
+            };
+            let _ = sender.send(match applied {
+                Some(applied) if term == entry.term => Ok(applied),
+                _ => Err(anyhow!("lost the raft leadership before it committed")),
+            });
+        }
+        // the erased client's records must not outlive it in the log
+        if erased || self.node.applied - self.node.log.base >= self.snapshot_every {
+            let mut state = Vec::new();
+            engine.snapshot(&mut state)?;
+            drop(engine);
+            self.node.compact(String::from_utf8(state)?);
+            self.disk.persist(&mut self.node)?;
+        }
+        Ok(())
+    }
+}
+
+// takes messages from members opening with the cluster's token
+async fn accept(
+    listener: TcpListener,
+    inbox: mpsc::Sender<Envelope>,
+    token: Arc<str>,
+) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let inbox = inbox.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            let mut reader = tokio::io::BufReader::new(stream);
+            if !auth::read_auth(&mut reader, &token).await {
+                log::warn!("raft: dropping a connection from {peer} without the raft token");
+                return;
+            }
+            let mut lines = reader.lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                match serde_json::from_str(&line) {
+                    Ok(envelope) => {
+                        if inbox.send(envelope).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        log::warn!("raft: dropping a connection sending an invalid message: {err}");
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+// queues messages to the member at `addr`, connecting on demand and opening
+// each connection with `token`; messages it cannot take are dropped
+fn connect(from: u64, addr: SocketAddr, token: Arc<str>) -> mpsc::Sender<Message> {
+    let (outbox, mut queued) = mpsc::channel::<Message>(OUTBOX);
+    tokio::spawn(async move {
+        let mut stream = None;
+        while let Some(message) = queued.recv().await {
+            if stream.is_none() {
+                let connecting = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr));
+                stream = connecting
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+                    .map(BufWriter::new);
+                if let Some(writer) = &mut stream {
+                    let auth = format!("{}{token}\n", auth::AUTH_COMMAND);
+                    if writer.write_all(auth.as_bytes()).await.is_err() {
+                        stream = None;
+                    }
+                }
+            }
+            let Some(writer) = &mut stream else {
+                continue;
+            };
+            let mut line =
+                serde_json::to_vec(&Envelope { from, message }).expect("messages serialize");
+            line.push(b'\n');
+            let written = match writer.write_all(&line).await {
+                Ok(()) if queued.is_empty() => writer.flush().await,
+                res => res,
+            };
+            if written.is_err() {
+                stream = None;
+            }
+        }
+    });
+    outbox
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // members exchanging messages in memory, some of them cut off
+    struct Cluster {
+        nodes: Vec<Node>,
+        down: HashSet<u64>,
+        now: Instant,
+    }
+
+    impl Cluster {
+        fn new(size: u64) -> Self {
+            let now = Instant::now();
+            let nodes = (1..=size)
+                .map(|id| {
+                    let peers = (1..=size).filter(|&p| p != id).collect();
+                    Node::new(id, peers, Persisted::default(), now)
+                })
+                .collect();
+            Self {
+                nodes,
+                down: HashSet::new(),
+                now,
+            }
+        }
+
+        fn node(&mut self, id: u64) -> &mut Node {
+            &mut self.nodes[id as usize - 1]
+        }
+
+        // delivers messages until none are left
+        fn settle(&mut self) {
+            loop {
+                let mut sent = Vec::new();
+                for node in &mut self.nodes {
+                    node.changes = Changes::default();
+                    for (to, message) in node.outbox.drain(..) {
+                        sent.push((node.id, to, message));
+                    }
+                }
+                if sent.is_empty() {
+                    return;
+                }
+                for (from, to, message) in sent {
+                    if self.down.contains(&from) || self.down.contains(&to) {
+                        continue;
+                    }
+                    let now = self.now;
+                    self.node(to).step(from, message, now);
+                }
+            }
+        }
+
+        fn elect(&mut self, id: u64) {
+            self.now += Duration::from_secs(5);
+            let now = self.now;
+            self.node(id).campaign(now);
+            self.settle();
+            assert!(self.node(id).is_leader());
+        }
+
+        fn heartbeat(&mut self, id: u64) {
+            self.now += RETRY;
+            let now = self.now;
+            self.node(id).replicate(now, true);
+            self.settle();
+        }
+
+        fn propose(&mut self, id: u64, tx: &str) -> u64 {
+            let index = self
+                .node(id)
+                .propose(Op::record(Tx::from_str(tx).unwrap()))
+                .unwrap();
+            let now = self.now;
+            self.node(id).replicate(now, false);
+            self.settle();
+            index
+        }
+    }
+
+    fn records(entries: &[Entry]) -> Vec<u32> {
+        entries
+            .iter()
+            .filter_map(|e| match &e.op {
+                Op::Record { tx, .. } => Some(tx.tx_id()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_entries_commit_once_a_majority_stored_them() {
+        let mut cluster = Cluster::new(3);
+        cluster.elect(1);
+        assert_eq!(cluster.node(2).leader, Some(1));
+        cluster.propose(1, "deposit, 1, 1, 10.0");
+        assert_eq!(records(&cluster.node(1).take_committed()), [1]);
+
+        cluster.down.insert(3);
+        let index = cluster.propose(1, "deposit, 1, 2, 5.0");
+        assert_eq!(cluster.node(1).commit, index);
+        assert_eq!(records(&cluster.node(1).take_committed()), [2]);
+
+        // without a majority nothing commits
+        cluster.down.insert(2);
+        let index = cluster.propose(1, "deposit, 1, 3, 5.0");
+        assert!(cluster.node(1).commit < index);
+        assert!(cluster.node(1).take_committed().is_empty());
+        assert_eq!(cluster.node(2).propose(Op::Noop).unwrap_err(), Some(1));
+
+        // the followers learn the leader committed it from its next message
+        cluster.down.clear();
+        cluster.heartbeat(1);
+        cluster.heartbeat(1);
+        assert_eq!(cluster.node(1).commit, index);
+        assert_eq!(records(&cluster.node(1).take_committed()), [3]);
+        assert_eq!(records(&cluster.node(3).take_committed()), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_a_new_leader_replaces_what_the_old_one_did_not_commit() {
+        let mut cluster = Cluster::new(3);
+        cluster.elect(1);
+        cluster.propose(1, "deposit, 1, 1, 10.0");
+        cluster.down.insert(1);
+        let lost = cluster.propose(1, "deposit, 1, 2, 5.0");
+
+        cluster.elect(2);
+        cluster.propose(2, "deposit, 2, 3, 5.0");
+        cluster.down.clear();
+        cluster.heartbeat(2);
+        let term = cluster.node(2).term;
+        let old = cluster.node(1);
+        assert!(!old.is_leader());
+        assert_eq!(old.log.term_at(lost), Some(term));
+        assert_eq!(records(&cluster.node(1).take_committed()), [1, 3]);
+    }
+
+    #[test]
+    fn test_members_elect_another_leader_once_theirs_is_cut_off() {
+        let mut cluster = Cluster::new(3);
+        cluster.elect(1);
+        cluster.propose(1, "deposit, 1, 1, 10.0");
+        cluster.down.insert(1);
+
+        // the followers' election timeouts run out, and one of them stands
+        let leader = (0..1000)
+            .find_map(|_| {
+                cluster.now += TICK;
+                let now = cluster.now;
+                for id in [2, 3] {
+                    cluster.node(id).tick(now);
+                }
+                cluster.settle();
+                [2, 3].into_iter().find(|&id| cluster.node(id).is_leader())
+            })
+            .expect("no member took over");
+        let term = cluster.node(leader).term;
+        let index = cluster.propose(leader, "deposit, 1, 2, 5.0");
+        assert_eq!(cluster.node(leader).commit, index);
+
+        // the old leader steps down on hearing from the new one
+        cluster.down.clear();
+        cluster.heartbeat(leader);
+        cluster.heartbeat(leader);
+        let old = cluster.node(1);
+        assert!(!old.is_leader());
+        assert_eq!((old.term, old.leader), (term, Some(leader)));
+        assert_eq!(records(&old.take_committed()), [1, 2]);
+    }
+
+    #[test]
+    fn test_a_member_drops_entries_conflicting_with_the_leader() {
+        let mut cluster = Cluster::new(3);
+        cluster.elect(1);
+        cluster.propose(1, "deposit, 1, 1, 10.0");
+        cluster.down.insert(1);
+        for tx in 2..=4 {
+            cluster.propose(1, &format!("deposit, 1, {tx}, 1.0"));
+        }
+        assert_eq!(cluster.node(1).log.last_index(), 5);
+
+        cluster.elect(2);
+        cluster.propose(2, "deposit, 2, 5, 1.0");
+        cluster.down.clear();
+        cluster.heartbeat(2);
+        cluster.heartbeat(2);
+
+        // the three entries only the old leader stored gave way to the new
+        // leader's
+        let new = records(&cluster.node(2).log.entries);
+        let old = cluster.node(1);
+        assert_eq!(records(&old.log.entries), [1, 5]);
+        assert_eq!(records(&old.log.entries), new);
+        assert_eq!(old.log.last_index(), 4);
+    }
+
+    #[test]
+    fn test_a_member_missing_compacted_entries_gets_the_snapshot() {
+        let mut cluster = Cluster::new(3);
+        cluster.elect(1);
+        cluster.down.insert(3);
+        for tx in 1..=5 {
+            cluster.propose(1, &format!("deposit, 1, {tx}, 1.0"));
+        }
+        let leader = cluster.node(1);
+        leader.take_committed();
+        leader.compact("{}".to_owned());
+        assert!(leader.log.entries.is_empty());
+
+        cluster.down.clear();
+        cluster.propose(1, "deposit, 1, 6, 1.0");
+        cluster.heartbeat(1);
+        let lagging = cluster.node(3);
+        assert!(std::mem::take(&mut lagging.installed));
+        assert_eq!(lagging.snapshot.as_ref().unwrap().state, "{}");
+        assert_eq!(records(&lagging.take_committed()), [6]);
+    }
+
+    #[test]
+    fn test_a_restarted_member_keeps_its_term_vote_and_log() {
+        let dir = std::env::temp_dir().join(format!("roinstxs-raft-{}", std::process::id()));
+        let (mut disk, persisted) = Disk::open(&dir).unwrap();
+        let mut node = Node::new(1, Vec::new(), persisted, Instant::now());
+        node.campaign(Instant::now());
+        node.propose(Op::record(Tx::new("bonus", 1, 1, None).unwrap()))
+            .unwrap();
+        node.replicate(Instant::now(), false);
+        disk.persist(&mut node).unwrap();
+        node.take_committed();
+        node.compact("{}".to_owned());
+        node.propose(Op::Lock { client: 1 }).unwrap();
+        disk.persist(&mut node).unwrap();
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(Disk::LOG))
+            .unwrap();
+        log.write_all(br#"{"index":4,"#).unwrap();
+
+        let (_, persisted) = Disk::open(&dir).unwrap();
+        assert_eq!((persisted.term, persisted.voted_for), (1, Some(1)));
+        assert_eq!(persisted.snapshot.unwrap().index, 2);
+        assert!(matches!(
+            persisted.entries.as_slice(),
+            [Entry {
+                index: 3,
+                term: 1,
+                op: Op::Lock { client: 1 }
+            }]
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_custom_records_keep_their_type() {
+        let mut engine = TxEngine::new();
+        let op = Op::record(Tx::new("bonus", 1, 1, None).unwrap());
+        let line = serde_json::to_string(&Entry {
+            index: 1,
+            term: 1,
+            op,
+        })
+        .unwrap();
+        let entry: Entry = serde_json::from_str(&line).unwrap();
+        let Some(Applied::Record(Err(err))) = apply(&mut engine, entry.op).unwrap() else {
+            panic!("expected a record");
+        };
+        assert_eq!(err.to_string(), "unknown transaction type");
+    }
+
+    #[tokio::test]
+    async fn test_members_take_messages_only_with_the_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (inbox, mut received) = mpsc::channel(OUTBOX);
+        tokio::spawn(accept(listener, inbox, Arc::from("s3cret")));
+        let vote = Message::Vote {
+            term: 7,
+            last_index: 0,
+            last_term: 0,
+        };
+
+        // the vote alone, and after a wrong token
+        for auth in ["", "AUTH guess\n"] {
+            let envelope = serde_json::to_string(&Envelope {
+                from: 9,
+                message: vote.clone(),
+            })
+            .unwrap();
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(format!("{auth}{envelope}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+        let outbox = connect(2, addr, Arc::from("s3cret"));
+        outbox.send(vote).await.unwrap();
+
+        let envelope = received.recv().await.unwrap();
+        assert_eq!(envelope.from, 2);
+        assert!(matches!(envelope.message, Message::Vote { term: 7, .. }));
+        let more = tokio::time::timeout(Duration::from_millis(200), received.recv()).await;
+        assert!(more.is_err(), "took a message without the token");
+    }
+}
@@ -0,0 +1,212 @@
+//! The last records of every client, for finding out why a balance is wrong
+//! on a live server without audit logging.
+//!
+//! With `--recent N` the last N entries of each client are kept in memory:
+//! the lines stream producers sent for it, and the engine's decision on each
+//! of its records (`applied`, `no_effect` when it changed nothing, e.g. a
+//! refused withdrawal, `filtered` by a hook, `deferred` until its effective
+//! date or `out_of_order` under `--deterministic`) with the balances it left.
+//! `GET /api/recent/{client}` returns a client's entries, oldest first. With
+//! `--recent-dump PATH` every client's entries are also written to PATH as
+//! JSON when the process panics. Lines go through `--redact` like recorded
+//! rejections.
+
+use crate::engine::Tx;
+use crate::events::Balances;
+use crate::log;
+use crate::redact;
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct RecentArgs {
+    /// Keep the last N received lines and engine decisions of every client
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    recent: Option<u64>,
+    /// Write the kept entries of every client to this file on a crash
+    #[arg(long, global = true, value_name = "PATH", requires = "recent")]
+    recent_dump: Option<PathBuf>,
+}
+
+impl RecentArgs {
+    /// Starts keeping entries and dumping them on panic, as requested.
+    pub(crate) fn install(&self) {
+        let Some(capacity) = self.recent else {
+            return;
+        };
+        RECENT.get_or_init(|| Recent {
+            capacity: capacity as usize,
+            clients: Mutex::new(HashMap::new()),
+        });
+        if let Some(path) = self.recent_dump.clone() {
+            let default = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                match dump(&path) {
+                    Ok(()) => log::info!("recent records dumped to {}", path.display()),
+                    Err(err) => log::error!("could not dump recent records: {err:#}"),
+                }
+                default(info)
+            }));
+        }
+    }
+}
+
+/// What the engine did with a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Decision {
+    Applied,
+    NoEffect,
+    Filtered,
+    Deferred,
+    OutOfOrder,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Entry {
+    /// A line received from a stream producer.
+    Received { at: u64, line: String },
+    /// The engine's decision on a record, with the balances it left.
+    Decided {
+        at: u64,
+        tx: u32,
+        #[serde(rename = "type")]
+        tx_type: String,
+        decision: Decision,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        after: Option<Balances>,
+    },
+}
+
+struct Recent {
+    capacity: usize,
+    clients: Mutex<HashMap<u16, VecDeque<Entry>>>,
+}
+
+// only set with `--recent`
+static RECENT: OnceLock<Recent> = OnceLock::new();
+
+pub(crate) fn enabled() -> bool {
+    RECENT.get().is_some()
+}
+
+fn push(client: u16, entry: Entry) {
+    let Some(recent) = RECENT.get() else {
+        return;
+    };
+    let mut clients = recent.clients.lock().unwrap_or_else(|e| e.into_inner());
+    let entries = clients.entry(client).or_default();
+    if entries.len() == recent.capacity {
+        entries.pop_front();
+    }
+    entries.push_back(entry);
+}
+
+/// Keeps a line a stream producer sent for `client`.
+pub(crate) fn received(client: u16, line: &str) {
+    if !enabled() {
+        return;
+    }
+    let at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let line = redact::record(line).into_owned();
+    push(client, Entry::Received { at, line });
+}
+
+/// Keeps the engine's decision on `tx` of `client` at engine time `at`.
+pub(crate) fn decided(client: u16, tx: &Tx, at: u64, decision: Decision, after: Option<Balances>) {
+    push(
+        client,
+        Entry::Decided {
+            at,
+            tx: tx.tx_id(),
+            tx_type: tx.type_name().to_owned(),
+            decision,
+            after,
+        },
+    );
+}
+
+/// The entries kept for `client`, oldest first; `None` without `--recent`.
+pub(crate) fn of(client: u16) -> Option<Vec<Entry>> {
+    let recent = RECENT.get()?;
+    let clients = recent.clients.lock().unwrap_or_else(|e| e.into_inner());
+    Some(
+        clients
+            .get(&client)
+            .map(|e| e.iter().cloned().collect())
+            .unwrap_or_default(),
+    )
+}
+
+fn dump(path: &Path) -> Result<()> {
+    let recent = RECENT.get().context("--recent is not set")?;
+    // a panic while the entries were being updated leaves them locked
+    let clients = match recent.clients.try_lock() {
+        Ok(clients) => clients,
+        Err(std::sync::TryLockError::Poisoned(err)) => err.into_inner(),
+        Err(std::sync::TryLockError::WouldBlock) => anyhow::bail!("entries are locked"),
+    };
+    let sorted: BTreeMap<_, _> = clients.iter().collect();
+    let body = serde_json::to_vec_pretty(&sorted)?;
+    std::fs::write(path, body).with_context(|| format!("could not write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::engine::TxEngine;
+
+    #[test]
+    fn test_keeps_last_entries_of_each_client() {
+        let args = RecentArgs {
+            recent: Some(3),
+            recent_dump: None,
+        };
+        args.install();
+        let mut engine = TxEngine::new();
+        received(60_001, "deposit, 60001, 1, 10.0");
+        for line in [
+            "deposit, 60001, 1, 10.0",
+            "withdrawal, 60001, 2, 50.0",
+            "deposit, 60001, 3, 1.0",
+        ] {
+            engine.apply_line(line);
+        }
+
+        // the received line was pushed out by the decisions
+        let entries = of(60_001).unwrap();
+        let decisions: Vec<Decision> = entries
+            .iter()
+            .filter_map(|e| match e {
+                Entry::Decided { decision, .. } => Some(*decision),
+                Entry::Received { .. } => None,
+            })
+            .collect();
+        assert_eq!(
+            decisions,
+            [Decision::Applied, Decision::NoEffect, Decision::Applied]
+        );
+        let Entry::Decided { after, .. } = &entries[2] else {
+            unreachable!()
+        };
+        assert_eq!(after.unwrap().total, Amount::from_units(11));
+        assert!(of(60_002).unwrap().is_empty());
+
+        let path = std::env::temp_dir().join(format!("roinstxs-recent-{}", std::process::id()));
+        dump(&path).unwrap();
+        let dumped: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dumped["60001"][1]["decision"], "no_effect");
+    }
+}
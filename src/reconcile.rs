@@ -0,0 +1,250 @@
+//! Reconciliation of processed balances against an externally provided file.
+//!
+//! The expected file uses the summary format, header included. Every
+//! difference larger than `--tolerance` is printed as a CSV row of
+//! `client,field,expected,actual` on stdout; accounts present on only one side
+//! are reported with field `account`. Balances are compared per currency,
+//! the rows then having a `currency` column after the client. Any
+//! discrepancy makes the run exit with the mismatch code.
+
+use crate::amount::Amount;
+use crate::engine::{Account, LockInfo, TxEngine, NO_CURRENCY};
+use crate::events::Balances;
+use crate::exit::Failure;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Args)]
+pub(crate) struct ReconcileArgs {
+    /// Transactions CSV to process
+    transactions: PathBuf,
+    /// Expected balances as `client,available,held,total,locked` rows, or
+    /// with a `currency` column after the client one per currency
+    expected: PathBuf,
+    /// Largest absolute difference between amounts still counted as a match
+    #[arg(long, default_value = "0.0001")]
+    tolerance: Amount,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Discrepancy {
+    client: u16,
+    currency: Box<str>,
+    field: &'static str,
+    expected: String,
+    actual: String,
+}
+
+/// Reads one summary row, with or without the `closed` column, or with both
+/// it and the lock columns.
+pub(crate) fn parse_summary_row(line: &str) -> Result<Account> {
+    let d: Vec<&str> = line.split(',').map(str::trim).collect();
+    anyhow::ensure!(
+        matches!(d.len(), 5 | 6 | 9),
+        "expected 5, 6 or 9 columns, got {}",
+        d.len()
+    );
+    Ok(Account {
+        client: d[0].parse().context("could not parse client to u16")?,
+        available: d[1].parse().context("could not parse available")?,
+        held: d[2].parse().context("could not parse held")?,
+        total: d[3].parse().context("could not parse total")?,
+        locked: d[4].parse().context("could not parse locked")?,
+        closed: match d.get(5) {
+            Some(closed) => closed.parse().context("could not parse closed")?,
+            None => false,
+        },
+        lock: match d.get(6..) {
+            Some([tx, rule, at]) if !rule.is_empty() => Some(LockInfo {
+                tx: match *tx {
+                    "" => None,
+                    tx => Some(tx.parse().context("could not parse lock_tx")?),
+                },
+                rule: rule.parse()?,
+                at: at.parse().context("could not parse locked_at")?,
+            }),
+            _ => None,
+        },
+        ..Default::default()
+    })
+}
+
+/// Reads one summary row into `accounts`; with `with_currency` the row has
+/// a `currency` column after the client and holds the balances of its
+/// client in that currency, the rows of a client merging into its account.
+pub(crate) fn merge_summary_row(
+    accounts: &mut BTreeMap<u16, Account>,
+    line: &str,
+    with_currency: bool,
+) -> Result<()> {
+    if !with_currency {
+        let account = parse_summary_row(line)?;
+        accounts.insert(account.client, account);
+        return Ok(());
+    }
+    let (client, rest) = line.split_once(',').context("expected a currency column")?;
+    let (currency, rest) = rest.split_once(',').context("expected a currency column")?;
+    let part = parse_summary_row(&format!("{client},{rest}"))?;
+    let account = accounts.entry(part.client).or_insert_with(|| Account {
+        client: part.client,
+        locked: part.locked,
+        closed: part.closed,
+        lock: part.lock,
+        ..Default::default()
+    });
+    let currency = Some(currency.trim()).filter(|c| *c != NO_CURRENCY);
+    account.set_currency(currency, Balances::from(&part));
+    Ok(())
+}
+
+/// Reads a summary CSV, header included, with or without a `currency`
+/// column.
+pub(crate) fn parse_expected(body: &str) -> Result<BTreeMap<u16, Account>> {
+    let mut accounts = BTreeMap::new();
+    let mut lines = body.lines().enumerate();
+    let with_currency = lines
+        .next()
+        .is_some_and(|(_, header)| header.split(',').nth(1).map(str::trim) == Some("currency"));
+    for (line_no, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        merge_summary_row(&mut accounts, line, with_currency)
+            .with_context(|| format!("line {}", line_no + 1))
+            .context(Failure::Parse)?;
+    }
+    Ok(accounts)
+}
+
+fn compare(engine: &TxEngine, expected: &BTreeMap<u16, Account>, tolerance: Amount) -> Vec<Discrepancy> {
+    let mut found = Vec::new();
+    for (&client, want) in expected {
+        let Some(got) = engine.account(client) else {
+            found.push(Discrepancy {
+                client,
+                currency: NO_CURRENCY.into(),
+                field: "account",
+                expected: "present".to_owned(),
+                actual: "missing".to_owned(),
+            });
+            continue;
+        };
+        let (want_parts, got_parts) = (want.currency_balances(), got.currency_balances());
+        let currencies: BTreeSet<&Box<str>> = want_parts.keys().chain(got_parts.keys()).collect();
+        for currency in currencies {
+            let want = want_parts.get(currency).copied().unwrap_or_default();
+            let got = got_parts.get(currency).copied().unwrap_or_default();
+            for (field, e, a) in [
+                ("available", want.available, got.available),
+                ("held", want.held, got.held),
+                ("total", want.total, got.total),
+            ] {
+                if (e - a).abs() > tolerance {
+                    found.push(Discrepancy {
+                        client,
+                        currency: currency.clone(),
+                        field,
+                        expected: e.to_string(),
+                        actual: a.to_string(),
+                    });
+                }
+            }
+        }
+        if want.locked != got.locked {
+            found.push(Discrepancy {
+                client,
+                currency: NO_CURRENCY.into(),
+                field: "locked",
+                expected: want.locked.to_string(),
+                actual: got.locked.to_string(),
+            });
+        }
+    }
+
+    let mut unexpected: Vec<u16> = engine
+        .accounts()
+        .map(|a| a.client)
+        .filter(|c| !expected.contains_key(c))
+        .collect();
+    unexpected.sort_unstable();
+    found.extend(unexpected.into_iter().map(|client| Discrepancy {
+        client,
+        currency: NO_CURRENCY.into(),
+        field: "account",
+        expected: "missing".to_owned(),
+        actual: "present".to_owned(),
+    }));
+    found
+}
+
+pub(crate) fn run(args: ReconcileArgs, mut engine: TxEngine) -> Result<()> {
+    crate::cli::process_file(&mut engine, &args.transactions, false)?;
+    let body = std::fs::read_to_string(&args.expected)
+        .with_context(|| format!("could not read {}", args.expected.display()))?;
+    let expected = parse_expected(&body)
+        .with_context(|| format!("invalid balance file {}", args.expected.display()))?;
+
+    let discrepancies = compare(&engine, &expected, args.tolerance);
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let with_currency = discrepancies.iter().any(|d| &*d.currency != NO_CURRENCY);
+    match with_currency {
+        true => writeln!(out, "client,currency,field,expected,actual")?,
+        false => writeln!(out, "client,field,expected,actual")?,
+    }
+    for d in &discrepancies {
+        match with_currency {
+            true => writeln!(
+                out,
+                "{},{},{},{},{}",
+                d.client, d.currency, d.field, d.expected, d.actual
+            )?,
+            false => writeln!(out, "{},{},{},{}", d.client, d.field, d.expected, d.actual)?,
+        }
+    }
+    out.flush()?;
+
+    if !discrepancies.is_empty() {
+        let mut clients: Vec<u16> = discrepancies.iter().map(|d| d.client).collect();
+        clients.dedup();
+        return Err(anyhow::anyhow!(
+            "{} discrepancies across {} clients",
+            discrepancies.len(),
+            clients.len()
+        ))
+        .context(Failure::Mismatch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Tx;
+
+    #[test]
+    fn test_compare_with_tolerance() {
+        let mut engine = TxEngine::new();
+        for line in ["deposit, 1, 1, 10.0", "deposit, 2, 2, 5.0", "deposit, 4, 3, 1.0"] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        let expected = parse_expected(
+            "client,available,held,total,locked\n\
+             1,10.0001,0,10,false\n\
+             2,4.5,0,4.5,true\n\
+             3,0,0,0,false\n",
+        )
+        .unwrap();
+
+        let found = compare(&engine, &expected, "0.0001".parse().unwrap());
+        let fields: Vec<(u16, &str)> = found.iter().map(|d| (d.client, d.field)).collect();
+        assert_eq!(
+            fields,
+            [(2, "available"), (2, "total"), (2, "locked"), (3, "account"), (4, "account")]
+        );
+        assert_eq!(found[3].actual, "missing");
+        assert!(parse_expected("header\n1,2,3\n").is_err());
+    }
+}
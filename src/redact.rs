@@ -0,0 +1,127 @@
+//! Redaction of client identifiers and amounts in logs.
+//!
+//! With `--redact` every log line and recorded rejection goes through these
+//! helpers: client ids are replaced by a short HMAC under a key drawn at
+//! startup, so one client can still be followed through a run's logs but not
+//! mapped back to an account, and amounts are masked. Script `print` output is
+//! free text, so every number in it is masked.
+
+use crate::amount::Amount;
+use hmac::{Hmac, KeyInit, Mac};
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::Sha256;
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+const MASK: &str = "***";
+
+// only set once redaction is enabled
+static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+pub(crate) fn enable() {
+    KEY.get_or_init(|| {
+        let mut key = [0; 32];
+        SystemRandom::new()
+            .fill(&mut key)
+            .expect("system random generator is available");
+        key
+    });
+}
+
+fn key() -> Option<&'static [u8]> {
+    KEY.get().map(|k| &k[..])
+}
+
+/// A client id as it may appear in logs.
+pub(crate) fn client(id: u16) -> String {
+    client_with(key(), id)
+}
+
+/// An amount as it may appear in logs.
+pub(crate) fn amount(value: Amount) -> String {
+    match key() {
+        Some(_) => MASK.to_owned(),
+        None => value.to_string(),
+    }
+}
+
+/// A raw input record as it may appear in logs: the type and tx columns are
+/// kept, the client is hashed and every later column masked.
+pub(crate) fn record(line: &str) -> Cow<'_, str> {
+    record_with(key(), line)
+}
+
+/// Free text as it may appear in logs, with every digit run masked.
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub(crate) fn text(s: &str) -> Cow<'_, str> {
+    text_with(key(), s)
+}
+
+fn client_with(key: Option<&[u8]>, id: u16) -> String {
+    let Some(key) = key else {
+        return id.to_string();
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any size");
+    mac.update(&id.to_be_bytes());
+    format!("c-{}", hex::encode(&mac.finalize().into_bytes()[..4]))
+}
+
+fn record_with<'a>(key: Option<&[u8]>, line: &'a str) -> Cow<'a, str> {
+    if key.is_none() {
+        return Cow::Borrowed(line);
+    }
+    let redacted: Vec<String> = line
+        .split(',')
+        .enumerate()
+        .map(|(i, field)| match i {
+            0 | 2 => field.to_owned(),
+            1 => match field.trim().parse() {
+                Ok(id) => client_with(key, id),
+                Err(_) => MASK.to_owned(),
+            },
+            _ => MASK.to_owned(),
+        })
+        .collect();
+    Cow::Owned(redacted.join(","))
+}
+
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+fn text_with<'a>(key: Option<&[u8]>, s: &'a str) -> Cow<'a, str> {
+    if key.is_none() || !s.contains(|c: char| c.is_ascii_digit()) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut in_number = false;
+    for c in s.chars() {
+        let digit = c.is_ascii_digit();
+        if digit && !in_number {
+            out.push_str(MASK);
+        } else if !digit {
+            out.push(c);
+        }
+        in_number = digit;
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redaction() {
+        let key = Some(&[7; 32][..]);
+        let hashed = client_with(key, 7);
+        assert!(hashed.starts_with("c-") && hashed.len() == 10);
+        assert_ne!(client_with(key, 8), hashed);
+        assert_ne!(client_with(Some(&[8; 32]), 7), hashed);
+        assert_eq!(client_with(None, 7), "7");
+
+        assert_eq!(
+            record_with(key, "deposit, 7, 12, 10.5"),
+            format!("deposit,{hashed}, 12,***")
+        );
+        assert_eq!(record_with(None, "deposit, 7, 12, 10.5"), "deposit, 7, 12, 10.5");
+        assert_eq!(text_with(key, "balance 1050.25 for 7"), "balance ***.*** for ***");
+    }
+}
@@ -0,0 +1,250 @@
+//! Report of the records that were not applied.
+//!
+//! With `--rejects PATH` every record the engine refused, e.g. a withdrawal
+//! exceeding the available funds, a dispute of an unknown transaction or a
+//! deposit to a locked account, and every line that could not be parsed is
+//! written to PATH, in file and stream mode alike. Each row holds the line of
+//! the record in the file, empty for streamed ones, its tx id when known, the
+//! reason and the input: the line as read for unparsable records, the record
+//! as the engine saw it otherwise. PATH is written as CSV, or as JSON lines
+//! when it ends in `.json`, `.jsonl` or `.ndjson`, and replaced on startup.
+//! Inputs go through `--redact` like recorded rejections. Refusals still go
+//! to stderr as before; silent ones, such as insufficient funds, are only
+//! reported here.
+//!
+//! Refusals are also counted by reason whether or not the report is written,
+//! for the `/metrics` route of `crate::http`. Refusals with an error of
+//! their own, such as a transfer naming no counterparty, are counted as
+//! `<type> refused`, keeping client ids and amounts out of the reasons.
+
+use crate::engine::Tx;
+use crate::log;
+use crate::redact;
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct RejectsArgs {
+    /// Write every refused or unparsable record to this CSV or JSON lines
+    /// report
+    #[arg(long, global = true, value_name = "PATH")]
+    rejects: Option<PathBuf>,
+}
+
+impl RejectsArgs {
+    /// Creates the report of `--rejects`, if set.
+    pub(crate) fn install(&self) -> Result<()> {
+        let Some(path) = &self.rejects else {
+            return Ok(());
+        };
+        let report = Report::create(path)?;
+        REJECTS.get_or_init(|| report);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct Row<'a> {
+    line: Option<u64>,
+    tx: Option<u32>,
+    reason: &'a str,
+    input: &'a str,
+}
+
+struct Report {
+    format: Format,
+    file: Mutex<LineWriter<File>>,
+}
+
+// only set with `--rejects`
+static REJECTS: OnceLock<Report> = OnceLock::new();
+// refusals since startup, by reason
+static REFUSED: Mutex<Option<HashMap<String, u64>>> = Mutex::new(None);
+
+impl Report {
+    fn create(path: &Path) -> Result<Self> {
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("json" | "jsonl" | "ndjson") => Format::Json,
+            _ => Format::Csv,
+        };
+        let mut file = LineWriter::new(
+            File::create(path).with_context(|| format!("could not create {}", path.display()))?,
+        );
+        if format == Format::Csv {
+            file.write_all(b"line,tx,reason,input\n")?;
+        }
+        Ok(Self {
+            format,
+            file: Mutex::new(file),
+        })
+    }
+
+    fn write(&self, row: &Row) -> Result<()> {
+        let mut bytes = match self.format {
+            Format::Json => serde_json::to_vec(row)?,
+            Format::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(Vec::new());
+                writer.serialize(row)?;
+                writer.into_inner()?
+            }
+        };
+        if !bytes.ends_with(b"\n") {
+            bytes.push(b'\n');
+        }
+        // one write per row, so that rows of concurrent shards and
+        // connections do not interleave
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+fn push(row: Row) {
+    let Some(report) = REJECTS.get() else {
+        return;
+    };
+    if let Err(err) = report.write(&row) {
+        log::error!("could not write the rejects report: {err:#}");
+    }
+}
+
+/// Reports a line that could not be parsed, at `line` of its file unless
+/// streamed.
+pub(crate) fn unparsed(line: Option<usize>, input: &str, err: &anyhow::Error) {
+    if REJECTS.get().is_none() {
+        return;
+    }
+    push(Row {
+        line: line.map(|l| l as u64),
+        tx: None,
+        reason: &format!("{err:#}"),
+        input: &redact::record(input),
+    });
+}
+
+/// Reports a record the engine refused for `reason`, a fixed phrase.
+pub(crate) fn refused(tx: &Tx, reason: &'static str) {
+    count(reason);
+    report(tx, &reason);
+}
+
+/// Reports a record the engine refused with `err`.
+pub(crate) fn refused_with(tx: &Tx, err: impl Display) {
+    count(&format!("{} refused", tx.tx_type().as_str()));
+    report(tx, &err);
+}
+
+fn count(reason: &str) {
+    let mut refused = REFUSED.lock().unwrap_or_else(|e| e.into_inner());
+    let counts = refused.get_or_insert_with(HashMap::new);
+    match counts.get_mut(reason) {
+        Some(n) => *n += 1,
+        None => {
+            counts.insert(reason.to_owned(), 1);
+        }
+    }
+}
+
+/// Refusals since startup, by reason.
+pub(crate) fn refusals() -> BTreeMap<String, u64> {
+    let refused = REFUSED.lock().unwrap_or_else(|e| e.into_inner());
+    refused
+        .iter()
+        .flatten()
+        .map(|(r, n)| (r.clone(), *n))
+        .collect()
+}
+
+fn report(tx: &Tx, reason: &dyn Display) {
+    if REJECTS.get().is_none() {
+        return;
+    }
+    let mut input = format!("{}, {}, {},", tx.type_name(), tx.client(), tx.tx_id());
+    if let Some(amount) = tx.amount() {
+        input.push_str(&format!(" {amount}"));
+    }
+    if let Some(counterparty) = tx.counterparty() {
+        input.push_str(&format!(", {counterparty}"));
+    }
+    push(Row {
+        line: tx.seq(),
+        tx: Some(tx.tx_id()),
+        reason: &reason.to_string(),
+        input: &redact::record(&input),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TxEngine;
+
+    #[test]
+    fn test_report_rows() {
+        let path =
+            std::env::temp_dir().join(format!("roinstxs-rejects-{}.csv", std::process::id()));
+        let report = Report::create(&path).unwrap();
+        report
+            .write(&Row {
+                line: Some(3),
+                tx: None,
+                reason: "invalid digit found in string",
+                input: "deposit, 1, x, 1.0",
+            })
+            .unwrap();
+        drop(report);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "line,tx,reason,input\n3,,invalid digit found in string,\"deposit, 1, x, 1.0\"\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // the engine's refusals, as the installed report gets them; other
+        // tests may report theirs meanwhile
+        let path = path.with_extension("jsonl");
+        RejectsArgs {
+            rejects: Some(path.clone()),
+        }
+        .install()
+        .unwrap();
+        let mut engine = TxEngine::new();
+        for (line_no, line) in [
+            "deposit, 60002, 1, 10.0",
+            "withdrawal, 60002, 2, 50.0",
+            "dispute, 60002, 9,",
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            engine.try_apply(line.parse::<Tx>().unwrap().with_seq(line_no as u64 + 1));
+        }
+        let rows: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .filter(|row: &serde_json::Value| row["input"].as_str().unwrap().contains("60002"))
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["line"], 2);
+        assert_eq!(rows[0]["reason"], "insufficient funds");
+        assert_eq!(rows[0]["input"], "withdrawal, 60002, 2, 50");
+        assert_eq!(rows[1]["tx"], 9);
+        assert_eq!(rows[1]["reason"], "unknown transaction");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
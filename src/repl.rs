@@ -0,0 +1,146 @@
+//! Interactive shell over a live engine, for exploring engine behaviour by hand.
+
+use crate::engine::{Tx, TxEngine};
+use crate::error::EngineError;
+use crate::output::{Output, OutputFormat};
+use anyhow::{Context, Result};
+use std::io::{BufRead, IsTerminal, Write};
+
+const HELP: &str = "\
+commands:
+  deposit <client> <tx> <amount>
+  withdrawal <client> <tx> <amount>
+  dispute <client> <tx>
+  resolve <client> <tx>
+  chargeback <client> <tx>
+  show <client>        print a single account
+  erase <client>       delete everything held about a client
+  summary              print every account
+  help                 show this message
+  quit                 leave the shell";
+
+const TX_COMMANDS: [&str; 5] = ["deposit", "withdrawal", "dispute", "resolve", "chargeback"];
+
+/// Runs one command against `engine`, writing its response to `out`.
+/// Returns `false` once the session should end.
+fn execute(
+    engine: &mut TxEngine,
+    output: &Output,
+    line: &str,
+    mut out: impl Write,
+) -> Result<bool> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&command, args)) = words.split_first() else {
+        return Ok(true);
+    };
+
+    match command {
+        "quit" | "exit" => return Ok(false),
+        "help" => writeln!(out, "{HELP}")?,
+        "summary" => output.write(engine, &mut out)?,
+        "show" => {
+            let client: u16 = args
+                .first()
+                .context("usage: show <client>")?
+                .parse()
+                .context("client must be a number")?;
+            match engine.account(client) {
+                Some(account) => writeln!(
+                    out,
+                    "client {}: available {} held {} total {}{}",
+                    account.client,
+                    account.available,
+                    account.held,
+                    account.total,
+                    if account.locked { " (locked)" } else { "" }
+                )?,
+                None => writeln!(out, "client {client} has no account")?,
+            }
+        }
+        "erase" => {
+            let client: u16 = args
+                .first()
+                .context("usage: erase <client>")?
+                .parse()
+                .context("client must be a number")?;
+            writeln!(out, "{}", serde_json::to_string(&engine.erase_client(client))?)?;
+        }
+        _ if TX_COMMANDS.contains(&command) => {
+            let tx = Tx::from_str(&words.join(","))?;
+            match engine.process_tx(tx) {
+                Ok(()) => writeln!(out, "ok")?,
+                Err(EngineError::Refused(reason)) => writeln!(out, "refused: {reason}")?,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        _ => anyhow::bail!("unknown command {command}, try `help`"),
+    }
+    Ok(true)
+}
+
+pub(crate) fn run(no_color: bool) -> Result<()> {
+    let mut engine = TxEngine::new();
+    let output = Output::new(OutputFormat::Human, no_color);
+    let interactive = std::io::stdin().is_terminal();
+    let mut stdout = std::io::stdout();
+
+    if interactive {
+        println!("roinstxs repl, type `help` for commands");
+    }
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("> ");
+            stdout.flush()?;
+        }
+        let Some(line) = lines.next() else {
+            break;
+        };
+        match execute(&mut engine, &output, &line?, &mut stdout) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => eprintln!("error: {err:#}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_script(script: &[&str]) -> String {
+        let mut engine = TxEngine::new();
+        let output = Output::default();
+        let mut out = Vec::new();
+        for line in script {
+            execute(&mut engine, &output, line, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let out = run_script(&[
+            "deposit 1 10 5.0",
+            "dispute 1 10",
+            "withdrawal 1 11 1.0",
+            "show 1",
+            "show 2",
+        ]);
+        assert_eq!(
+            out,
+            "ok\nok\nrefused: insufficient funds\n\
+             client 1: available 0 held 5 total 5\nclient 2 has no account\n"
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_commands() {
+        let mut engine = TxEngine::new();
+        let output = Output::default();
+        assert!(execute(&mut engine, &output, "bonus 1 2 3", std::io::sink()).is_err());
+        assert!(execute(&mut engine, &output, "show x", std::io::sink()).is_err());
+        assert!(!execute(&mut engine, &output, "quit", std::io::sink()).unwrap());
+    }
+}
@@ -0,0 +1,207 @@
+//! Rebuilding the accounts from the change log alone.
+//!
+//! `replay EVENTS` reads the NDJSON change records written by `--cdc` and
+//! reconstructs every account from them, without the transactions: balance
+//! records set the balances, `lock` and `close` records the flags and `erase`
+//! records drop the account, balances being kept per currency once a record
+//! names one. Each balance record must follow from the one before it for its
+//! client and currency, its delta taking the previous balances to the new
+//! ones, so a lost or reordered record fails the replay. The accounts are
+//! printed as a summary, or with `--against SNAPSHOT` compared to a snapshot
+//! taken at the same point as in `snapshot-diff`, the rebuilt accounts being
+//! `before` and the snapshot `after`, independently checking the snapshots
+//! against the log. The log must go back to the engine's first record, and
+//! accounts archived by `--archive-after` are rebuilt though snapshots leave
+//! them out.
+
+use crate::amount::Amount;
+use crate::cdc::CdcRecord;
+use crate::crypt::KeyArgs;
+use crate::engine::Account;
+use crate::exit::Failure;
+use crate::output::Output;
+use crate::snapshot_diff;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::path::PathBuf;
+
+#[derive(Debug, Args)]
+pub(crate) struct ReplayArgs {
+    /// Change records written by --cdc
+    events: PathBuf,
+    /// Compare the rebuilt accounts to this snapshot instead of printing them
+    #[arg(long, value_name = "SNAPSHOT")]
+    against: Option<PathBuf>,
+    /// Largest absolute difference between amounts still counted as a match
+    #[arg(long, default_value = "0.0001")]
+    tolerance: Amount,
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+/// Accounts rebuilt from change records.
+#[derive(Debug, Default)]
+struct Replay {
+    accounts: BTreeMap<u16, Account>,
+    tolerance: Amount,
+}
+
+impl Replay {
+    fn apply(&mut self, record: CdcRecord) -> Result<()> {
+        match record {
+            CdcRecord::Balance {
+                client,
+                tx,
+                currency,
+                delta,
+                after,
+                ..
+            } => {
+                let account = self.accounts.entry(client).or_insert_with(|| Account {
+                    client,
+                    ..Default::default()
+                });
+                let before = account.in_currency(currency.as_deref());
+                let gap = [
+                    before.available + delta.available - after.available,
+                    before.held + delta.held - after.held,
+                    before.total + delta.total - after.total,
+                ];
+                anyhow::ensure!(
+                    gap.iter().all(|g| g.abs() <= self.tolerance),
+                    "tx {tx} of client {client} does not follow from its balances \
+                     (available {}, held {}, total {})",
+                    before.available,
+                    before.held,
+                    before.total
+                );
+                account.set_currency(currency.as_deref(), after);
+            }
+            CdcRecord::Lock { client, lock } => {
+                let account = self
+                    .accounts
+                    .get_mut(&client)
+                    .with_context(|| format!("client {client} is locked but has no account"))?;
+                account.locked = true;
+                account.lock = Some(lock);
+            }
+            CdcRecord::Unlock { client } => {
+                let account = self
+                    .accounts
+                    .get_mut(&client)
+                    .with_context(|| format!("client {client} is unlocked but has no account"))?;
+                account.locked = false;
+                account.lock = None;
+            }
+            CdcRecord::Close { client, tx } => self.account(client, tx)?.closed = true,
+            // the balances moved are in the balance record that comes with it
+            CdcRecord::Hold { .. } => {}
+            CdcRecord::Erase { client } => {
+                self.accounts.remove(&client);
+            }
+        }
+        Ok(())
+    }
+
+    fn account(&mut self, client: u16, tx: u32) -> Result<&mut Account> {
+        self.accounts
+            .get_mut(&client)
+            .with_context(|| format!("tx {tx} changes client {client}, which has no account"))
+    }
+}
+
+pub(crate) fn run(args: ReplayArgs) -> Result<()> {
+    let file = std::fs::File::open(&args.events)
+        .with_context(|| format!("could not open {}", args.events.display()))?;
+    let mut replay = Replay {
+        tolerance: args.tolerance,
+        ..Default::default()
+    };
+    for (line_no, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .with_context(|| format!("line {}", line_no + 1))
+            .context(Failure::Parse)?;
+        replay
+            .apply(record)
+            .with_context(|| format!("line {}", line_no + 1))
+            .context(Failure::Invariant)?;
+    }
+
+    match &args.against {
+        Some(snapshot) => {
+            let expected = snapshot_diff::read_snapshot(snapshot, &args.key)?.accounts;
+            snapshot_diff::report(&snapshot_diff::diff(
+                &replay.accounts,
+                &expected,
+                args.tolerance,
+            ))
+        }
+        None => {
+            let accounts: Vec<&Account> = replay.accounts.values().collect();
+            Output::default().write_accounts(&accounts, std::io::stdout().lock())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Tx, TxEngine};
+    use crate::events::Event;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_replay_matches_engine() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = TxEngine::new();
+        let log = records.clone();
+        engine.subscribe(move |event: &Event| {
+            if let Some(record) = CdcRecord::from_event(event) {
+                log.lock().unwrap().push(record);
+            }
+        });
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "hold, 2, 3, 2.0, legal",
+            "dispute, 1, 1,",
+            "chargeback, 1, 1,",
+            "deposit, 3, 4, 1.0",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        for line in ["deposit, 4, 5, 3.0,, EUR", "deposit, 4, 6, 2.0,, USD"] {
+            let tx = crate::schema::Schema::V2.parse(line).unwrap();
+            engine.process_tx(tx).unwrap();
+        }
+        engine.erase_client(3);
+
+        let records = std::mem::take(&mut *records.lock().unwrap());
+        let new = || Replay {
+            tolerance: Amount::ZERO,
+            ..Default::default()
+        };
+        let mut replay = new();
+        for record in records.iter().cloned() {
+            replay.apply(record).unwrap();
+        }
+        let expected: BTreeMap<u16, Account> =
+            engine.accounts().map(|a| (a.client, a.clone())).collect();
+        assert!(snapshot_diff::diff(&replay.accounts, &expected, Amount::ZERO).is_empty());
+        assert!(replay.accounts[&1].locked);
+        let currencies = &engine.account(4).unwrap().currencies;
+        assert_eq!(&replay.accounts[&4].currencies, currencies);
+        assert!(!replay.accounts.contains_key(&3));
+
+        // a record applied twice, or lost, breaks the chain of balances
+        let mut duplicated = new();
+        duplicated.apply(records[0].clone()).unwrap();
+        assert!(duplicated.apply(records[0].clone()).is_err());
+    }
+}
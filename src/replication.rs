@@ -0,0 +1,414 @@
+//! Streaming the primary's accounts to read replicas.
+//!
+//! A server started with `--replicate-listen` accepts replicas on that
+//! address, or on loopback when given a bare port. Under `--stream-token`
+//! replicas must open with the same `AUTH <token>` line as producers, see
+//! `auth`, and a replica passes the token of its own `--stream-token`. A
+//! listener reachable from other hosts requires the token. Each new replica first gets every account, then a `synced`
+//! marker, then every `--replicate-every` the full state of the accounts
+//! changed since the last batch and an `erase` for erased clients, as NDJSON
+//! lines stamped with the primary's sequence number. Changed accounts are
+//! tracked through engine events like snapshot deltas are.
+//!
+//! A server started with `--replica-of` follows such a primary instead of
+//! accepting transactions: it mirrors the accounts into its own engine and
+//! serves them through `--http` and `--tui`, taking query and reporting
+//! traffic off the primary. It reconnects when the stream breaks; accounts
+//! missing from the next full sync are dropped. A replica too slow to keep up
+//! is disconnected by the primary and resyncs the same way. Transaction
+//! history and disputes stay on the primary.
+
+use crate::auth;
+use crate::engine::{Account, TxEngine};
+use crate::events::Event;
+use crate::loadgen::parse_duration;
+use crate::log;
+use crate::metrics::Metrics;
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Mutex};
+
+// batches a replica may fall behind by before it is disconnected
+const BACKLOG: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct ReplicationArgs {
+    /// Stream account changes to replicas connecting to this address, e.g.
+    /// 0.0.0.0:7070, or to this port on loopback
+    #[arg(long, global = true, value_name = "ADDR", value_parser = parse_listen, conflicts_with = "replica_of")]
+    replicate_listen: Option<SocketAddr>,
+    /// How often changed accounts are sent to replicas
+    #[arg(long, global = true, value_parser = parse_duration, default_value = "100ms", requires = "replicate_listen")]
+    replicate_every: Duration,
+    /// Mirror the primary replicating on this address instead of accepting transactions
+    #[arg(long, global = true, value_name = "ADDR", conflicts_with = "schedule")]
+    replica_of: Option<SocketAddr>,
+}
+
+// a bare port listens on loopback only
+fn parse_listen(v: &str) -> Result<SocketAddr> {
+    match v.parse::<u16>() {
+        Ok(port) => Ok((Ipv4Addr::LOCALHOST, port).into()),
+        Err(_) => v.parse().with_context(|| format!("invalid address {v:?}")),
+    }
+}
+
+impl ReplicationArgs {
+    pub(crate) fn replica_of(&self) -> Option<SocketAddr> {
+        self.replica_of
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Update {
+    Account {
+        seq: u64,
+        account: Account,
+    },
+    Erase {
+        seq: u64,
+        client: u16,
+    },
+    /// Ends the full state sent to a newly connected replica.
+    Synced {
+        seq: u64,
+    },
+}
+
+fn push_line(batch: &mut String, update: &Update) {
+    batch.push_str(&serde_json::to_string(update).expect("updates serialize"));
+    batch.push('\n');
+}
+
+/// The primary's side: change tracking and the replica listener.
+pub(crate) struct Primary {
+    addr: SocketAddr,
+    token: Option<Arc<str>>,
+    every: Duration,
+    changed: Arc<std::sync::Mutex<HashSet<u16>>>,
+    erased: Arc<std::sync::Mutex<HashSet<u16>>>,
+}
+
+impl Primary {
+    /// The primary of `--replicate-listen`, taking replicas opening with
+    /// `token`. Fails for a listener other hosts reach without a token.
+    pub(crate) fn new(args: &ReplicationArgs, token: Option<Arc<str>>) -> Result<Option<Self>> {
+        let Some(addr) = args.replicate_listen else {
+            return Ok(None);
+        };
+        anyhow::ensure!(
+            addr.ip().is_loopback() || token.is_some(),
+            "--replicate-listen on {addr} needs --stream-token, as other hosts can reach it"
+        );
+        Ok(Some(Self {
+            addr,
+            token,
+            every: args.replicate_every,
+            changed: Arc::default(),
+            erased: Arc::default(),
+        }))
+    }
+
+    /// Engine observer recording which accounts changed or were erased.
+    pub(crate) fn observer(&self) -> impl FnMut(&Event) + Send + 'static {
+        let (changed, erased) = (self.changed.clone(), self.erased.clone());
+        move |event: &Event| match *event {
+            Event::BalanceChanged { client, .. }
+            | Event::AccountLocked { client, .. }
+            | Event::AccountUnlocked { client }
+            | Event::AccountClosed { client, .. }
+            | Event::AdminHold { client, .. } => {
+                changed.lock().unwrap().insert(client);
+            }
+            Event::ClientErased { client } => {
+                erased.lock().unwrap().insert(client);
+            }
+            _ => {}
+        }
+    }
+
+    // every account, then the marker ending the sync
+    fn full_sync(engine: &TxEngine) -> String {
+        let seq = engine.seq();
+        let mut batch = String::new();
+        for account in engine.accounts() {
+            let account = account.clone();
+            push_line(&mut batch, &Update::Account { seq, account });
+        }
+        push_line(&mut batch, &Update::Synced { seq });
+        batch
+    }
+
+    // taken while the caller holds the engine, so no change slips between the
+    // sets and the balances
+    fn changes(&self, engine: &TxEngine) -> String {
+        let changed = std::mem::take(&mut *self.changed.lock().unwrap());
+        let erased = std::mem::take(&mut *self.erased.lock().unwrap());
+        let seq = engine.seq();
+        let mut batch = String::new();
+        for &client in erased.iter().filter(|c| engine.account(**c).is_none()) {
+            push_line(&mut batch, &Update::Erase { seq, client });
+        }
+        // accounts archived since their change are not sent; replicas keep
+        // their last state
+        for client in changed.union(&erased) {
+            if let Some(account) = engine.account(*client) {
+                let account = account.clone();
+                push_line(&mut batch, &Update::Account { seq, account });
+            }
+        }
+        batch
+    }
+
+    pub(crate) async fn run(
+        self,
+        engine: Arc<Mutex<TxEngine>>,
+        metrics: Arc<Metrics>,
+    ) -> Result<()> {
+        let listener = TcpListener::bind(self.addr)
+            .await
+            .with_context(|| format!("could not bind {}", self.addr))?;
+        let (batches, _) = broadcast::channel::<Arc<str>>(BACKLOG);
+        let mut ticker = tokio::time::interval(self.every);
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (socket, peer) = accepted?;
+                    // subscribed under the engine lock, so the replica misses
+                    // no batch taken after its full sync
+                    let (sync, rx) = {
+                        let engine = engine.lock().await;
+                        (Self::full_sync(&engine), batches.subscribe())
+                    };
+                    let (token, metrics) = (self.token.clone(), metrics.clone());
+                    tokio::spawn(async move {
+                        let served = serve_replica(socket, token, &metrics, sync, rx);
+                        if let Err(err) = served.await {
+                            log::warn!("replication: {peer}: {err:#}");
+                        }
+                    });
+                }
+                _ = ticker.tick() => {
+                    let batch = self.changes(&*engine.lock().await);
+                    if !batch.is_empty() {
+                        // no receivers just means no replica is connected
+                        let _ = batches.send(batch.into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn serve_replica(
+    mut socket: TcpStream,
+    token: Option<Arc<str>>,
+    metrics: &Metrics,
+    sync: String,
+    mut batches: broadcast::Receiver<Arc<str>>,
+) -> Result<()> {
+    if let Some(token) = token {
+        let (reader, mut writer) = socket.split();
+        let mut reader = BufReader::new(reader);
+        if !auth::handshake(&mut reader, &mut writer, &token, metrics).await {
+            anyhow::bail!("replica did not open with the stream token, disconnecting");
+        }
+    }
+    socket.write_all(sync.as_bytes()).await?;
+    loop {
+        match batches.recv().await {
+            Ok(batch) => socket.write_all(batch.as_bytes()).await?,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                anyhow::bail!("replica fell {missed} batches behind, disconnecting")
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// A replica's view of the stream: which clients the current full sync has
+/// named, until its `synced` marker arrives.
+#[derive(Default)]
+struct Follower {
+    syncing: Option<HashSet<u16>>,
+}
+
+impl Follower {
+    fn connected() -> Self {
+        Self {
+            syncing: Some(HashSet::new()),
+        }
+    }
+
+    fn apply(&mut self, engine: &mut TxEngine, update: Update) {
+        match update {
+            Update::Account { seq, account } => {
+                if let Some(seen) = &mut self.syncing {
+                    seen.insert(account.client);
+                }
+                engine.mirror(account.client, Some(account), seq);
+            }
+            Update::Erase { seq, client } => engine.mirror(client, None, seq),
+            Update::Synced { seq } => {
+                let seen = self.syncing.take().unwrap_or_default();
+                let stale: Vec<u16> = engine
+                    .accounts()
+                    .map(|a| a.client)
+                    .filter(|c| !seen.contains(c))
+                    .collect();
+                for client in stale {
+                    engine.mirror(client, None, seq);
+                }
+            }
+        }
+    }
+}
+
+/// Mirrors the primary at `addr` into `engine`, opening with `token` if any,
+/// reconnecting forever.
+pub(crate) async fn follow(
+    addr: SocketAddr,
+    token: Option<Arc<str>>,
+    engine: Arc<Mutex<TxEngine>>,
+) -> Result<()> {
+    loop {
+        if let Err(err) = follow_once(addr, token.as_deref(), &engine).await {
+            log::warn!("replication: {addr}: {err:#}");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn follow_once(
+    addr: SocketAddr,
+    token: Option<&str>,
+    engine: &Mutex<TxEngine>,
+) -> Result<()> {
+    let mut socket = TcpStream::connect(addr).await?;
+    if let Some(token) = token {
+        let auth = format!("{}{token}\n", auth::AUTH_COMMAND);
+        socket.write_all(auth.as_bytes()).await?;
+    }
+    let mut lines = BufReader::new(socket).lines();
+    let mut follower = Follower::connected();
+    while let Some(line) = lines.next_line().await? {
+        if line == format!("ERR {}", auth::UNAUTHENTICATED) {
+            anyhow::bail!("the primary refused the stream token");
+        }
+        let update = serde_json::from_str(&line).context("invalid replication record")?;
+        follower.apply(&mut *engine.lock().await, update);
+    }
+    anyhow::bail!("primary closed the stream")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::engine::Tx;
+
+    fn replay(replica: &mut TxEngine, follower: &mut Follower, batch: &str) {
+        for line in batch.lines() {
+            follower.apply(replica, serde_json::from_str(line).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_replica_mirrors_primary() {
+        let args = ReplicationArgs {
+            replicate_listen: Some("127.0.0.1:0".parse().unwrap()),
+            replicate_every: Duration::from_millis(100),
+            replica_of: None,
+        };
+        let primary = Primary::new(&args, None).unwrap().unwrap();
+        let mut engine = TxEngine::new();
+        engine.subscribe(primary.observer());
+        let mut replica = TxEngine::new();
+        // left over from an earlier connection, gone from the primary since
+        replica.mirror(
+            9,
+            Some(Account {
+                client: 9,
+                ..Default::default()
+            }),
+            1,
+        );
+
+        for line in ["deposit, 1, 1, 10.0", "deposit, 2, 2, 5.0"] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        let mut follower = Follower::connected();
+        replay(&mut replica, &mut follower, &Primary::full_sync(&engine));
+        assert!(replica.account(9).is_none());
+
+        for line in [
+            "withdrawal, 1, 3, 4.0",
+            "dispute, 2, 2,",
+            "chargeback, 2, 2,",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        engine.erase_client(1);
+        replay(&mut replica, &mut follower, &primary.changes(&engine));
+
+        assert!(replica.account(1).is_none());
+        let mirrored = replica.account(2).unwrap();
+        assert!(mirrored.locked);
+        assert_eq!(mirrored.total, Amount::ZERO);
+        assert_eq!(replica.seq(), engine.seq());
+        assert!(primary.changes(&engine).is_empty());
+    }
+
+    #[test]
+    fn test_listeners_reachable_from_other_hosts_need_the_token() {
+        let loopback: SocketAddr = "127.0.0.1:7070".parse().unwrap();
+        assert_eq!(parse_listen("7070").unwrap(), loopback);
+        let args = |addr: &str| ReplicationArgs {
+            replicate_listen: Some(parse_listen(addr).unwrap()),
+            replicate_every: Duration::from_millis(100),
+            replica_of: None,
+        };
+        assert!(Primary::new(&args("7070"), None).unwrap().is_some());
+        assert!(Primary::new(&args("0.0.0.0:7070"), None).is_err());
+        let token = Some(Arc::from("s3cret"));
+        let primary = Primary::new(&args("0.0.0.0:7070"), token).unwrap();
+        assert!(primary.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_replicas_must_open_with_the_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Metrics::default();
+        for (auth, expected) in [
+            ("AUTH s3cret\n", "{\"op\":\"synced\",\"seq\":0}\n"),
+            ("AUTH guess\n", "ERR unauthenticated\n"),
+        ] {
+            let mut replica = TcpStream::connect(addr).await.unwrap();
+            replica.write_all(auth.as_bytes()).await.unwrap();
+            let (socket, _) = listener.accept().await.unwrap();
+            let sync = Primary::full_sync(&TxEngine::new());
+            let (batches, rx) = broadcast::channel(BACKLOG);
+            let token = Some(Arc::from("s3cret"));
+            let served = serve_replica(socket, token, &metrics, sync, rx);
+            drop(batches);
+            let refused = served.await.is_err();
+
+            let mut received = String::new();
+            tokio::io::AsyncReadExt::read_to_string(&mut replica, &mut received)
+                .await
+                .unwrap();
+            assert_eq!(received, expected);
+            assert_eq!(refused, auth.contains("guess"));
+        }
+    }
+}
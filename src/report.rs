@@ -0,0 +1,482 @@
+//! Reports computed over the final account book, printed instead of the
+//! summary when requested.
+
+use crate::amount::Amount;
+use crate::engine::{Account, TxEngine, TxType};
+use crate::events::{Event, Observer};
+use crate::output::Output;
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct ReportArgs {
+    /// Print a distribution of available balances instead of the summary
+    #[arg(long, global = true, conflicts_with = "partitions")]
+    histogram: bool,
+    /// Ascending bucket edges for --histogram
+    #[arg(
+        long,
+        global = true,
+        value_delimiter = ',',
+        default_value = "0,1,10,100,1000,10000,100000",
+        requires = "histogram"
+    )]
+    buckets: Vec<Amount>,
+    /// Print only the N accounts with the largest --by balance, largest first
+    #[arg(long, global = true, value_name = "N", conflicts_with_all = ["histogram", "partitions"])]
+    top: Option<usize>,
+    /// Balance ranked by --top
+    #[arg(long, global = true, value_enum, default_value_t, requires = "top")]
+    by: Balance,
+    /// Print run-level aggregates as JSON instead of the summary
+    #[arg(long, global = true, conflicts_with_all = ["histogram", "top", "partitions"])]
+    stats: bool,
+    /// List clients whose dispute or chargeback ratio exceeds the thresholds below
+    #[arg(long, global = true, conflicts_with_all = ["histogram", "top", "stats", "partitions"])]
+    dispute_ratios: bool,
+    /// Flag clients disputing more than this share of their transactions
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 0.1,
+        requires = "dispute_ratios"
+    )]
+    dispute_threshold: f64,
+    /// Flag clients charging back more than this share of their transactions
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 0.02,
+        requires = "dispute_ratios"
+    )]
+    chargeback_threshold: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Balance {
+    Available,
+    #[default]
+    Held,
+    Total,
+}
+
+impl Balance {
+    fn of(self, account: &Account) -> Amount {
+        match self {
+            Self::Available => account.available,
+            Self::Held => account.held,
+            Self::Total => account.total,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Report {
+    /// Available balances counted per `[edge, next edge)` bucket.
+    Histogram { edges: Vec<Amount> },
+    /// The largest `n` accounts by one balance.
+    Top { n: usize, by: Balance },
+    /// Aggregates collected from engine events while the input is processed.
+    Stats(Arc<Mutex<RunStats>>),
+    /// Clients with suspicious dispute or chargeback ratios.
+    DisputeRatios {
+        activity: Arc<Mutex<HashMap<u16, ClientActivity>>>,
+        dispute_threshold: f64,
+        chargeback_threshold: f64,
+    },
+}
+
+impl ReportArgs {
+    pub(crate) fn report(&self) -> Result<Option<Report>> {
+        if self.stats {
+            return Ok(Some(Report::Stats(Arc::default())));
+        }
+        if self.dispute_ratios {
+            return Ok(Some(Report::DisputeRatios {
+                activity: Arc::default(),
+                dispute_threshold: self.dispute_threshold,
+                chargeback_threshold: self.chargeback_threshold,
+            }));
+        }
+        if let Some(n) = self.top {
+            return Ok(Some(Report::Top { n, by: self.by }));
+        }
+        if !self.histogram {
+            return Ok(None);
+        }
+        anyhow::ensure!(
+            self.buckets.windows(2).all(|w| w[0] < w[1]),
+            "--buckets must be strictly ascending"
+        );
+        Ok(Some(Report::Histogram {
+            edges: self.buckets.clone(),
+        }))
+    }
+}
+
+impl Report {
+    /// Engine observer the report needs while the input is processed, if any.
+    pub(crate) fn observer(&self) -> Option<Observer> {
+        match self {
+            Self::Stats(stats) => {
+                let stats = stats.clone();
+                Some(Box::new(move |event: &Event| stats.lock().unwrap().record(event)))
+            }
+            Self::DisputeRatios { activity, .. } => {
+                let activity = activity.clone();
+                Some(Box::new(move |event: &Event| {
+                    let mut activity = activity.lock().unwrap();
+                    match *event {
+                        Event::BalanceChanged { client, cause, .. } => {
+                            activity.entry(client).or_default().record(cause)
+                        }
+                        Event::ClientErased { client } => {
+                            activity.remove(&client);
+                        }
+                        _ => {}
+                    }
+                }))
+            }
+            Self::Histogram { .. } | Self::Top { .. } => None,
+        }
+    }
+
+    pub(crate) fn write(&self, engine: &TxEngine, output: &Output, mut w: impl Write) -> Result<()> {
+        match self {
+            Self::Histogram { edges } => write_histogram(engine, edges, w),
+            Self::Top { n, by } => output.write_accounts(&top(engine, *n, *by), w),
+            Self::Stats(stats) => {
+                let mut json = stats.lock().unwrap().to_json();
+                json["pending"] = json!(engine.pending());
+                json["locked"] = json!(engine.locked_records());
+                serde_json::to_writer_pretty(&mut w, &json)?;
+                writeln!(w)?;
+                Ok(())
+            }
+            Self::DisputeRatios {
+                activity,
+                dispute_threshold,
+                chargeback_threshold,
+            } => {
+                let activity = activity.lock().unwrap();
+                let flagged = flag_clients(&activity, *dispute_threshold, *chargeback_threshold);
+                let mut writer = BufWriter::new(w);
+                writeln!(writer, "client,txs,disputes,chargebacks,dispute_ratio,chargeback_ratio")?;
+                for (client, a) in flagged {
+                    writeln!(
+                        writer,
+                        "{},{},{},{},{},{}",
+                        output.client_label(client),
+                        a.txs,
+                        a.disputes,
+                        a.chargebacks,
+                        a.dispute_ratio(),
+                        a.chargeback_ratio()
+                    )?;
+                }
+                writer.flush()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Applied transactions of one client, counted from engine events.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ClientActivity {
+    /// Deposits and withdrawals.
+    txs: u64,
+    disputes: u64,
+    chargebacks: u64,
+}
+
+impl ClientActivity {
+    fn record(&mut self, cause: TxType) {
+        match cause {
+            TxType::Deposit | TxType::Withdrawal => self.txs += 1,
+            TxType::Dispute => self.disputes += 1,
+            TxType::Chargeback => self.chargebacks += 1,
+            TxType::Resolve
+            | TxType::Close
+            | TxType::Hold
+            | TxType::Release
+            | TxType::Correction
+            | TxType::Transfer
+            | TxType::Unlock
+            | TxType::Custom
+            | TxType::Noop => {}
+        }
+    }
+
+    fn dispute_ratio(&self) -> f64 {
+        self.disputes as f64 / self.txs.max(1) as f64
+    }
+
+    fn chargeback_ratio(&self) -> f64 {
+        self.chargebacks as f64 / self.txs.max(1) as f64
+    }
+}
+
+/// Clients over either threshold, worst chargeback ratio first.
+fn flag_clients(
+    activity: &HashMap<u16, ClientActivity>,
+    dispute_threshold: f64,
+    chargeback_threshold: f64,
+) -> Vec<(u16, ClientActivity)> {
+    let mut flagged: Vec<(u16, ClientActivity)> = activity
+        .iter()
+        .filter(|(_, a)| {
+            a.dispute_ratio() > dispute_threshold || a.chargeback_ratio() > chargeback_threshold
+        })
+        .map(|(c, a)| (*c, *a))
+        .collect();
+    flagged.sort_unstable_by(|(ca, a), (cb, b)| {
+        b.chargeback_ratio()
+            .total_cmp(&a.chargeback_ratio())
+            .then(b.dispute_ratio().total_cmp(&a.dispute_ratio()))
+            .then(ca.cmp(cb))
+    });
+    flagged
+}
+
+/// Money and dispute flow over a whole run. Only applied transactions count.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RunStats {
+    deposited: Amount,
+    withdrawn: Amount,
+    charged_back: Amount,
+    // net change of balances by corrections
+    corrected: Amount,
+    deposits: u64,
+    withdrawals: u64,
+    disputes: u64,
+    resolves: u64,
+    chargebacks: u64,
+    corrections: u64,
+    active_clients: HashSet<u16>,
+}
+
+impl RunStats {
+    fn record(&mut self, event: &Event) {
+        match *event {
+            Event::BalanceChanged {
+                client,
+                cause,
+                before,
+                after,
+                ..
+            } => {
+                self.active_clients.insert(client);
+                let moved = after.total - before.total;
+                match cause {
+                    TxType::Deposit => {
+                        self.deposits += 1;
+                        self.deposited += moved;
+                    }
+                    TxType::Withdrawal => {
+                        self.withdrawals += 1;
+                        self.withdrawn -= moved;
+                    }
+                    TxType::Dispute => self.disputes += 1,
+                    TxType::Resolve => self.resolves += 1,
+                    TxType::Chargeback => self.chargebacks += 1,
+                    TxType::Correction => {
+                        self.corrections += 1;
+                        self.corrected += moved;
+                    }
+                    TxType::Close
+                    | TxType::Hold
+                    | TxType::Release
+                    | TxType::Transfer
+                    | TxType::Unlock
+                    | TxType::Custom
+                    | TxType::Noop => {}
+                }
+            }
+            Event::Chargeback { amount, .. } => self.charged_back += amount,
+            Event::AccountLocked { .. }
+            | Event::AccountUnlocked { .. }
+            | Event::AccountClosed { .. }
+            | Event::MinimumBreached { .. }
+            | Event::AdminHold { .. } => {}
+            Event::ClientErased { client } => {
+                self.active_clients.remove(&client);
+            }
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let ratio = |n: u64, d: u64| if d == 0 { 0. } else { n as f64 / d as f64 };
+        json!({
+            "total_deposited": self.deposited,
+            "total_withdrawn": self.withdrawn,
+            "total_charged_back": self.charged_back,
+            "total_corrected": self.corrected,
+            "deposits": self.deposits,
+            "withdrawals": self.withdrawals,
+            "disputes": self.disputes,
+            "resolves": self.resolves,
+            "chargebacks": self.chargebacks,
+            "corrections": self.corrections,
+            // chargebacks per applied deposit
+            "chargeback_ratio": ratio(self.chargebacks, self.deposits),
+            // share of opened disputes that ended in a resolve
+            "dispute_resolution_rate": ratio(self.resolves, self.disputes),
+            "active_clients": self.active_clients.len(),
+        })
+    }
+}
+
+/// The `n` accounts with the largest `by` balance, ties broken by client id.
+fn top(engine: &TxEngine, n: usize, by: Balance) -> Vec<&Account> {
+    let mut accounts: Vec<&Account> = engine.accounts().collect();
+    let rank = |a: &&Account, b: &&Account| {
+        by.of(b).cmp(&by.of(a)).then(a.client.cmp(&b.client))
+    };
+    if n < accounts.len() {
+        accounts.select_nth_unstable_by(n, rank);
+        accounts.truncate(n);
+    }
+    accounts.sort_unstable_by(rank);
+    accounts
+}
+
+/// Nearest-rank percentile of an ascending slice.
+fn percentile(sorted: &[Amount], p: f64) -> Amount {
+    if sorted.is_empty() {
+        return Amount::ZERO;
+    }
+    let rank = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[rank]
+}
+
+/// Counts per bucket; the first and last entries catch values below the first
+/// edge and at or above the last one.
+fn bucket_counts(sorted: &[Amount], edges: &[Amount]) -> Vec<usize> {
+    let mut counts = vec![0; edges.len() + 1];
+    for &value in sorted {
+        counts[edges.partition_point(|&edge| edge <= value)] += 1;
+    }
+    counts
+}
+
+fn write_histogram(engine: &TxEngine, edges: &[Amount], w: impl Write) -> Result<()> {
+    let accounts: Vec<&Account> = engine.accounts().collect();
+    let mut available: Vec<Amount> = accounts.iter().map(|a| a.available).collect();
+    available.sort_unstable();
+
+    let negative = available.iter().filter(|v| v.is_negative()).count();
+    let zero = available.iter().filter(|v| **v == Amount::ZERO).count();
+    let locked = accounts.iter().filter(|a| a.locked).count();
+
+    let mut writer = BufWriter::new(w);
+    writeln!(
+        writer,
+        "accounts: {} (negative {negative}, zero {zero}, locked {locked})",
+        accounts.len()
+    )?;
+    writeln!(
+        writer,
+        "available p50/p90/p99/max: {} / {} / {} / {}",
+        percentile(&available, 0.50),
+        percentile(&available, 0.90),
+        percentile(&available, 0.99),
+        available.last().copied().unwrap_or_default()
+    )?;
+    writeln!(writer)?;
+    writeln!(writer, "{:<24}  {:>8}", "available", "accounts")?;
+
+    let counts = bucket_counts(&available, edges);
+    for (i, count) in counts.iter().enumerate() {
+        let lower = i.checked_sub(1).map_or("-inf".to_owned(), |j| edges[j].to_string());
+        let upper = edges.get(i).map_or("+inf".to_owned(), Amount::to_string);
+        writeln!(writer, "{:<24}  {count:>8}", format!("[{lower}, {upper})"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Tx;
+
+    #[test]
+    fn test_buckets_and_percentiles() {
+        let amounts = |s: &[&str]| -> Vec<Amount> {
+            s.iter().map(|a| a.parse().unwrap()).collect()
+        };
+        let sorted = amounts(&["-5", "0", "0.5", "1", "99", "100", "2500"]);
+        assert_eq!(bucket_counts(&sorted, &amounts(&["0", "1", "100"])), [1, 2, 2, 2]);
+        assert_eq!(bucket_counts(&[], &[Amount::ZERO]), [0, 0]);
+        assert_eq!(percentile(&sorted, 0.5), Amount::from_units(1));
+        assert_eq!(percentile(&sorted, 1.), Amount::from_units(2500));
+    }
+
+    #[test]
+    fn test_top_accounts_by_held() {
+        let mut engine = TxEngine::new();
+        for line in [
+            "deposit, 1, 1, 50.0",
+            "deposit, 2, 2, 80.0",
+            "deposit, 3, 3, 20.0",
+            "deposit, 4, 4, 80.0",
+            "dispute, 1, 1,",
+            "dispute, 2, 2,",
+            "dispute, 4, 4,",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        let clients = |n, by| -> Vec<u16> { top(&engine, n, by).iter().map(|a| a.client).collect() };
+        assert_eq!(clients(2, Balance::Held), [2, 4]);
+        assert_eq!(clients(10, Balance::Held), [2, 4, 1, 3]);
+        assert_eq!(clients(1, Balance::Available), [3]);
+        assert!(clients(0, Balance::Total).is_empty());
+    }
+
+    #[test]
+    fn test_run_stats() {
+        let report = Report::Stats(Arc::default());
+        let mut engine = TxEngine::new();
+        engine.subscribe(report.observer().unwrap());
+        for line in [
+            "deposit, 1, 1, 50.0",
+            "deposit, 2, 2, 30.0",
+            "withdrawal, 2, 3, 10.0",
+            "withdrawal, 2, 4, 100.0",
+            "dispute, 1, 1,",
+            "chargeback, 1, 1,",
+            "dispute, 2, 2,",
+            "resolve, 2, 2,",
+        ] {
+            engine.apply_line(line);
+        }
+
+        let Report::Stats(stats) = &report else { unreachable!() };
+        let json = stats.lock().unwrap().to_json();
+        assert_eq!(json["total_deposited"], "80");
+        assert_eq!(json["total_withdrawn"], "10");
+        assert_eq!(json["total_charged_back"], "50");
+        assert_eq!(json["chargeback_ratio"], 0.5);
+        assert_eq!(json["dispute_resolution_rate"], 0.5);
+        assert_eq!(json["active_clients"], 2);
+    }
+
+    #[test]
+    fn test_flag_clients_over_thresholds() {
+        let activity = HashMap::from([
+            (1, ClientActivity { txs: 100, disputes: 2, chargebacks: 0 }),
+            (2, ClientActivity { txs: 10, disputes: 3, chargebacks: 0 }),
+            (3, ClientActivity { txs: 10, disputes: 1, chargebacks: 1 }),
+            (4, ClientActivity { txs: 0, disputes: 0, chargebacks: 0 }),
+        ]);
+        let flagged: Vec<u16> = flag_clients(&activity, 0.1, 0.02)
+            .into_iter()
+            .map(|(c, _)| c)
+            .collect();
+        assert_eq!(flagged, [3, 2]);
+    }
+}
@@ -0,0 +1,230 @@
+//! Router mode: one front end for several engine instances.
+//!
+//! `route` accepts producer connections speaking the stream server's line
+//! protocol and forwards every record to one of the `--backend` servers,
+//! chosen by a hash of its client id, so each backend holds a disjoint part
+//! of the book and the book can outgrow one machine. A connection's
+//! `#schema=N` directive is passed on to every backend the connection
+//! reaches, and records are read in its schema, CSV or JSON, like the server
+//! reads them; signed records are forwarded as they are. Records without a
+//! readable client id cannot be routed and are dropped.
+//!
+//! With `--http` the router also serves `GET /api/accounts`, the accounts of
+//! every backend fetched from the HTTP API given with `--backend-http` (in
+//! `--backend` order) and merged by client.
+
+use crate::engine::Account;
+use crate::log;
+use crate::net;
+use crate::partition::PartitionBy;
+use crate::redact;
+use crate::schema::{client_of, Schema};
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use clap::Args;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+#[derive(Debug, Args)]
+pub(crate) struct RouteArgs {
+    /// Address producers connect to
+    #[arg(long, default_value = "127.0.0.1:6969")]
+    listen: SocketAddr,
+    /// Stream server a share of the clients is routed to; repeat once per backend
+    #[arg(long = "backend", value_name = "ADDR", required = true)]
+    backends: Vec<SocketAddr>,
+    /// HTTP API of each backend, in --backend order, for merged summaries
+    #[arg(long = "backend-http", value_name = "ADDR")]
+    backend_http: Vec<SocketAddr>,
+    /// Serve the merged accounts of all backends at /api/accounts on this address
+    #[arg(long, value_name = "ADDR", requires = "backend_http")]
+    http: Option<SocketAddr>,
+}
+
+/// Index of the backend holding `client`.
+fn backend_of(client: u16, backends: usize) -> usize {
+    PartitionBy::Hash.partition_of(client, backends as u16)
+}
+
+/// Forwards every record read from `reader` to its client's backend,
+/// connecting to each backend on its first record.
+async fn route_lines<R: AsyncRead + Unpin>(reader: R, backends: &[SocketAddr]) -> Result<()> {
+    let mut lines = BufReader::new(reader).lines();
+    let mut directive: Option<String> = None;
+    let mut schema = Schema::default();
+    let mut connections: Vec<Option<TcpStream>> = backends.iter().map(|_| None).collect();
+    let mut first = true;
+
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = match std::mem::take(&mut first) {
+            true => Schema::from_directive(line),
+            false => None,
+        };
+        if let Some(parsed) = parsed {
+            // backends refuse a bad directive themselves
+            schema = parsed.unwrap_or_default();
+            directive = Some(line.to_owned());
+            continue;
+        }
+        let Some(client) = client_of(line, schema) else {
+            log::warn!(
+                "router: dropping record without a client: {}",
+                redact::record(line)
+            );
+            continue;
+        };
+        let index = backend_of(client, backends.len());
+        let backend = match &mut connections[index] {
+            Some(backend) => backend,
+            slot @ None => {
+                let addr = backends[index];
+                let mut backend = TcpStream::connect(addr)
+                    .await
+                    .with_context(|| format!("could not connect to backend {addr}"))?;
+                if let Some(directive) = &directive {
+                    backend
+                        .write_all(format!("{directive}\n").as_bytes())
+                        .await?;
+                }
+                slot.insert(backend)
+            }
+        };
+        backend.write_all(format!("{line}\n").as_bytes()).await?;
+    }
+    for backend in connections.iter_mut().flatten() {
+        backend.shutdown().await?;
+    }
+    Ok(())
+}
+
+async fn get_accounts(
+    State(backends): State<Arc<[SocketAddr]>>,
+) -> Result<Json<Vec<Account>>, StatusCode> {
+    let client = reqwest::Client::new();
+    let mut accounts = Vec::new();
+    for addr in backends.iter() {
+        let fetched = async {
+            let body = client
+                .get(format!("http://{addr}/api/accounts"))
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            anyhow::Ok(serde_json::from_slice::<Vec<Account>>(&body)?)
+        };
+        match fetched.await {
+            Ok(fetched) => accounts.extend(fetched),
+            Err(err) => {
+                log::warn!("router: backend {addr}: {err:#}");
+                return Err(StatusCode::BAD_GATEWAY);
+            }
+        }
+    }
+    accounts.sort_unstable_by_key(|a| a.client);
+    Ok(Json(accounts))
+}
+
+async fn serve_http(addr: SocketAddr, backends: Arc<[SocketAddr]>) -> Result<()> {
+    let app = Router::new()
+        .route("/api/accounts", get(get_accounts))
+        .with_state(backends);
+    let listener = net::bind_tcp(addr)?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+pub(crate) async fn run(args: RouteArgs) -> Result<()> {
+    anyhow::ensure!(
+        args.backend_http.is_empty() || args.backend_http.len() == args.backends.len(),
+        "expected one --backend-http per --backend"
+    );
+    anyhow::ensure!(
+        args.backends.len() <= usize::from(u16::MAX),
+        "too many backends"
+    );
+    let backends: Arc<[SocketAddr]> = args.backends.into();
+    let listener = net::bind_tcp(args.listen)?;
+
+    let http = async {
+        match args.http {
+            Some(addr) => serve_http(addr, args.backend_http.into()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let tcp = async {
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            let peer = net::peer_addr(peer);
+            let backends = backends.clone();
+            tokio::spawn(async move {
+                if let Err(err) = route_lines(socket, &backends).await {
+                    log::warn!("router: {peer}: {err:#}");
+                }
+            });
+        }
+    };
+    tokio::select! {
+        res = tcp => res,
+        res = http => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // a backend collecting every line it is sent
+    async fn backend() -> (SocketAddr, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut lines = BufReader::new(socket).lines();
+            let mut received = Vec::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                received.push(line);
+            }
+            received
+        });
+        (addr, received)
+    }
+
+    #[tokio::test]
+    async fn test_records_routed_by_client() {
+        let (a, received_a) = backend().await;
+        let (b, received_b) = backend().await;
+        let clients: Vec<u16> = (1..=20).collect();
+        let mut input = String::from("#schema=2\n");
+        for &client in &clients {
+            input.push_str(&format!("deposit, {client}, {client}, 1.0\n"));
+        }
+        input.push_str("not a record\n");
+
+        route_lines(input.as_bytes(), &[a, b]).await.unwrap();
+        let (received_a, received_b) = (received_a.await.unwrap(), received_b.await.unwrap());
+
+        for (index, received) in [received_a, received_b].into_iter().enumerate() {
+            assert_eq!(received[0], "#schema=2");
+            let client = |line: &String| client_of(line, Schema::V2);
+            let routed: Vec<u16> = received[1..].iter().filter_map(client).collect();
+            let expected: Vec<u16> = clients
+                .iter()
+                .copied()
+                .filter(|&c| backend_of(c, 2) == index)
+                .collect();
+            assert!(!expected.is_empty());
+            assert_eq!(routed, expected);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Column layouts of transaction inputs.
+//!
+//! An input may start with a `#schema=N` directive, before the header, to pick
+//! its layout. Inputs without one are read as schema 1, so legacy files keep
+//! working unchanged.
+//!
+//! - schema 1: `type, client, tx, amount`, `hold` and `release` records
+//!   adding a reason code and `transfer` records a counterparty column
+//! - schema 2: `type, client, tx, amount, timestamp, currency, correlation_id,
+//!   reason, sub_account, counterparty`, where the trailing columns may be
+//!   left empty, `timestamp` is in seconds since the unix epoch, `reason` is
+//!   the reason code of `hold` and `release` records, `sub_account` the
+//!   bucket of the client's account the record applies to (`main` when
+//!   empty) and `counterparty` the client `transfer` records credit
+
+use crate::engine::{parse_counterparty, Tx, TxMeta, TxType};
+use crate::input;
+use anyhow::{Context, Result};
+
+const DIRECTIVE: &str = "#schema=";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Schema {
+    #[default]
+    V1,
+    V2,
+}
+
+impl Schema {
+    /// Reads a `#schema=N` directive; `None` when `line` isn't one.
+    pub(crate) fn from_directive(line: &str) -> Option<Result<Self>> {
+        let version = line.trim().strip_prefix(DIRECTIVE)?.trim();
+        Some(match version {
+            "1" => Ok(Self::V1),
+            "2" => Ok(Self::V2),
+            other => Err(anyhow::anyhow!("unsupported schema version {other}")),
+        })
+    }
+
+    pub(crate) fn parse(self, line: &str) -> Result<Tx> {
+        match self {
+            Self::V1 => Ok(Tx::from_str(line)?),
+            Self::V2 => {
+                let d: Vec<&str> = line
+                    .splitn(10, &[',', ';'])
+                    .map(|chunk| chunk.trim())
+                    .collect();
+                self.parse_fields(&d)
+            }
+        }
+    }
+
+    /// Parses a record already split into its trimmed columns.
+    pub(crate) fn parse_fields(self, d: &[&str]) -> Result<Tx> {
+        let tx = Tx::from_fields(&d[..d.len().min(4)])?;
+        let column = |i: usize| d.get(i).copied().filter(|v| !v.is_empty());
+        match self {
+            // administrative records carry their reason code in a fifth column,
+            // transfers their counterparty
+            Self::V1 => Ok(match tx.tx_type() {
+                TxType::Hold | TxType::Release => tx.with_meta(TxMeta {
+                    reason: column(4).map(Into::into),
+                    ..Default::default()
+                }),
+                TxType::Transfer => tx.with_meta(TxMeta {
+                    counterparty: column(4).map(parse_counterparty).transpose()?,
+                    ..Default::default()
+                }),
+                _ => tx,
+            }),
+            Self::V2 => {
+                let timestamp = column(4)
+                    .map(str::parse)
+                    .transpose()
+                    .context("could not parse timestamp to u64")?;
+                Ok(tx.with_meta(TxMeta {
+                    timestamp,
+                    currency: column(5).map(Into::into),
+                    correlation_id: column(6).map(Into::into),
+                    reason: column(7).map(Into::into),
+                    sub_account: column(8).map(Into::into),
+                    counterparty: column(9).map(parse_counterparty).transpose()?,
+                }))
+            }
+        }
+    }
+}
+
+/// Client of a streamed record of `schema`, CSV or JSON, parsed like the
+/// server parses it (see `input::parse_streamed`); a signed record is read
+/// without its signature column.
+pub(crate) fn client_of(line: &str, schema: Schema) -> Option<u16> {
+    let unsigned = line.rsplit_once(',').map(|(record, _)| record);
+    [Some(line), unsigned]
+        .into_iter()
+        .flatten()
+        .find_map(|line| input::parse_unchecked(line, schema).ok())
+        .map(|tx| tx.client())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+
+    #[test]
+    fn test_directive_and_v2_columns() {
+        assert!(Schema::from_directive("type, client, tx, amount").is_none());
+        assert_eq!(Schema::from_directive("#schema=2").unwrap().unwrap(), Schema::V2);
+        assert!(Schema::from_directive("#schema=9").unwrap().is_err());
+
+        let tx = Schema::V2
+            .parse("deposit, 1, 7, 2.5, 1700000000, EUR, order-42")
+            .unwrap();
+        assert_eq!(
+            (tx.client(), tx.tx_id(), tx.amount()),
+            (1, 7, Some("2.5".parse::<Amount>().unwrap()))
+        );
+        let meta = tx.meta().unwrap();
+        assert_eq!(meta.timestamp, Some(1_700_000_000));
+        assert_eq!(meta.currency.as_deref(), Some("EUR"));
+        assert_eq!(meta.correlation_id.as_deref(), Some("order-42"));
+
+        // trailing columns are optional, and legacy rows still parse
+        assert!(Schema::V2.parse("dispute, 1, 7,").unwrap().meta().is_none());
+        assert!(Schema::V2.parse("deposit, 1, 8, 1.0, soon").is_err());
+        assert!(Schema::V1.parse("deposit, 1, 9, 1.0").unwrap().meta().is_none());
+    }
+
+    #[test]
+    fn test_client_of_streamed_records() {
+        assert_eq!(client_of("deposit; 3; 1; 2.0", Schema::V2), Some(3));
+        let json = r#"{"type": "deposit", "client": 4, "tx": 2, "amount": 1.0}"#;
+        assert_eq!(client_of(json, Schema::V1), Some(4));
+        let signed = crate::signing::Verifier::new("key").sign("deposit, 5, 3, 1.0, , EUR");
+        assert_eq!(client_of(&signed, Schema::V2), Some(5));
+        assert_eq!(client_of("deposit, x, 4, 1.0", Schema::V2), None);
+    }
+}
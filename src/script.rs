@@ -0,0 +1,158 @@
+//! Site-specific transaction rules written in rhai.
+//!
+//! A script passed with `--script rules.rhai` may define either or both of
+//!
+//! ```text
+//! fn filter(tx, account) { ... }   // return false to drop the record
+//! fn after(tx, account) { ... }    // runs once the record has been applied
+//! ```
+//!
+//! `tx` is a map with `type`, `client`, `tx` and `amount` (`()` when absent);
+//! `account` is a map with `client`, `available`, `held`, `total` and `locked`,
+//! or `()` for clients that have no account yet. Both are copies, so scripts
+//! cannot change engine state. `print` and `debug` go to stderr, and each call
+//! is capped in operations so a runaway script fails instead of hanging.
+
+use crate::engine::{Account, Tx, TxHook};
+use crate::redact;
+use anyhow::{Context, Result};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::path::Path;
+
+const MAX_OPERATIONS: u64 = 100_000;
+
+pub(crate) struct ScriptHook {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    has_filter: bool,
+    has_after: bool,
+}
+
+impl ScriptHook {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        Self::from_source(&source).with_context(|| format!("invalid script {}", path.display()))
+    }
+
+    fn from_source(source: &str) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|s| eprintln!("{}", redact::text(s)));
+        engine.on_debug(|s, _, pos| eprintln!("{pos:?}: {}", redact::text(s)));
+
+        let ast = engine.compile(source)?;
+        let defines = |name: &str| ast.iter_functions().any(|f| f.name == name && f.params.len() == 2);
+        let (has_filter, has_after) = (defines("filter"), defines("after"));
+        anyhow::ensure!(
+            has_filter || has_after,
+            "script defines neither filter(tx, account) nor after(tx, account)"
+        );
+
+        // top-level statements run once, so scripts can set up constants
+        let mut scope = Scope::new();
+        engine.run_ast_with_scope(&mut scope, &ast)?;
+        Ok(Self {
+            engine,
+            ast,
+            scope,
+            has_filter,
+            has_after,
+        })
+    }
+
+    fn call(&mut self, name: &str, tx: &Tx, account: Option<&Account>) -> Result<Dynamic> {
+        let args = (tx_map(tx), account.map_or(Dynamic::UNIT, account_map));
+        self.engine
+            .call_fn(&mut self.scope, &self.ast, name, args)
+            .map_err(|err| anyhow::anyhow!("{name}: {err}"))
+    }
+}
+
+fn tx_map(tx: &Tx) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("type".into(), tx.type_name().into());
+    map.insert("client".into(), i64::from(tx.client()).into());
+    map.insert("tx".into(), i64::from(tx.tx_id()).into());
+    map.insert("amount".into(), tx.amount().map_or(Dynamic::UNIT, |a| a.to_f64().into()));
+    map.into()
+}
+
+fn account_map(account: &Account) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("client".into(), i64::from(account.client).into());
+    map.insert("available".into(), account.available.to_f64().into());
+    map.insert("held".into(), account.held.to_f64().into());
+    map.insert("total".into(), account.total.to_f64().into());
+    map.insert("locked".into(), account.locked.into());
+    map.into()
+}
+
+impl TxHook for ScriptHook {
+    fn filter(&mut self, tx: &Tx, account: Option<&Account>) -> Result<bool> {
+        if !self.has_filter {
+            return Ok(true);
+        }
+        let keep = self.call("filter", tx, account)?;
+        keep.as_bool()
+            .map_err(|ty| anyhow::anyhow!("filter must return a bool, got {ty}"))
+    }
+
+    fn after(&mut self, tx: &Tx, account: Option<&Account>) -> Result<()> {
+        if self.has_after {
+            let _ = self.call("after", tx, account)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::engine::TxEngine;
+
+    const RULES: &str = r#"
+        const LIMIT = 100.0;
+
+        fn filter(tx, account) {
+            if tx.type != "withdrawal" { return true; }
+            tx.amount <= LIMIT && account != () && !account.locked
+        }
+
+        fn after(tx, account) {
+            if account.total > 1000.0 { print(`client ${tx.client} over 1000`); }
+        }
+    "#;
+
+    #[test]
+    fn test_filter_vetoes_transactions() {
+        let mut engine = TxEngine::new();
+        engine.add_hook(Box::new(ScriptHook::from_source(RULES).unwrap()));
+
+        for line in [
+            "deposit, 1, 1, 2000.0",
+            "withdrawal, 1, 2, 500.0",
+            "withdrawal, 1, 3, 50.0",
+            "withdrawal, 2, 4, 10.0",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+
+        assert_eq!(engine.account(1).unwrap().available, Amount::from_units(1950));
+        assert!(engine.account(2).is_none());
+    }
+
+    #[test]
+    fn test_runaway_script_is_rejected() {
+        let mut engine = TxEngine::new();
+        let hook = ScriptHook::from_source("fn filter(tx, account) { loop {} }").unwrap();
+        engine.add_hook(Box::new(hook));
+        engine
+            .process_tx(Tx::from_str("deposit, 1, 1, 5.0").unwrap())
+            .unwrap();
+        assert!(engine.account(1).is_none());
+        assert!(ScriptHook::from_source("let x = 1;").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Client-sharded engine, applying a file's records on several threads.
+//!
+//! With `--shards N` the records of a transaction file are spread across N
+//! worker threads by `client % N`, each thread owning an engine of its own
+//! with its accounts, transactions and disputes. All engine state is keyed
+//! by client, so every record of a client reaches the same shard in input
+//! order and its account ends as it would in one engine. A dispute, resolve
+//! or chargeback goes to the shard of the client it names, which holds every
+//! transaction that client may dispute. Once the file is read the shards are
+//! merged into one engine, which writes the summary.
+//!
+//! Two kinds of records end differently than in one engine, and are
+//! rejected rather than applied differently:
+//!
+//! - A transfer can only credit a client of its own shard, since no shard
+//!   holds both accounts otherwise. Transfers to a client of another shard
+//!   are refused like any other invalid record, so both accounts stay as
+//!   they were.
+//! - A transaction id reused by a client of another shard reaches an engine
+//!   that never saw the first, which would take it. The merge finds the id
+//!   on two shards and fails the run instead of keeping either record.
+//!
+//! Stream servers keep a single engine, which the HTTP API, snapshots and
+//! replication read while records arrive, so `--shards` only applies to
+//! files and is refused when serving.
+
+use crate::engine::{Tx, TxEngine};
+use crate::log;
+use crate::rejects;
+use anyhow::{Context, Result};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+
+// records handed to a shard at once, so that threads meet per batch rather
+// than per record
+const BATCH: usize = 1024;
+// batches queued per shard before the reader waits for it
+const QUEUED: usize = 16;
+
+pub(crate) struct ShardedTxEngine {
+    senders: Vec<SyncSender<Vec<Tx>>>,
+    batches: Vec<Vec<Tx>>,
+    workers: Vec<JoinHandle<TxEngine>>,
+}
+
+impl ShardedTxEngine {
+    /// Starts a worker thread on each of `engines`, shard `i` being
+    /// `engines[i]`.
+    pub(crate) fn new(engines: Vec<TxEngine>) -> Result<Self> {
+        anyhow::ensure!(!engines.is_empty(), "no shards");
+        let (mut senders, mut workers) = (Vec::new(), Vec::new());
+        for (i, mut engine) in engines.into_iter().enumerate() {
+            let (sender, receiver) = mpsc::sync_channel::<Vec<Tx>>(QUEUED);
+            let worker = thread::Builder::new()
+                .name(format!("shard-{i}"))
+                .spawn(move || {
+                    // refusals are logged and reported by the engine
+                    for tx in receiver.into_iter().flatten() {
+                        let _ = engine.process_tx(tx);
+                    }
+                    engine
+                })
+                .context("could not start a shard")?;
+            senders.push(sender);
+            workers.push(worker);
+        }
+        let batches = senders.iter().map(|_| Vec::with_capacity(BATCH)).collect();
+        Ok(Self {
+            senders,
+            batches,
+            workers,
+        })
+    }
+
+    /// Queues `tx` on the shard of its client.
+    pub(crate) fn process_tx(&mut self, tx: Tx) {
+        let shard = usize::from(tx.client()) % self.senders.len();
+        if let Some(counterparty) = tx.counterparty() {
+            if usize::from(counterparty) % self.senders.len() != shard {
+                log::warn!(
+                    "tx {}: transfer refused: counterparty on another shard",
+                    tx.tx_id()
+                );
+                rejects::refused(&tx, "counterparty on another shard");
+                return;
+            }
+        }
+        self.batches[shard].push(tx);
+        if self.batches[shard].len() == BATCH {
+            self.flush(shard);
+        }
+    }
+
+    fn flush(&mut self, shard: usize) {
+        let batch = std::mem::replace(&mut self.batches[shard], Vec::with_capacity(BATCH));
+        // a worker only hangs up by panicking, which `finish` reports
+        let _ = self.senders[shard].send(batch);
+    }
+
+    /// Waits until every shard applied its records, then merges them into
+    /// `engine`. Fails if two shards took the same transaction id.
+    pub(crate) fn finish(mut self, mut engine: TxEngine) -> Result<TxEngine> {
+        for shard in 0..self.senders.len() {
+            self.flush(shard);
+        }
+        drop(self.senders);
+        for worker in self.workers {
+            let mut shard = worker
+                .join()
+                .map_err(|_| anyhow::anyhow!("a shard panicked"))?;
+            engine.merge_state(shard.take_state()?)?;
+        }
+        Ok(engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shards_end_like_one_engine() {
+        let mut lines = Vec::new();
+        for tx in 1..=5000u32 {
+            let client = tx % 7;
+            lines.push(format!("deposit, {client}, {tx}, 2.0"));
+            if tx % 3 == 0 {
+                lines.push(format!("withdrawal, {client}, {}, 1.5", tx + 100_000));
+            }
+            if tx % 11 == 0 {
+                lines.push(format!("dispute, {client}, {tx},"));
+            }
+            if tx % 55 == 0 {
+                lines.push(format!("chargeback, {client}, {tx},"));
+            }
+        }
+        let mut single = TxEngine::new();
+        let shards = (0..3).map(|_| TxEngine::new()).collect();
+        let mut sharded = ShardedTxEngine::new(shards).unwrap();
+        for line in &lines {
+            single.apply_line(line);
+            sharded.process_tx(line.parse().unwrap());
+        }
+        let merged = sharded.finish(TxEngine::new()).unwrap();
+
+        let summary = |engine: &TxEngine| {
+            let mut accounts: Vec<_> = engine
+                .accounts()
+                .map(|a| (a.client(), a.available(), a.held(), a.locked()))
+                .collect();
+            accounts.sort();
+            accounts
+        };
+        assert_eq!(summary(&merged), summary(&single));
+        assert_eq!(merged.retained().txs, single.retained().txs);
+        assert_eq!(merged.retained().disputes, single.retained().disputes);
+        assert_eq!(merged.seq(), lines.len() as u64);
+    }
+
+    #[test]
+    fn test_transfers_across_shards_are_refused() {
+        let mut sharded = ShardedTxEngine::new(vec![TxEngine::new(), TxEngine::new()]).unwrap();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 10.0",
+            "deposit, 3, 3, 10.0",
+            "transfer, 1, 4, 4.0, 3",
+            "transfer, 1, 5, 4.0, 2",
+        ] {
+            sharded.process_tx(line.parse().unwrap());
+        }
+        let merged = sharded.finish(TxEngine::new()).unwrap();
+
+        let available = |client| merged.account(client).unwrap().available();
+        let amount = |s: &str| s.parse().unwrap();
+        // 1 and 3 share a shard, 2 is on the other one
+        assert_eq!((available(1), available(3)), (amount("6"), amount("14")));
+        assert_eq!(available(2), amount("10"));
+        assert!(rejects::refusals()["counterparty on another shard"] >= 1);
+    }
+
+    #[test]
+    fn test_tx_id_on_two_shards_fails_the_merge() {
+        let mut sharded = ShardedTxEngine::new(vec![TxEngine::new(), TxEngine::new()]).unwrap();
+        sharded.process_tx("deposit, 1, 7, 10.0".parse().unwrap());
+        sharded.process_tx("deposit, 2, 7, 5.0".parse().unwrap());
+
+        let Err(err) = sharded.finish(TxEngine::new()) else {
+            panic!("merged a tx id taken by two shards");
+        };
+        assert!(err.to_string().contains("tx 7"), "{err:#}");
+    }
+}
@@ -0,0 +1,169 @@
+//! Deterministic simulation of the streaming server.
+//!
+//! Every run is driven by a seed: the seed picks the transactions, how they are
+//! spread over concurrent connections, where connections drop, and how far the
+//! mock clock moves between steps. Invariants are checked after every step and
+//! the server-side engine is compared against a reference engine fed the same
+//! delivered lines in order, so a failure can be replayed from its seed alone.
+
+use crate::clock::MockClock;
+use crate::csv_stream::ingest_lines;
+use crate::metrics::Metrics;
+use crate::rng::XorShift;
+use crate::TxEngine;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+const CLIENTS: u16 = 12;
+const MAX_CONNECTIONS: u64 = 3;
+const MAX_LINES_PER_CONNECTION: u64 = 20;
+
+struct Simulation {
+    rng: XorShift,
+    clock: Arc<MockClock>,
+    server: Arc<Mutex<TxEngine>>,
+    reference: TxEngine,
+    next_tx_id: u32,
+    // tx ids issued so far, grouped by client, so dispute-family records stay
+    // within the owning client's partition
+    issued: Vec<Vec<u32>>,
+}
+
+impl Simulation {
+    fn new(seed: u64) -> Self {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        let window = Some(Duration::from_secs(120));
+
+        let mut server = TxEngine::with_clock(clock.clone());
+        server.dispute_window = window;
+        let mut reference = TxEngine::with_clock(clock.clone());
+        reference.dispute_window = window;
+
+        Self {
+            rng: XorShift::new(seed),
+            clock,
+            server: Arc::new(Mutex::new(server)),
+            reference,
+            next_tx_id: 1,
+            issued: vec![Vec::new(); CLIENTS as usize + 1],
+        }
+    }
+
+    fn gen_line(&mut self, client: u16) -> String {
+        let roll = self.rng.below(100);
+        let known = &self.issued[client as usize];
+        if roll >= 60 && !known.is_empty() {
+            let tx_id = known[self.rng.below(known.len() as u64) as usize];
+            let kind = match roll {
+                60..=79 => "dispute",
+                80..=91 => "resolve",
+                _ => "chargeback",
+            };
+            return format!("{kind}, {client}, {tx_id},");
+        }
+
+        let kind = if roll < 40 { "deposit" } else { "withdrawal" };
+        let tx_id = self.next_tx_id;
+        self.next_tx_id += 1;
+        self.issued[client as usize].push(tx_id);
+        let cents = 1 + self.rng.below(100_000);
+        format!(
+            "{kind}, {client}, {tx_id}, {}.{:02}",
+            cents / 100,
+            cents % 100
+        )
+    }
+
+    /// Builds the lines one connection delivers before closing. Clients are
+    /// partitioned by connection index so concurrent connections never touch
+    /// the same account and the reference order stays well defined.
+    fn gen_connection(&mut self, index: u64, connections: u64) -> Vec<String> {
+        let owned: Vec<u16> = (1..=CLIENTS)
+            .filter(|c| u64::from(*c) % connections == index)
+            .collect();
+        let len = 1 + self.rng.below(MAX_LINES_PER_CONNECTION);
+        let mut lines: Vec<String> = (0..len)
+            .map(|_| {
+                let client = owned[self.rng.below(owned.len() as u64) as usize];
+                self.gen_line(client)
+            })
+            .collect();
+
+        // simulate the producer dropping the connection part-way through
+        if self.rng.below(5) == 0 {
+            let keep = self.rng.below(lines.len() as u64) as usize;
+            lines.truncate(keep);
+        }
+        lines
+    }
+
+    async fn step(&mut self) {
+        self.clock.advance(Duration::from_secs(self.rng.below(10)));
+
+        let connections = 1 + self.rng.below(MAX_CONNECTIONS);
+        let batches: Vec<Vec<String>> = (0..connections)
+            .map(|i| self.gen_connection(i, connections))
+            .collect();
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for lines in &batches {
+            let bytes: Vec<u8> = lines
+                .iter()
+                .flat_map(|l| format!("{l}\n").into_bytes())
+                .collect();
+            let server = self.server.clone();
+            tasks.spawn(async move {
+                ingest_lines(&bytes[..], &server, &Metrics::default(), None).await;
+            });
+        }
+        while let Some(res) = tasks.join_next().await {
+            res.expect("connection task panicked");
+        }
+
+        for line in batches.iter().flatten() {
+            self.reference.apply_line(line);
+        }
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        let server = self.server.lock().await;
+        server.check_invariants().map_err(|e| e.to_string())?;
+        self.reference
+            .check_invariants()
+            .map_err(|e| format!("reference: {e}"))?;
+
+        let (actual, expected) = (sorted_summary(&server), sorted_summary(&self.reference));
+        if actual != expected {
+            return Err(format!(
+                "server diverged from reference\n{actual}\n--\n{expected}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn sorted_summary(engine: &TxEngine) -> String {
+    let mut out = Vec::new();
+    engine.summarize_accounts(&mut out).unwrap();
+    let mut lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+    lines.sort_unstable();
+    lines.join("\n")
+}
+
+async fn run(seed: u64, steps: usize) {
+    let mut sim = Simulation::new(seed);
+    for step in 0..steps {
+        sim.step().await;
+        if let Err(err) = sim.check().await {
+            panic!("seed {seed} failed at step {step}: {err}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn simulate_concurrent_connections() {
+    for seed in 0..32 {
+        run(seed, 200).await;
+    }
+}
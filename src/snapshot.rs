@@ -0,0 +1,291 @@
+//! Periodic account snapshots written while the stream server runs.
+//!
+//! Every `--snapshot-every` interval the accounts are written to
+//! `--snapshot-dir` as `snapshot-<id>.csv`, in the summary CSV format. With
+//! `--snapshot-delta` only the first file is a full snapshot; later ones are
+//! `delta-<id>.csv` files holding just the accounts changed since snapshot
+//! `id - 1`, so applying them in id order reproduces the book. Changed accounts
+//! are tracked through engine events. With an encryption key (see
+//! `crate::crypt`) files are sealed and get an extra `.enc` suffix. Rows of
+//! erased clients are scrubbed from every earlier file before the next
+//! snapshot is written.
+//!
+//! Account snapshots cannot restart a server: disputes need the stored
+//! transactions. `--snapshot-path` writes the engine's whole state there once
+//! the server stopped (see `TxEngine::snapshot`), replacing the file
+//! atomically and sealing it like snapshots, and `--restore-from` starts the
+//! next run from such a file. A server that handed its state to a successor
+//! writes none.
+
+use crate::crypt::Key;
+use crate::drain::Draining;
+use crate::engine::{Account, TxEngine};
+use crate::events::Event;
+use crate::loadgen::parse_duration;
+use crate::log;
+use crate::output::Sink;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct SnapshotArgs {
+    /// Write an account snapshot to --snapshot-dir this often, e.g. 30s
+    #[arg(long, global = true, value_parser = parse_duration, requires = "snapshot_dir")]
+    snapshot_every: Option<Duration>,
+    /// Directory periodic snapshots are written to
+    #[arg(long, global = true, requires = "snapshot_every")]
+    snapshot_dir: Option<PathBuf>,
+    /// After the first snapshot, only write accounts changed since the previous one
+    #[arg(long, global = true, requires = "snapshot_every")]
+    snapshot_delta: bool,
+    /// Write the engine's state, transactions and disputes included, here when
+    /// the server stops
+    #[arg(long, global = true, value_name = "PATH")]
+    snapshot_path: Option<PathBuf>,
+    /// Start from the engine state --snapshot-path wrote
+    #[arg(long, global = true, value_name = "PATH")]
+    restore_from: Option<PathBuf>,
+}
+
+impl SnapshotArgs {
+    /// Replaces the engine's state with the one of `--restore-from`, if given.
+    pub(crate) fn restore(&self, engine: &mut TxEngine, key: Option<&Key>) -> Result<()> {
+        let Some(path) = &self.restore_from else {
+            return Ok(());
+        };
+        let mut body =
+            std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+        if let Some(key) = key {
+            body = key.open(&body)?;
+        }
+        engine
+            .restore(&body[..])
+            .with_context(|| format!("could not restore {}", path.display()))?;
+        let accounts = engine.accounts().count();
+        log::info!("restored {accounts} accounts from {}", path.display());
+        Ok(())
+    }
+
+    /// Writes the engine's state to `--snapshot-path`, if given, returning
+    /// whether it did.
+    pub(crate) fn save(&self, engine: &TxEngine, key: Option<&Key>) -> Result<bool> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(false);
+        };
+        let mut body = Vec::new();
+        engine.snapshot(&mut body)?;
+        if let Some(key) = key {
+            body = key.seal(&body)?;
+        }
+        let mut sink = Sink::open(Some(path))?;
+        sink.write_all(&body)?;
+        sink.commit()?;
+        Ok(true)
+    }
+}
+
+pub(crate) struct Snapshots {
+    every: Duration,
+    dir: PathBuf,
+    delta: bool,
+    next_id: u64,
+    changed: Arc<std::sync::Mutex<HashSet<u16>>>,
+    erased: Arc<std::sync::Mutex<HashSet<u16>>>,
+    key: Option<Key>,
+}
+
+impl Snapshots {
+    pub(crate) fn new(args: &SnapshotArgs, key: Option<Key>) -> Option<Self> {
+        Some(Self {
+            every: args.snapshot_every?,
+            dir: args.snapshot_dir.clone()?,
+            delta: args.snapshot_delta,
+            next_id: 1,
+            changed: Arc::default(),
+            erased: Arc::default(),
+            key,
+        })
+    }
+
+    /// Engine observer recording which accounts changed, in delta mode, and
+    /// which clients were erased.
+    pub(crate) fn observer(&self) -> impl FnMut(&Event) + Send + 'static {
+        let (delta, changed, erased) = (self.delta, self.changed.clone(), self.erased.clone());
+        move |event: &Event| match *event {
+            Event::BalanceChanged { client, .. }
+            | Event::AccountLocked { client, .. }
+            | Event::AccountUnlocked { client }
+            | Event::AccountClosed { client, .. }
+                if delta =>
+            {
+                changed.lock().unwrap().insert(client);
+            }
+            Event::ClientErased { client } => {
+                erased.lock().unwrap().insert(client);
+            }
+            _ => {}
+        }
+    }
+
+    /// Removes the rows of `erased` clients from every snapshot and delta file
+    /// written so far.
+    fn scrub(&self, erased: &HashSet<u16>) -> Result<()> {
+        let entries = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("could not read {}", self.dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            if !name.starts_with("snapshot-") && !name.starts_with("delta-") {
+                continue;
+            }
+            let sealed = name.ends_with(".enc");
+            let mut body = std::fs::read(&path)
+                .with_context(|| format!("could not read {}", path.display()))?;
+            if sealed {
+                let key = self.key.as_ref().with_context(|| {
+                    format!("{} is encrypted, scrubbing it needs the key", path.display())
+                })?;
+                body = key.open(&body)?;
+            }
+
+            let text = String::from_utf8(body)?;
+            let kept: Vec<&str> = text
+                .lines()
+                .filter(|line| {
+                    let client = line.split(',').next().and_then(|c| c.parse().ok());
+                    !client.is_some_and(|c| erased.contains(&c))
+                })
+                .collect();
+            if kept.len() == text.lines().count() {
+                continue;
+            }
+            let mut body = kept.join("\n").into_bytes();
+            body.push(b'\n');
+            if let Some(key) = self.key.as_ref().filter(|_| sealed) {
+                body = key.seal(&body)?;
+            }
+            // replace atomically so a crash never leaves a half-scrubbed file
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, body)
+                .with_context(|| format!("could not write {}", tmp.display()))?;
+            std::fs::rename(&tmp, &path)
+                .with_context(|| format!("could not replace {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the next snapshot and returns its path.
+    fn write_next(&mut self, engine: &TxEngine) -> Result<PathBuf> {
+        // taken while the caller holds the engine, so no change slips between
+        // the set and the balances
+        let changed = std::mem::take(&mut *self.changed.lock().unwrap());
+        let erased = std::mem::take(&mut *self.erased.lock().unwrap());
+        if !erased.is_empty() {
+            self.scrub(&erased)?;
+        }
+        let full = !self.delta || self.next_id == 1;
+        let mut accounts: Vec<Account> = if full {
+            engine.accounts().cloned().collect()
+        } else {
+            changed.iter().filter_map(|&c| engine.account(c)).cloned().collect()
+        };
+        accounts.sort_unstable_by_key(|a| a.client);
+
+        let mut body = Vec::new();
+        writeln!(body, "client,available,held,total,locked")?;
+        for account in &accounts {
+            writeln!(body, "{}", account.to_csv_line())?;
+        }
+
+        let kind = if full { "snapshot" } else { "delta" };
+        let mut name = format!("{kind}-{:06}.csv", self.next_id);
+        if let Some(key) = &self.key {
+            body = key.seal(&body)?;
+            name.push_str(".enc");
+        }
+        let path = self.dir.join(name);
+        std::fs::write(&path, body).with_context(|| format!("could not write {}", path.display()))?;
+        self.next_id += 1;
+        Ok(path)
+    }
+
+    /// Writes a snapshot every interval, and a last one once the server has
+    /// drained, returning after it.
+    pub(crate) async fn run(
+        mut self,
+        engine: Arc<tokio::sync::Mutex<TxEngine>>,
+        mut draining: Draining,
+    ) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("could not create {}", self.dir.display()))?;
+        let mut ticker = tokio::time::interval(self.every);
+        ticker.tick().await;
+        loop {
+            let last = tokio::select! {
+                _ = ticker.tick() => false,
+                _ = draining.finished() => true,
+            };
+            let engine = engine.lock().await;
+            let path = self.write_next(&engine)?;
+            if last {
+                log::info!("drain: wrote final snapshot {}", path.display());
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_snapshots_hold_changed_accounts_only() {
+        let dir = std::env::temp_dir().join(format!("roinstxs-snapshots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut snapshots = Snapshots {
+            every: Duration::from_secs(1),
+            dir: dir.clone(),
+            delta: true,
+            next_id: 1,
+            changed: Arc::default(),
+            erased: Arc::default(),
+            key: None,
+        };
+        let mut engine = TxEngine::new();
+        engine.subscribe(snapshots.observer());
+
+        let apply = |engine: &mut TxEngine, lines: &[&str]| {
+            for line in lines {
+                engine.apply_line(line);
+            }
+        };
+        let rows = |path: PathBuf| -> Vec<String> {
+            let body = std::fs::read_to_string(&path).unwrap();
+            body.lines().skip(1).map(str::to_owned).collect()
+        };
+
+        apply(&mut engine, &["deposit, 1, 1, 5.0", "deposit, 2, 2, 3.0"]);
+        let first = snapshots.write_next(&engine).unwrap();
+        assert!(first.ends_with("snapshot-000001.csv"));
+        assert_eq!(rows(first).len(), 2);
+
+        assert!(rows(snapshots.write_next(&engine).unwrap()).is_empty());
+
+        apply(&mut engine, &["withdrawal, 2, 3, 1.0", "withdrawal, 1, 4, 50.0"]);
+        let third = snapshots.write_next(&engine).unwrap();
+        assert!(third.ends_with("delta-000003.csv"));
+        assert_eq!(rows(third), ["2,2,0,2,false"]);
+
+        engine.erase_client(2);
+        snapshots.write_next(&engine).unwrap();
+        assert_eq!(rows(dir.join("snapshot-000001.csv")), ["1,5,0,5,false"]);
+        assert!(rows(dir.join("delta-000003.csv")).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+//! Differences between two account snapshots, for validating upgrades and
+//! incremental runs.
+//!
+//! `snapshot-diff A B` reads two full snapshots, or summaries, sealed ones
+//! (`.enc`) given the encryption key, and prints a CSV row of
+//! `client,field,before,after` for every difference larger than
+//! `--tolerance`. Accounts present in only one file are reported with field
+//! `account`. Engine snapshots (see `TxEngine::snapshot`) record the open
+//! disputes, so between two of them `disputes` lists the disputed transaction
+//! ids of a client, or `none`, when they changed; a chargeback also flips
+//! `locked`. Account snapshots and summaries hold balances only, funds held
+//! by a dispute and by an administrative hold looking alike, so their dispute
+//! state is not compared. Balances and disputes are compared per currency,
+//! the rows then having a `currency` column after the client. Any difference
+//! makes the run exit with the mismatch code.
+
+use crate::amount::Amount;
+use crate::crypt::KeyArgs;
+use crate::engine::{Account, TxEngine, NO_CURRENCY};
+use crate::exit::Failure;
+use crate::reconcile::parse_expected;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Args)]
+pub(crate) struct SnapshotDiffArgs {
+    /// Snapshot before, e.g. taken by the previous version
+    before: PathBuf,
+    /// Snapshot after
+    after: PathBuf,
+    /// Largest absolute difference between amounts still counted as a match
+    #[arg(long, default_value = "0.0001")]
+    tolerance: Amount,
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Difference {
+    client: u16,
+    currency: Box<str>,
+    field: &'static str,
+    before: String,
+    after: String,
+}
+
+/// Open disputes by client and currency of the disputed transaction.
+pub(crate) type Disputes = BTreeMap<u16, BTreeMap<Box<str>, BTreeSet<u32>>>;
+
+/// The accounts of a snapshot or summary, and the open disputes when it is an
+/// engine snapshot.
+pub(crate) struct Snapshot {
+    pub(crate) accounts: BTreeMap<u16, Account>,
+    pub(crate) disputes: Option<Disputes>,
+}
+
+impl Snapshot {
+    fn parse(body: &str) -> Result<Self> {
+        if !body.trim_start().starts_with('{') {
+            let accounts = parse_expected(body)?;
+            let disputes = None;
+            return Ok(Self { accounts, disputes });
+        }
+        let mut engine = TxEngine::new();
+        engine.restore(body.as_bytes()).context(Failure::Parse)?;
+        let accounts = engine.accounts().map(|a| (a.client, a.clone())).collect();
+        let mut disputes = Disputes::new();
+        for tx in engine.disputes() {
+            let currency = tx.currency().unwrap_or(NO_CURRENCY);
+            let open = disputes.entry(tx.client()).or_default();
+            open.entry(currency.into()).or_default().insert(tx.tx_id());
+        }
+        let disputes = Some(disputes);
+        Ok(Self { accounts, disputes })
+    }
+}
+
+fn open_disputes(ids: Option<&BTreeSet<u32>>) -> String {
+    match ids.filter(|ids| !ids.is_empty()) {
+        Some(ids) => ids.iter().map(u32::to_string).collect::<Vec<_>>().join(" "),
+        None => "none".to_owned(),
+    }
+}
+
+/// Differences between the open disputes of two engine snapshots.
+pub(crate) fn diff_disputes(before: &Disputes, after: &Disputes) -> Vec<Difference> {
+    let mut found = Vec::new();
+    let none = BTreeMap::new();
+    let clients: BTreeSet<u16> = before.keys().chain(after.keys()).copied().collect();
+    for client in clients {
+        let b = before.get(&client).unwrap_or(&none);
+        let a = after.get(&client).unwrap_or(&none);
+        let currencies: BTreeSet<&Box<str>> = b.keys().chain(a.keys()).collect();
+        for currency in currencies {
+            let before = open_disputes(b.get(currency));
+            let after = open_disputes(a.get(currency));
+            if before != after {
+                found.push(Difference {
+                    client,
+                    currency: currency.clone(),
+                    field: "disputes",
+                    before,
+                    after,
+                });
+            }
+        }
+    }
+    found
+}
+
+pub(crate) fn diff(
+    before: &BTreeMap<u16, Account>,
+    after: &BTreeMap<u16, Account>,
+    tolerance: Amount,
+) -> Vec<Difference> {
+    let mut found = Vec::new();
+    let clients: BTreeSet<u16> = before.keys().chain(after.keys()).copied().collect();
+    for client in clients {
+        let (b, a) = match (before.get(&client), after.get(&client)) {
+            (Some(b), Some(a)) => (b, a),
+            (b, _) => {
+                let state = |present: bool| match present {
+                    true => "present".to_owned(),
+                    false => "missing".to_owned(),
+                };
+                found.push(Difference {
+                    client,
+                    currency: NO_CURRENCY.into(),
+                    field: "account",
+                    before: state(b.is_some()),
+                    after: state(b.is_none()),
+                });
+                continue;
+            }
+        };
+        let (b_parts, a_parts) = (b.currency_balances(), a.currency_balances());
+        let currencies: BTreeSet<&Box<str>> = b_parts.keys().chain(a_parts.keys()).collect();
+        for currency in currencies {
+            let b = b_parts.get(currency).copied().unwrap_or_default();
+            let a = a_parts.get(currency).copied().unwrap_or_default();
+            for (field, b, a) in [
+                ("available", b.available, a.available),
+                ("held", b.held, a.held),
+                ("total", b.total, a.total),
+            ] {
+                if (b - a).abs() > tolerance {
+                    found.push(Difference {
+                        client,
+                        currency: currency.clone(),
+                        field,
+                        before: b.to_string(),
+                        after: a.to_string(),
+                    });
+                }
+            }
+        }
+        for (field, b, a) in [
+            ("locked", b.locked.to_string(), a.locked.to_string()),
+            ("closed", b.closed.to_string(), a.closed.to_string()),
+        ] {
+            if b != a {
+                found.push(Difference {
+                    client,
+                    currency: NO_CURRENCY.into(),
+                    field,
+                    before: b,
+                    after: a,
+                });
+            }
+        }
+    }
+    found
+}
+
+/// Reads an engine snapshot, account snapshot or summary, opening it with
+/// `key` when sealed.
+pub(crate) fn read_snapshot(path: &Path, key: &KeyArgs) -> Result<Snapshot> {
+    let mut body =
+        std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+    if path.extension().is_some_and(|e| e == "enc") {
+        let key = key.key()?.with_context(|| {
+            format!("{} is encrypted, reading it needs the key", path.display())
+        })?;
+        body = key
+            .open(&body)
+            .with_context(|| format!("could not decrypt {}", path.display()))?;
+    }
+    let body = String::from_utf8(body)
+        .with_context(|| format!("{} is not valid utf-8", path.display()))
+        .context(Failure::Parse)?;
+    Snapshot::parse(&body).with_context(|| format!("invalid snapshot {}", path.display()))
+}
+
+/// Prints `differences` as CSV, with a `currency` column once one is in a
+/// named currency, failing with the mismatch code if there are any.
+pub(crate) fn report(differences: &[Difference]) -> Result<()> {
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let with_currency = differences.iter().any(|d| &*d.currency != NO_CURRENCY);
+    match with_currency {
+        true => writeln!(out, "client,currency,field,before,after")?,
+        false => writeln!(out, "client,field,before,after")?,
+    }
+    for d in differences {
+        match with_currency {
+            true => writeln!(
+                out,
+                "{},{},{},{},{}",
+                d.client, d.currency, d.field, d.before, d.after
+            )?,
+            false => writeln!(out, "{},{},{},{}", d.client, d.field, d.before, d.after)?,
+        }
+    }
+    out.flush()?;
+
+    if !differences.is_empty() {
+        let mut clients: Vec<u16> = differences.iter().map(|d| d.client).collect();
+        clients.dedup();
+        return Err(anyhow::anyhow!(
+            "{} differences across {} clients",
+            differences.len(),
+            clients.len()
+        ))
+        .context(Failure::Mismatch);
+    }
+    Ok(())
+}
+
+pub(crate) fn run(args: SnapshotDiffArgs) -> Result<()> {
+    let before = read_snapshot(&args.before, &args.key)?;
+    let after = read_snapshot(&args.after, &args.key)?;
+    let mut found = diff(&before.accounts, &after.accounts, args.tolerance);
+    if let (Some(b), Some(a)) = (&before.disputes, &after.disputes) {
+        found.extend(diff_disputes(b, a));
+        // stable, keeping each client's rows in field order
+        found.sort_by_key(|d| d.client);
+    }
+    report(&found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_reports_balances_accounts_and_disputes() {
+        let before = parse_expected(
+            "client,available,held,total,locked\n\
+             1,10,0,10,false\n\
+             2,5,0,5,false\n\
+             3,1,0,1,false\n",
+        )
+        .unwrap();
+        let after = parse_expected(
+            "client,available,held,total,locked\n\
+             1,10.0001,0,10,false\n\
+             2,0,5,5,false\n\
+             4,2,0,2,false\n",
+        )
+        .unwrap();
+
+        let found = diff(&before, &after, "0.0001".parse().unwrap());
+        let fields: Vec<(u16, &str)> = found.iter().map(|d| (d.client, d.field)).collect();
+        assert_eq!(
+            fields,
+            [
+                (2, "available"),
+                (2, "held"),
+                (3, "account"),
+                (4, "account")
+            ]
+        );
+        assert_eq!(
+            (found[2].before.as_str(), found[2].after.as_str()),
+            ("present", "missing")
+        );
+        assert_eq!(
+            (found[3].before.as_str(), found[3].after.as_str()),
+            ("missing", "present")
+        );
+    }
+
+    #[test]
+    fn test_diff_reads_disputes_from_engine_snapshots() {
+        let snapshot = |engine: &TxEngine| {
+            let mut body = Vec::new();
+            engine.snapshot(&mut body).unwrap();
+            Snapshot::parse(std::str::from_utf8(&body).unwrap()).unwrap()
+        };
+        let mut engine = TxEngine::new();
+        engine.apply_line("deposit, 1, 1, 10.0");
+        engine.apply_line("deposit, 2, 2, 5.0");
+        let before = snapshot(&engine);
+        assert_eq!(before.disputes.as_ref().map(Disputes::len), Some(0));
+
+        // held funds alike, but only the dispute is one
+        assert_eq!(engine.apply_line("dispute, 1, 1,"), None);
+        assert_eq!(engine.apply_line("hold, 2, 3, 1.0, legal"), None);
+        let after = snapshot(&engine);
+        let found = diff_disputes(
+            before.disputes.as_ref().unwrap(),
+            after.disputes.as_ref().unwrap(),
+        );
+        assert_eq!(found.len(), 1);
+        let d = &found[0];
+        assert_eq!((d.client, d.field), (1, "disputes"));
+        assert_eq!((d.before.as_str(), d.after.as_str()), ("none", "1"));
+
+        // summaries record no disputes
+        let summary = "client,available,held,total,locked\n1,0,10,10,false\n";
+        assert!(Snapshot::parse(summary).unwrap().disputes.is_none());
+    }
+
+    #[test]
+    fn test_diff_compares_each_currency() {
+        let before = parse_expected(
+            "client,currency,available,held,total,locked\n\
+             1,EUR,10,0,10,false\n\
+             1,USD,5,0,5,false\n",
+        )
+        .unwrap();
+        let after = parse_expected(
+            "client,currency,available,held,total,locked\n\
+             1,EUR,5,0,5,false\n\
+             1,USD,10,0,10,false\n",
+        )
+        .unwrap();
+        assert_eq!(before[&1].total, after[&1].total);
+
+        // the same total moved between currencies
+        let found = diff(&before, &after, Amount::ZERO);
+        let fields: Vec<(&str, &str)> = found.iter().map(|d| (&*d.currency, d.field)).collect();
+        assert_eq!(
+            fields,
+            [
+                ("EUR", "available"),
+                ("EUR", "total"),
+                ("USD", "available"),
+                ("USD", "total")
+            ]
+        );
+    }
+}
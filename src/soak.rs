@@ -0,0 +1,190 @@
+//! Long-running soak test of the in-process stream path.
+//!
+//! Synthetic transactions are piped through the same ingestion code the TCP
+//! server uses. At every checkpoint the engine invariants are verified, a full
+//! summary is rendered, and the process resident set size is compared against
+//! the configured bound. Any violation aborts the run with an error.
+
+use crate::csv_stream::ingest_lines;
+use crate::exit::Failure;
+use crate::loadgen::{parse_duration, produce_into, TxGenerator};
+use crate::log;
+use crate::metrics::Metrics;
+use crate::TxEngine;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+#[derive(Debug, Args)]
+pub(crate) struct SoakArgs {
+    /// How long to run, e.g. 30m, 4h
+    #[arg(long, default_value = "1h", value_parser = parse_duration)]
+    duration: Duration,
+    /// Transactions per second to feed the engine
+    #[arg(long, default_value_t = 10_000)]
+    tps: u64,
+    /// Fail once the resident set size exceeds this many MiB
+    #[arg(long, default_value_t = 1024)]
+    max_rss_mb: u64,
+    /// Interval between invariant and memory checkpoints
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    check_every: Duration,
+    /// Seed for the synthetic transaction stream
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// Resident set size of the current process in KiB, where the platform
+/// exposes it.
+fn rss_kib() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+async fn checkpoint(
+    engine: &Mutex<TxEngine>,
+    metrics: &Metrics,
+    args: &SoakArgs,
+    elapsed: Duration,
+) -> Result<()> {
+    let engine = engine.lock().await;
+    engine
+        .check_invariants()
+        .context("invariant violated during soak")
+        .context(Failure::Invariant)?;
+    engine
+        .summarize_accounts(std::io::sink())
+        .context("summary failed during soak")?;
+
+    let retained = engine.retained();
+    let snapshot = metrics.snapshot();
+    let by_type: Vec<String> = snapshot
+        .processed
+        .iter()
+        .map(|(kind, n)| format!("{}={n}", kind.as_str()))
+        .collect();
+    if let Some(reason) = snapshot.recent_rejections.first() {
+        return Err(anyhow::anyhow!(
+            "{} generated lines were rejected, latest: {reason}",
+            snapshot.rejected
+        ))
+        .context(Failure::Invariant);
+    }
+
+    let rss = rss_kib();
+    eprintln!(
+        "[{:>8.0?}] processed={} ({}) accounts={} txs={} disputes={} rss={}",
+        elapsed,
+        snapshot.total_processed(),
+        by_type.join(" "),
+        retained.accounts,
+        retained.txs,
+        retained.disputes,
+        rss.map_or_else(|| "n/a".to_owned(), |kib| format!("{}MiB", kib / 1024)),
+    );
+
+    if let Some(kib) = rss {
+        if kib / 1024 > args.max_rss_mb {
+            return Err(anyhow::anyhow!(
+                "resident set size {}MiB exceeds bound of {}MiB",
+                kib / 1024,
+                args.max_rss_mb
+            ))
+            .context(Failure::Invariant);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn run(args: SoakArgs) -> Result<()> {
+    if rss_kib().is_none() {
+        log::warn!(
+            "resident set size is unavailable on this platform, memory bound is not enforced"
+        );
+    }
+
+    let engine = Arc::new(Mutex::new(TxEngine::new()));
+    let metrics = Arc::new(Metrics::default());
+    let (producer, consumer) = tokio::io::duplex(64 * 1024);
+    let gen = TxGenerator::new(args.seed, 0, 1);
+    let producer = tokio::spawn(produce_into(producer, args.tps, args.duration, gen));
+    let consumer = {
+        let engine = engine.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move { ingest_lines(consumer, &engine, &metrics, None).await })
+    };
+
+    let start = Instant::now();
+    let mut ticker = tokio::time::interval(args.check_every);
+    ticker.tick().await;
+    while !consumer.is_finished() {
+        ticker.tick().await;
+        checkpoint(&engine, &metrics, &args, start.elapsed()).await?;
+    }
+
+    let report = producer.await??;
+    checkpoint(&engine, &metrics, &args, start.elapsed()).await?;
+    println!(
+        "soak passed: {} txs in {:.2?}",
+        report.sent,
+        start.elapsed()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args() -> SoakArgs {
+        SoakArgs {
+            duration: Duration::from_millis(200),
+            tps: 500,
+            max_rss_mb: u64::MAX,
+            check_every: Duration::from_millis(50),
+            seed: 7,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_short_soak_passes() {
+        run(args()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_fails_on_rejections_and_memory() {
+        let engine = Mutex::new(TxEngine::new());
+        let metrics = Metrics::default();
+        checkpoint(&engine, &metrics, &args(), Duration::ZERO)
+            .await
+            .unwrap();
+
+        metrics.record_rejected("deposit, x: invalid client".to_owned());
+        let err = checkpoint(&engine, &metrics, &args(), Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("1 generated lines were rejected"),
+            "{err:#}"
+        );
+        assert!(matches!(err.downcast_ref(), Some(Failure::Invariant)));
+
+        if rss_kib().is_some() {
+            let bounded = SoakArgs {
+                max_rss_mb: 0,
+                ..args()
+            };
+            let metrics = Metrics::default();
+            let err = checkpoint(&engine, &metrics, &bounded, Duration::ZERO)
+                .await
+                .unwrap_err();
+            assert!(
+                format!("{err:#}").contains("exceeds bound of 0MiB"),
+                "{err:#}"
+            );
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Pushing the stream server's metrics to StatsD, for shops that do not
+//! scrape `/api/metrics`.
+//!
+//! With `--statsd HOST:PORT`, every `--statsd-every` the counters of
+//! `/api/metrics` are sent over UDP as StatsD counters holding the increase
+//! since the last push (`processed.<type>`, `rejected`, `accepted.ipv4`,
+//! `accepted.ipv6`, `batches`, `batched`), the current values as gauges
+//! (`connections`, `largest_batch`, `pending`) and the mean time a batch held
+//! the engine lock as a timer (`batch_apply`), every name prefixed with
+//! `--statsd-prefix`. With `--statsd-format dogstatsd` the `--statsd-tag`s are
+//! attached to every metric, and `processed`, `rejected` and `connections`
+//! are also sent per listener, tagged `listener:<url>`. Lines are packed into
+//! datagrams small enough not to be fragmented; a lost datagram loses its
+//! increments, as is usual with StatsD.
+
+use crate::loadgen::parse_duration;
+use crate::log;
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::TxEngine;
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+// payload that fits an Ethernet frame with IPv6 and UDP headers
+const DATAGRAM: usize = 1432;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum StatsdFormat {
+    #[default]
+    Statsd,
+    /// StatsD with Datadog's tag extension
+    Dogstatsd,
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct StatsdArgs {
+    /// Push metrics to the StatsD server at this address, e.g. 127.0.0.1:8125
+    #[arg(long, global = true, value_name = "HOST:PORT")]
+    statsd: Option<String>,
+    /// Prepended to every StatsD metric name
+    #[arg(long, global = true, default_value = "roinstxs.", requires = "statsd")]
+    statsd_prefix: String,
+    /// How often metrics are pushed to StatsD
+    #[arg(long, global = true, value_parser = parse_duration, default_value = "10s", requires = "statsd")]
+    statsd_every: Duration,
+    #[arg(long, global = true, value_enum, default_value_t, requires = "statsd")]
+    statsd_format: StatsdFormat,
+    /// Tag attached to every metric, e.g. env:prod; repeat to attach several
+    #[arg(
+        long = "statsd-tag",
+        global = true,
+        value_name = "KEY:VALUE",
+        requires = "statsd"
+    )]
+    statsd_tags: Vec<String>,
+}
+
+pub(crate) struct Exporter {
+    addr: String,
+    every: Duration,
+    prefix: String,
+    format: StatsdFormat,
+    tags: Vec<String>,
+}
+
+impl Exporter {
+    pub(crate) fn new(args: &StatsdArgs) -> Result<Option<Self>> {
+        let Some(addr) = &args.statsd else {
+            return Ok(None);
+        };
+        if !args.statsd_tags.is_empty() && args.statsd_format != StatsdFormat::Dogstatsd {
+            anyhow::bail!("--statsd-tag needs --statsd-format dogstatsd");
+        }
+        Ok(Some(Self {
+            addr: addr.clone(),
+            every: args.statsd_every,
+            prefix: args.statsd_prefix.clone(),
+            format: args.statsd_format,
+            tags: args.statsd_tags.clone(),
+        }))
+    }
+
+    fn line(
+        &self,
+        lines: &mut Vec<String>,
+        name: &str,
+        value: impl ToString,
+        kind: &str,
+        tag: Option<&str>,
+    ) {
+        let mut line = format!("{}{name}:{}|{kind}", self.prefix, value.to_string());
+        let tags: Vec<&str> = self.tags.iter().map(String::as_str).chain(tag).collect();
+        if self.format == StatsdFormat::Dogstatsd && !tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        lines.push(line);
+    }
+
+    /// The metrics of the interval from `prev` to `now`, `pending` records
+    /// being parked in the engine.
+    fn lines(&self, prev: &MetricsSnapshot, now: &MetricsSnapshot, pending: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        for ((kind, n), (_, p)) in now.processed.iter().zip(&prev.processed) {
+            let name = format!("processed.{}", kind.as_str());
+            self.line(&mut lines, &name, n.saturating_sub(*p), "c", None);
+        }
+        for (name, n, p) in [
+            ("rejected", now.rejected, prev.rejected),
+            ("accepted.ipv4", now.accepted_ipv4, prev.accepted_ipv4),
+            ("accepted.ipv6", now.accepted_ipv6, prev.accepted_ipv6),
+            ("batches", now.batches, prev.batches),
+            ("batched", now.batched, prev.batched),
+        ] {
+            self.line(&mut lines, name, n.saturating_sub(p), "c", None);
+        }
+        self.line(&mut lines, "connections", now.connections, "g", None);
+        self.line(&mut lines, "largest_batch", now.largest_batch, "g", None);
+        self.line(&mut lines, "pending", pending, "g", None);
+        let batches = now.batches.saturating_sub(prev.batches);
+        if batches > 0 {
+            let took = now.batch_time.saturating_sub(prev.batch_time) / batches as u32;
+            self.line(
+                &mut lines,
+                "batch_apply",
+                took.as_secs_f64() * 1000.,
+                "ms",
+                None,
+            );
+        }
+
+        if self.format == StatsdFormat::Dogstatsd {
+            let empty = MetricsSnapshot::default();
+            for (i, (listener, now)) in now.listeners.iter().enumerate() {
+                let prev = prev.listeners.get(i).map_or(&empty, |(_, p)| p);
+                let tag = format!("listener:{listener}");
+                let processed = now.total_processed().saturating_sub(prev.total_processed());
+                self.line(&mut lines, "listener.processed", processed, "c", Some(&tag));
+                let rejected = now.rejected.saturating_sub(prev.rejected);
+                self.line(&mut lines, "listener.rejected", rejected, "c", Some(&tag));
+                self.line(
+                    &mut lines,
+                    "listener.connections",
+                    now.connections,
+                    "g",
+                    Some(&tag),
+                );
+            }
+        }
+        lines
+    }
+
+    pub(crate) async fn run(
+        self,
+        engine: Arc<Mutex<TxEngine>>,
+        metrics: Arc<Metrics>,
+    ) -> Result<()> {
+        let addr = tokio::net::lookup_host(&self.addr)
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .with_context(|| format!("could not resolve {}", self.addr))?;
+        let local = match addr {
+            std::net::SocketAddr::V4(_) => "0.0.0.0:0",
+            std::net::SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket
+            .connect(addr)
+            .await
+            .with_context(|| format!("could not connect to {addr}"))?;
+
+        let mut ticker = tokio::time::interval(self.every);
+        ticker.tick().await;
+        let mut last = metrics.snapshot();
+        loop {
+            ticker.tick().await;
+            let now = metrics.snapshot();
+            let pending = engine.lock().await.pending().count;
+            for datagram in pack(&self.lines(&last, &now, pending)) {
+                // nobody listening is no reason to stop
+                if let Err(err) = socket.send(datagram.as_bytes()).await {
+                    log::error!("statsd: {addr}: {err}");
+                }
+            }
+            last = now;
+        }
+    }
+}
+
+// newline-separated lines, as many per datagram as fit
+fn pack(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > DATAGRAM {
+            datagrams.push(std::mem::take(&mut datagram));
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        datagrams.push(datagram);
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TxType;
+
+    #[test]
+    fn test_lines_hold_increments_gauges_and_tags() {
+        let exporter = Exporter {
+            addr: "127.0.0.1:8125".to_owned(),
+            every: Duration::from_secs(10),
+            prefix: "tx.".to_owned(),
+            format: StatsdFormat::Dogstatsd,
+            tags: vec!["env:prod".to_owned()],
+        };
+        let metrics = Arc::new(Metrics::default());
+        let listener = metrics.listener("tcp://127.0.0.1:6969".to_owned());
+        listener.record_processed(TxType::Deposit);
+        let prev = metrics.snapshot();
+        listener.record_processed(TxType::Deposit);
+        listener.record_processed(TxType::Deposit);
+        listener.record_batch(2, Duration::from_millis(3));
+        let _connection = listener.connection();
+
+        let lines = exporter.lines(&prev, &metrics.snapshot(), 4);
+        for expected in [
+            "tx.processed.deposit:2|c|#env:prod",
+            "tx.processed.withdrawal:0|c|#env:prod",
+            "tx.connections:1|g|#env:prod",
+            "tx.pending:4|g|#env:prod",
+            "tx.batch_apply:3|ms|#env:prod",
+            "tx.listener.processed:2|c|#env:prod,listener:tcp://127.0.0.1:6969",
+        ] {
+            assert!(
+                lines.iter().any(|l| l == expected),
+                "{expected} not in {lines:?}"
+            );
+        }
+
+        assert_eq!(pack(&lines), [lines.join("\n")]);
+        assert_eq!(pack(&vec!["x".repeat(1000); 3]).len(), 3);
+    }
+}
@@ -0,0 +1,378 @@
+//! Storage of the transactions the engine keeps for later disputes.
+//!
+//! Every deposit and withdrawal is kept by id, so that disputes, resolves,
+//! chargebacks and corrections can find it, and kept for good, the engine
+//! never knowing when a dispute may still come; with `--evict-expired`,
+//! those older than `--dispute-window` are dropped unless disputed. With
+//! `--tx-store memory`, the default, they are held in a map, growing with
+//! every record. `--tx-store compact` keeps only what disputes and
+//! corrections read, the client, type, amount, currency and sub-account,
+//! dropping timestamps, correlation ids and reasons, which then read as
+//! empty wherever stored transactions are shown, e.g. by the HTTP API. With
+//! `--tx-store sled` (`sled` feature) they are kept in a sled database at
+//! `--tx-store-path` instead, memory holding only its page cache, so files
+//! and streams with more records than fit in memory can be processed.
+//! Accounts, open disputes and the timestamps of `--dispute-window` stay in
+//! memory either way.
+//!
+//! The database is cleared on startup, since it is only meaningful next to
+//! the engine that wrote it; snapshots and the write-ahead log carry state
+//! across restarts.
+
+use crate::amount::Amount;
+use crate::engine::{Tx, TxMeta, TxType};
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum StoreKind {
+    #[default]
+    Memory,
+    Compact,
+    #[cfg(feature = "sled")]
+    Sled,
+}
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct StoreArgs {
+    /// Where deposits and withdrawals are kept for later disputes
+    #[arg(long, global = true, value_enum, default_value_t)]
+    tx_store: StoreKind,
+    /// Directory of the on-disk transaction store
+    #[arg(long, global = true, value_name = "PATH")]
+    tx_store_path: Option<PathBuf>,
+}
+
+impl StoreArgs {
+    /// The store `--tx-store` selects, for the engine of `shard` when the
+    /// engine is sharded, see `crate::shard`.
+    #[cfg_attr(not(feature = "sled"), allow(unused_variables))]
+    pub(crate) fn store(&self, shard: Option<usize>) -> Result<Box<dyn TxStore>> {
+        match self.tx_store {
+            StoreKind::Memory => Ok(Box::<MemoryStore>::default()),
+            StoreKind::Compact => Ok(Box::<CompactStore>::default()),
+            #[cfg(feature = "sled")]
+            StoreKind::Sled => {
+                let path = self
+                    .tx_store_path
+                    .as_deref()
+                    .ok_or_else(|| anyhow::anyhow!("--tx-store sled needs --tx-store-path"))?;
+                // a database per shard, next to the merged engine's
+                let path = match shard {
+                    Some(shard) => {
+                        let mut name = path.as_os_str().to_owned();
+                        name.push(format!(".{shard}"));
+                        PathBuf::from(name)
+                    }
+                    None => path.to_owned(),
+                };
+                Ok(Box::new(SledStore::open(&path)?))
+            }
+        }
+    }
+}
+
+/// Where the engine keeps transactions by id.
+pub(crate) trait TxStore: Send {
+    fn get(&self, tx_id: u32) -> Result<Option<Tx>>;
+    /// Stores `tx`, replacing any transaction by its id.
+    fn insert(&mut self, tx: Tx) -> Result<()>;
+    fn remove(&mut self, tx_id: u32) -> Result<Option<Tx>>;
+    /// Removes and returns the transactions `pred` holds for.
+    fn extract(&mut self, pred: &mut dyn FnMut(&Tx) -> bool) -> Result<Vec<Tx>>;
+    fn len(&self) -> usize;
+    /// Every stored transaction, in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Tx>> + '_>;
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct MemoryStore(HashMap<u32, Tx>);
+
+impl TxStore for MemoryStore {
+    fn get(&self, tx_id: u32) -> Result<Option<Tx>> {
+        Ok(self.0.get(&tx_id).cloned())
+    }
+
+    fn insert(&mut self, tx: Tx) -> Result<()> {
+        self.0.insert(tx.tx_id(), tx);
+        Ok(())
+    }
+
+    fn remove(&mut self, tx_id: u32) -> Result<Option<Tx>> {
+        Ok(self.0.remove(&tx_id))
+    }
+
+    fn extract(&mut self, pred: &mut dyn FnMut(&Tx) -> bool) -> Result<Vec<Tx>> {
+        Ok(self
+            .0
+            .extract_if(|_, tx| pred(tx))
+            .map(|(_, tx)| tx)
+            .collect())
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Tx>> + '_> {
+        Box::new(self.0.values().cloned().map(Ok))
+    }
+}
+
+// what `CompactStore` keeps of a transaction
+#[derive(Debug)]
+struct CompactTx {
+    client: u16,
+    tx_type: TxType,
+    amount: Option<Amount>,
+    currency: Option<Box<str>>,
+    sub_account: Option<Box<str>>,
+}
+
+impl CompactTx {
+    fn tx(&self, tx_id: u32) -> Tx {
+        Tx::new(self.tx_type.as_str(), self.client, tx_id, self.amount)
+            .expect("type names are never empty")
+            .with_meta(TxMeta {
+                currency: self.currency.clone(),
+                sub_account: self.sub_account.clone(),
+                ..Default::default()
+            })
+    }
+}
+
+/// Transactions reduced to the fields disputes and corrections read.
+#[derive(Debug, Default)]
+pub(crate) struct CompactStore(HashMap<u32, CompactTx>);
+
+impl TxStore for CompactStore {
+    fn get(&self, tx_id: u32) -> Result<Option<Tx>> {
+        Ok(self.0.get(&tx_id).map(|compact| compact.tx(tx_id)))
+    }
+
+    fn insert(&mut self, tx: Tx) -> Result<()> {
+        let compact = CompactTx {
+            client: tx.client(),
+            tx_type: tx.tx_type(),
+            amount: tx.amount(),
+            currency: tx.currency().map(Into::into),
+            sub_account: tx.sub_account().map(Into::into),
+        };
+        self.0.insert(tx.tx_id(), compact);
+        Ok(())
+    }
+
+    fn remove(&mut self, tx_id: u32) -> Result<Option<Tx>> {
+        Ok(self.0.remove(&tx_id).map(|compact| compact.tx(tx_id)))
+    }
+
+    fn extract(&mut self, pred: &mut dyn FnMut(&Tx) -> bool) -> Result<Vec<Tx>> {
+        let extracted = self
+            .0
+            .extract_if(|tx_id, compact| pred(&compact.tx(*tx_id)));
+        Ok(extracted
+            .map(|(tx_id, compact)| compact.tx(tx_id))
+            .collect())
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Tx>> + '_> {
+        Box::new(self.0.iter().map(|(tx_id, compact)| Ok(compact.tx(*tx_id))))
+    }
+}
+
+/// Transactions as JSON values of a sled tree, keyed by big-endian id.
+#[cfg(feature = "sled")]
+pub(crate) struct SledStore {
+    db: sled::Db,
+    // sled counts entries by scanning them
+    len: usize,
+}
+
+#[cfg(feature = "sled")]
+impl SledStore {
+    fn open(path: &std::path::Path) -> Result<Self> {
+        use anyhow::Context;
+
+        let db = sled::open(path).with_context(|| format!("could not open {}", path.display()))?;
+        db.clear()
+            .with_context(|| format!("could not clear {}", path.display()))?;
+        Ok(Self { db, len: 0 })
+    }
+}
+
+#[cfg(feature = "sled")]
+impl TxStore for SledStore {
+    fn get(&self, tx_id: u32) -> Result<Option<Tx>> {
+        match self.db.get(tx_id.to_be_bytes())? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, tx: Tx) -> Result<()> {
+        let value = serde_json::to_vec(&tx)?;
+        if self.db.insert(tx.tx_id().to_be_bytes(), value)?.is_none() {
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, tx_id: u32) -> Result<Option<Tx>> {
+        match self.db.remove(tx_id.to_be_bytes())? {
+            Some(value) => {
+                self.len -= 1;
+                Ok(Some(serde_json::from_slice(&value)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn extract(&mut self, pred: &mut dyn FnMut(&Tx) -> bool) -> Result<Vec<Tx>> {
+        let mut extracted = Vec::new();
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            let tx: Tx = serde_json::from_slice(&value)?;
+            if pred(&tx) {
+                self.db.remove(key)?;
+                self.len -= 1;
+                extracted.push(tx);
+            }
+        }
+        Ok(extracted)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Tx>> + '_> {
+        Box::new(self.db.iter().map(|entry| {
+            let (_, value) = entry?;
+            Ok(serde_json::from_slice(&value)?)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store_keeps_and_extracts() {
+        let mut store = MemoryStore::default();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "withdrawal, 1, 3, 2.0",
+        ] {
+            store.insert(line.parse().unwrap()).unwrap();
+        }
+        assert_eq!(store.get(2).unwrap().unwrap().client(), 2);
+        let extracted = store.extract(&mut |tx| tx.client() == 1).unwrap();
+        assert_eq!(extracted.len(), 2);
+        assert_eq!(store.len(), 1);
+        assert!(store.get(1).unwrap().is_none());
+    }
+
+    // a store that takes no more than `capacity` transactions
+    #[derive(Default)]
+    struct FullStore {
+        store: MemoryStore,
+        capacity: usize,
+    }
+
+    impl TxStore for FullStore {
+        fn get(&self, tx_id: u32) -> Result<Option<Tx>> {
+            self.store.get(tx_id)
+        }
+
+        fn insert(&mut self, tx: Tx) -> Result<()> {
+            anyhow::ensure!(self.store.len() < self.capacity, "store full");
+            self.store.insert(tx)
+        }
+
+        fn remove(&mut self, tx_id: u32) -> Result<Option<Tx>> {
+            self.store.remove(tx_id)
+        }
+
+        fn extract(&mut self, pred: &mut dyn FnMut(&Tx) -> bool) -> Result<Vec<Tx>> {
+            self.store.extract(pred)
+        }
+
+        fn len(&self) -> usize {
+            self.store.len()
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = Result<Tx>> + '_> {
+            self.store.iter()
+        }
+    }
+
+    #[test]
+    fn test_records_the_store_refuses_are_not_applied() {
+        use crate::{EngineError, TxEngine};
+
+        let mut engine = TxEngine::new();
+        engine.set_store(Box::new(FullStore {
+            capacity: 1,
+            ..Default::default()
+        }));
+        engine
+            .process_tx("deposit, 1, 1, 10.0".parse().unwrap())
+            .unwrap();
+        for line in ["deposit, 1, 2, 5.0", "withdrawal, 1, 3, 5.0"] {
+            assert!(matches!(
+                engine.process_tx(line.parse().unwrap()),
+                Err(EngineError::Store(_))
+            ));
+        }
+        assert_eq!(
+            engine.account(1).unwrap().available(),
+            Amount::from_units(10)
+        );
+
+        // nor can another engine's state move in
+        let mut other = TxEngine::new();
+        for line in ["deposit, 2, 4, 1.0", "deposit, 2, 5, 1.0"] {
+            other.process_tx(line.parse().unwrap()).unwrap();
+        }
+        assert!(engine.restore_state(other.take_state().unwrap()).is_err());
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_store_backs_disputes() {
+        use crate::amount::Amount;
+        use crate::TxEngine;
+
+        let path = std::env::temp_dir().join(format!("roinstxs-store-{}", std::process::id()));
+        let mut engine = TxEngine::new();
+        engine.set_store(Box::new(SledStore::open(&path).unwrap()));
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 5.0",
+            "dispute, 1, 2,",
+            "correction, 1, 1, 8.0",
+        ] {
+            engine.process_tx(line.parse().unwrap()).unwrap();
+        }
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available(), Amount::from_units(8));
+        assert_eq!(account.held(), Amount::from_units(5));
+        assert_eq!(
+            engine.transaction(1).unwrap().unwrap().tx.amount(),
+            Some(Amount::from_units(8))
+        );
+        assert_eq!(engine.retained().txs, 2);
+        engine.erase_client(1);
+        assert_eq!(engine.retained().txs, 0);
+        drop(engine);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+//! Summary rows rendered from a user template, for `--format template`.
+//!
+//! A template is literal text with `{{field}}` placeholders, e.g.
+//! `{{client}}|{{total}}|{{locked}}`, rendered once per account. The fields
+//! are those of the CSV summary, `client`, `available`, `held`, `total`,
+//! `locked` and `closed`, plus `admin_held`, `currency`, rendered once per
+//! currency when a record named one, and, with `--sub-accounts`,
+//! `sub_account`. Values are written as in the CSV summary. Unknown fields
+//! and unclosed placeholders are rejected when the command line is parsed.
+
+use crate::engine::Account;
+use anyhow::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Client,
+    SubAccount,
+    Currency,
+    Available,
+    Held,
+    Total,
+    Locked,
+    Closed,
+    AdminHeld,
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "client" => Field::Client,
+            "sub_account" => Field::SubAccount,
+            "currency" => Field::Currency,
+            "available" => Field::Available,
+            "held" => Field::Held,
+            "total" => Field::Total,
+            "locked" => Field::Locked,
+            "closed" => Field::Closed,
+            "admin_held" => Field::AdminHeld,
+            _ => anyhow::bail!("unknown template field {name:?}"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Template(Vec<Segment>);
+
+impl Template {
+    pub(crate) fn parse(s: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| anyhow::anyhow!("unclosed `{{{{` in template"))?;
+            let name = rest[start + 2..start + end].trim();
+            segments.push(Segment::Field(Field::parse(name)?));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_owned()));
+        }
+        Ok(Self(segments))
+    }
+
+    /// The row of `account`, whose client is written as `client`,
+    /// sub-account, when summarized per sub-account, as `sub_account` and
+    /// currency, when summarized per currency, as `currency`.
+    pub(crate) fn render(
+        &self,
+        client: &str,
+        sub_account: Option<&str>,
+        currency: Option<&str>,
+        account: &Account,
+    ) -> String {
+        let mut row = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Text(text) => row.push_str(text),
+                Segment::Field(field) => row.push_str(&match field {
+                    Field::Client => client.to_owned(),
+                    Field::SubAccount => sub_account.unwrap_or_default().to_owned(),
+                    Field::Currency => currency.unwrap_or_default().to_owned(),
+                    Field::Available => account.available.to_string(),
+                    Field::Held => account.held.to_string(),
+                    Field::Total => account.total.to_string(),
+                    Field::Locked => account.locked.to_string(),
+                    Field::Closed => account.closed.to_string(),
+                    Field::AdminHeld => account.admin_held.to_string(),
+                }),
+            }
+        }
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+
+    #[test]
+    fn test_parse_and_render() {
+        let template = Template::parse("{{client}}|{{ total }}|{{locked}}!").unwrap();
+        let account = Account {
+            client: 7,
+            available: "1.5".parse::<Amount>().unwrap(),
+            held: Amount::from_units(1),
+            total: "2.5".parse::<Amount>().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(template.render("7", None, None, &account), "7|2.5|false!");
+
+        assert!(Template::parse("{{client}}|{{balance}}").is_err());
+        assert!(Template::parse("{{client").is_err());
+    }
+}
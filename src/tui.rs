@@ -0,0 +1,215 @@
+//! Terminal dashboard for server mode, enabled with `--tui`.
+//!
+//! Runs on a blocking thread next to the listener and redraws a few times per
+//! second from the shared [`Metrics`] and engine. Press `q` or `Esc` to stop
+//! the dashboard, which also shuts the server down.
+
+use crate::amount::Amount;
+use crate::engine::{Account, TxEngine};
+use crate::metrics::Metrics;
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::widgets::{Block, List, Paragraph, Row, Table};
+use ratatui::Frame;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const REFRESH: Duration = Duration::from_millis(250);
+const TOP_ACCOUNTS: usize = 10;
+
+struct View {
+    rate: f64,
+    counters: Vec<(&'static str, u64)>,
+    rejected: u64,
+    connections: u64,
+    recent_rejections: Vec<String>,
+    top_held: Vec<Account>,
+}
+
+fn top_by_held(engine: &TxEngine) -> Vec<Account> {
+    let mut accounts: Vec<&Account> = engine
+        .accounts()
+        .filter(|a| a.held > Amount::ZERO)
+        .collect();
+    accounts.sort_unstable_by_key(|a| std::cmp::Reverse(a.held));
+    accounts.into_iter().take(TOP_ACCOUNTS).cloned().collect()
+}
+
+fn draw(frame: &mut Frame, view: &View) {
+    let [header, body] =
+        Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(frame.area());
+    let [left, right] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(body);
+    let [counters, rejections] =
+        Layout::vertical([Constraint::Length(8), Constraint::Min(0)]).areas(left);
+
+    let summary = format!(
+        "ingest {:.0} tx/s   connections {}   rejected {}   (q to quit)",
+        view.rate, view.connections, view.rejected
+    );
+    frame.render_widget(
+        Paragraph::new(summary).block(Block::bordered().title(" roinstxs ")),
+        header,
+    );
+
+    let rows = view
+        .counters
+        .iter()
+        .map(|(kind, n)| Row::new([kind.to_string(), n.to_string()]));
+    frame.render_widget(
+        Table::new(rows, [Constraint::Length(12), Constraint::Min(8)])
+            .block(Block::bordered().title(" processed by type ")),
+        counters,
+    );
+
+    frame.render_widget(
+        List::new(view.recent_rejections.iter().map(String::as_str))
+            .style(Style::default().fg(Color::Yellow))
+            .block(Block::bordered().title(" recent rejections ")),
+        rejections,
+    );
+
+    let rows = view.top_held.iter().map(|a| {
+        let row = Row::new([
+            a.client.to_string(),
+            a.held.to_string(),
+            a.available.to_string(),
+            a.total.to_string(),
+        ]);
+        if a.locked {
+            row.red()
+        } else {
+            row
+        }
+    });
+    frame.render_widget(
+        Table::new(
+            rows,
+            [Constraint::Length(8); 4].map(|_| Constraint::Fill(1)),
+        )
+        .header(Row::new(["client", "held", "available", "total"]).bold())
+        .block(Block::bordered().title(" top accounts by held ")),
+        right,
+    );
+}
+
+fn dashboard(engine: &Mutex<TxEngine>, metrics: &Metrics) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let mut last = (Instant::now(), metrics.snapshot().total_processed());
+
+    let result = loop {
+        let snapshot = metrics.snapshot();
+        let now = Instant::now();
+        let total = snapshot.total_processed();
+        let rate = (total - last.1) as f64 / now.duration_since(last.0).as_secs_f64().max(1e-3);
+        last = (now, total);
+
+        let view = View {
+            rate,
+            counters: snapshot
+                .processed
+                .iter()
+                .map(|(kind, n)| (kind.as_str(), *n))
+                .collect(),
+            rejected: snapshot.rejected,
+            connections: snapshot.connections,
+            recent_rejections: snapshot.recent_rejections,
+            top_held: top_by_held(&engine.blocking_lock()),
+        };
+        if let Err(err) = terminal.draw(|frame| draw(frame, &view)) {
+            break Err(err.into());
+        }
+
+        match event::poll(REFRESH) {
+            Ok(true) => match event::read() {
+                Ok(Event::Key(key)) if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) => {
+                    break Ok(());
+                }
+                Ok(_) => {}
+                Err(err) => break Err(err.into()),
+            },
+            Ok(false) => {}
+            Err(err) => break Err(err.into()),
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+/// Shows the dashboard until the user quits.
+pub(crate) async fn run(engine: Arc<Mutex<TxEngine>>, metrics: Arc<Metrics>) -> Result<()> {
+    tokio::task::spawn_blocking(move || dashboard(&engine, &metrics)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Tx;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    fn engine(lines: &[&str]) -> TxEngine {
+        let mut engine = TxEngine::new();
+        for line in lines {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        engine
+    }
+
+    // the text of the cells of a drawn row
+    fn cells(row: &str) -> Vec<&str> {
+        row.split(['│', ' ']).filter(|c| !c.is_empty()).collect()
+    }
+
+    #[test]
+    fn test_top_accounts_by_held_only() {
+        let mut lines = vec!["deposit, 1, 1, 100.0"];
+        let deposits: Vec<String> = (2..=13)
+            .map(|c| format!("deposit, {c}, {c}, {c}.0"))
+            .collect();
+        let disputes: Vec<String> = (2..=13).map(|c| format!("dispute, {c}, {c},")).collect();
+        lines.extend(deposits.iter().map(String::as_str));
+        lines.extend(disputes.iter().map(String::as_str));
+
+        let top: Vec<u16> = top_by_held(&engine(&lines))
+            .iter()
+            .map(Account::client)
+            .collect();
+        // client 1 holds nothing, and only the first ten make it
+        assert_eq!(top, [13, 12, 11, 10, 9, 8, 7, 6, 5, 4]);
+    }
+
+    #[test]
+    fn test_draws_counters_rejections_and_accounts() {
+        let engine = engine(&["deposit, 7, 1, 30.0", "dispute, 7, 1,"]);
+        let view = View {
+            rate: 12.0,
+            counters: vec![("deposit", 1), ("dispute", 1)],
+            rejected: 1,
+            connections: 2,
+            recent_rejections: vec!["withdrawal, x: invalid client".to_owned()],
+            top_held: top_by_held(&engine),
+        };
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        terminal.draw(|frame| draw(frame, &view)).unwrap();
+
+        let buffer = terminal.backend().buffer();
+        let rows: Vec<String> = buffer
+            .content()
+            .chunks(usize::from(buffer.area.width))
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+        let shows = |text: &str| rows.iter().any(|row| row.contains(text));
+        assert!(shows("ingest 12 tx/s   connections 2   rejected 1"));
+        assert!(shows("withdrawal, x: invalid client"));
+        // client, held, available and total of the disputed account
+        let account = ["7", "30", "0", "30"];
+        assert!(
+            rows.iter().any(|row| cells(row).ends_with(&account)),
+            "{rows:#?}"
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! Write-ahead log of the stream server, for recovering from crashes.
+//!
+//! With `--wal PATH` every record is appended to PATH as a JSON line before
+//! the engine applies it, whichever listener, source or API it came from, and
+//! so are admin locks and unlocks. On startup the server replays the log into the engine
+//! before it accepts anything, so a crash loses no record that reached the
+//! engine and producers need not send history again. Each line goes out in
+//! one write, surviving a crash of the process; with `--wal-fsync` it is also
+//! synced to disk before the record is applied, surviving power loss at the
+//! cost of throughput. A record that cannot be logged is not applied.
+//!
+//! Once `--snapshot-path` is written the log is emptied, the snapshot holding
+//! everything in it, so a server restarting from that file with
+//! `--restore-from` only replays what came after. Erasing a client rewrites
+//! the log without its entries. A partial last line, left by a crash in the
+//! middle of a write, is dropped on startup. Replay reads the engine clock
+//! anew, so dispute windows and future-dated records are judged as of the
+//! restart, and emit no events, their events having gone out before the
+//! crash.
+//!
+//! With an encryption key, see `crypt`, every line is sealed on its own and
+//! written as hex, so the log holds no client, amount or record in the clear
+//! and a partial last line is still found by its missing newline. A log
+//! written without the key cannot be replayed with it, nor the other way
+//! round.
+
+use crate::crypt::Key;
+use crate::engine::{Tx, TxEngine};
+use crate::log;
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct WalArgs {
+    /// Append every record to this write-ahead log before applying it, and
+    /// replay the log on startup
+    #[arg(long, global = true, value_name = "PATH")]
+    wal: Option<PathBuf>,
+    /// Sync the write-ahead log to disk after every record
+    #[arg(long, global = true, requires = "wal")]
+    wal_fsync: bool,
+}
+
+impl WalArgs {
+    /// Replays the log of `--wal` into `engine`, then has the engine log to
+    /// it, sealing its lines with `key` if any.
+    pub(crate) fn recover(&self, engine: &mut TxEngine, key: Option<&Key>) -> Result<()> {
+        let Some(path) = &self.wal else {
+            return Ok(());
+        };
+        let (wal, entries) = Wal::open(path.clone(), self.wal_fsync, key.cloned())?;
+        let replayed = entries.len();
+        engine.unobserved(|engine| -> Result<()> {
+            for entry in entries {
+                match entry {
+                    Entry::Record { tx } => {
+                        // refused as before the crash, and logged again
+                        let _ = engine.process_tx(tx);
+                    }
+                    Entry::Lock { client } => {
+                        engine.lock_account(client);
+                    }
+                    Entry::Unlock { client } => {
+                        engine.unlock_account(client);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        if replayed > 0 {
+            log::info!("wal: replayed {replayed} entries from {}", path.display());
+        }
+        engine.set_wal(wal);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Entry {
+    Record { tx: Tx },
+    Lock { client: u16 },
+    Unlock { client: u16 },
+}
+
+// `Entry` as written, borrowing the record
+#[derive(Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum EntryRef<'a> {
+    Record { tx: &'a Tx },
+    Lock { client: u16 },
+    Unlock { client: u16 },
+}
+
+impl Entry {
+    fn client(&self) -> u16 {
+        match self {
+            Self::Record { tx } => tx.client(),
+            Self::Lock { client } | Self::Unlock { client } => *client,
+        }
+    }
+}
+
+/// The open log, appended to by the engine.
+pub(crate) struct Wal {
+    path: PathBuf,
+    file: File,
+    fsync: bool,
+    key: Option<Key>,
+}
+
+impl Wal {
+    // opens the log at `path`, returning the entries it holds
+    fn open(path: PathBuf, fsync: bool, key: Option<Key>) -> Result<(Self, Vec<Entry>)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        let mut entries = Vec::new();
+        let mut reader = BufReader::new(&file);
+        let (mut line, mut valid) = (String::new(), 0);
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            // a crash cut the last write short; later appends must not
+            // continue it
+            if !line.ends_with('\n') {
+                log::warn!("wal: dropping a partial last line of {}", path.display());
+                file.set_len(valid)?;
+                break;
+            }
+            let entry = decode(key.as_ref(), line.trim_end())
+                .with_context(|| format!("{}: line {}", path.display(), entries.len() + 1))?;
+            entries.push(entry);
+            valid += read as u64;
+        }
+        let wal = Self {
+            path,
+            file,
+            fsync,
+            key,
+        };
+        Ok((wal, entries))
+    }
+
+    fn append(&mut self, entry: &EntryRef) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        if let Some(key) = &self.key {
+            line = hex::encode(key.seal(&line)?).into_bytes();
+        }
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        if self.fsync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Logs a record about to be applied.
+    pub(crate) fn record(&mut self, tx: &Tx) -> Result<()> {
+        self.append(&EntryRef::Record { tx })
+    }
+
+    /// Logs an admin lock about to be applied.
+    pub(crate) fn lock(&mut self, client: u16) -> Result<()> {
+        self.append(&EntryRef::Lock { client })
+    }
+
+    /// Logs an admin unlock about to be applied.
+    pub(crate) fn unlock(&mut self, client: u16) -> Result<()> {
+        self.append(&EntryRef::Unlock { client })
+    }
+
+    /// Rewrites the log without the entries of `client`.
+    pub(crate) fn scrub(&mut self, client: u16) -> Result<()> {
+        let body = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("could not read {}", self.path.display()))?;
+        let mut kept = String::with_capacity(body.len());
+        for line in body.lines() {
+            if decode(self.key.as_ref(), line)?.client() != client {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        // replaced atomically, so a crash leaves either log whole
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, kept).with_context(|| format!("could not write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("could not replace {}", self.path.display()))?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    /// Empties the log, once a snapshot holds everything in it.
+    pub(crate) fn truncate(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+// reads a line as `Wal::append` wrote it
+fn decode(key: Option<&Key>, line: &str) -> Result<Entry> {
+    let Some(key) = key else {
+        return Ok(serde_json::from_str(line)?);
+    };
+    let sealed = hex::decode(line).context("not an encrypted log line")?;
+    Ok(serde_json::from_slice(&key.open(&sealed)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+
+    #[test]
+    fn test_replay_rebuilds_the_engine() {
+        let path = std::env::temp_dir().join(format!("roinstxs-wal-{}", std::process::id()));
+        let args = WalArgs {
+            wal: Some(path.clone()),
+            wal_fsync: true,
+        };
+        let mut engine = TxEngine::new();
+        args.recover(&mut engine, None).unwrap();
+        for line in [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "deposit, 1, 3, 2.0",
+            "dispute, 1, 3,",
+        ] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        engine.lock_account(1);
+        engine.erase_client(2);
+        // a crash in the middle of the next write
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"op":"record","tx":{"#).unwrap();
+
+        let mut restarted = TxEngine::new();
+        args.recover(&mut restarted, None).unwrap();
+        let account = restarted.account(1).unwrap();
+        assert_eq!(account.held, Amount::from_units(2));
+        assert!(account.locked);
+        assert!(restarted.account(2).is_none());
+        restarted
+            .process_tx(Tx::from_str("resolve, 1, 3,").unwrap())
+            .unwrap();
+        let mut again = TxEngine::new();
+        args.recover(&mut again, None).unwrap();
+        assert_eq!(again.account(1).unwrap().held, Amount::ZERO);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sealed_log_holds_no_plaintext() {
+        let path = std::env::temp_dir().join(format!("roinstxs-wal-sealed-{}", std::process::id()));
+        let args = WalArgs {
+            wal: Some(path.clone()),
+            wal_fsync: false,
+        };
+        let key = Key::from_hex(&"11".repeat(32)).unwrap();
+        let mut engine = TxEngine::new();
+        args.recover(&mut engine, Some(&key)).unwrap();
+        for line in ["deposit, 4242, 1, 1234.5678", "deposit, 7, 2, 5.0"] {
+            engine.process_tx(Tx::from_str(line).unwrap()).unwrap();
+        }
+        engine.lock_account(4242);
+        engine.erase_client(7);
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(raw.lines().count(), 2);
+        for plain in ["deposit", "4242", "1234.5678", "record", "lock"] {
+            assert!(!raw.contains(plain), "{plain} in the clear");
+        }
+
+        let mut restarted = TxEngine::new();
+        args.recover(&mut restarted, Some(&key)).unwrap();
+        let account = restarted.account(4242).unwrap();
+        assert_eq!(account.total, "1234.5678".parse().unwrap());
+        assert!(account.locked);
+        assert!(restarted.account(7).is_none());
+
+        let wrong = Key::from_hex(&"22".repeat(32)).unwrap();
+        assert!(args.recover(&mut TxEngine::new(), Some(&wrong)).is_err());
+        assert!(args.recover(&mut TxEngine::new(), None).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
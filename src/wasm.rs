@@ -0,0 +1,220 @@
+//! WebAssembly plugins handling custom transaction types.
+//!
+//! A plugin is a core wasm module bound to one type string with
+//! `--plugin <type>=<path>`. It must export
+//!
+//! ```text
+//! handle(client: i32, tx: i64, amount: f64, has_amount: i32) -> i32
+//! ```
+//!
+//! returning `0` to apply its changes or anything else to reject the record.
+//! The account is reachable only through these imports from module `roinstxs`:
+//!
+//! ```text
+//! available() -> f64    held() -> f64    total() -> f64    locked() -> i32
+//! credit(f64) -> i32    debit(f64) -> i32    hold(f64) -> i32    release(f64) -> i32
+//! lock()
+//! ```
+//!
+//! Mutations return `0` on success and `-1` when refused (locked account,
+//! insufficient funds, negative amount). Amounts are rounded to the engine's
+//! four decimal places. Changes only take effect if `handle`
+//! returns `0`, and every call runs with a fuel budget so a misbehaving plugin
+//! cannot stall the engine.
+
+use crate::amount::Amount;
+use crate::engine::{Account, AccountHandle, Tx, TxHandler};
+use anyhow::{Context, Result};
+use std::path::Path;
+use wasmtime::{Caller, Config, Engine, Linker, Module, Store, TypedFunc};
+
+const FUEL_PER_CALL: u64 = 10_000_000;
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Credit(Amount),
+    Debit(Amount),
+    Hold(Amount),
+    Release(Amount),
+    Lock,
+}
+
+impl Op {
+    fn apply(self, account: &mut AccountHandle<'_>) -> Result<()> {
+        match self {
+            Self::Credit(amount) => account.credit(amount),
+            Self::Debit(amount) => account.debit(amount),
+            Self::Hold(amount) => account.hold(amount),
+            Self::Release(amount) => account.release(amount),
+            Self::Lock => {
+                account.lock();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Per-call state: a working copy of the account the plugin may inspect and
+/// the validated operations to replay on the real account afterwards.
+#[derive(Default)]
+struct HostState {
+    account: Account,
+    overdraft: Amount,
+    ops: Vec<Op>,
+}
+
+impl HostState {
+    fn record(&mut self, op: Op) -> i32 {
+        match op.apply(&mut AccountHandle::new(&mut self.account, self.overdraft)) {
+            Ok(()) => {
+                self.ops.push(op);
+                0
+            }
+            Err(_) => -1,
+        }
+    }
+
+    fn record_amount(&mut self, amount: f64, op: fn(Amount) -> Op) -> i32 {
+        match Amount::from_f64(amount) {
+            Some(amount) => self.record(op(amount)),
+            None => -1,
+        }
+    }
+}
+
+pub(crate) struct WasmHandler {
+    store: Store<HostState>,
+    handle: TypedFunc<(i32, i64, f64, i32), i32>,
+}
+
+fn link(linker: &mut Linker<HostState>) -> Result<()> {
+    linker.func_wrap("roinstxs", "available", |c: Caller<'_, HostState>| {
+        c.data().account.available.to_f64()
+    })?;
+    linker.func_wrap("roinstxs", "held", |c: Caller<'_, HostState>| {
+        c.data().account.held.to_f64()
+    })?;
+    linker.func_wrap("roinstxs", "total", |c: Caller<'_, HostState>| {
+        c.data().account.total.to_f64()
+    })?;
+    linker.func_wrap("roinstxs", "locked", |c: Caller<'_, HostState>| {
+        i32::from(c.data().account.locked)
+    })?;
+    linker.func_wrap("roinstxs", "credit", |mut c: Caller<'_, HostState>, amount: f64| {
+        c.data_mut().record_amount(amount, Op::Credit)
+    })?;
+    linker.func_wrap("roinstxs", "debit", |mut c: Caller<'_, HostState>, amount: f64| {
+        c.data_mut().record_amount(amount, Op::Debit)
+    })?;
+    linker.func_wrap("roinstxs", "hold", |mut c: Caller<'_, HostState>, amount: f64| {
+        c.data_mut().record_amount(amount, Op::Hold)
+    })?;
+    linker.func_wrap("roinstxs", "release", |mut c: Caller<'_, HostState>, amount: f64| {
+        c.data_mut().record_amount(amount, Op::Release)
+    })?;
+    linker.func_wrap("roinstxs", "lock", |mut c: Caller<'_, HostState>| {
+        c.data_mut().record(Op::Lock);
+    })?;
+    Ok(())
+}
+
+impl WasmHandler {
+    /// Compiles and instantiates a plugin from a `.wasm` (or `.wat`) file.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let bytes =
+            std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+        Self::from_bytes(&bytes).with_context(|| format!("invalid plugin {}", path.display()))
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::new(&engine, bytes)?;
+
+        let mut linker = Linker::new(&engine);
+        link(&mut linker)?;
+        let mut store = Store::new(&engine, HostState::default());
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = linker.instantiate(&mut store, &module)?;
+        let handle = instance
+            .get_typed_func(&mut store, "handle")
+            .context("plugin must export `handle(i32, i64, f64, i32) -> i32`")?;
+        Ok(Self { store, handle })
+    }
+}
+
+impl TxHandler for WasmHandler {
+    fn handle(&mut self, tx: &Tx, account: &mut AccountHandle<'_>) -> Result<()> {
+        *self.store.data_mut() = HostState {
+            account: account.account().clone(),
+            overdraft: account.overdraft(),
+            ops: Vec::new(),
+        };
+        self.store.set_fuel(FUEL_PER_CALL)?;
+
+        let args = (
+            i32::from(tx.client()),
+            i64::from(tx.tx_id()),
+            tx.amount().unwrap_or_default().to_f64(),
+            i32::from(tx.amount().is_some()),
+        );
+        let status = self.handle.call(&mut self.store, args)?;
+        anyhow::ensure!(status == 0, "plugin rejected {} with status {status}", tx.type_name());
+
+        for op in std::mem::take(&mut self.store.data_mut().ops) {
+            op.apply(account)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a `--plugin` value of the form `<type>=<path>`.
+pub(crate) fn parse_plugin_arg(s: &str) -> Result<(String, std::path::PathBuf)> {
+    let (name, path) = s
+        .split_once('=')
+        .context("expected <type>=<path to .wasm>")?;
+    anyhow::ensure!(!name.is_empty(), "plugin type name must not be empty");
+    Ok((name.to_owned(), path.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TxEngine;
+
+    // credits twice the amount, refuses records without one
+    const BONUS: &str = r#"
+        (module
+          (import "roinstxs" "credit" (func $credit (param f64) (result i32)))
+          (func (export "handle") (param i32 i64 f64 i32) (result i32)
+            (if (i32.eqz (local.get 3)) (then (return (i32.const 1))))
+            (call $credit (f64.mul (local.get 2) (f64.const 2)))))
+    "#;
+
+    // holds the amount, then spins forever
+    const RUNAWAY: &str = r#"
+        (module
+          (import "roinstxs" "hold" (func $hold (param f64) (result i32)))
+          (func (export "handle") (param i32 i64 f64 i32) (result i32)
+            (drop (call $hold (local.get 2)))
+            (loop $l (br $l))
+            (i32.const 0)))
+    "#;
+
+    #[test]
+    fn test_plugin_mutates_account_through_host_api() {
+        let mut engine = TxEngine::new();
+        engine.register_handler("bonus", WasmHandler::from_bytes(BONUS.as_bytes()).unwrap());
+        engine.register_handler("spin", WasmHandler::from_bytes(RUNAWAY.as_bytes()).unwrap());
+
+        for line in ["deposit, 1, 1, 10.0", "bonus, 1, 2, 2.5", "bonus, 1, 3,", "spin, 1, 4, 5.0"] {
+            engine.apply_line(line);
+        }
+
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, Amount::from_units(15));
+        assert_eq!(account.held, Amount::ZERO);
+        assert_eq!(account.total, Amount::from_units(15));
+    }
+}
@@ -0,0 +1,92 @@
+//! Webhook channel for account alerts.
+//!
+//! Payloads are POSTed as JSON, retried with exponential backoff on transport
+//! errors and 5xx responses, and signed with HMAC-SHA256
+//! (`X-Roinstxs-Signature: sha256=<hex>`) when a secret is set.
+
+use crate::log;
+use crate::notify::{BoxFuture, Notification, Notifier};
+use anyhow::Result;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+
+const SIGNATURE_HEADER: &str = "x-roinstxs-signature";
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+pub(crate) struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+    secret: Option<String>,
+    attempts: u32,
+}
+
+impl WebhookNotifier {
+    pub(crate) fn new(url: String, secret: Option<String>, attempts: u32) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+            secret,
+            attempts: attempts.max(1),
+        }
+    }
+
+    async fn deliver(&self, body: Vec<u8>) -> Result<()> {
+        let signature = self.secret.as_deref().map(|s| sign(s, &body));
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=self.attempts {
+            let mut request = self
+                .client
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            let failure = match request.send().await {
+                Ok(res) if res.status().is_success() => return Ok(()),
+                Ok(res) if res.status().is_server_error() => format!("status {}", res.status()),
+                Ok(res) => anyhow::bail!("rejected with status {}", res.status()),
+                Err(err) => err.to_string(),
+            };
+            log::warn!("webhook {} attempt {attempt} failed: {failure}", self.url);
+            if attempt < self.attempts {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        anyhow::bail!("giving up after {} attempts", self.attempts)
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> String {
+        format!("webhook {}", self.url)
+    }
+
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.deliver(notification.payload.to_string().into_bytes()))
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}